@@ -3,8 +3,13 @@ use clap::ArgMatches;
 use sage_cloudpath::CloudPath;
 use sage_core::{
     database::{Builder, Parameters},
+    enzyme::{Enzyme, EnzymePreset, ENZYME_PRESETS},
+    fdr::Entrapment,
+    ion_series::IonWeights,
     lfq::LfqSettings,
-    mass::Tolerance,
+    mass::{Adduct, Tolerance},
+    scoring::RankingScore,
+    spectrum::{NoiseFilter, PeakSelection, PrecursorRemoval},
     tmt::Isobaric,
 };
 use serde::{Deserialize, Serialize};
@@ -18,18 +23,45 @@ pub struct Search {
     pub precursor_tol: Tolerance,
     pub fragment_tol: Tolerance,
     pub precursor_charge: (u8, u8),
+    pub precursor_charge_limits: (u8, u8),
     pub isotope_errors: (i8, i8),
+    pub averagine_isotope_errors: bool,
+    pub averagine_precursor_correction: bool,
+    pub isotope_error_penalty: f64,
+    pub precursor_offsets: Vec<f32>,
+    pub precursor_adduct: Adduct,
     pub deisotope: bool,
     pub chimera: bool,
     pub wide_window: bool,
     pub min_peaks: usize,
     pub max_peaks: usize,
+    pub max_chimericity: Option<f32>,
+    pub noise_filter: NoiseFilter,
+    pub remove_precursor: Option<PrecursorRemoval>,
+    pub ion_weights: IonWeights,
+    pub activation_ion_kinds: bool,
+    pub peak_selection: PeakSelection,
+    pub ranking_score: RankingScore,
+    pub cluster_spectra: Option<ClusterSettings>,
     pub max_fragment_charge: Option<u8>,
+    pub exclude_above_precursor_mz: bool,
+    pub dedup_fragment_charges: bool,
+    pub fragment_charge_offset: u8,
+    pub intact_mass_tol: Option<Tolerance>,
+    pub min_scored_fragment_mz: Option<f32>,
     pub min_matched_peaks: u16,
+    pub min_consecutive_ions: u16,
+    pub min_score: Option<f64>,
     pub report_psms: usize,
     pub predict_rt: bool,
+    pub max_delta_ims: Option<f32>,
+    pub redundancy_threshold: Option<usize>,
+    pub database_partitions: usize,
     pub mzml_paths: Vec<String>,
     pub output_paths: Vec<String>,
+    /// Entrapment identifications, if `database.entrapment_tag` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrapment: Option<EntrapmentResults>,
 
     #[serde(skip_serializing)]
     pub output_directory: CloudPath,
@@ -37,6 +69,9 @@ pub struct Search {
     #[serde(skip_serializing)]
     pub write_pin: bool,
 
+    #[serde(skip_serializing)]
+    pub write_peptide_table: bool,
+
     #[serde(skip_serializing)]
     pub annotate_matches: bool,
 }
@@ -52,18 +87,43 @@ pub struct Input {
     wide_window: Option<bool>,
     min_peaks: Option<usize>,
     max_peaks: Option<usize>,
+    max_chimericity: Option<f32>,
+    noise_filter: Option<NoiseFilterOptions>,
+    remove_precursor: Option<PrecursorRemovalOptions>,
+    ion_weights: Option<IonWeights>,
+    activation_ion_kinds: Option<bool>,
+    peak_selection: Option<PeakSelection>,
+    ranking_score: Option<RankingScore>,
+    cluster_spectra: Option<ClusterOptions>,
     max_fragment_charge: Option<u8>,
+    exclude_above_precursor_mz: Option<bool>,
+    dedup_fragment_charges: Option<bool>,
+    fragment_charge_offset: Option<u8>,
+    intact_mass_tol: Option<Tolerance>,
+    min_scored_fragment_mz: Option<f32>,
     min_matched_peaks: Option<u16>,
+    min_consecutive_ions: Option<u16>,
+    min_score: Option<f64>,
     precursor_charge: Option<(u8, u8)>,
+    precursor_charge_limits: Option<(u8, u8)>,
     isotope_errors: Option<(i8, i8)>,
+    averagine_isotope_errors: Option<bool>,
+    averagine_precursor_correction: Option<bool>,
+    isotope_error_penalty: Option<f64>,
+    precursor_offsets: Option<Vec<f32>>,
+    precursor_adduct: Option<Adduct>,
     deisotope: Option<bool>,
     quant: Option<QuantOptions>,
     predict_rt: Option<bool>,
+    max_delta_ims: Option<f32>,
+    redundancy_threshold: Option<usize>,
+    database_partitions: Option<usize>,
     output_directory: Option<String>,
     mzml_paths: Option<Vec<String>>,
 
     annotate_matches: Option<bool>,
     write_pin: Option<bool>,
+    write_peptide_table: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -98,6 +158,72 @@ impl From<LfqOptions> for LfqSettings {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NoiseFilterOptions {
+    min_intensity: Option<f32>,
+    min_base_peak_pct: Option<f32>,
+    min_signal_to_noise: Option<f32>,
+}
+
+impl From<NoiseFilterOptions> for NoiseFilter {
+    fn from(value: NoiseFilterOptions) -> NoiseFilter {
+        let settings = NoiseFilter {
+            min_intensity: value.min_intensity.unwrap_or_default().max(0.0),
+            min_base_peak_pct: value.min_base_peak_pct.unwrap_or_default().max(0.0),
+            min_signal_to_noise: value.min_signal_to_noise.unwrap_or_default().max(0.0),
+        };
+        if settings.min_base_peak_pct >= 100.0 {
+            log::warn!(
+                "noise_filter.min_base_peak_pct is a percentage, only the base peak will be kept"
+            );
+        }
+        settings
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrecursorRemovalOptions {
+    isotopes: Option<u8>,
+    tolerance: Option<Tolerance>,
+}
+
+impl From<PrecursorRemovalOptions> for PrecursorRemoval {
+    fn from(value: PrecursorRemovalOptions) -> PrecursorRemoval {
+        PrecursorRemoval {
+            isotopes: value.isotopes.unwrap_or(2),
+            tolerance: value.tolerance.unwrap_or(Tolerance::Ppm(-20.0, 20.0)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+/// Entrapment identifications at 1% FDR, see [`Entrapment`]
+pub struct EntrapmentResults {
+    pub psms: Entrapment,
+    pub peptides: Entrapment,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ClusterSettings {
+    pub min_similarity: f32,
+    pub precursor_tol: Tolerance,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClusterOptions {
+    min_similarity: Option<f32>,
+    precursor_tol: Option<Tolerance>,
+}
+
+impl From<ClusterOptions> for ClusterSettings {
+    fn from(value: ClusterOptions) -> ClusterSettings {
+        ClusterSettings {
+            min_similarity: value.min_similarity.unwrap_or(0.9).clamp(0.0, 1.0),
+            precursor_tol: value.precursor_tol.unwrap_or(Tolerance::Ppm(-10.0, 10.0)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TmtOptions {
     level: Option<u8>,
@@ -238,7 +364,58 @@ impl Input {
         }
     }
 
+    /// Add the isobaric tag selected in `quant.tmt` as a static modification on
+    /// peptide N-termini and lysines, unless those residues already carry a
+    /// user-specified static or variable modification
+    fn apply_isobaric_tag(&mut self) {
+        let mass = match self
+            .quant
+            .as_ref()
+            .and_then(|q| q.tmt.as_ref())
+            .and_then(|tmt| tmt.modification_mass())
+        {
+            Some(mass) => mass,
+            None => return,
+        };
+
+        let variable_mods = self.database.variable_mods.as_ref();
+        let static_mods = self
+            .database
+            .static_mods
+            .get_or_insert_with(Default::default);
+        for site in ["^", "K"] {
+            if static_mods.contains_key(site)
+                || variable_mods.map(|v| v.contains_key(site)).unwrap_or(false)
+            {
+                continue;
+            }
+            log::warn!(
+                "quant.tmt: adding isobaric tag static modification {}: {} (specify a static or variable modification for \"{}\" to override)",
+                site,
+                mass,
+                site
+            );
+            static_mods.insert(site.into(), mass.into());
+        }
+    }
+
     pub fn build(mut self) -> anyhow::Result<Search> {
+        if let Some(enzyme) = &self.database.enzyme {
+            if let Some(name) = enzyme.name.as_deref() {
+                ensure!(
+                    EnzymePreset::find(name).is_some(),
+                    "unknown `enzyme.name` \"{}\", expected one of: {}",
+                    name,
+                    ENZYME_PRESETS.map(|preset| preset.name).join(", ")
+                );
+            }
+            if let Some(motif) = enzyme.motif.as_deref() {
+                Enzyme::motif(motif, None, true, false)
+                    .with_context(|| format!("invalid `enzyme.motif` \"{}\"", motif))?;
+            }
+        }
+
+        self.apply_isobaric_tag();
         let database = self.database.make_parameters();
 
         Self::check_tolerances(&self.fragment_tol);
@@ -260,6 +437,35 @@ impl Input {
                 std::process::exit(1);
             }
         }
+        if let Some(limits) = self.precursor_charge_limits {
+            if limits.0 > limits.1 {
+                log::error!(
+                    "Precursor charge limits should be specified [low, high], user provided: [{}, {}]",
+                    limits.0,
+                    limits.1
+                );
+                std::process::exit(1);
+            }
+        }
+
+        if let Some(offsets) = &self.precursor_offsets {
+            // PSMs reference offsets by a one-based `u8` index, see `Scorer::precursor_offsets`
+            if offsets.len() > u8::MAX as usize {
+                anyhow::bail!(
+                    "at most {} `precursor_offsets` can be searched, user provided {}",
+                    u8::MAX,
+                    offsets.len()
+                );
+            }
+        }
+        // Peptides reference label channels by a zero-based `u8` index, see `Peptide::label_channel`
+        if database.label_channels.len() > u8::MAX as usize + 1 {
+            anyhow::bail!(
+                "at most {} `database.label_channels` can be searched, user provided {}",
+                u8::MAX as usize + 1,
+                database.label_channels.len()
+            );
+        }
 
         if !self.predict_rt.unwrap_or(true)
             && self.quant.as_ref().and_then(|q| q.lfq).unwrap_or(false)
@@ -293,25 +499,53 @@ impl Input {
             fragment_tol: self.fragment_tol,
             report_psms: self.report_psms.unwrap_or(1),
             max_peaks: self.max_peaks.unwrap_or(150),
+            noise_filter: self.noise_filter.map(Into::into).unwrap_or_default(),
+            remove_precursor: self.remove_precursor.map(Into::into),
+            ion_weights: self.ion_weights.unwrap_or_default(),
+            activation_ion_kinds: self.activation_ion_kinds.unwrap_or(false),
+            peak_selection: self.peak_selection.unwrap_or_default(),
+            ranking_score: self.ranking_score.unwrap_or_default(),
+            cluster_spectra: self.cluster_spectra.map(Into::into),
             min_peaks: self.min_peaks.unwrap_or(15),
+            max_chimericity: self.max_chimericity,
             min_matched_peaks: self.min_matched_peaks.unwrap_or(4),
+            min_consecutive_ions: self.min_consecutive_ions.unwrap_or(0),
+            min_score: self.min_score,
             max_fragment_charge: self.max_fragment_charge,
+            exclude_above_precursor_mz: self.exclude_above_precursor_mz.unwrap_or(false),
+            dedup_fragment_charges: self.dedup_fragment_charges.unwrap_or(false),
+            fragment_charge_offset: self.fragment_charge_offset.unwrap_or(1),
+            intact_mass_tol: self.intact_mass_tol,
+            min_scored_fragment_mz: self.min_scored_fragment_mz,
             annotate_matches: self.annotate_matches.unwrap_or(false),
             precursor_charge: self.precursor_charge.unwrap_or((2, 4)),
+            precursor_charge_limits: self.precursor_charge_limits.unwrap_or((1, u8::MAX)),
             isotope_errors: self.isotope_errors.unwrap_or((0, 0)),
+            averagine_isotope_errors: self.averagine_isotope_errors.unwrap_or(false),
+            averagine_precursor_correction: self.averagine_precursor_correction.unwrap_or(false),
+            isotope_error_penalty: self.isotope_error_penalty.unwrap_or(0.0),
+            precursor_offsets: self.precursor_offsets.unwrap_or_default(),
+            precursor_adduct: self.precursor_adduct.unwrap_or_default(),
             deisotope: self.deisotope.unwrap_or(true),
             chimera: self.chimera.unwrap_or(false),
             wide_window: self.wide_window.unwrap_or(false),
             predict_rt: self.predict_rt.unwrap_or(true),
+            max_delta_ims: self.max_delta_ims,
+            redundancy_threshold: self.redundancy_threshold,
+            database_partitions: self.database_partitions.unwrap_or(1).max(1),
             output_paths: Vec::new(),
+            entrapment: None,
             write_pin: self.write_pin.unwrap_or(false),
+            write_peptide_table: self.write_peptide_table.unwrap_or(false),
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use sage_core::{database::EnzymeBuilder, enzyme::EnzymeParameters};
+    use sage_core::{
+        database::EnzymeBuilder, enzyme::EnzymeParameters, modification::StaticMass, tmt::Isobaric,
+    };
 
     #[test]
     fn deserialize_enzyme_builder() -> Result<(), serde_json::Error> {
@@ -331,4 +565,136 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn deserialize_enzyme_preset() -> Result<(), serde_json::Error> {
+        let digest = |enzyme: serde_json::Value| -> Result<Vec<String>, serde_json::Error> {
+            let mut params: EnzymeParameters =
+                serde_json::from_value::<EnzymeBuilder>(enzyme)?.into();
+            params.min_len = 2;
+            params.missed_cleavages = 0;
+            Ok(params
+                .digest("MAEGKPLSTRPEVIDKAAGR", Default::default())
+                .into_iter()
+                .map(|d| d.sequence)
+                .collect())
+        };
+
+        let a = digest(serde_json::json!({ "name": "Arg-C" }))?;
+        let b = digest(serde_json::json!({ "name": "glu-c", "cleave_at": "K" }))?;
+        let c = digest(serde_json::json!({ "name": "arg-c", "cleave_before_proline": true }))?;
+
+        assert_eq!(a, vec!["MAEGKPLSTRPEVIDKAAGR"]);
+        assert_eq!(b, vec!["MAEGK", "PLSTRPEVIDK", "AAGR"]);
+        assert_eq!(c, vec!["MAEGKPLSTR", "PEVIDKAAGR"]);
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_cleave_before_proline() -> Result<(), serde_json::Error> {
+        let digest = |enzyme: serde_json::Value| -> Result<Vec<String>, serde_json::Error> {
+            let mut params: EnzymeParameters =
+                serde_json::from_value::<EnzymeBuilder>(enzyme)?.into();
+            params.min_len = 2;
+            params.missed_cleavages = 0;
+            Ok(params
+                .digest("MAEGKPLSTRPEVIDKAAGR", Default::default())
+                .into_iter()
+                .map(|d| d.sequence)
+                .collect())
+        };
+
+        let a = digest(serde_json::json!({ "restrict": "P" }))?;
+        let b = digest(serde_json::json!({ "restrict": "P", "cleave_before_proline": true }))?;
+
+        assert_eq!(a, vec!["MAEGKPLSTRPEVIDK", "AAGR"]);
+        assert_eq!(b, vec!["MAEGK", "PLSTR", "PEVIDK", "AAGR"]);
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_tmtpro_aliases() -> Result<(), serde_json::Error> {
+        let a: Isobaric = serde_json::from_str("\"TMTpro16\"")?;
+        let b: Isobaric = serde_json::from_str("\"TmtPro18\"")?;
+        assert_eq!(a, Isobaric::Tmt16);
+        assert_eq!(b, Isobaric::Tmt18);
+        Ok(())
+    }
+
+    #[test]
+    fn isobaric_tag_static_mods() -> Result<(), serde_json::Error> {
+        let mut input: super::Input = serde_json::from_value(serde_json::json!({
+            "database": {
+                "fasta": "none",
+                "static_mods": { "C": 57.0215, "^": 42.0106 },
+            },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+            "quant": { "tmt": "Tmt16" },
+        }))?;
+        input.apply_isobaric_tag();
+
+        let static_mods = input.database.static_mods.unwrap();
+        assert_eq!(
+            static_mods.get("K"),
+            Some(&StaticMass::Single(
+                Isobaric::Tmt16.modification_mass().unwrap()
+            ))
+        );
+        assert_eq!(static_mods.get("^"), Some(&StaticMass::Single(42.0106)));
+        assert_eq!(static_mods.get("C"), Some(&StaticMass::Single(57.0215)));
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_precursor_offsets() -> Result<(), serde_json::Error> {
+        let input: super::Input = serde_json::from_value(serde_json::json!({
+            "database": { "fasta": "none" },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+            "precursor_offsets": vec![1.0; 256],
+        }))?;
+        let err = input.build().err().unwrap();
+        assert!(err.to_string().contains("precursor_offsets"));
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_label_channels() -> Result<(), serde_json::Error> {
+        let input: super::Input = serde_json::from_value(serde_json::json!({
+            "database": {
+                "fasta": "none",
+                "label_channels": vec![serde_json::json!({ "K": 8.0142 }); 257],
+            },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+        }))?;
+        let err = input.build().err().unwrap();
+        assert!(err.to_string().contains("label_channels"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_enzyme_preset() -> Result<(), serde_json::Error> {
+        let input: super::Input = serde_json::from_value(serde_json::json!({
+            "database": { "fasta": "none", "enzyme": { "name": "pepsin" } },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+        }))?;
+        let err = input.build().err().unwrap();
+        assert!(err.to_string().contains("pepsin"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_enzyme_motif() -> Result<(), serde_json::Error> {
+        let input: super::Input = serde_json::from_value(serde_json::json!({
+            "database": { "fasta": "none", "enzyme": { "motif": "(D" } },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+        }))?;
+        let err = input.build().err().unwrap();
+        assert!(err.to_string().contains("enzyme.motif"));
+        Ok(())
+    }
 }
@@ -1,10 +1,10 @@
 use anyhow::Context;
 use clap::{value_parser, Arg, Command, ValueHint};
-use input::{Input, Search};
+use input::{EntrapmentResults, Input, Search};
 use log::info;
 use rayon::prelude::*;
 use sage_cloudpath::CloudPath;
-use sage_core::database::IndexedDatabase;
+use sage_core::database::{PartitionedDatabase, SearchScratch};
 use sage_core::mass::Tolerance;
 use sage_core::scoring::{Feature, Scorer};
 use sage_core::spectrum::{ProcessedSpectrum, SpectrumProcessor};
@@ -16,7 +16,7 @@ mod output;
 mod telemetry;
 
 struct Runner {
-    database: IndexedDatabase,
+    database: PartitionedDatabase,
     parameters: input::Search,
     start: Instant,
 }
@@ -75,13 +75,32 @@ impl Runner {
             )
         })?;
 
-        let database = parameters.database.clone().build(fasta);
-        info!(
-            "generated {} fragments, {} peptides in {}ms",
-            database.fragments.len(),
-            database.peptides.len(),
-            (Instant::now() - start).as_millis()
-        );
+        let mut database = parameters
+            .database
+            .clone()
+            .try_build_partitioned(fasta, parameters.database_partitions)
+            .with_context(|| {
+                format!(
+                    "Failed to build database from `{}`",
+                    parameters.database.fasta
+                )
+            })?;
+        if database.len() == 1 {
+            database.load(0);
+            info!(
+                "generated {} fragments, {} peptides in {}ms",
+                database.fragments.len(),
+                database.peptides.len(),
+                (Instant::now() - start).as_millis()
+            );
+        } else {
+            info!(
+                "generated {} peptides in {}ms, fragments will be generated for {} mass partitions",
+                database.peptides.len(),
+                (Instant::now() - start).as_millis(),
+                database.len()
+            );
+        }
         Ok(Self {
             database,
             parameters,
@@ -110,11 +129,51 @@ impl Runner {
         path
     }
 
+    fn scorer(&self) -> Scorer<'_> {
+        Scorer {
+            db: &self.database,
+            precursor_tol: self.parameters.precursor_tol,
+            fragment_tol: self.parameters.fragment_tol,
+            min_matched_peaks: self.parameters.min_matched_peaks,
+            min_consecutive_ions: self.parameters.min_consecutive_ions,
+            min_score: self.parameters.min_score,
+            min_isotope_err: self.parameters.isotope_errors.0,
+            max_isotope_err: self.parameters.isotope_errors.1,
+            averagine_isotope_errors: self.parameters.averagine_isotope_errors,
+            averagine_precursor_correction: self.parameters.averagine_precursor_correction,
+            isotope_error_penalty: self.parameters.isotope_error_penalty,
+            precursor_offsets: &self.parameters.precursor_offsets,
+            min_precursor_charge: self.parameters.precursor_charge.0,
+            max_precursor_charge: self.parameters.precursor_charge.1,
+            precursor_charge_limits: self.parameters.precursor_charge_limits,
+            max_fragment_charge: self.parameters.max_fragment_charge,
+            exclude_above_precursor_mz: self.parameters.exclude_above_precursor_mz,
+            dedup_fragment_charges: self.parameters.dedup_fragment_charges,
+            fragment_charge_offset: self.parameters.fragment_charge_offset,
+            intact_mass_tol: self.parameters.intact_mass_tol,
+            min_fragment_mass: self.parameters.database.fragment_min_mz,
+            max_fragment_mass: self.parameters.database.fragment_max_mz,
+            min_scored_fragment_mz: self.parameters.min_scored_fragment_mz,
+            chimera: self.parameters.chimera,
+            report_psms: self.parameters.report_psms,
+            ion_weights: self.parameters.ion_weights,
+            activation_ion_kinds: self.parameters.activation_ion_kinds,
+            peak_selection: self.parameters.peak_selection,
+            ranking_score: self.parameters.ranking_score,
+            wide_window: self.parameters.wide_window,
+            annotate_matches: self.parameters.annotate_matches,
+        }
+    }
+
+    /// Search all MS2 spectra against the currently loaded database. If
+    /// `mass_range` is provided, only spectra with a precursor that could
+    /// match a peptide within that range are searched
     fn search_processed_spectra(
         &self,
         scorer: &Scorer,
-        spectra: Vec<ProcessedSpectrum>,
-    ) -> SageResults {
+        spectra: &[ProcessedSpectrum],
+        mass_range: Option<(f32, f32)>,
+    ) -> Vec<Feature> {
         use std::sync::atomic::{AtomicUsize, Ordering};
         let counter = AtomicUsize::new(0);
         let start = Instant::now();
@@ -122,6 +181,19 @@ impl Runner {
         let features: Vec<_> = spectra
             .par_iter()
             .filter(|spec| spec.peaks.len() >= self.parameters.min_peaks && spec.level == 2)
+            .filter(
+                |spec| match (spec.chimericity, self.parameters.max_chimericity) {
+                    (Some(chimericity), Some(max)) => chimericity <= max,
+                    _ => true,
+                },
+            )
+            .filter(|spec| match mass_range {
+                Some((lo, hi)) => {
+                    let (precursor_lo, precursor_hi) = scorer.precursor_mass_bounds(spec);
+                    precursor_hi >= lo && precursor_lo <= hi
+                }
+                None => true,
+            })
             .map(|x| {
                 let prev = counter.fetch_add(1, Ordering::Relaxed);
                 if prev > 0 && prev % 10_000 == 0 {
@@ -132,7 +204,10 @@ impl Runner {
                 }
                 x
             })
-            .flat_map(|spec| scorer.score(spec))
+            .map_init(SearchScratch::default, |scratch, spec| {
+                scorer.score_with(scratch, spec)
+            })
+            .flatten_iter()
             .collect();
 
         let duration = Instant::now().duration_since(start).as_millis() as usize;
@@ -140,31 +215,49 @@ impl Runner {
         let rate = prev * 1000 / (duration + 1);
         log::info!("- search:  {:8} ms ({} spectra/s)", duration, rate);
 
-        let quant = self
-            .parameters
-            .quant
-            .tmt
-            .as_ref()
-            .map(|isobaric| {
-                let level = self.parameters.quant.tmt_settings.level;
-                if level != 2 && level != 3 {
-                    log::warn!("TMT quant level set at {}, is this correct?", level);
-                }
-                sage_core::tmt::quantify(&spectra, isobaric, Tolerance::Ppm(-20.0, 20.0), level)
-            })
-            .unwrap_or_default();
-        let ms1 = spectra.into_iter().filter(|s| s.level == 1).collect();
+        features
+    }
 
-        SageResults {
-            features,
-            quant,
-            ms1,
+    /// Search spectra against each database partition in turn, only keeping
+    /// the fragment index for a single partition in memory
+    fn search_partitions(&mut self, spectra: &[ProcessedSpectrum]) -> Vec<Feature> {
+        if self.database.len() == 1 {
+            return self.search_processed_spectra(&self.scorer(), spectra, None);
+        }
+
+        // Start with the currently loaded partition, if possible, to avoid
+        // rebuilding it for subsequent batches of files
+        let partitions = match self.database.loaded() {
+            Some(idx) if idx + 1 == self.database.len() => {
+                (0..self.database.len()).rev().collect::<Vec<_>>()
+            }
+            _ => (0..self.database.len()).collect(),
+        };
+
+        let mut features = Vec::new();
+        for partition in partitions {
+            let start = Instant::now();
+            self.database.load(partition);
+            let (lo, hi) = self.database.mass_range(partition);
+            info!(
+                "- partition {} ({:.2} - {:.2} Da): generated {} fragments in {}ms",
+                partition,
+                lo,
+                hi,
+                self.database.fragments.len(),
+                (Instant::now() - start).as_millis()
+            );
+            features.extend(self.search_processed_spectra(&self.scorer(), spectra, Some((lo, hi))));
         }
+        sage_core::scoring::merge_psms(
+            features,
+            self.parameters.report_psms,
+            self.parameters.ranking_score,
+        )
     }
 
     fn process_chunk(
-        &self,
-        scorer: &Scorer,
+        &mut self,
         chunk: &[String],
         chunk_idx: usize,
         batch_size: usize,
@@ -195,34 +288,23 @@ impl Runner {
             None => (None, None),
         };
 
-        let sp = SpectrumProcessor::new(
+        let mut sp = SpectrumProcessor::new(
             self.parameters.max_peaks,
             min_fragment_mz.unwrap_or(self.parameters.database.fragment_min_mz),
             self.parameters.database.fragment_max_mz,
             self.parameters.deisotope,
             min_deisotope_mz.unwrap_or(0.0),
         );
+        sp.noise_filter = self.parameters.noise_filter;
+        sp.precursor_removal = self.parameters.remove_precursor;
+        let adduct = self.parameters.precursor_adduct;
 
-        let bruker_extensions = [".d", ".tdf", ".tdf_bin"];
-        let spectra = chunk
+        let mut spectra = chunk
             .par_iter()
             .enumerate()
             .flat_map(|(idx, path)| {
                 let file_id = chunk_idx * batch_size + idx;
-
-                let path_lower = path.to_lowercase();
-                let res = if path_lower.ends_with(".mgf.gz") || path_lower.ends_with(".mgf") {
-                    sage_cloudpath::util::read_mgf(path, file_id)
-                } else if bruker_extensions
-                    .iter()
-                    .any(|ext| path_lower.ends_with(ext))
-                {
-                    sage_cloudpath::util::read_tdf(path, file_id)
-                } else {
-                    sage_cloudpath::util::read_mzml(path, file_id, sn)
-                };
-
-                match res {
+                match sage_cloudpath::util::read_spectra(path, file_id, sn) {
                     Ok(s) => {
                         log::trace!("- {}: read {} spectra", path, s.len());
                         Ok(s)
@@ -234,65 +316,124 @@ impl Runner {
                 }
             })
             .flat_map_iter(|spectra| spectra.into_iter().map(|s| sp.process(s)))
+            .map(|mut spectrum| {
+                spectrum
+                    .precursors
+                    .iter_mut()
+                    .for_each(|precursor| precursor.adduct = adduct);
+                spectrum
+            })
             .collect::<Vec<_>>();
 
         let io_time = Instant::now() - start;
         info!("- file IO: {:8} ms", io_time.as_millis());
 
-        self.search_processed_spectra(scorer, spectra)
+        sage_core::spectrum::assign_chimericity(&mut spectra, self.parameters.precursor_tol);
+
+        let features = match self.parameters.cluster_spectra {
+            Some(settings) => {
+                let start = Instant::now();
+                let clusters = sage_core::cluster::cluster_spectra(
+                    &spectra,
+                    settings.precursor_tol,
+                    self.parameters.fragment_tol,
+                    settings.min_similarity,
+                );
+                let representatives = clusters
+                    .iter()
+                    .map(|cluster| spectra[cluster.representative].clone())
+                    .collect::<Vec<_>>();
+                info!(
+                    "- clustering: {:8} ms ({} clusters)",
+                    (Instant::now() - start).as_millis(),
+                    clusters.len()
+                );
+                let features = self.search_partitions(&representatives);
+                sage_core::cluster::propagate(&clusters, &spectra, features)
+            }
+            None => self.search_partitions(&spectra),
+        };
+
+        let quant = self
+            .parameters
+            .quant
+            .tmt
+            .as_ref()
+            .map(|isobaric| {
+                let level = self.parameters.quant.tmt_settings.level;
+                if level != 2 && level != 3 {
+                    log::warn!("TMT quant level set at {}, is this correct?", level);
+                }
+                sage_core::tmt::quantify(&spectra, isobaric, Tolerance::Ppm(-20.0, 20.0), level)
+            })
+            .unwrap_or_default();
+        let ms1 = spectra.into_iter().filter(|s| s.level == 1).collect();
+
+        SageResults {
+            features,
+            quant,
+            ms1,
+        }
     }
 
-    pub fn batch_files(&self, scorer: &Scorer, batch_size: usize) -> SageResults {
-        self.parameters
-            .mzml_paths
+    pub fn batch_files(&mut self, batch_size: usize) -> SageResults {
+        let mzml_paths = self.parameters.mzml_paths.clone();
+        mzml_paths
             .chunks(batch_size)
             .enumerate()
-            .map(|(chunk_idx, chunk)| self.process_chunk(scorer, chunk, chunk_idx, batch_size))
+            .map(|(chunk_idx, chunk)| self.process_chunk(chunk, chunk_idx, batch_size))
             .collect::<SageResults>()
     }
 
     pub fn run(mut self, parallel: usize, parquet: bool) -> anyhow::Result<telemetry::Telemetry> {
-        let scorer = Scorer {
-            db: &self.database,
-            precursor_tol: self.parameters.precursor_tol,
-            fragment_tol: self.parameters.fragment_tol,
-            min_matched_peaks: self.parameters.min_matched_peaks,
-            min_isotope_err: self.parameters.isotope_errors.0,
-            max_isotope_err: self.parameters.isotope_errors.1,
-            min_precursor_charge: self.parameters.precursor_charge.0,
-            max_precursor_charge: self.parameters.precursor_charge.1,
-            max_fragment_charge: self.parameters.max_fragment_charge,
-            min_fragment_mass: self.parameters.database.fragment_min_mz,
-            max_fragment_mass: self.parameters.database.fragment_max_mz,
-            chimera: self.parameters.chimera,
-            report_psms: self.parameters.report_psms,
-            wide_window: self.parameters.wide_window,
-            annotate_matches: self.parameters.annotate_matches,
-        };
-
         //Collect all results into a single container
-        let mut outputs = self.batch_files(&scorer, parallel);
+        let mut outputs = self.batch_files(parallel);
 
-        let alignments = if self.parameters.predict_rt {
+        // Fragment counts of a partitioned database are only complete once
+        // every partition has been searched
+        if self.parameters.write_peptide_table {
+            let path = self.write_peptide_table()?;
+            self.parameters.output_paths.push(path);
+        }
+
+        // Ion mobility prediction is required to filter PSMs by ion mobility error,
+        // even if retention time prediction is disabled
+        let predict_ims = self.parameters.predict_rt || self.parameters.max_delta_ims.is_some();
+        if predict_ims {
             // Poisson probability is usually the best single feature for refining FDR.
-            // Take our set of 1% FDR filtered PSMs, and use them to train a linear
-            // regression model for predicting retention time
+            // Take our set of 1% FDR filtered PSMs, and use them to train linear
+            // regression models for predicting retention time and ion mobility
             outputs
                 .features
                 .par_sort_unstable_by(|a, b| a.poisson.total_cmp(&b.poisson));
             sage_core::ml::qvalue::spectrum_q_value(&mut outputs.features);
+        }
 
+        let alignments = if self.parameters.predict_rt {
             let alignments = sage_core::ml::retention_alignment::global_alignment(
                 &mut outputs.features,
                 self.parameters.mzml_paths.len(),
             );
             let _ = sage_core::ml::retention_model::predict(&self.database, &mut outputs.features);
-            let _ = sage_core::ml::mobility_model::predict(&self.database, &mut outputs.features);
             Some(alignments)
         } else {
             None
         };
 
+        let ims = match predict_ims {
+            true => sage_core::ml::mobility_model::predict(&self.database, &mut outputs.features),
+            false => None,
+        };
+        if let (Some(_), Some(max_delta_ims)) = (ims, self.parameters.max_delta_ims) {
+            let removed =
+                sage_core::ml::mobility_model::filter(&mut outputs.features, max_delta_ims);
+            log::info!(
+                "removed {} PSMs with an ion mobility error above {}",
+                removed,
+                max_delta_ims
+            );
+        }
+
         let q_spectrum = self.spectrum_fdr(&mut outputs.features);
         let q_peptide = sage_core::fdr::picked_peptide(&self.database, &mut outputs.features);
         let q_protein = sage_core::fdr::picked_protein(&self.database, &mut outputs.features);
@@ -333,6 +474,14 @@ impl Runner {
         );
         log::info!("discovered {} target peptides at 1% FDR", q_peptide);
         log::info!("discovered {} target proteins at 1% FDR", q_protein);
+        if self.database.entrapment_tag.is_some() {
+            let psms = sage_core::fdr::Entrapment::psms(&self.database, &outputs.features, 0.01);
+            let peptides =
+                sage_core::fdr::Entrapment::peptides(&self.database, &outputs.features, 0.01);
+            log::info!("entrapment PSMs at 1% FDR: {}", psms);
+            log::info!("entrapment peptides at 1% FDR: {}", peptides);
+            self.parameters.entrapment = Some(EntrapmentResults { psms, peptides });
+        }
         log::trace!("writing outputs");
 
         // Write either a single parquet file, or multiple tsv files
@@ -389,6 +538,12 @@ impl Runner {
             }
         }
 
+        if let Some(max_proteins) = self.parameters.redundancy_threshold {
+            self.parameters
+                .output_paths
+                .push(self.write_redundancy(&outputs.features, max_proteins)?);
+        }
+
         // Write percolator input file if requested
         if self.parameters.write_pin {
             self.parameters
@@ -3,6 +3,7 @@ use std::collections::HashMap;
 
 use rayon::prelude::*;
 use sage_core::ion_series::Kind;
+use sage_core::properties::{gravy, isoelectric_point};
 use sage_core::scoring::Fragments;
 use sage_core::{
     lfq::{Peak, PrecursorId},
@@ -20,11 +21,7 @@ impl Runner {
 
         let peptide = &self.database[feature.peptide_idx];
         record.push_field(peptide.to_string().as_bytes());
-        record.push_field(
-            peptide
-                .proteins(&self.database.decoy_tag, self.database.generate_decoys)
-                .as_bytes(),
-        );
+        record.push_field(self.database.proteins(peptide).as_bytes());
         record.push_field(
             itoa::Buffer::new()
                 .format(peptide.proteins.len())
@@ -48,7 +45,31 @@ impl Runner {
                 .format(peptide.semi_enzymatic as u8)
                 .as_bytes(),
         );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.ragged_nterm as u8)
+                .as_bytes(),
+        );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.is_tryptic_cterm() as u8)
+                .as_bytes(),
+        );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.enzymatic_termini)
+                .as_bytes(),
+        );
+        match feature.label_channel {
+            Some(channel) => record.push_field(itoa::Buffer::new().format(channel).as_bytes()),
+            None => record.push_field(b""),
+        }
         record.push_field(ryu::Buffer::new().format(feature.isotope_error).as_bytes());
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.precursor_offset)
+                .as_bytes(),
+        );
         record.push_field(ryu::Buffer::new().format(feature.delta_mass).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.average_ppm).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.hyperscore).as_bytes());
@@ -58,6 +79,10 @@ impl Runner {
         record.push_field(ryu::Buffer::new().format(feature.aligned_rt).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.predicted_rt).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.delta_rt_model).as_bytes());
+        match feature.delta_rt_external {
+            Some(delta) => record.push_field(ryu::Buffer::new().format(delta).as_bytes()),
+            None => record.push_field(b""),
+        }
         record.push_field(ryu::Buffer::new().format(feature.ims).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.predicted_ims).as_bytes());
         record.push_field(
@@ -65,10 +90,30 @@ impl Runner {
                 .format(feature.delta_ims_model)
                 .as_bytes(),
         );
+        match feature.faims_cv {
+            Some(cv) => record.push_field(ryu::Buffer::new().format(cv).as_bytes()),
+            None => record.push_field(b""),
+        }
+        match feature.chimericity {
+            Some(chimericity) => {
+                record.push_field(ryu::Buffer::new().format(chimericity).as_bytes())
+            }
+            None => record.push_field(b""),
+        }
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.propagated as u8)
+                .as_bytes(),
+        );
         record.push_field(itoa::Buffer::new().format(feature.matched_peaks).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_b).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_y).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.longest_y_pct).as_bytes());
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.complementary_pairs)
+                .as_bytes(),
+        );
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.matched_intensity_pct)
@@ -80,6 +125,7 @@ impl Runner {
                 .as_bytes(),
         );
         record.push_field(ryu::Buffer::new().format(feature.poisson).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.andromeda).as_bytes());
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.discriminant_score)
@@ -171,7 +217,12 @@ impl Runner {
             "peptide_len",
             "missed_cleavages",
             "semi_enzymatic",
+            "ragged_nterm",
+            "tryptic_cterm",
+            "enzymatic_termini",
+            "label_channel",
             "isotope_error",
+            "precursor_offset",
             "precursor_ppm",
             "fragment_ppm",
             "hyperscore",
@@ -181,16 +232,22 @@ impl Runner {
             "aligned_rt",
             "predicted_rt",
             "delta_rt_model",
+            "delta_rt_external",
             "ion_mobility",
             "predicted_mobility",
             "delta_mobility",
+            "faims_cv",
+            "chimericity",
+            "propagated",
             "matched_peaks",
             "longest_b",
             "longest_y",
             "longest_y_pct",
+            "complementary_pairs",
             "matched_intensity_pct",
             "scored_candidates",
             "poisson",
+            "andromeda",
             "sage_discriminant_score",
             "posterior_error",
             "spectrum_q",
@@ -308,6 +365,16 @@ impl Runner {
                 .as_bytes(),
         );
         record.push_field(itoa::Buffer::new().format(feature.peptide_len).as_bytes());
+        record.push_field(
+            ryu::Buffer::new()
+                .format(isoelectric_point(&peptide.sequence))
+                .as_bytes(),
+        );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(gravy(&peptide.sequence))
+                .as_bytes(),
+        );
         record.push_field(
             itoa::Buffer::new()
                 .format(feature.missed_cleavages)
@@ -318,7 +385,27 @@ impl Runner {
                 .format(peptide.semi_enzymatic as u8)
                 .as_bytes(),
         );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.is_tryptic_cterm() as u8)
+                .as_bytes(),
+        );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.enzymatic_termini)
+                .as_bytes(),
+        );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.label_channel.unwrap_or(0))
+                .as_bytes(),
+        );
         record.push_field(ryu::Buffer::new().format(feature.isotope_error).as_bytes());
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.precursor_offset)
+                .as_bytes(),
+        );
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.delta_mass.ln_1p())
@@ -347,6 +434,11 @@ impl Runner {
                 .format(feature.delta_rt_model.clamp(0.001, 1.0).sqrt())
                 .as_bytes(),
         );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.delta_rt_external.unwrap_or(0.0))
+                .as_bytes(),
+        );
         record.push_field(ryu::Buffer::new().format(feature.predicted_ims).as_bytes());
         record.push_field(
             ryu::Buffer::new()
@@ -357,6 +449,11 @@ impl Runner {
         record.push_field(itoa::Buffer::new().format(feature.longest_b).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_y).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.longest_y_pct).as_bytes());
+        record.push_field(
+            itoa::Buffer::new()
+                .format(feature.complementary_pairs)
+                .as_bytes(),
+        );
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.matched_intensity_pct.ln_1p())
@@ -372,17 +469,19 @@ impl Runner {
                 .format((-feature.poisson).ln_1p())
                 .as_bytes(),
         );
+        record.push_field(ryu::Buffer::new().format(feature.andromeda).as_bytes());
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.posterior_error)
                 .as_bytes(),
         );
-        record.push_field(peptide.to_string().as_bytes());
         record.push_field(
-            peptide
-                .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+            ryu::Buffer::new()
+                .format(feature.chimericity.unwrap_or(0.0))
                 .as_bytes(),
         );
+        record.push_field(peptide.to_string().as_bytes());
+        record.push_field(self.database.proteins(peptide).as_bytes());
         record
     }
 
@@ -410,9 +509,15 @@ impl Runner {
             "z=6",
             "z=other",
             "peptide_len",
+            "isoelectric_point",
+            "gravy",
             "missed_cleavages",
             "semi_enzymatic",
+            "tryptic_cterm",
+            "enzymatic_termini",
+            "label_channel",
             "isotope_error",
+            "precursor_offset",
             "ln(precursor_ppm)",
             "fragment_ppm",
             "ln(hyperscore)",
@@ -421,16 +526,20 @@ impl Runner {
             "aligned_rt",
             "predicted_rt",
             "sqrt(delta_rt_model)",
+            "delta_rt_external",
             "predicted_mobility",
             "sqrt(delta_mobility)",
             "matched_peaks",
             "longest_b",
             "longest_y",
             "longest_y_pct",
+            "complementary_pairs",
             "ln(matched_intensity_pct)",
             "scored_candidates",
             "ln(-poisson)",
+            "andromeda",
             "posterior_error",
+            "chimericity",
             "Peptide",
             "Proteins",
         ]);
@@ -452,6 +561,63 @@ impl Runner {
         Ok(path.to_string())
     }
 
+    /// Write the number of proteins each confidently identified target peptide
+    /// (`peptide_q <= 0.01`) maps to, flagging peptides shared by more than
+    /// `max_proteins` proteins as non-unique
+    pub fn write_redundancy(
+        &self,
+        features: &[Feature],
+        max_proteins: usize,
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("peptide_redundancy.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+        let headers = csv::ByteRecord::from(vec!["peptide", "proteins", "num_proteins", "unique"]);
+        wtr.write_byte_record(&headers)?;
+
+        let peptides = features
+            .iter()
+            .filter(|feat| feat.label == 1 && feat.peptide_q <= 0.01)
+            .map(|feat| feat.peptide_idx)
+            .collect::<Vec<_>>();
+
+        for redundancy in self.database.peptide_redundancy(&peptides, max_proteins) {
+            let peptide = &self.database[redundancy.peptide_index];
+            let mut record = csv::ByteRecord::new();
+            record.push_field(peptide.to_string().as_bytes());
+            record.push_field(self.database.proteins(peptide).as_bytes());
+            record.push_field(
+                itoa::Buffer::new()
+                    .format(redundancy.num_proteins)
+                    .as_bytes(),
+            );
+            record.push_field(
+                itoa::Buffer::new()
+                    .format(redundancy.unique as u8)
+                    .as_bytes(),
+            );
+            wtr.write_byte_record(&record)?;
+        }
+        wtr.flush()?;
+
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Write the in-silico peptide table of the database, see
+    /// [`sage_core::database::write_peptide_table`]
+    pub fn write_peptide_table(&self) -> anyhow::Result<String> {
+        let path = self.make_path("peptides.tsv");
+
+        let mut bytes = Vec::new();
+        sage_core::database::write_peptide_table(&self.database, &mut bytes)?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
     pub fn write_tmt(&self, quant: &[TmtQuant], filenames: &[String]) -> anyhow::Result<String> {
         let path = self.make_path("tmt.tsv");
 
@@ -530,8 +696,8 @@ impl Runner {
                 record.push_field(self.database[peptide_ix].to_string().as_bytes());
                 record.push_field(itoa::Buffer::new().format(charge.unwrap_or(-1)).as_bytes());
                 record.push_field(
-                    self.database[peptide_ix]
-                        .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                    self.database
+                        .proteins(&self.database[peptide_ix])
                         .as_bytes(),
                 );
                 record.push_field(ryu::Buffer::new().format(peak.q_value).as_bytes());
@@ -1,43 +1,423 @@
-use sage_core::database::Builder;
-use sage_core::mass::Tolerance;
+use sage_core::calibration::{calibrate_fragments, correct_fragments};
+use sage_core::cluster::{cluster_spectra, propagate};
+use sage_core::database::{Builder, IndexedDatabase};
+use sage_core::ion_series::IonWeights;
+use sage_core::mass::{Tolerance, NEUTRON};
 use sage_core::scoring::Scorer;
-use sage_core::spectrum::SpectrumProcessor;
+use sage_core::spectrum::{Activation, ProcessedSpectrum, SpectrumProcessor};
 
-#[test]
-fn integration() -> anyhow::Result<()> {
+/// Database of Q99536 (and its decoys)
+fn database() -> anyhow::Result<IndexedDatabase> {
     let mut builder = Builder::default();
     builder.update_fasta("foo".into());
 
     let fasta = sage_cloudpath::util::read_fasta("../../tests/Q99536.fasta", "rev_", true)?;
-    let database = builder.make_parameters().build(fasta);
-    let spectra = sage_cloudpath::util::read_mzml("../../tests/LQSRPAAPPAPGPGQLTLR.mzML", 0, None)?;
-    assert_eq!(spectra.len(), 1);
+    Ok(builder.make_parameters().build(fasta))
+}
 
+/// The single MS2 spectrum of LQSRPAAPPAPGPGQLTLR.mzML, processed
+fn spectrum() -> anyhow::Result<ProcessedSpectrum> {
+    let spectra = sage_cloudpath::util::read_mzml("../../tests/LQSRPAAPPAPGPGQLTLR.mzML", 0, None)?;
     let sp = SpectrumProcessor::new(100, 0.0, 1500.0, true, 0.0);
-    let processed = sp.process(spectra[0].clone());
-    assert!(processed.peaks.len() <= 300);
+    Ok(sp.process(spectra[0].clone()))
+}
 
-    let scorer = Scorer {
-        db: &database,
+/// Scorer shared by all tests - tests override the fields under test
+fn scorer(db: &IndexedDatabase) -> Scorer<'_> {
+    Scorer {
+        db,
         precursor_tol: Tolerance::Ppm(-50.0, 50.0),
         fragment_tol: Tolerance::Ppm(-10.0, 10.0),
         min_matched_peaks: 4,
+        min_consecutive_ions: 0,
+        min_score: None,
         min_isotope_err: -1,
         max_isotope_err: 3,
+        averagine_isotope_errors: false,
+        averagine_precursor_correction: false,
+        isotope_error_penalty: 0.0,
+        precursor_offsets: &[],
         min_precursor_charge: 2,
         max_precursor_charge: 4,
+        precursor_charge_limits: (1, u8::MAX),
         max_fragment_charge: Some(1),
+        exclude_above_precursor_mz: false,
+        dedup_fragment_charges: false,
+        fragment_charge_offset: 1,
+        intact_mass_tol: None,
         min_fragment_mass: 0.0,
         max_fragment_mass: 1500.0,
+        min_scored_fragment_mz: None,
         chimera: false,
         report_psms: 1,
+        ion_weights: IonWeights::default(),
+        activation_ion_kinds: false,
+        peak_selection: Default::default(),
+        ranking_score: Default::default(),
         wide_window: false,
         annotate_matches: false,
-    };
+    }
+}
 
-    let psm = scorer.score(&processed);
+#[test]
+fn integration() -> anyhow::Result<()> {
+    let database = database()?;
+    let spectra = sage_cloudpath::util::read_mzml("../../tests/LQSRPAAPPAPGPGQLTLR.mzML", 0, None)?;
+    assert_eq!(spectra.len(), 1);
+
+    let sp = SpectrumProcessor::new(100, 0.0, 1500.0, true, 0.0);
+    let processed = sp.process(spectra[0].clone());
+    assert!(processed.peaks.len() <= 300);
+
+    let psm = scorer(&database).score(&processed);
     assert_eq!(psm.len(), 1);
     assert_eq!(psm[0].matched_peaks, 21);
 
     Ok(())
 }
+
+#[test]
+fn complementary_pairs_and_andromeda() -> anyhow::Result<()> {
+    let database = database()?;
+    let psm = scorer(&database).score(&spectrum()?);
+    assert_eq!(psm[0].complementary_pairs, 5);
+    assert!(psm[0].andromeda > 0.0);
+    Ok(())
+}
+
+#[test]
+fn ion_weights() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Up-weighting y-ions increases hyperscore, without changing matched ions
+    let weighted = Scorer {
+        ion_weights: IonWeights {
+            y: 2.0,
+            ..Default::default()
+        },
+        ..scorer
+    };
+    let weighted = weighted.score(&processed);
+    assert_eq!(weighted[0].matched_peaks, 21);
+    assert!(weighted[0].hyperscore > psm[0].hyperscore);
+    Ok(())
+}
+
+#[test]
+fn min_consecutive_ions() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // PSMs are only reported if the longest ion series is long enough
+    let longest = psm[0].longest_b.max(psm[0].longest_y) as u16;
+    let tagged = Scorer {
+        min_consecutive_ions: longest,
+        ..scorer
+    };
+    assert_eq!(tagged.score(&processed).len(), 1);
+    let tagged = Scorer {
+        min_consecutive_ions: longest + 1,
+        ..scorer
+    };
+    assert!(tagged.score(&processed).is_empty());
+    Ok(())
+}
+
+#[test]
+fn exclude_above_precursor_mz() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Fragments above the precursor m/z can be excluded from matching
+    let below = Scorer {
+        exclude_above_precursor_mz: true,
+        ..scorer
+    };
+    let below = below.score(&processed);
+    assert_eq!(below[0].peptide_idx, psm[0].peptide_idx);
+    assert!(below[0].matched_peaks < psm[0].matched_peaks);
+    Ok(())
+}
+
+#[test]
+fn intact_mass_tol() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // A matching intact precursor mass counts as an additional pseudo-fragment
+    let intact = Scorer {
+        intact_mass_tol: Some(Tolerance::Ppm(-50.0, 50.0)),
+        ..scorer
+    };
+    let intact = intact.score(&processed);
+    assert_eq!(intact[0].peptide_idx, psm[0].peptide_idx);
+    assert_eq!(intact[0].matched_peaks, psm[0].matched_peaks);
+    assert!(intact[0].hyperscore > psm[0].hyperscore);
+    Ok(())
+}
+
+#[test]
+fn averagine_precursor_correction() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Precursors reported at the most abundant isotope are interpreted
+    // relative to the monoisotopic mass estimated from the averagine model
+    let mut heavy = processed.clone();
+    heavy.precursors[0].mz += NEUTRON / psm[0].charge as f32;
+    let corrected = Scorer {
+        min_isotope_err: 0,
+        max_isotope_err: 0,
+        averagine_precursor_correction: true,
+        ..scorer
+    };
+    let corrected = corrected.score(&heavy);
+    assert_eq!(corrected[0].peptide_idx, psm[0].peptide_idx);
+    assert_eq!(corrected[0].isotope_error, psm[0].isotope_error + NEUTRON);
+    assert!((corrected[0].delta_mass - psm[0].delta_mass).abs() < 5.0);
+    Ok(())
+}
+
+#[test]
+fn ambiguous_precursor_charge() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Ambiguous precursor charge states are all searched, and the charge of
+    // the best scoring interpretation is reported
+    let mut ambiguous = processed.clone();
+    ambiguous.precursors[0].charge = None;
+    ambiguous.precursors[0].possible_charges = vec![psm[0].charge + 1, psm[0].charge];
+    let ambiguous_psm = scorer.score(&ambiguous);
+    assert_eq!(ambiguous_psm[0].charge, psm[0].charge);
+    assert_eq!(ambiguous_psm[0].peptide_idx, psm[0].peptide_idx);
+    assert_eq!(ambiguous_psm[0].hyperscore, psm[0].hyperscore);
+    Ok(())
+}
+
+#[test]
+fn mgf_ambiguous_precursor_charge() -> anyhow::Result<()> {
+    let database = database()?;
+    let spectra = sage_cloudpath::util::read_mzml("../../tests/LQSRPAAPPAPGPGQLTLR.mzML", 0, None)?;
+
+    // Write the same spectrum as MGF, with an ambiguous precursor charge
+    let mut mgf = format!(
+        "BEGIN IONS\nTITLE=ambiguous\nPEPMASS={}\nCHARGE=2+ and 3+\n",
+        spectra[0].precursors[0].mz
+    );
+    for (mz, int) in spectra[0].mz.iter().zip(&spectra[0].intensity) {
+        mgf.push_str(&format!("{} {}\n", mz, int));
+    }
+    mgf.push_str("END IONS\n");
+    let spectra = sage_cloudpath::mgf::MgfReader::with_file_id(0).parse(mgf)?;
+    assert_eq!(spectra[0].precursors.len(), 1);
+
+    // Each charge state is searched once, so there are no duplicate PSMs
+    let sp = SpectrumProcessor::new(100, 0.0, 1500.0, true, 0.0);
+    let processed = sp.process(spectra[0].clone());
+    let scorer = Scorer {
+        precursor_tol: Tolerance::Da(-500.0, 500.0),
+        min_matched_peaks: 1,
+        min_isotope_err: 0,
+        max_isotope_err: 0,
+        report_psms: 3,
+        ..scorer(&database)
+    };
+    let psm = scorer.score(&processed);
+    assert_eq!(psm.len(), 3);
+    for (idx, a) in psm.iter().enumerate() {
+        assert!(psm[idx + 1..]
+            .iter()
+            .all(|b| (a.peptide_idx, a.charge) != (b.peptide_idx, b.charge)));
+    }
+    assert!(psm[0].delta_next > 0.0);
+    Ok(())
+}
+
+#[test]
+fn precursor_charge_limits() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    let mut ambiguous = processed.clone();
+    ambiguous.precursors[0].charge = None;
+    ambiguous.precursors[0].possible_charges = vec![psm[0].charge + 1, psm[0].charge];
+
+    // Charge states outside of the charge limits are never searched, and
+    // spectra without any charge state within the limits are skipped
+    let limited = Scorer {
+        precursor_charge_limits: (psm[0].charge + 1, 6),
+        ..scorer
+    };
+    let limited_psm = limited.score(&ambiguous);
+    assert!(limited_psm
+        .iter()
+        .all(|feat| feat.charge == psm[0].charge + 1));
+    assert!(limited.score(&processed).is_empty());
+    let exact = Scorer {
+        precursor_charge_limits: (psm[0].charge, psm[0].charge),
+        ..scorer
+    };
+    assert_eq!(exact.score(&processed).len(), 1);
+    Ok(())
+}
+
+#[test]
+fn precursor_offsets() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Precursors shifted by a user-defined mass offset are only matched if
+    // that offset is searched, and the offset is reported
+    let offset = 21.9819;
+    let mut shifted = processed.clone();
+    shifted.precursors[0].mz += offset / psm[0].charge as f32;
+    let offsets = [-18.0106, offset];
+    let with_offsets = Scorer {
+        precursor_offsets: &offsets,
+        ..scorer
+    };
+    let shifted_psm = with_offsets.score(&shifted);
+    assert_eq!(shifted_psm[0].peptide_idx, psm[0].peptide_idx);
+    assert_eq!(shifted_psm[0].precursor_offset, offset);
+    assert!((shifted_psm[0].delta_mass - psm[0].delta_mass).abs() < 5.0);
+    assert!(scorer
+        .score(&shifted)
+        .iter()
+        .all(|feat| feat.peptide_idx != psm[0].peptide_idx));
+    assert_eq!(with_offsets.score(&processed)[0].precursor_offset, 0.0);
+    Ok(())
+}
+
+#[test]
+fn activation_ion_kinds() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let scorer = scorer(&database);
+    let psm = scorer.score(&processed);
+
+    // Only ion kinds produced by the spectrum's activation method are scored:
+    // c/z ions are not indexed, so nothing is matched for an ETD spectrum
+    let by_activation = Scorer {
+        activation_ion_kinds: true,
+        ..scorer
+    };
+    assert_eq!(processed.activation, Some(Activation::Hcd));
+    let collisional = by_activation.score(&processed);
+    assert_eq!(collisional[0].matched_peaks, psm[0].matched_peaks);
+    let mut electron = processed.clone();
+    electron.activation = Some(Activation::Etd);
+    assert!(by_activation.score(&electron).is_empty());
+    assert_eq!(scorer.score(&electron)[0].peptide_idx, psm[0].peptide_idx);
+    Ok(())
+}
+
+#[test]
+fn fragment_calibration() -> anyhow::Result<()> {
+    let database = database()?;
+    let mut processed = spectrum()?;
+    let scorer = Scorer {
+        fragment_tol: Tolerance::Ppm(-20.0, 20.0),
+        ..scorer(&database)
+    };
+
+    let calibrate = |spectrum: &ProcessedSpectrum| {
+        let mut psms = scorer.score(spectrum);
+        psms.iter_mut().for_each(|psm| psm.spectrum_q = 0.0);
+        calibrate_fragments(
+            &database,
+            &psms,
+            std::slice::from_ref(spectrum),
+            scorer.fragment_tol,
+        )
+    };
+
+    let baseline = calibrate(&processed);
+
+    // Introduce a systematic +5 ppm error, which should be measured...
+    correct_fragments(&mut processed, -5.0);
+    let error = calibrate(&processed);
+    assert!(
+        (error - baseline - 5.0).abs() < 0.1,
+        "{} {}",
+        baseline,
+        error
+    );
+
+    // ... and then removed
+    correct_fragments(&mut processed, error);
+    let corrected = calibrate(&processed);
+    assert!(corrected.abs() < 0.1, "{}", corrected);
+
+    Ok(())
+}
+
+#[test]
+fn search_multiple_files() -> anyhow::Result<()> {
+    let database = database()?;
+    let sp = SpectrumProcessor::new(100, 0.0, 1500.0, true, 0.0);
+    let scorer = scorer(&database);
+
+    let paths = vec!["../../tests/LQSRPAAPPAPGPGQLTLR.mzML"; 2];
+    let files = sage_cloudpath::util::search_files(&paths, &sp, &scorer)?;
+    assert_eq!(files.len(), 2);
+    for (file_id, file) in files.iter().enumerate() {
+        assert_eq!(file.file_id, file_id);
+        assert_eq!(file.filename, "LQSRPAAPPAPGPGQLTLR.mzML");
+        assert_eq!(file.features.len(), 1);
+        assert_eq!(file.features[0].file_id, file_id);
+        assert_eq!(file.features[0].matched_peaks, 21);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cluster_and_propagate() -> anyhow::Result<()> {
+    let database = database()?;
+    let processed = spectrum()?;
+    let mut duplicate = processed.clone();
+    duplicate.id = "duplicate".into();
+    duplicate.scan_start_time += 0.1;
+    let spectra = vec![processed, duplicate];
+
+    let fragment_tol = Tolerance::Ppm(-10.0, 10.0);
+    let clusters = cluster_spectra(&spectra, Tolerance::Ppm(-10.0, 10.0), fragment_tol, 0.9);
+    assert_eq!(clusters.len(), 1);
+    assert_eq!(clusters[0].members.len(), 2);
+
+    let scorer = Scorer {
+        fragment_tol,
+        ..scorer(&database)
+    };
+
+    let features = scorer.score(&spectra[clusters[0].representative]);
+    let features = propagate(&clusters, &spectra, features);
+    assert_eq!(features.len(), 2);
+    assert_ne!(features[0].spec_id, features[1].spec_id);
+    assert_ne!(features[0].psm_id, features[1].psm_id);
+    assert_eq!(features[0].peptide_idx, features[1].peptide_idx);
+    assert_eq!(features[0].hyperscore, features[1].hyperscore);
+    assert!((features[1].rt - features[0].rt).abs() > 0.05);
+    assert!(!features[0].propagated);
+    assert!(features[1].propagated);
+
+    Ok(())
+}
@@ -285,6 +285,8 @@ pub enum Error {
     TDF(#[from] timsrust::Error),
     #[error("MGF error: {0}")]
     MGF(#[from] mgf::MgfError),
+    #[error("spectral library error: {0}")]
+    Library(#[from] sage_core::library::LibraryError),
 }
 
 #[cfg(test)]
@@ -91,11 +91,14 @@ impl QueryData {
             precursor.isolation_window = isolation_window;
 
             if let Some(charge_array) = &self.precursor_charge_array {
-                for &charge in charge_array.iter() {
-                    let mut precursor_with_charge = precursor.clone();
-                    precursor_with_charge.charge = Some(charge);
-                    new_precursors.push(precursor_with_charge);
+                let mut precursor_with_charge = precursor.clone();
+                // An ambiguous charge (e.g. `CHARGE=2+ and 3+`) is a single
+                // precursor that is searched at each of the possible charges
+                match charge_array.as_slice() {
+                    [charge] => precursor_with_charge.charge = Some(*charge),
+                    _ => precursor_with_charge.possible_charges = charge_array.clone(),
                 }
+                new_precursors.push(precursor_with_charge);
             } else {
                 new_precursors.push(precursor.clone());
             }
@@ -420,21 +423,15 @@ mod test {
         assert_eq!(s.id, "spectrum 0");
         assert_eq!(s.ms_level, 2);
         assert_eq!(s.representation, Representation::Centroid);
-        assert_eq!(s.precursors.len(), 2);
-        assert_eq!(s.precursors[0].charge, Some(2));
-        assert_eq!(s.precursors[1].charge, Some(3));
+        assert_eq!(s.precursors.len(), 1);
+        assert_eq!(s.precursors[0].charge, None);
+        assert_eq!(s.precursors[0].possible_charges, vec![2, 3]);
         assert!((s.precursors[0].mz - 367.069682741984).abs() < 0.0001);
         assert_eq!(s.precursors[0].intensity, Some(56700.5185546875));
         assert_eq!(
             s.precursors[0].isolation_window,
             Some(Tolerance::Ppm(-10.0, 10.0))
         );
-        assert!((s.precursors[1].mz - 367.069682741984).abs() < 0.0001);
-        assert_eq!(s.precursors[1].intensity, Some(56700.5185546875));
-        assert_eq!(
-            s.precursors[1].isolation_window,
-            Some(Tolerance::Ppm(-10.0, 10.0))
-        );
         assert!((s.scan_start_time - 0.8963232289 / 60.0).abs() < 0.0001);
         assert_eq!(s.ion_injection_time, 0.0);
         assert_eq!(s.intensity.len(), s.mz.len());
@@ -510,9 +507,9 @@ mod test {
         assert_eq!(spectra.len(), 2);
 
         let s = spectra.pop().unwrap();
-        assert_eq!(s.precursors.len(), 2);
-        assert_eq!(s.precursors[0].charge, Some(2));
-        assert_eq!(s.precursors[1].charge, Some(3));
+        assert_eq!(s.precursors.len(), 1);
+        assert_eq!(s.precursors[0].charge, None);
+        assert_eq!(s.precursors[0].possible_charges, vec![2, 3]);
         assert_eq!(s.precursors[0].isolation_window, None);
         Ok(())
     }
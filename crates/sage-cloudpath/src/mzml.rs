@@ -1,8 +1,10 @@
-use async_compression::tokio::bufread::ZlibDecoder;
+use async_compression::tokio::bufread::{ZlibDecoder, ZlibEncoder};
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use sage_core::spectrum::{Precursor, Representation};
+use sage_core::mass::PROTON;
+use sage_core::spectrum::{Activation, Precursor, ProcessedSpectrum, Representation};
 use sage_core::{mass::Tolerance, spectrum::RawSpectrum};
+use std::fmt::Write;
 use tokio::io::{AsyncBufRead, AsyncReadExt};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -43,9 +45,13 @@ const FLOAT_64: &[u8] = b"MS:1000523";
 const FLOAT_32: &[u8] = b"MS:1000521";
 
 const MS_LEVEL: &[u8] = b"MS:1000511";
+const MS1_SPECTRUM: &[u8] = b"MS:1000579";
+const MSN_SPECTRUM: &[u8] = b"MS:1000580";
 const PROFILE: &[u8] = b"MS:1000128";
 const CENTROID: &[u8] = b"MS:1000127";
 const TOTAL_ION_CURRENT: &[u8] = b"MS:1000285";
+const BASE_PEAK_INTENSITY: &[u8] = b"MS:1000505";
+const NO_COMBINATION: &[u8] = b"MS:1000795";
 
 const SCAN_START_TIME: &[u8] = b"MS:1000016";
 const UNIT_SECONDS: &[u8] = b"UO:0000010";
@@ -55,11 +61,32 @@ const ION_INJECTION_TIME: &[u8] = b"MS:1000927";
 const SELECTED_ION_MZ: &[u8] = b"MS:1000744";
 const SELECTED_ION_INT: &[u8] = b"MS:1000042";
 const SELECTED_ION_CHARGE: &[u8] = b"MS:1000041";
+const POSSIBLE_CHARGE: &[u8] = b"MS:1000633";
 
+const ISO_WINDOW_TARGET: &[u8] = b"MS:1000827";
 const ISO_WINDOW_LOWER: &[u8] = b"MS:1000828";
 const ISO_WINDOW_UPPER: &[u8] = b"MS:1000829";
 
+const DISSOCIATION_METHOD: &[u8] = b"MS:1000044";
+const ACTIVATION_CID: &[u8] = b"MS:1000133";
+const ACTIVATION_HCD: &[u8] = b"MS:1000422";
+const ACTIVATION_ETD: &[u8] = b"MS:1000598";
+const ACTIVATION_ECD: &[u8] = b"MS:1000250";
+const ACTIVATION_ETHCD: &[u8] = b"MS:1002631";
+const SUPPLEMENTAL_CID: &[u8] = b"MS:1002679";
+const SUPPLEMENTAL_HCD: &[u8] = b"MS:1002678";
+
 const INVERSE_ION_MOBILITY: &[u8] = b"MS:1002815";
+const FAIMS_COMPENSATION_VOLTAGE: &[u8] = b"MS:1001581";
+
+/// Multiple activation cvParams may be present for a single precursor, e.g.
+/// ETD followed by supplemental beam-type CID
+fn add_activation(spectrum: &mut RawSpectrum, activation: Activation) {
+    spectrum.activation = Some(match spectrum.activation {
+        Some(existing) => existing.combine(activation),
+        None => activation,
+    });
+}
 
 pub struct MzMLReader {
     ms_level: Option<u8>,
@@ -119,7 +146,10 @@ impl MzMLReader {
         let mut binary_array = None;
 
         let mut spectrum = RawSpectrum::default_with_file_id(self.file_id);
+        // Precursor-level information (e.g. spectrumRef), shared by all selected
+        // ions of the precursor, each of which is reported as a separate `Precursor`
         let mut precursor = Precursor::default();
+        let mut selected_ions: Vec<Precursor> = Vec::new();
         let mut iso_window_lo: Option<f32> = None;
         let mut iso_window_hi: Option<f32> = None;
         let mut spectra = Vec::new();
@@ -159,17 +189,18 @@ impl MzMLReader {
                     };
                     match ev.name().into_inner() {
                         b"spectrum" => {
-                            let id = extract!(ev, b"id");
-                            let id = std::str::from_utf8(&id)?;
-                            spectrum.id = id.to_string();
+                            let id = ev.try_get_attribute(b"id")?.ok_or(MzMLError::Malformed)?;
+                            spectrum.id = id.unescape_value()?.into_owned();
                         }
                         b"precursor" => {
                             // Not all precursor fields have a spectrumRef
                             if let Some(scan) = ev.try_get_attribute(b"spectrumRef")? {
-                                let scan = std::str::from_utf8(&scan.value)?;
-                                precursor.spectrum_ref = Some(scan.to_string())
+                                precursor.spectrum_ref = Some(scan.unescape_value()?.into_owned())
                             }
                         }
+                        b"selectedIon" if state == Some(State::SelectedIon) => {
+                            selected_ions.push(precursor.clone());
+                        }
                         _ => {}
                     }
                 }
@@ -215,6 +246,9 @@ impl MzMLReader {
                                     spectrum.total_ion_current = value;
                                 }
                             }
+                            FAIMS_COMPENSATION_VOLTAGE => {
+                                spectrum.faims_cv = Some(extract_value!(ev));
+                            }
                             _ => {}
                         }
                     }
@@ -223,25 +257,39 @@ impl MzMLReader {
                         match accession.as_ref() {
                             ISO_WINDOW_LOWER => iso_window_lo = Some(extract_value!(ev)),
                             ISO_WINDOW_UPPER => iso_window_hi = Some(extract_value!(ev)),
+                            ACTIVATION_CID | SUPPLEMENTAL_CID => {
+                                add_activation(&mut spectrum, Activation::Cid)
+                            }
+                            ACTIVATION_HCD | SUPPLEMENTAL_HCD => {
+                                add_activation(&mut spectrum, Activation::Hcd)
+                            }
+                            ACTIVATION_ETD => add_activation(&mut spectrum, Activation::Etd),
+                            ACTIVATION_ECD => add_activation(&mut spectrum, Activation::Ecd),
+                            ACTIVATION_ETHCD => add_activation(&mut spectrum, Activation::EThcd),
                             _ => {}
                         }
                     }
                     (Some(State::SelectedIon), b"cvParam") => {
                         let accession = extract!(ev, b"accession");
-                        match accession.as_ref() {
-                            SELECTED_ION_CHARGE => {
-                                precursor.charge = Some(extract_value!(ev));
-                            }
-                            SELECTED_ION_MZ => {
-                                precursor.mz = extract_value!(ev);
-                            }
-                            SELECTED_ION_INT => {
-                                precursor.intensity = Some(extract_value!(ev));
-                            }
-                            INVERSE_ION_MOBILITY => {
-                                precursor.inverse_ion_mobility = Some(extract_value!(ev));
+                        if let Some(ion) = selected_ions.last_mut() {
+                            match accession.as_ref() {
+                                SELECTED_ION_CHARGE => {
+                                    ion.charge = Some(extract_value!(ev));
+                                }
+                                POSSIBLE_CHARGE => {
+                                    ion.possible_charges.push(extract_value!(ev));
+                                }
+                                SELECTED_ION_MZ => {
+                                    ion.mz = extract_value!(ev);
+                                }
+                                SELECTED_ION_INT => {
+                                    ion.intensity = Some(extract_value!(ev));
+                                }
+                                INVERSE_ION_MOBILITY => {
+                                    ion.inverse_ion_mobility = Some(extract_value!(ev));
+                                }
+                                _ => {}
                             }
-                            _ => {}
                         }
                     }
                     (Some(State::Scan), b"cvParam") => {
@@ -260,6 +308,9 @@ impl MzMLReader {
                             ION_INJECTION_TIME => {
                                 spectrum.ion_injection_time = extract_value!(ev);
                             }
+                            FAIMS_COMPENSATION_VOLTAGE => {
+                                spectrum.faims_cv = Some(extract_value!(ev));
+                            }
                             INVERSE_ION_MOBILITY => {
                                 precursor.inverse_ion_mobility = Some(extract_value!(ev));
                             }
@@ -338,14 +389,17 @@ impl MzMLReader {
                         (Some(State::BinaryDataArray), b"binaryDataArray") => Some(State::Spectrum),
                         (Some(State::SelectedIon), b"selectedIon") => Some(State::Precursor),
                         (Some(State::Precursor), b"precursor") => {
-                            if precursor.mz != 0.0 {
-                                precursor.isolation_window = match (iso_window_lo, iso_window_hi) {
-                                    (Some(lo), Some(hi)) => Some(Tolerance::Da(-lo, hi)),
-                                    _ => None,
-                                };
-                                spectrum.precursors.push(precursor);
-                                precursor = Precursor::default();
+                            let isolation_window = match (iso_window_lo, iso_window_hi) {
+                                (Some(lo), Some(hi)) => Some(Tolerance::Da(-lo, hi)),
+                                _ => None,
+                            };
+                            for mut ion in selected_ions.drain(..) {
+                                if ion.mz != 0.0 {
+                                    ion.isolation_window = isolation_window;
+                                    spectrum.precursors.push(ion);
+                                }
                             }
+                            precursor = Precursor::default();
                             Some(State::Spectrum)
                         }
                         (Some(State::Scan), b"scan") => Some(State::Spectrum),
@@ -391,6 +445,384 @@ impl MzMLReader {
     }
 }
 
+/// Write a `cvParam` element. `unit` is an optional (accession, name) pair
+fn cv_param(
+    out: &mut String,
+    indent: usize,
+    accession: &[u8],
+    name: &str,
+    value: &str,
+    unit: Option<(&str, &str)>,
+) {
+    // All accessions are ASCII constants
+    let accession = std::str::from_utf8(accession).expect("accession is valid UTF-8");
+    let cv = accession.split(':').next().unwrap_or("MS");
+    let _ = write!(
+        out,
+        r#"{:indent$}<cvParam cvRef="{}" accession="{}" name="{}" value="{}""#,
+        "",
+        cv,
+        accession,
+        name,
+        quick_xml::escape::escape(value),
+        indent = indent
+    );
+    if let Some((unit_accession, unit_name)) = unit {
+        let unit_cv = unit_accession.split(':').next().unwrap_or("UO");
+        let _ = write!(
+            out,
+            r#" unitCvRef="{}" unitAccession="{}" unitName="{}""#,
+            unit_cv, unit_accession, unit_name
+        );
+    }
+    out.push_str("/>\n");
+}
+
+/// Serialize [`ProcessedSpectrum`]s to mzML, e.g. for sharing preprocessed
+/// spectra. The output can be read back with [`MzMLReader`].
+///
+/// Peaks are written as singly charged m/z values (`mass + PROTON`), since
+/// processed spectra may have been deisotoped and charge-deconvolved. m/z
+/// values are stored as 64-bit and intensities as 32-bit floats, so that
+/// reading the file back yields the exact same peaks
+pub struct MzMLWriter {
+    compress: bool,
+}
+
+impl Default for MzMLWriter {
+    fn default() -> Self {
+        Self { compress: true }
+    }
+}
+
+impl MzMLWriter {
+    /// Compress binary data arrays with zlib (default: true)
+    pub fn set_compression(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    async fn encode(&self, bytes: Vec<u8>) -> Result<String, MzMLError> {
+        let bytes = match self.compress {
+            true => {
+                let mut compressed = Vec::with_capacity(bytes.len());
+                ZlibEncoder::new(bytes.as_slice())
+                    .read_to_end(&mut compressed)
+                    .await?;
+                compressed
+            }
+            false => bytes,
+        };
+        Ok(base64::encode(bytes))
+    }
+
+    async fn write_binary_array(
+        &self,
+        out: &mut String,
+        bytes: Vec<u8>,
+        dtype: (&[u8], &str),
+        array: (&[u8], &str),
+        unit: (&str, &str),
+    ) -> Result<(), MzMLError> {
+        let encoded = self.encode(bytes).await?;
+        let _ = writeln!(
+            out,
+            r#"          <binaryDataArray encodedLength="{}">"#,
+            encoded.len()
+        );
+        cv_param(out, 12, dtype.0, dtype.1, "", None);
+        match self.compress {
+            true => cv_param(out, 12, ZLIB_COMPRESSION, "zlib compression", "", None),
+            false => cv_param(out, 12, NO_COMPRESSION, "no compression", "", None),
+        }
+        cv_param(out, 12, array.0, array.1, "", Some(unit));
+        let _ = writeln!(out, "            <binary>{}</binary>", encoded);
+        out.push_str("          </binaryDataArray>\n");
+        Ok(())
+    }
+
+    fn write_precursor(out: &mut String, precursor: &Precursor, activation: Option<Activation>) {
+        match &precursor.spectrum_ref {
+            Some(spectrum_ref) => {
+                let _ = writeln!(
+                    out,
+                    r#"          <precursor spectrumRef="{}">"#,
+                    quick_xml::escape::escape(spectrum_ref)
+                );
+            }
+            None => out.push_str("          <precursor>\n"),
+        }
+        if let Some(window) = precursor.isolation_window {
+            let (lo, hi) = window.bounds(precursor.mz);
+            out.push_str("            <isolationWindow>\n");
+            let mz = Some(("MS:1000040", "m/z"));
+            cv_param(
+                out,
+                14,
+                ISO_WINDOW_TARGET,
+                "isolation window target m/z",
+                &precursor.mz.to_string(),
+                mz,
+            );
+            let lower = (precursor.mz - lo).to_string();
+            let upper = (hi - precursor.mz).to_string();
+            cv_param(
+                out,
+                14,
+                ISO_WINDOW_LOWER,
+                "isolation window lower offset",
+                &lower,
+                mz,
+            );
+            cv_param(
+                out,
+                14,
+                ISO_WINDOW_UPPER,
+                "isolation window upper offset",
+                &upper,
+                mz,
+            );
+            out.push_str("            </isolationWindow>\n");
+        }
+
+        out.push_str("            <selectedIonList count=\"1\">\n");
+        out.push_str("              <selectedIon>\n");
+        cv_param(
+            out,
+            16,
+            SELECTED_ION_MZ,
+            "selected ion m/z",
+            &precursor.mz.to_string(),
+            Some(("MS:1000040", "m/z")),
+        );
+        if let Some(charge) = precursor.charge {
+            cv_param(
+                out,
+                16,
+                SELECTED_ION_CHARGE,
+                "charge state",
+                &charge.to_string(),
+                None,
+            );
+        }
+        for charge in &precursor.possible_charges {
+            cv_param(
+                out,
+                16,
+                POSSIBLE_CHARGE,
+                "possible charge state",
+                &charge.to_string(),
+                None,
+            );
+        }
+        if let Some(intensity) = precursor.intensity {
+            cv_param(
+                out,
+                16,
+                SELECTED_ION_INT,
+                "peak intensity",
+                &intensity.to_string(),
+                Some(("MS:1000131", "number of detector counts")),
+            );
+        }
+        if let Some(mobility) = precursor.inverse_ion_mobility {
+            cv_param(
+                out,
+                16,
+                INVERSE_ION_MOBILITY,
+                "inverse reduced ion mobility",
+                &mobility.to_string(),
+                Some(("MS:1002814", "volt-second per square centimeter")),
+            );
+        }
+        out.push_str("              </selectedIon>\n");
+        out.push_str("            </selectedIonList>\n");
+
+        let (accession, name) = match activation {
+            Some(Activation::Cid) => (ACTIVATION_CID, "collision-induced dissociation"),
+            Some(Activation::Hcd) => (ACTIVATION_HCD, "beam-type collision-induced dissociation"),
+            Some(Activation::Etd) => (ACTIVATION_ETD, "electron transfer dissociation"),
+            Some(Activation::Ecd) => (ACTIVATION_ECD, "electron capture dissociation"),
+            Some(Activation::EThcd) => (
+                ACTIVATION_ETHCD,
+                "electron-transfer/higher-energy collision dissociation",
+            ),
+            None => (DISSOCIATION_METHOD, "dissociation method"),
+        };
+        out.push_str("            <activation>\n");
+        cv_param(out, 14, accession, name, "", None);
+        out.push_str("            </activation>\n");
+        out.push_str("          </precursor>\n");
+    }
+
+    /// Serialize `spectra` into an mzML document
+    pub async fn write(&self, spectra: &[ProcessedSpectrum]) -> Result<Vec<u8>, MzMLError> {
+        let mut out = String::new();
+        out.push_str(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<mzML xmlns="http://psi.hupo.org/ms/mzml" version="1.1.0">
+  <cvList count="2">
+    <cv id="MS" fullName="Proteomics Standards Initiative Mass Spectrometry Ontology" URI="https://raw.githubusercontent.com/HUPO-PSI/psi-ms-CV/master/psi-ms.obo"/>
+    <cv id="UO" fullName="Unit Ontology" URI="https://raw.githubusercontent.com/bio-ontology-research-group/unit-ontology/master/unit.obo"/>
+  </cvList>
+  <fileDescription>
+    <fileContent>
+      <cvParam cvRef="MS" accession="MS:1000580" name="MSn spectrum" value=""/>
+      <cvParam cvRef="MS" accession="MS:1000127" name="centroid spectrum" value=""/>
+    </fileContent>
+  </fileDescription>
+  <softwareList count="1">
+"#,
+        );
+        let _ = writeln!(
+            out,
+            r#"    <software id="sage" version="{}">"#,
+            env!("CARGO_PKG_VERSION")
+        );
+        out.push_str(
+            r#"      <cvParam cvRef="MS" accession="MS:1000799" name="custom unreleased software tool" value="sage"/>
+    </software>
+  </softwareList>
+  <instrumentConfigurationList count="1">
+    <instrumentConfiguration id="IC1">
+      <cvParam cvRef="MS" accession="MS:1000031" name="instrument model" value=""/>
+    </instrumentConfiguration>
+  </instrumentConfigurationList>
+  <dataProcessingList count="1">
+    <dataProcessing id="sage_processing">
+      <processingMethod order="0" softwareRef="sage">
+        <cvParam cvRef="MS" accession="MS:1000544" name="Conversion to mzML" value=""/>
+      </processingMethod>
+    </dataProcessing>
+  </dataProcessingList>
+  <run id="sage" defaultInstrumentConfigurationRef="IC1">
+"#,
+        );
+        let _ = writeln!(
+            out,
+            r#"    <spectrumList count="{}" defaultDataProcessingRef="sage_processing">"#,
+            spectra.len()
+        );
+
+        for (index, spectrum) in spectra.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                r#"      <spectrum index="{}" id="{}" defaultArrayLength="{}">"#,
+                index,
+                quick_xml::escape::escape(&spectrum.id),
+                spectrum.peaks.len()
+            );
+            cv_param(
+                &mut out,
+                8,
+                MS_LEVEL,
+                "ms level",
+                &spectrum.level.to_string(),
+                None,
+            );
+            match spectrum.level {
+                1 => cv_param(&mut out, 8, MS1_SPECTRUM, "MS1 spectrum", "", None),
+                _ => cv_param(&mut out, 8, MSN_SPECTRUM, "MSn spectrum", "", None),
+            }
+            cv_param(&mut out, 8, CENTROID, "centroid spectrum", "", None);
+            cv_param(
+                &mut out,
+                8,
+                TOTAL_ION_CURRENT,
+                "total ion current",
+                &spectrum.total_ion_current.to_string(),
+                None,
+            );
+            cv_param(
+                &mut out,
+                8,
+                BASE_PEAK_INTENSITY,
+                "base peak intensity",
+                &spectrum.base_peak_intensity.to_string(),
+                Some(("MS:1000131", "number of detector counts")),
+            );
+            if let Some(cv) = spectrum.faims_cv {
+                cv_param(
+                    &mut out,
+                    8,
+                    FAIMS_COMPENSATION_VOLTAGE,
+                    "FAIMS compensation voltage",
+                    &cv.to_string(),
+                    Some(("UO:0000218", "volt")),
+                );
+            }
+
+            out.push_str("        <scanList count=\"1\">\n");
+            cv_param(&mut out, 10, NO_COMBINATION, "no combination", "", None);
+            out.push_str("          <scan>\n");
+            cv_param(
+                &mut out,
+                12,
+                SCAN_START_TIME,
+                "scan start time",
+                &spectrum.scan_start_time.to_string(),
+                Some(("UO:0000031", "minute")),
+            );
+            cv_param(
+                &mut out,
+                12,
+                ION_INJECTION_TIME,
+                "ion injection time",
+                &spectrum.ion_injection_time.to_string(),
+                Some(("UO:0000028", "millisecond")),
+            );
+            out.push_str("          </scan>\n");
+            out.push_str("        </scanList>\n");
+
+            if !spectrum.precursors.is_empty() {
+                let _ = writeln!(
+                    out,
+                    r#"        <precursorList count="{}">"#,
+                    spectrum.precursors.len()
+                );
+                for precursor in &spectrum.precursors {
+                    Self::write_precursor(&mut out, precursor, spectrum.activation);
+                }
+                out.push_str("        </precursorList>\n");
+            }
+
+            let mz = spectrum
+                .peaks
+                .iter()
+                .flat_map(|peak| ((peak.mass + PROTON) as f64).to_le_bytes())
+                .collect::<Vec<u8>>();
+            let intensity = spectrum
+                .peaks
+                .iter()
+                .flat_map(|peak| peak.intensity.to_le_bytes())
+                .collect::<Vec<u8>>();
+
+            out.push_str("        <binaryDataArrayList count=\"2\">\n");
+            self.write_binary_array(
+                &mut out,
+                mz,
+                (FLOAT_64, "64-bit float"),
+                (MZ_ARRAY, "m/z array"),
+                ("MS:1000040", "m/z"),
+            )
+            .await?;
+            self.write_binary_array(
+                &mut out,
+                intensity,
+                (FLOAT_32, "32-bit float"),
+                (INTENSITY_ARRAY, "intensity array"),
+                ("MS:1000131", "number of detector counts"),
+            )
+            .await?;
+            out.push_str("        </binaryDataArrayList>\n");
+            out.push_str("      </spectrum>\n");
+        }
+
+        out.push_str("    </spectrumList>\n  </run>\n</mzML>\n");
+        Ok(out.into_bytes())
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum MzMLError {
     #[error("malformed MzML")]
@@ -415,7 +847,7 @@ pub enum MzMLError {
 mod test {
     use sage_core::{mass::Tolerance, spectrum::Representation};
 
-    use super::{MzMLError, MzMLReader};
+    use super::{Activation, MzMLError, MzMLReader};
 
     #[tokio::test]
     async fn parse_spectrum_issue_78() -> Result<(), MzMLError> {
@@ -502,6 +934,8 @@ mod test {
         );
         assert!((s.scan_start_time - 25.066).abs() < 0.0001);
         assert_eq!(s.ion_injection_time, 0.0);
+        assert_eq!(s.faims_cv, None);
+        assert!(s.precursors[0].possible_charges.is_empty());
         assert_eq!(s.intensity.len(), s.mz.len());
         Ok(())
     }
@@ -595,4 +1029,228 @@ mod test {
         assert_eq!(s.intensity.len(), s.mz.len());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn parse_faims_cv() -> Result<(), MzMLError> {
+        // Converters write the compensation voltage either as part of the scan,
+        // or the spectrum itself
+        let s = r#"
+        <spectrum id="scan=1" index="0" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="1.5" unitAccession="UO:0000031" unitName="minute" unitCvRef="UO" />
+                    <cvParam cvRef="MS" accession="MS:1001581" name="FAIMS compensation voltage" value="-45.0" unitAccession="UO:0000218" unitName="volt" unitCvRef="UO" />
+                </scan>
+            </scanList>
+        </spectrum>
+        <spectrum id="scan=2" index="1" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <cvParam cvRef="MS" accession="MS:1001581" name="FAIMS compensation voltage" value="-60.0" unitAccession="UO:0000218" unitName="volt" unitCvRef="UO" />
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].faims_cv, Some(-45.0));
+        assert_eq!(spectra[1].faims_cv, Some(-60.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_possible_charges() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=1" index="0" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <precursorList count="1">
+                <precursor>
+                    <selectedIonList count="1">
+                        <selectedIon>
+                            <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="457.72" unitAccession="MS:1000040" unitName="m/z" unitCvRef="MS" />
+                            <cvParam cvRef="MS" accession="MS:1000633" name="possible charge state" value="2" />
+                            <cvParam cvRef="MS" accession="MS:1000633" name="possible charge state" value="3" />
+                        </selectedIon>
+                    </selectedIonList>
+                </precursor>
+            </precursorList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 1);
+        assert_eq!(spectra[0].precursors[0].charge, None);
+        assert_eq!(spectra[0].precursors[0].possible_charges, vec![2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_multiple_selected_ion_charges() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=1" index="0" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <precursorList count="1">
+                <precursor>
+                    <selectedIonList count="2">
+                        <selectedIon>
+                            <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="457.72" unitAccession="MS:1000040" unitName="m/z" unitCvRef="MS" />
+                            <cvParam cvRef="MS" accession="MS:1000041" name="charge state" value="2" />
+                        </selectedIon>
+                        <selectedIon>
+                            <cvParam cvRef="MS" accession="MS:1000744" name="selected ion m/z" value="457.72" unitAccession="MS:1000040" unitName="m/z" unitCvRef="MS" />
+                            <cvParam cvRef="MS" accession="MS:1000041" name="charge state" value="3" />
+                        </selectedIon>
+                    </selectedIonList>
+                </precursor>
+            </precursorList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        // One precursor per selected ion
+        let precursors = &spectra[0].precursors;
+        assert_eq!(precursors.len(), 2);
+        assert!(precursors.iter().all(|p| p.mz == 457.72));
+        assert_eq!(precursors[0].charge, Some(2));
+        assert_eq!(precursors[1].charge, Some(3));
+        assert!(precursors.iter().all(|p| p.possible_charges.is_empty()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_activation() -> Result<(), MzMLError> {
+        let spectrum = |id: usize, activation: &str| {
+            format!(
+                r#"
+        <spectrum id="scan={id}" index="{id}" defaultArrayLength="0">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <precursorList count="1">
+                <precursor>
+                    <activation>
+                        {activation}
+                    </activation>
+                </precursor>
+            </precursorList>
+        </spectrum>"#
+            )
+        };
+        let s = [
+            spectrum(1, r#"<cvParam cvRef="MS" accession="MS:1000422" name="beam-type collision-induced dissociation" />"#),
+            spectrum(2, r#"<cvParam cvRef="MS" accession="MS:1000598" name="electron transfer dissociation" />"#),
+            spectrum(
+                3,
+                r#"<cvParam cvRef="MS" accession="MS:1000598" name="electron transfer dissociation" />
+                <cvParam cvRef="MS" accession="MS:1002678" name="supplemental beam-type collision-induced dissociation" />"#,
+            ),
+            spectrum(4, ""),
+        ]
+        .concat();
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 4);
+        assert_eq!(spectra[0].activation, Some(Activation::Hcd));
+        assert_eq!(spectra[1].activation, Some(Activation::Etd));
+        assert_eq!(spectra[2].activation, Some(Activation::EThcd));
+        assert_eq!(spectra[3].activation, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_round_trip() -> Result<(), MzMLError> {
+        use super::MzMLWriter;
+        use sage_core::mass::PROTON;
+        use sage_core::spectrum::{Peak, Precursor, ProcessedSpectrum};
+
+        let peaks = vec![
+            Peak {
+                mass: 200.1,
+                intensity: 10.0,
+            },
+            Peak {
+                mass: 512.25,
+                intensity: 1234.5,
+            },
+        ];
+        let spectra = vec![
+            ProcessedSpectrum {
+                level: 1,
+                id: "scan=1".into(),
+                scan_start_time: 1.5,
+                ion_injection_time: 20.0,
+                peaks: peaks.clone(),
+                total_ion_current: 1244.5,
+                base_peak_intensity: 1234.5,
+                ..Default::default()
+            },
+            ProcessedSpectrum {
+                level: 2,
+                id: "controllerType=0 scan=2 & \"more\"".into(),
+                scan_start_time: 1.51,
+                ion_injection_time: 35.0,
+                faims_cv: Some(-45.0),
+                activation: Some(Activation::Hcd),
+                precursors: vec![Precursor {
+                    mz: 450.75,
+                    intensity: Some(5E6),
+                    charge: Some(2),
+                    possible_charges: vec![3],
+                    spectrum_ref: Some("scan=1".into()),
+                    isolation_window: Some(Tolerance::Da(-0.7, 0.8)),
+                    inverse_ion_mobility: Some(0.95),
+                    ..Default::default()
+                }],
+                peaks,
+                total_ion_current: 1244.5,
+                base_peak_intensity: 1234.5,
+                ..Default::default()
+            },
+        ];
+
+        for compress in [true, false] {
+            let bytes = MzMLWriter::default()
+                .set_compression(compress)
+                .write(&spectra)
+                .await?;
+            let parsed = MzMLReader::with_file_id(0).parse(bytes.as_slice()).await?;
+            assert_eq!(parsed.len(), 2);
+
+            for (raw, spectrum) in parsed.iter().zip(&spectra) {
+                assert_eq!(raw.id, spectrum.id);
+                assert_eq!(raw.ms_level, spectrum.level);
+                assert_eq!(raw.representation, Representation::Centroid);
+                assert_eq!(raw.scan_start_time, spectrum.scan_start_time);
+                assert_eq!(raw.ion_injection_time, spectrum.ion_injection_time);
+                assert_eq!(raw.total_ion_current, spectrum.total_ion_current);
+                assert_eq!(raw.faims_cv, spectrum.faims_cv);
+                assert_eq!(raw.activation, spectrum.activation);
+                let mz = spectrum
+                    .peaks
+                    .iter()
+                    .map(|peak| peak.mass + PROTON)
+                    .collect::<Vec<_>>();
+                let intensity = spectrum
+                    .peaks
+                    .iter()
+                    .map(|peak| peak.intensity)
+                    .collect::<Vec<_>>();
+                assert_eq!(raw.mz, mz);
+                assert_eq!(raw.intensity, intensity);
+            }
+
+            let precursor = &parsed[1].precursors[0];
+            assert!(parsed[0].precursors.is_empty());
+            assert_eq!(precursor.mz, 450.75);
+            assert_eq!(precursor.intensity, Some(5E6));
+            assert_eq!(precursor.charge, Some(2));
+            assert_eq!(precursor.possible_charges, vec![3]);
+            assert_eq!(precursor.spectrum_ref.as_deref(), Some("scan=1"));
+            assert_eq!(precursor.inverse_ion_mobility, Some(0.95));
+            match precursor.isolation_window {
+                Some(Tolerance::Da(lo, hi)) => {
+                    assert!((lo + 0.7).abs() < 1E-4 && (hi - 0.8).abs() < 1E-4)
+                }
+                window => panic!("unexpected isolation window {:?}", window),
+            }
+        }
+        Ok(())
+    }
 }
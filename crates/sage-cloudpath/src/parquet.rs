@@ -43,8 +43,12 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required int32 peptide_len;
             required int32 missed_cleavages;
             required boolean semi_enzymatic;
+            required boolean tryptic_cterm;
+            required int32 enzymatic_termini;
+            optional int32 label_channel;
             required float ms2_intensity;
             required float isotope_error;
+            required float precursor_offset;
             required float precursor_ppm;
             required float fragment_ppm;
             required float hyperscore;
@@ -54,16 +58,22 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required float aligned_rt;
             required float predicted_rt;
             required float delta_rt_model;
+            optional float delta_rt_external;
             required float ion_mobility;
             required float predicted_mobility;
             required float delta_mobility;
+            optional float faims_cv;
+            optional float chimericity;
+            required boolean propagated;
             required int32 matched_peaks;
             required int32 longest_b;
             required int32 longest_y;
             required float longest_y_pct;
+            required int32 complementary_pairs;
             required float matched_intensity_pct;
             required int32 scored_candidates;
             required float poisson;
+            required float andromeda;
             required float sage_discriminant_score;
             required float posterior_error;
             required float spectrum_q;
@@ -182,10 +192,7 @@ pub fn serialize_features(
             ByteArrayType
         );
         write_col!(
-            |f: &Feature| database[f.peptide_idx]
-                .proteins(&database.decoy_tag, database.generate_decoys)
-                .as_str()
-                .into(),
+            |f: &Feature| database.proteins(&database[f.peptide_idx]).as_str().into(),
             ByteArrayType
         );
         write_col!(
@@ -203,8 +210,27 @@ pub fn serialize_features(
             |f: &Feature| database[f.peptide_idx].semi_enzymatic,
             BoolType
         );
+        write_col!(
+            |f: &Feature| database[f.peptide_idx].is_tryptic_cterm(),
+            BoolType
+        );
+        write_col!(enzymatic_termini, Int32Type);
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.label_channel.map(i32::from))
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.label_channel.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<Int32Type>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
         write_col!(ms2_intensity, FloatType);
         write_col!(isotope_error, FloatType);
+        write_col!(precursor_offset, FloatType);
         write_col!(delta_mass, FloatType);
         write_col!(average_ppm, FloatType);
         write_col!(hyperscore, FloatType);
@@ -214,16 +240,58 @@ pub fn serialize_features(
         write_col!(aligned_rt, FloatType);
         write_col!(predicted_rt, FloatType);
         write_col!(delta_rt_model, FloatType);
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.delta_rt_external)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.delta_rt_external.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
         write_col!(ims, FloatType);
         write_col!(predicted_ims, FloatType);
         write_col!(delta_ims_model, FloatType);
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.faims_cv)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.faims_cv.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.chimericity)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.chimericity.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+        write_col!(|f: &Feature| f.propagated, BoolType);
         write_col!(matched_peaks, Int32Type);
         write_col!(longest_b, Int32Type);
         write_col!(longest_y, Int32Type);
         write_col!(longest_y_pct, FloatType);
+        write_col!(complementary_pairs, Int32Type);
         write_col!(matched_intensity_pct, FloatType);
         write_col!(scored_candidates, Int32Type);
         write_col!(poisson, FloatType);
+        write_col!(andromeda, FloatType);
         write_col!(discriminant_score, FloatType);
         write_col!(posterior_error, FloatType);
         write_col!(spectrum_q, FloatType);
@@ -495,10 +563,7 @@ pub fn serialize_lfq<H: BuildHasher>(
                 let peptide_idx = match id {
                     PrecursorId::Combined(x) | PrecursorId::Charged((x, _)) => x,
                 };
-                let val = database[*peptide_idx]
-                    .proteins(&database.decoy_tag, database.generate_decoys)
-                    .as_str()
-                    .into();
+                let val = database.proteins(&database[*peptide_idx]).as_str().into();
                 std::iter::repeat(val).take(filenames.len())
             })
             .collect::<Vec<_>>();
@@ -555,3 +620,81 @@ pub fn serialize_lfq<H: BuildHasher>(
     rg.close()?;
     writer.into_inner()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use sage_core::database::{Builder, PeptideIx};
+    use sage_core::fasta::Fasta;
+
+    #[test]
+    fn typed_psm_columns() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", true);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let decoy = database
+            .peptides
+            .iter()
+            .position(|peptide| peptide.decoy)
+            .unwrap();
+
+        let features = [
+            Feature {
+                psm_id: 1,
+                peptide_idx: PeptideIx(0),
+                spec_id: "scan=1".into(),
+                label: 1,
+                charge: 2,
+                hyperscore: 30.0,
+                spectrum_q: 0.005,
+                ..Default::default()
+            },
+            Feature {
+                psm_id: 2,
+                peptide_idx: PeptideIx(decoy as u32),
+                spec_id: "scan=2".into(),
+                label: -1,
+                charge: 3,
+                hyperscore: 10.0,
+                spectrum_q: 1.0,
+                faims_cv: Some(-45.0),
+                ..Default::default()
+            },
+        ];
+        let bytes = serialize_features(&features, &[], &["a.mzML".into()], &database).unwrap();
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(bytes)).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                row.unwrap()
+                    .get_column_iter()
+                    .map(|(name, field)| (name.clone(), field.clone()))
+                    .collect::<HashMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+
+        for (row, feature) in rows.iter().zip(&features) {
+            let peptide = database[feature.peptide_idx].to_string();
+            assert_eq!(row["peptide"], Field::Str(peptide));
+            assert_eq!(row["filename"], Field::Str("a.mzML".into()));
+            assert_eq!(row["scannr"], Field::Str(feature.spec_id.clone()));
+            assert_eq!(row["charge"], Field::Int(feature.charge as i32));
+            assert_eq!(row["is_decoy"], Field::Bool(feature.label == -1));
+            assert_eq!(row["hyperscore"], Field::Float(feature.hyperscore as f32));
+            assert_eq!(row["spectrum_q"], Field::Float(feature.spectrum_q));
+        }
+        // Optional columns are null when missing
+        assert_eq!(rows[0]["faims_cv"], Field::Null);
+        assert_eq!(rows[1]["faims_cv"], Field::Float(-45.0));
+    }
+}
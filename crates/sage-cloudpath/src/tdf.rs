@@ -30,6 +30,8 @@ impl TdfReader {
                     representation: Representation::Centroid,
                     scan_start_time: dda_precursor.rt as f32 / 60.0,
                     ion_injection_time: dda_precursor.rt as f32,
+                    faims_cv: None,
+                    activation: None,
                     total_ion_current: 0.0,
                     mz: dda_spectrum.mz_values.iter().map(|&x| x as f32).collect(),
                     ms_level: 2,
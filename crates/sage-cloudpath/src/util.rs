@@ -1,5 +1,8 @@
-use crate::{read_and_execute, Error};
-use sage_core::spectrum::RawSpectrum;
+use crate::{read_and_execute, CloudPath, Error};
+use rayon::prelude::*;
+use sage_core::database::SearchScratch;
+use sage_core::scoring::{Feature, Scorer};
+use sage_core::spectrum::{RawSpectrum, SpectrumProcessor};
 use serde::Serialize;
 use tokio::io::AsyncReadExt;
 
@@ -38,6 +41,90 @@ pub fn read_mgf<S: AsRef<str>>(path: S, file_id: usize) -> Result<Vec<RawSpectru
     })
 }
 
+/// Read spectra from an MGF, Bruker .d/.tdf or mzML file, depending on the extension of `path`
+pub fn read_spectra<S: AsRef<str>>(
+    path: S,
+    file_id: usize,
+    signal_to_noise: Option<u8>,
+) -> Result<Vec<RawSpectrum>, Error> {
+    let bruker_extensions = [".d", ".tdf", ".tdf_bin"];
+    let path_lower = path.as_ref().to_lowercase();
+    if path_lower.ends_with(".mgf.gz") || path_lower.ends_with(".mgf") {
+        read_mgf(path, file_id)
+    } else if bruker_extensions
+        .iter()
+        .any(|ext| path_lower.ends_with(ext))
+    {
+        read_tdf(path, file_id)
+    } else {
+        read_mzml(path, file_id, signal_to_noise)
+    }
+}
+
+/// PSMs identified from a single input file by [`search_files`]
+pub struct SearchedFile {
+    /// Index of this file in the list of searched paths, matches [`Feature::file_id`]
+    pub file_id: usize,
+    pub path: String,
+    /// File name, without any directory components
+    pub filename: String,
+    pub features: Vec<Feature>,
+}
+
+/// Read, process and search each file in `paths` in turn, returning the PSMs
+/// for each file tagged with its file name. Files are assigned `file_id`s in
+/// the order they are supplied, so spectrum ids are unique across files when
+/// combined with [`Feature::file_id`]. Only one file is held in memory at a
+/// time, while the spectra within each file are searched in parallel
+pub fn search_files<S: AsRef<str>>(
+    paths: &[S],
+    processor: &SpectrumProcessor,
+    scorer: &Scorer,
+) -> Result<Vec<SearchedFile>, Error> {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(file_id, path)| {
+            let path = path.as_ref();
+            let features = read_spectra(path, file_id, None)?
+                .into_par_iter()
+                .map(|spectrum| processor.process(spectrum))
+                .filter(|spectrum| spectrum.level == 2 && !spectrum.peaks.is_empty())
+                .map_init(SearchScratch::default, |scratch, spectrum| {
+                    scorer.score_with(scratch, &spectrum)
+                })
+                .flatten_iter()
+                .collect();
+
+            let filename = path
+                .parse::<CloudPath>()
+                .ok()
+                .and_then(|c| c.filename().map(|s| s.to_string()))
+                .unwrap_or_else(|| path.to_string());
+
+            Ok(SearchedFile {
+                file_id,
+                path: path.to_string(),
+                filename,
+                features,
+            })
+        })
+        .collect()
+}
+
+/// Read a tab-separated spectral library, see [`sage_core::library::parse_tsv`]
+pub fn read_library<S: AsRef<str>>(
+    path: S,
+) -> Result<Vec<sage_core::library::LibraryEntry>, Error> {
+    read_and_execute(path, |mut bf| async move {
+        let mut contents = String::new();
+        bf.read_to_string(&mut contents)
+            .await
+            .map_err(crate::Error::IO)?;
+        Ok(sage_core::library::parse_tsv(&contents)?)
+    })
+}
+
 pub fn read_fasta<S>(
     path: S,
     decoy_tag: S,
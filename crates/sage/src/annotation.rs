@@ -0,0 +1,496 @@
+//! Fully annotated peptide-spectrum matches, e.g. for rendering interactive
+//! spectra in a web viewer
+//!
+//! An [`AnnotatedPsm`] contains every observed peak and every theoretical ion
+//! of the matched peptide, each flagged with whether it was matched, alongside
+//! precursor information and scores. It implements [`serde::Serialize`], e.g.
+//! for export as JSON
+//!
+//! For PSMs carrying variable residue modifications, [`SiteEvidence`] lists the
+//! site-determining ions that distinguish the reported modification site from
+//! each alternative site, and whether they were observed
+
+use crate::database::IndexedDatabase;
+use crate::ion_series::{IonSeries, Kind};
+use crate::mass::{Tolerance, PROTON};
+use crate::modification::ModificationSpecificity;
+use crate::peptide::Peptide;
+use crate::scoring::{max_fragment_charge, Feature};
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ObservedPeak {
+    /// Observed m/z, at the charge of the matched ion. Deisotoped peaks are
+    /// charge-reduced, and are therefore reported as singly charged
+    pub mz: f32,
+    pub intensity: f32,
+    /// Lowest charge of the theoretical ions matching this peak, if any
+    pub charge: Option<u8>,
+    /// Is this peak matched by at least one theoretical ion?
+    pub matched: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TheoreticalIon {
+    pub kind: Kind,
+    /// Ion number, e.g. 3 for b3 or y3
+    pub ordinal: i32,
+    pub charge: u8,
+    pub mz: f32,
+    pub matched: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AnnotatedPsm {
+    pub spec_id: String,
+    /// Modified peptide sequence, see [`crate::peptide::Peptide::to_modified_string`]
+    pub peptide: String,
+    pub proteins: String,
+    pub label: i32,
+    pub rank: u32,
+    /// Selected precursor m/z
+    pub precursor_mz: f32,
+    pub charge: u8,
+    pub expmass: f32,
+    pub calcmass: f32,
+    pub rt: f32,
+    pub hyperscore: f64,
+    pub discriminant_score: f32,
+    pub spectrum_q: f32,
+    pub peptide_q: f32,
+    pub protein_q: f32,
+    /// Observed peaks, sorted by m/z
+    pub peaks: Vec<ObservedPeak>,
+    /// Theoretical ion ladder, ordered by kind, ordinal and charge
+    pub ions: Vec<TheoreticalIon>,
+    /// Site-determining ions for each variable residue modification
+    pub sites: Vec<SiteEvidence>,
+}
+
+/// Evidence for the localization of a variable modification, compared to an
+/// alternative site where the same modification could have been placed
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SiteEvidence {
+    /// Modification delta mass
+    pub mass: f32,
+    /// Reported modification site (1-based residue position)
+    pub position: usize,
+    /// Alternative modification site (1-based residue position)
+    pub alternative: usize,
+    /// Ions of the reported localization that differ in m/z from those of the
+    /// alternative localization
+    pub observed_ions: Vec<TheoreticalIon>,
+    /// Ions of the alternative localization that differ in m/z from those of
+    /// the reported localization
+    pub alternative_ions: Vec<TheoreticalIon>,
+}
+
+impl SiteEvidence {
+    /// Number of matched site-determining ions supporting the reported site
+    pub fn observed_matches(&self) -> usize {
+        self.observed_ions.iter().filter(|ion| ion.matched).count()
+    }
+
+    /// Number of matched site-determining ions supporting the alternative site
+    pub fn alternative_matches(&self) -> usize {
+        self.alternative_ions
+            .iter()
+            .filter(|ion| ion.matched)
+            .count()
+    }
+}
+
+/// Generate the theoretical ion ladder of `peptide`, along with the index of
+/// the peak in `spectrum` matched by each ion
+pub(crate) fn ion_ladder(
+    peptide: &Peptide,
+    ion_kinds: &[Kind],
+    spectrum: &ProcessedSpectrum,
+    fragment_tol: Tolerance,
+    max_charge: u8,
+) -> Vec<(TheoreticalIon, Option<usize>)> {
+    let mut ions = Vec::new();
+    for kind in ion_kinds {
+        for (idx, ion) in IonSeries::new(peptide, *kind).enumerate() {
+            let ordinal = match kind {
+                Kind::A | Kind::B | Kind::C => idx as i32 + 1,
+                Kind::X | Kind::Y | Kind::Z => {
+                    peptide.sequence.len().saturating_sub(1) as i32 - idx as i32
+                }
+            };
+            for charge in 1..max_charge {
+                // Experimental peaks are multipled by charge, therefore theoretical are divided
+                let mz = ion.monoisotopic_mass / charge as f32;
+                let peak = select_most_intense_peak(&spectrum.peaks, mz, fragment_tol, None)
+                    .and_then(|peak| spectrum.peaks.iter().position(|p| std::ptr::eq(p, peak)));
+                ions.push((
+                    TheoreticalIon {
+                        kind: *kind,
+                        ordinal,
+                        charge,
+                        mz: mz + PROTON,
+                        matched: peak.is_some(),
+                    },
+                    peak,
+                ));
+            }
+        }
+    }
+    ions
+}
+
+/// Site-determining ions of each variable residue modification of the PSM
+/// `feature`, against each alternative site of the same residue that is not
+/// already modified. Positions carrying a variable modification are identified
+/// by their mass difference to the least modified residue of the same kind, so
+/// static modifications are accounted for
+pub fn site_evidence(
+    db: &IndexedDatabase,
+    feature: &Feature,
+    spectrum: &ProcessedSpectrum,
+    fragment_tol: Tolerance,
+    max_charge: Option<u8>,
+    fragment_charge_offset: u8,
+) -> Vec<SiteEvidence> {
+    const EPSILON: f32 = 1E-3;
+
+    let peptide = &db[feature.peptide_idx];
+    let max_charge = max_fragment_charge(max_charge, feature.charge, fragment_charge_offset);
+    let mut evidence = Vec::new();
+    let mut observed = None;
+
+    for (specificity, mass) in &db.potential_mods {
+        let residue = match specificity {
+            ModificationSpecificity::Residue(residue) => *residue,
+            _ => continue,
+        };
+        let sites = peptide
+            .sequence
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| **r == residue)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let base = sites
+            .iter()
+            .map(|&idx| peptide.modifications[idx])
+            .fold(f32::INFINITY, f32::min);
+
+        let (modified, unmodified): (Vec<usize>, Vec<usize>) = sites
+            .into_iter()
+            .filter(|&idx| {
+                let delta = peptide.modifications[idx] - base;
+                delta.abs() < EPSILON || (delta - mass).abs() < EPSILON
+            })
+            .partition(|&idx| (peptide.modifications[idx] - base - mass).abs() < EPSILON);
+        if mass.abs() < EPSILON || modified.is_empty() || unmodified.is_empty() {
+            continue;
+        }
+
+        let observed = observed.get_or_insert_with(|| {
+            ion_ladder(peptide, &db.ion_kinds, spectrum, fragment_tol, max_charge)
+        });
+        for &site in &modified {
+            for &alternative in &unmodified {
+                let mut moved = peptide.clone();
+                moved.modifications[site] -= mass;
+                moved.modifications[alternative] += mass;
+                let moved = ion_ladder(&moved, &db.ion_kinds, spectrum, fragment_tol, max_charge);
+
+                let (observed_ions, alternative_ions) = observed
+                    .iter()
+                    .zip(moved)
+                    .filter(|((a, _), (b, _))| (a.mz - b.mz).abs() > EPSILON)
+                    .map(|((a, _), (b, _))| (a.clone(), b))
+                    .unzip();
+                evidence.push(SiteEvidence {
+                    mass: *mass,
+                    position: site + 1,
+                    alternative: alternative + 1,
+                    observed_ions,
+                    alternative_ions,
+                });
+            }
+        }
+    }
+    evidence
+}
+
+impl AnnotatedPsm {
+    /// Annotate the PSM `feature` against the `spectrum` it was matched to.
+    ///
+    /// `fragment_tol`, `max_charge` (maximum fragment charge) and
+    /// `fragment_charge_offset` should match the settings of the
+    /// [`crate::scoring::Scorer`] that generated `feature`
+    pub fn new(
+        db: &IndexedDatabase,
+        feature: &Feature,
+        spectrum: &ProcessedSpectrum,
+        fragment_tol: Tolerance,
+        max_charge: Option<u8>,
+        fragment_charge_offset: u8,
+    ) -> Self {
+        let peptide = &db[feature.peptide_idx];
+        let sites = site_evidence(
+            db,
+            feature,
+            spectrum,
+            fragment_tol,
+            max_charge,
+            fragment_charge_offset,
+        );
+        let max_charge = max_fragment_charge(max_charge, feature.charge, fragment_charge_offset);
+
+        let mut matched_charges: Vec<Option<u8>> = vec![None; spectrum.peaks.len()];
+        let ions = ion_ladder(peptide, &db.ion_kinds, spectrum, fragment_tol, max_charge)
+            .into_iter()
+            .map(|(ion, peak)| {
+                if let Some(peak) = peak {
+                    let charge = &mut matched_charges[peak];
+                    *charge = Some(charge.map_or(ion.charge, |c| c.min(ion.charge)));
+                }
+                ion
+            })
+            .collect();
+
+        let peaks = spectrum
+            .peaks
+            .iter()
+            .zip(matched_charges)
+            .map(|(peak, charge)| ObservedPeak {
+                // Peaks are matched against the ion mass divided by charge,
+                // i.e. [M + zH]/z - H, at any matched charge
+                mz: peak.mass + PROTON,
+                intensity: peak.intensity,
+                charge,
+                matched: charge.is_some(),
+            })
+            .collect();
+
+        AnnotatedPsm {
+            spec_id: feature.spec_id.clone(),
+            peptide: peptide.to_modified_string(),
+            proteins: db.proteins(peptide),
+            label: feature.label,
+            rank: feature.rank,
+            precursor_mz: spectrum
+                .precursors
+                .first()
+                .map(|precursor| precursor.mz)
+                .unwrap_or_default(),
+            charge: feature.charge,
+            expmass: feature.expmass,
+            calcmass: feature.calcmass,
+            rt: feature.rt,
+            hyperscore: feature.hyperscore,
+            discriminant_score: feature.discriminant_score,
+            spectrum_q: feature.spectrum_q,
+            peptide_q: feature.peptide_q,
+            protein_q: feature.protein_q,
+            peaks,
+            ions,
+            sites,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Builder, PeptideIx};
+    use crate::fasta::Fasta;
+    use crate::scoring::Scorer;
+    use crate::spectrum::{Peak, Precursor};
+
+    #[test]
+    fn annotate_psm() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let ix = db
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"AQLTQLK")
+            .unwrap();
+        let peptide = &db[PeptideIx(ix as u32)];
+
+        // Only b-ions are present in the spectrum, alongside a noise peak
+        let mut peaks = IonSeries::new(peptide, Kind::B)
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .chain(std::iter::once(Peak {
+                mass: 1234.5,
+                intensity: 10.0,
+            }))
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let fragment_tol = Tolerance::Ppm(-10.0, 10.0);
+        let scorer = Scorer {
+            db: &db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol,
+            min_matched_peaks: 4,
+            min_consecutive_ions: 0,
+            min_score: None,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            averagine_isotope_errors: false,
+            averagine_precursor_correction: false,
+            isotope_error_penalty: 0.0,
+            precursor_offsets: &[],
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            precursor_charge_limits: (1, u8::MAX),
+            max_fragment_charge: Some(1),
+            exclude_above_precursor_mz: false,
+            dedup_fragment_charges: false,
+            fragment_charge_offset: 1,
+            intact_mass_tol: None,
+            min_fragment_mass: 150.0,
+            max_fragment_mass: 2000.0,
+            min_scored_fragment_mz: None,
+            chimera: false,
+            report_psms: 1,
+            ion_weights: Default::default(),
+            activation_ion_kinds: false,
+            peak_selection: Default::default(),
+            ranking_score: Default::default(),
+            wide_window: false,
+            annotate_matches: false,
+        };
+        let feature = scorer.score(&spectrum).remove(0);
+        assert_eq!(feature.peptide_idx.0 as usize, ix);
+
+        let psm = AnnotatedPsm::new(&db, &feature, &spectrum, fragment_tol, Some(1), 1);
+        assert_eq!(psm.peptide, "AQLTQLK");
+        assert_eq!(psm.ions.len(), 12);
+        assert!(psm
+            .ions
+            .iter()
+            .all(|ion| ion.matched == (ion.kind == Kind::B)));
+        assert_eq!(
+            psm.ions
+                .iter()
+                .filter(|ion| ion.kind == Kind::Y)
+                .map(|ion| ion.ordinal)
+                .collect::<Vec<_>>(),
+            vec![6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(psm.peaks.iter().filter(|peak| peak.matched).count(), 6);
+        assert!(!psm.peaks.last().unwrap().matched);
+        assert!(psm
+            .peaks
+            .iter()
+            .all(|peak| peak.charge == peak.matched.then_some(1)));
+
+        let json = serde_json::to_string(&psm).unwrap();
+        assert!(json.contains(r#""kind":"b","ordinal":1,"charge":1"#));
+        assert!(json.contains(r#""peptide":"AQLTQLK""#));
+    }
+
+    #[test]
+    fn site_determining_ions() {
+        let fasta = Fasta::parse(">sp|AAAAA\nKASPTSLEKR".into(), "rev_", false);
+        let mut parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        parameters.variable_mods = [(ModificationSpecificity::Residue(b'S'), vec![79.96633])]
+            .into_iter()
+            .collect();
+        let db = parameters.build(fasta);
+
+        let find = |phospho: bool| {
+            db.peptides
+                .iter()
+                .position(|p| {
+                    p.sequence.as_ref() == b"ASPTSLEK" && (p.modifications[1] > 0.0) == phospho
+                })
+                .map(|ix| PeptideIx(ix as u32))
+                .unwrap()
+        };
+        let ix = find(true);
+        let peptide = &db[ix];
+        assert_eq!(peptide.modifications[4], 0.0);
+
+        let mut peaks = IonSeries::new(peptide, Kind::B)
+            .chain(IonSeries::new(peptide, Kind::Y))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            peaks,
+            ..Default::default()
+        };
+
+        let feature = Feature {
+            peptide_idx: ix,
+            charge: 2,
+            ..Default::default()
+        };
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let evidence = site_evidence(&db, &feature, &spectrum, tol, Some(1), 1);
+
+        // Threonine is not a candidate site, so S2 is only compared to S5
+        assert_eq!(evidence.len(), 1);
+        let site = &evidence[0];
+        assert_eq!((site.position, site.alternative), (2, 5));
+        // b2-b4 and y4-y6 differ between the two localizations
+        assert_eq!(
+            site.observed_ions
+                .iter()
+                .map(|ion| (ion.kind, ion.ordinal))
+                .collect::<Vec<_>>(),
+            vec![
+                (Kind::B, 2),
+                (Kind::B, 3),
+                (Kind::B, 4),
+                (Kind::Y, 6),
+                (Kind::Y, 5),
+                (Kind::Y, 4)
+            ]
+        );
+        assert_eq!(site.alternative_ions.len(), 6);
+        assert_eq!(site.observed_matches(), 6);
+        assert_eq!(site.alternative_matches(), 0);
+
+        let psm = AnnotatedPsm::new(&db, &feature, &spectrum, tol, Some(1), 1);
+        assert_eq!(psm.sites, evidence);
+        assert!(serde_json::to_string(&psm)
+            .unwrap()
+            .contains(r#""position":2,"alternative":5"#));
+
+        // Unmodified peptides have no sites to localize
+        let unmodified = Feature {
+            peptide_idx: find(false),
+            ..feature
+        };
+        assert!(site_evidence(&db, &unmodified, &spectrum, tol, Some(1), 1).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::annotation::ion_ladder;
+use crate::database::IndexedDatabase;
+use crate::mass::{Tolerance, PROTON};
+use crate::scoring::Feature;
+use crate::spectrum::ProcessedSpectrum;
+use fnv::FnvHashMap;
+
+/// Only PSMs passing this spectrum-level q-value are used for calibration
+const CALIBRATION_Q_VALUE: f32 = 0.01;
+
+/// Estimate the systematic fragment mass error (in ppm) as the median ppm
+/// difference between experimental and theoretical m/z across all matched
+/// fragment ions of confident (rank 1, target, spectrum q-value <= 1%) PSMs.
+///
+/// Singly charged fragment ions of each PSM are matched (within `fragment_tol`)
+/// against the spectrum it was identified from, looked up in `spectra` by file
+/// and spectrum id. Call this once per file after FDR has been assigned, and
+/// apply the offset to the spectra of a second search pass with
+/// [`correct_fragments`].
+/// Returns 0.0 if there are no matched fragment ions to calibrate against
+pub fn calibrate_fragments(
+    db: &IndexedDatabase,
+    features: &[Feature],
+    spectra: &[ProcessedSpectrum],
+    fragment_tol: Tolerance,
+) -> f32 {
+    let spectra = spectra
+        .iter()
+        .map(|spectrum| ((spectrum.file_id, spectrum.id.as_str()), spectrum))
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut errors = features
+        .iter()
+        .filter(|feat| feat.rank == 1 && feat.label == 1)
+        .filter(|feat| feat.spectrum_q <= CALIBRATION_Q_VALUE)
+        .filter_map(|feat| {
+            let spectrum = *spectra.get(&(feat.file_id, feat.spec_id.as_str()))?;
+            Some((feat, spectrum))
+        })
+        .flat_map(|(feat, spectrum)| {
+            ion_ladder(
+                &db[feat.peptide_idx],
+                &db.ion_kinds,
+                spectrum,
+                fragment_tol,
+                2,
+            )
+            .into_iter()
+            .filter_map(move |(ion, peak)| {
+                let exp = spectrum.peaks[peak?].mass + PROTON;
+                Some((exp - ion.mz) / ion.mz * 1E6)
+            })
+        })
+        .collect::<Vec<f32>>();
+
+    if errors.is_empty() {
+        return 0.0;
+    }
+
+    errors.sort_unstable_by(|a, b| a.total_cmp(b));
+    let mid = errors.len() / 2;
+    if errors.len() % 2 == 0 {
+        (errors[mid - 1] + errors[mid]) / 2.0
+    } else {
+        errors[mid]
+    }
+}
+
+/// Remove a systematic fragment mass error of `ppm` (as measured by
+/// [`calibrate_fragments`]) from the peaks of a processed spectrum
+pub fn correct_fragments(spectrum: &mut ProcessedSpectrum, ppm: f32) {
+    // Peaks are stored as neutral masses, but the error is relative to m/z
+    let scale = 1.0 + ppm / 1E6;
+    for peak in spectrum.peaks.iter_mut() {
+        peak.mass = (peak.mass + PROTON) / scale - PROTON;
+    }
+}
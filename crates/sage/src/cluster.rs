@@ -0,0 +1,263 @@
+use crate::mass::Tolerance;
+use crate::scoring::{increment_psm_counter, Feature};
+use crate::spectrum::{Peak, ProcessedSpectrum};
+use fnv::FnvHashMap;
+use rayon::prelude::*;
+
+/// A group of near-duplicate MS2 spectra, indices refer to the slice of
+/// spectra passed to [`cluster_spectra`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cluster {
+    /// Spectrum that is searched on behalf of the whole cluster
+    pub representative: usize,
+    /// All spectra in the cluster, including the representative
+    pub members: Vec<usize>,
+}
+
+/// Cosine similarity of two spectra (peaks sorted by mass), using square-root
+/// transformed intensities. Each peak is matched at most once
+pub fn cosine_similarity(a: &[Peak], b: &[Peak], fragment_tol: Tolerance) -> f32 {
+    let (mut i, mut j) = (0, 0);
+    let mut dot = 0.0;
+    while i < a.len() && j < b.len() {
+        let (lo, hi) = fragment_tol.bounds(a[i].mass);
+        if b[j].mass < lo {
+            j += 1;
+        } else if b[j].mass > hi {
+            i += 1;
+        } else {
+            dot += (a[i].intensity * b[j].intensity).sqrt();
+            i += 1;
+            j += 1;
+        }
+    }
+
+    // Norm of sqrt-transformed intensities is the sum of the intensities
+    let norm_a = a.iter().map(|peak| peak.intensity).sum::<f32>();
+    let norm_b = b.iter().map(|peak| peak.intensity).sum::<f32>();
+    if norm_a <= 0.0 || norm_b <= 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b).sqrt()
+}
+
+/// Precursor charge and FAIMS compensation voltage (as bits) - only spectra
+/// with the same key can be clustered together
+type ClusterKey = (Option<u8>, Option<u32>);
+
+/// Greedily cluster MS2 spectra with the same precursor charge and FAIMS
+/// compensation voltage, a precursor m/z within `precursor_tol`, and a
+/// fragment cosine similarity of at least `min_similarity` to the cluster
+/// representative (the first spectrum, in
+/// order of precursor m/z, that did not match an existing cluster).
+///
+/// Spectra without a precursor are placed in their own cluster. Spectra that
+/// are not MS2 are not assigned to any cluster
+pub fn cluster_spectra(
+    spectra: &[ProcessedSpectrum],
+    precursor_tol: Tolerance,
+    fragment_tol: Tolerance,
+    min_similarity: f32,
+) -> Vec<Cluster> {
+    let mut groups: FnvHashMap<ClusterKey, Vec<(usize, f32)>> = FnvHashMap::default();
+    let mut clusters = Vec::new();
+    for (idx, spectrum) in spectra.iter().enumerate() {
+        if spectrum.level != 2 {
+            continue;
+        }
+        match spectrum.precursors.first() {
+            Some(precursor) => groups
+                .entry((precursor.charge, spectrum.faims_cv.map(f32::to_bits)))
+                .or_default()
+                .push((idx, precursor.mz)),
+            None => clusters.push(Cluster {
+                representative: idx,
+                members: vec![idx],
+            }),
+        }
+    }
+
+    let mut groups = groups.into_values().collect::<Vec<_>>();
+    clusters.par_extend(groups.par_iter_mut().flat_map_iter(|group| {
+        group.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        // Clusters (and the precursor m/z of their representative) are created
+        // in order of increasing m/z, so we only need to consider a sliding
+        // window of clusters within precursor tolerance
+        let mut group_clusters: Vec<(Cluster, f32)> = Vec::new();
+        let mut window = 0;
+        for &(idx, mz) in group.iter() {
+            let (lo, _) = precursor_tol.bounds(mz);
+            while window < group_clusters.len() && group_clusters[window].1 < lo {
+                window += 1;
+            }
+
+            let best = group_clusters[window..]
+                .iter()
+                .enumerate()
+                .map(|(offset, (cluster, _))| {
+                    let similarity = cosine_similarity(
+                        &spectra[cluster.representative].peaks,
+                        &spectra[idx].peaks,
+                        fragment_tol,
+                    );
+                    (window + offset, similarity)
+                })
+                .filter(|(_, similarity)| *similarity >= min_similarity)
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+
+            match best {
+                Some((cluster, _)) => group_clusters[cluster].0.members.push(idx),
+                None => group_clusters.push((
+                    Cluster {
+                        representative: idx,
+                        members: vec![idx],
+                    },
+                    mz,
+                )),
+            }
+        }
+        group_clusters.into_iter().map(|(cluster, _)| cluster)
+    }));
+
+    clusters.sort_by_key(|cluster| cluster.representative);
+    clusters
+}
+
+/// Copy the PSMs of each cluster representative (found by searching only the
+/// representatives) to all other members of the cluster. Spectrum-specific
+/// fields (spectrum id, file, retention time, ion mobility and experimental
+/// mass) are taken from the member spectrum; scores are those of the representative.
+/// Copied PSMs are flagged as [`Feature::propagated`], and are not used for
+/// training the linear discriminant model
+pub fn propagate(
+    clusters: &[Cluster],
+    spectra: &[ProcessedSpectrum],
+    features: Vec<Feature>,
+) -> Vec<Feature> {
+    let representatives = clusters
+        .iter()
+        .map(|cluster| {
+            let spectrum = &spectra[cluster.representative];
+            ((spectrum.file_id, spectrum.id.as_str()), cluster)
+        })
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut propagated = Vec::with_capacity(features.len());
+    for feature in &features {
+        let cluster = match representatives.get(&(feature.file_id, feature.spec_id.as_str())) {
+            Some(cluster) => cluster,
+            None => continue,
+        };
+        for &member in cluster.members.iter() {
+            if member == cluster.representative {
+                continue;
+            }
+            let spectrum = &spectra[member];
+            let precursor = match spectrum.precursors.first() {
+                Some(precursor) => precursor,
+                None => continue,
+            };
+
+            let mut feat = feature.clone();
+            feat.psm_id = increment_psm_counter();
+            feat.spec_id = spectrum.id.clone();
+            feat.file_id = spectrum.file_id;
+            feat.rt = spectrum.scan_start_time;
+            feat.aligned_rt = spectrum.scan_start_time;
+            feat.ims = precursor.inverse_ion_mobility.unwrap_or(0.0);
+            feat.faims_cv = spectrum.faims_cv;
+            feat.chimericity = spectrum.chimericity;
+            feat.propagated = true;
+            feat.expmass = precursor.neutral_mz() * feat.charge as f32;
+
+            // `isotope_error` and `precursor_offset` are already in Da
+            let shift = feat.isotope_error + feat.precursor_offset;
+            feat.delta_mass = (feat.expmass - feat.calcmass - shift).abs() * 2E6
+                / (feat.expmass - shift + feat.calcmass);
+            propagated.push(feat);
+        }
+    }
+
+    let mut features = features;
+    features.extend(propagated);
+    features
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spectrum::Precursor;
+
+    fn spectrum(mz: f32, peaks: &[(f32, f32)]) -> ProcessedSpectrum {
+        ProcessedSpectrum {
+            level: 2,
+            precursors: vec![Precursor {
+                mz,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks: peaks
+                .iter()
+                .map(|&(mass, intensity)| Peak { mass, intensity })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cosine() {
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let a = spectrum(500.0, &[(200.0, 4.0), (300.0, 9.0), (400.0, 1.0)]);
+        let b = spectrum(500.0, &[(200.001, 4.0), (300.0, 9.0), (400.0, 1.0)]);
+        let c = spectrum(500.0, &[(250.0, 4.0), (350.0, 9.0)]);
+        assert!((cosine_similarity(&a.peaks, &b.peaks, tol) - 1.0).abs() < 1E-6);
+        assert_eq!(cosine_similarity(&a.peaks, &c.peaks, tol), 0.0);
+    }
+
+    #[test]
+    fn clustering() {
+        let peaks = [(200.0, 4.0), (300.0, 9.0), (400.0, 1.0)];
+        let other = [(250.0, 4.0), (350.0, 9.0)];
+        let spectra = vec![
+            spectrum(500.0, &peaks),
+            // Different fragments, same precursor
+            spectrum(500.0, &other),
+            // Same fragments, different precursor
+            spectrum(510.0, &peaks),
+            // Duplicates of spectra 0 and 1
+            spectrum(500.001, &peaks),
+            spectrum(499.999, &other),
+            // Duplicate of spectrum 0, acquired at a different FAIMS CV
+            ProcessedSpectrum {
+                faims_cv: Some(-45.0),
+                ..spectrum(500.0, &peaks)
+            },
+            ProcessedSpectrum {
+                level: 1,
+                ..Default::default()
+            },
+        ];
+
+        let clusters = cluster_spectra(
+            &spectra,
+            Tolerance::Ppm(-10.0, 10.0),
+            Tolerance::Ppm(-10.0, 10.0),
+            0.9,
+        );
+
+        let mut members = clusters
+            .iter()
+            .map(|c| {
+                let mut m = c.members.clone();
+                m.sort();
+                m
+            })
+            .collect::<Vec<_>>();
+        members.sort();
+        assert_eq!(members, vec![vec![0, 3], vec![1, 4], vec![2], vec![5]]);
+        for cluster in &clusters {
+            assert!(cluster.members.contains(&cluster.representative));
+        }
+    }
+}
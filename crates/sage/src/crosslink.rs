@@ -0,0 +1,479 @@
+//! Search for pairs of peptides connected by a non-cleavable crosslinker
+//!
+//! The precursor mass of a crosslinked spectrum is the sum of both partner
+//! peptides and the crosslinker. Candidate "alpha" peptides are first selected
+//! by an open search of linear fragments against the fragment index, and the
+//! complementary "beta" peptides are then found by precursor mass. Each partner
+//! is scored separately - fragments containing the crosslink site carry the
+//! mass of the other partner and the linker.
+
+use crate::database::{binary_search_slice, IndexedDatabase, PeptideIx};
+use crate::enzyme::Position;
+use crate::ion_series::{IonSeries, Kind};
+use crate::mass::Tolerance;
+use crate::peptide::Peptide;
+use crate::scoring::{lnfact, max_fragment_charge};
+use crate::spectrum::ProcessedSpectrum;
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Crosslinker {
+    /// Mass added to the precursor by the crosslinker
+    pub mass: f32,
+    /// Residues that the crosslinker can react with
+    pub residues: Vec<u8>,
+    /// Can the crosslinker react with protein N-termini?
+    pub protein_nterm: bool,
+}
+
+impl Crosslinker {
+    /// DSS/BS3: lysine and protein N-termini
+    pub fn dss() -> Self {
+        Crosslinker {
+            mass: 138.06808,
+            residues: vec![b'K'],
+            protein_nterm: true,
+        }
+    }
+
+    /// Return all residue indices of `peptide` that can be crosslinked.
+    ///
+    /// A crosslinked residue blocks enzymatic cleavage, so the C-terminal residue
+    /// of a peptide can only be crosslinked if it is also the protein C-terminus
+    pub fn sites(&self, peptide: &Peptide) -> Vec<usize> {
+        let len = peptide.sequence.len();
+        let protein_cterm = matches!(peptide.position, Position::Cterm | Position::Full);
+        let protein_nterm = matches!(peptide.position, Position::Nterm | Position::Full);
+        peptide
+            .sequence
+            .iter()
+            .enumerate()
+            .filter(|&(idx, residue)| {
+                (idx + 1 < len || protein_cterm)
+                    && (self.residues.contains(residue)
+                        || (idx == 0 && protein_nterm && self.protein_nterm))
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+/// A crosslinked peptide-spectrum match
+pub struct Crosslink {
+    pub alpha: PeptideIx,
+    pub beta: PeptideIx,
+    /// Crosslinked residue index (0-based) within the alpha peptide
+    pub alpha_site: usize,
+    /// Crosslinked residue index (0-based) within the beta peptide
+    pub beta_site: usize,
+    pub alpha_residue: char,
+    pub beta_residue: char,
+    pub spec_id: String,
+    pub file_id: usize,
+    pub rank: u32,
+    /// Target-target crosslinks are labeled 1, any decoy partner -1
+    pub label: i32,
+    pub charge: u8,
+    pub expmass: f32,
+    /// Calculated mass: alpha + beta + crosslinker
+    pub calcmass: f32,
+    pub hyperscore: f64,
+    pub delta_next: f64,
+    pub matched_alpha: u16,
+    pub matched_beta: u16,
+}
+
+/// Matched fragments of a single crosslink partner
+#[derive(Copy, Clone, Default, Debug)]
+struct PartnerScore {
+    site: usize,
+    matched_b: u16,
+    matched_y: u16,
+    summed_b: f32,
+    summed_y: f32,
+}
+
+impl PartnerScore {
+    fn matched(&self) -> u16 {
+        self.matched_b + self.matched_y
+    }
+}
+
+pub struct CrosslinkScorer<'db> {
+    pub db: &'db IndexedDatabase,
+    pub crosslinker: Crosslinker,
+    pub precursor_tol: Tolerance,
+    pub fragment_tol: Tolerance,
+    /// Minimum number of matched fragments required for *each* partner
+    pub min_matched_peaks: u16,
+    pub min_precursor_charge: u8,
+    pub max_precursor_charge: u8,
+    pub max_fragment_charge: Option<u8>,
+    /// See [`crate::scoring::Scorer::fragment_charge_offset`]
+    pub fragment_charge_offset: u8,
+    pub report_psms: usize,
+    /// Number of alpha peptide candidates (ranked by matched linear fragments)
+    /// to consider for each spectrum
+    pub candidates: usize,
+}
+
+impl<'db> CrosslinkScorer<'db> {
+    /// Score a single [`ProcessedSpectrum`] against all crosslinked peptide pairs
+    pub fn score(&self, query: &ProcessedSpectrum) -> Vec<Crosslink> {
+        let precursor = query.precursors.first().unwrap_or_else(|| {
+            panic!("missing MS1 precursor for {}", query.id);
+        });
+
+        // Sage operates on masses without protons; [M] instead of [MH+]
+        let mz = precursor.neutral_mz();
+        let charges =
+            precursor.candidate_charges(self.min_precursor_charge, self.max_precursor_charge);
+
+        let mut crosslinks = charges
+            .into_iter()
+            .flat_map(|charge| self.score_charge(query, mz * charge as f32, charge))
+            .collect::<Vec<_>>();
+        crosslinks.sort_by(|a, b| b.hyperscore.total_cmp(&a.hyperscore));
+        crosslinks.truncate(self.report_psms);
+
+        let next = crosslinks
+            .iter()
+            .skip(1)
+            .map(|xl| xl.hyperscore)
+            .chain(std::iter::once(0.0))
+            .collect::<Vec<_>>();
+        for (idx, xl) in crosslinks.iter_mut().enumerate() {
+            xl.rank = idx as u32 + 1;
+            xl.delta_next = xl.hyperscore - next[idx];
+        }
+        crosslinks
+    }
+
+    fn score_charge(
+        &self,
+        query: &ProcessedSpectrum,
+        precursor_mass: f32,
+        charge: u8,
+    ) -> Vec<Crosslink> {
+        let min_mass = match self.db.peptides.first() {
+            Some(peptide) => peptide.monoisotopic,
+            None => return Vec::new(),
+        };
+        let (precursor_lo, precursor_hi) = self.precursor_tol.bounds(precursor_mass);
+        let pair_lo = precursor_lo - self.crosslinker.mass;
+        let pair_hi = precursor_hi - self.crosslinker.mass;
+        if pair_hi - min_mass < min_mass {
+            return Vec::new();
+        }
+
+        let max_fragment_charge = max_fragment_charge(
+            self.max_fragment_charge,
+            charge,
+            self.fragment_charge_offset,
+        );
+        let mut crosslinks = Vec::new();
+        let mut seen = FnvHashSet::default();
+
+        for alpha in self.alpha_candidates(query, min_mass, pair_hi - min_mass, max_fragment_charge)
+        {
+            let alpha_peptide = &self.db[alpha];
+            let (lo, hi) = (
+                pair_lo - alpha_peptide.monoisotopic,
+                pair_hi - alpha_peptide.monoisotopic,
+            );
+            let (left, right) = binary_search_slice(
+                &self.db.peptides,
+                |p, bounds| p.monoisotopic.total_cmp(bounds),
+                lo,
+                hi,
+            );
+
+            for (idx, beta_peptide) in self.db.peptides[left..right].iter().enumerate() {
+                let beta = PeptideIx((left + idx) as u32);
+                if beta_peptide.monoisotopic < lo
+                    || beta_peptide.monoisotopic > hi
+                    || !seen.insert((alpha.min(beta), alpha.max(beta)))
+                {
+                    continue;
+                }
+
+                let alpha_score = self.score_partner(
+                    query,
+                    alpha_peptide,
+                    beta_peptide.monoisotopic + self.crosslinker.mass,
+                    max_fragment_charge,
+                );
+                let beta_score = self.score_partner(
+                    query,
+                    beta_peptide,
+                    alpha_peptide.monoisotopic + self.crosslinker.mass,
+                    max_fragment_charge,
+                );
+                let (alpha_score, beta_score) = match (alpha_score, beta_score) {
+                    (Some(a), Some(b))
+                        if a.matched() >= self.min_matched_peaks
+                            && b.matched() >= self.min_matched_peaks =>
+                    {
+                        (a, b)
+                    }
+                    _ => continue,
+                };
+
+                let matched_b = alpha_score.matched_b + beta_score.matched_b;
+                let matched_y = alpha_score.matched_y + beta_score.matched_y;
+                let i = (alpha_score.summed_b + beta_score.summed_b + 1.0) as f64
+                    * (alpha_score.summed_y + beta_score.summed_y + 1.0) as f64;
+                let mut hyperscore = i.ln() + lnfact(matched_b) + lnfact(matched_y);
+                if !hyperscore.is_finite() {
+                    hyperscore = 255.0;
+                }
+
+                crosslinks.push(Crosslink {
+                    alpha,
+                    beta,
+                    alpha_site: alpha_score.site,
+                    beta_site: beta_score.site,
+                    alpha_residue: alpha_peptide.sequence[alpha_score.site] as char,
+                    beta_residue: beta_peptide.sequence[beta_score.site] as char,
+                    spec_id: query.id.clone(),
+                    file_id: query.file_id,
+                    rank: 0,
+                    label: match alpha_peptide.decoy || beta_peptide.decoy {
+                        true => -1,
+                        false => 1,
+                    },
+                    charge,
+                    expmass: precursor_mass,
+                    calcmass: alpha_peptide.monoisotopic
+                        + beta_peptide.monoisotopic
+                        + self.crosslinker.mass,
+                    hyperscore,
+                    delta_next: 0.0,
+                    matched_alpha: alpha_score.matched(),
+                    matched_beta: beta_score.matched(),
+                });
+            }
+        }
+        crosslinks
+    }
+
+    /// Open search of linear fragments against the fragment index, returning
+    /// the `candidates` crosslinkable peptides with the most matched fragments
+    fn alpha_candidates(
+        &self,
+        query: &ProcessedSpectrum,
+        min_mass: f32,
+        max_mass: f32,
+        max_fragment_charge: u8,
+    ) -> Vec<PeptideIx> {
+        let center = (min_mass + max_mass) / 2.0;
+        let candidates = self.db.query(
+            center,
+            Tolerance::Da(min_mass - center, max_mass - center),
+            self.fragment_tol,
+        );
+
+        let mut matched = vec![0u16; candidates.pre_idx_hi - candidates.pre_idx_lo + 1];
+        for peak in query.peaks.iter() {
+            for charge in 1..max_fragment_charge {
+                for frag in candidates.page_search(peak.mass * charge as f32) {
+                    matched[frag.peptide_index.0 as usize - candidates.pre_idx_lo] += 1;
+                }
+            }
+        }
+
+        let mut hits = matched
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, count)| count > 0)
+            .map(|(idx, count)| (count, PeptideIx((candidates.pre_idx_lo + idx) as u32)))
+            .filter(|&(_, ix)| !self.crosslinker.sites(&self.db[ix]).is_empty())
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.cmp(a));
+        hits.truncate(self.candidates);
+        hits.into_iter().map(|(_, ix)| ix).collect()
+    }
+
+    /// Score all potential crosslink sites of `peptide`, returning the best site.
+    /// Fragments containing the crosslink site are shifted by `shift` (mass of
+    /// the other partner and the crosslinker)
+    fn score_partner(
+        &self,
+        query: &ProcessedSpectrum,
+        peptide: &Peptide,
+        shift: f32,
+        max_fragment_charge: u8,
+    ) -> Option<PartnerScore> {
+        let ions = self
+            .db
+            .ion_kinds
+            .iter()
+            .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate())
+            .collect::<Vec<_>>();
+
+        self.crosslinker
+            .sites(peptide)
+            .into_iter()
+            .map(|site| {
+                let mut score = PartnerScore {
+                    site,
+                    ..Default::default()
+                };
+                for (idx, ion) in &ions {
+                    // N-terminal ion `idx` contains residues 0..=idx, C-terminal ions
+                    // contain the remaining residues
+                    let crosslinked = match ion.kind {
+                        Kind::A | Kind::B | Kind::C => site <= *idx,
+                        Kind::X | Kind::Y | Kind::Z => site > *idx,
+                    };
+                    let mass = match crosslinked {
+                        true => ion.monoisotopic_mass + shift,
+                        false => ion.monoisotopic_mass,
+                    };
+                    for charge in 1..max_fragment_charge {
+                        // Experimental peaks are multipled by charge, therefore theoretical are divided
+                        if let Some(peak) = crate::spectrum::select_most_intense_peak(
+                            &query.peaks,
+                            mass / charge as f32,
+                            self.fragment_tol,
+                            None,
+                        ) {
+                            match ion.kind {
+                                Kind::A | Kind::B | Kind::C => {
+                                    score.matched_b += 1;
+                                    score.summed_b += peak.intensity;
+                                }
+                                Kind::X | Kind::Y | Kind::Z => {
+                                    score.matched_y += 1;
+                                    score.summed_y += peak.intensity;
+                                }
+                            }
+                        }
+                    }
+                }
+                score
+            })
+            .max_by(|a, b| {
+                a.matched()
+                    .cmp(&b.matched())
+                    .then((a.summed_b + a.summed_y).total_cmp(&(b.summed_b + b.summed_y)))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::mass::PROTON;
+    use crate::spectrum::{Peak, Precursor};
+
+    #[test]
+    fn crosslink_sites() {
+        let peptide = |s: &str, position| {
+            Peptide::try_from(crate::enzyme::Digest {
+                sequence: s.into(),
+                position,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let dss = Crosslinker::dss();
+        assert_eq!(
+            dss.sites(&peptide("AKPEKR", Position::Internal)),
+            vec![1, 4]
+        );
+        assert_eq!(dss.sites(&peptide("AKPEK", Position::Internal)), vec![1]);
+        assert_eq!(dss.sites(&peptide("AKPEK", Position::Full)), vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn search_crosslink() {
+        let fasta = Fasta::parse(
+            ">sp|A\nGLVKPEPTIDER\n>sp|B\nAFGTKPWSTNR\n>sp|C\nMSSTHELLAKPAR\n".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let find = |s: &str| {
+            let idx = db
+                .peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == s.as_bytes())
+                .unwrap();
+            PeptideIx(idx as u32)
+        };
+        let (alpha, beta) = (find("GLVKPEPTIDER"), find("AFGTKPWSTNR"));
+        let dss = Crosslinker::dss();
+
+        // Generate a synthetic spectrum containing all linear and crosslinked b/y ions
+        let mut peaks = Vec::new();
+        for (ix, site, other) in [(alpha, 3, beta), (beta, 4, alpha)] {
+            let shift = db[other].monoisotopic + dss.mass;
+            for kind in [Kind::B, Kind::Y] {
+                for (idx, ion) in IonSeries::new(&db[ix], kind).enumerate() {
+                    let crosslinked = match kind {
+                        Kind::B => site <= idx,
+                        _ => site > idx,
+                    };
+                    peaks.push(Peak {
+                        mass: ion.monoisotopic_mass + if crosslinked { shift } else { 0.0 },
+                        intensity: 100.0,
+                    });
+                }
+            }
+        }
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let mass = db[alpha].monoisotopic + db[beta].monoisotopic + dss.mass;
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "xl".into(),
+            precursors: vec![Precursor {
+                mz: mass / 3.0 + PROTON,
+                charge: Some(3),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = CrosslinkScorer {
+            db: &db,
+            crosslinker: dss,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 4,
+            min_precursor_charge: 2,
+            max_precursor_charge: 4,
+            max_fragment_charge: Some(1),
+            fragment_charge_offset: 1,
+            report_psms: 1,
+            candidates: 10,
+        };
+
+        let xls = scorer.score(&query);
+        assert_eq!(xls.len(), 1);
+        let xl = &xls[0];
+        let mut pair = [
+            (xl.alpha, xl.alpha_site, xl.alpha_residue),
+            (xl.beta, xl.beta_site, xl.beta_residue),
+        ];
+        pair.sort();
+        let mut expected = [(alpha, 3, 'K'), (beta, 4, 'K')];
+        expected.sort();
+        assert_eq!(pair, expected);
+        assert_eq!(xl.label, 1);
+        assert_eq!(xl.rank, 1);
+        assert!((xl.calcmass - mass).abs() < 1E-3);
+    }
+}
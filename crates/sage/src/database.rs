@@ -1,19 +1,33 @@
-use crate::enzyme::{Enzyme, EnzymeParameters};
+use crate::enzyme::{Enzyme, EnzymeParameters, EnzymePreset, ENZYME_PRESETS};
 use crate::fasta::Fasta;
-use crate::ion_series::{IonSeries, Kind};
-use crate::mass::Tolerance;
-use crate::modification::{validate_mods, validate_var_mods, ModificationSpecificity};
+use crate::ion_series::{Ion, IonSeries, Kind};
+use crate::mass::{Tolerance, PROTON};
+use crate::modification::{
+    add_pyro_glu, validate_exclusive_mods, validate_mods, validate_var_mods,
+    ModificationSpecificity,
+};
 use crate::peptide::Peptide;
+use crate::spectrum::ProcessedSpectrum;
 use dashmap::DashSet;
-use fnv::FnvBuildHasher;
+use fnv::{FnvBuildHasher, FnvHasher};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::hash::Hash;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize};
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct EnzymeBuilder {
+    /// Name of a built-in enzyme preset, e.g. `chymotrypsin`, providing the
+    /// defaults for `cleave_at` and `restrict`. See [`ENZYME_PRESETS`]
+    pub name: Option<String>,
     /// How many missed cleavages to use
     pub missed_cleavages: Option<u8>,
     /// Minimum peptide length that will be fragmented
@@ -21,41 +35,128 @@ pub struct EnzymeBuilder {
     /// Maximum peptide length that will be fragmented
     pub max_len: Option<usize>,
     pub cleave_at: Option<String>,
+    /// Do not cleave if this amino acid follows the cleavage site
     pub restrict: Option<char>,
+    /// Ignore `restrict`, and cleave even if the cleavage site is followed by
+    /// the restricted amino acid (e.g. proline for trypsin)
+    pub cleave_before_proline: Option<bool>,
+    /// Cleave at matches of this regex instead of `cleave_at`, e.g. `(D)P`.
+    /// See [`Enzyme::motif`]
+    pub motif: Option<String>,
     pub c_terminal: Option<bool>,
     pub semi_enzymatic: Option<bool>,
+    /// Generate peptides with non-enzymatic N-termini within the first N
+    /// residues of each protein, e.g. to account for signal peptides
+    pub ragged_nterm: Option<usize>,
 }
 
 impl Default for EnzymeBuilder {
     fn default() -> Self {
         Self {
+            name: None,
             missed_cleavages: Some(0),
             min_len: Some(5),
             max_len: Some(50),
             cleave_at: Some("KR".into()),
             restrict: Some('P'),
+            cleave_before_proline: Some(false),
+            motif: None,
             c_terminal: Some(true),
             semi_enzymatic: Some(false),
+            ragged_nterm: None,
         }
     }
 }
 
 impl From<EnzymeBuilder> for EnzymeParameters {
     fn from(en: EnzymeBuilder) -> EnzymeParameters {
+        let preset = en.name.as_deref().map(|name| {
+            EnzymePreset::find(name).unwrap_or_else(|| {
+                panic!(
+                    "Unknown enzyme preset {}, expected one of: {}",
+                    name,
+                    ENZYME_PRESETS.map(|preset| preset.name).join(", ")
+                )
+            })
+        });
+        let restrict = match en.cleave_before_proline {
+            Some(true) => None,
+            _ => en
+                .restrict
+                .or_else(|| preset.and_then(|preset| preset.restrict)),
+        };
+        let c_terminal = en.c_terminal.unwrap_or(true);
+        let semi_enzymatic = en.semi_enzymatic.unwrap_or(false);
         EnzymeParameters {
             missed_cleavages: en.missed_cleavages.unwrap_or(1),
             min_len: en.min_len.unwrap_or(5),
             max_len: en.max_len.unwrap_or(50),
-            enyzme: Enzyme::new(
-                &en.cleave_at.unwrap_or_else(|| "KR".into()),
-                en.restrict,
-                en.c_terminal.unwrap_or(true),
-                en.semi_enzymatic.unwrap_or(false),
-            ),
+            ragged_nterm: en.ragged_nterm.unwrap_or(0),
+            enyzme: match en.motif {
+                Some(motif) => Some(
+                    Enzyme::motif(&motif, restrict, c_terminal, semi_enzymatic).unwrap_or_else(
+                        |err| panic!("Invalid enzyme cleavage motif {}: {}", motif, err),
+                    ),
+                ),
+                None => Enzyme::new(
+                    &en.cleave_at.unwrap_or_else(|| {
+                        preset.map(|preset| preset.cleave_at).unwrap_or("KR").into()
+                    }),
+                    restrict,
+                    c_terminal,
+                    semi_enzymatic,
+                ),
+            },
         }
     }
 }
 
+/// Default seed for randomized steps of database generation
+pub const DEFAULT_SEED: u64 = 23150;
+
+/// Maximum number of times a shuffled decoy is reshuffled when it coincides
+/// with a target sequence
+const MAX_DECOY_RESHUFFLES: usize = 10;
+
+/// Warn if a non-specific digest generates more than this many peptides
+const NONSPECIFIC_DIGEST_WARNING: usize = 10_000_000;
+
+/// How to handle decoy peptides whose sequence coincides with a target peptide
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DecoyCollision {
+    /// Remove the decoy
+    #[default]
+    Drop,
+    /// Reshuffle the decoy until it no longer coincides with a target. Only
+    /// applies to shuffled decoys - reversed decoys and decoys supplied in the
+    /// FASTA file are dropped
+    Regenerate,
+}
+
+/// Number of decoy peptides that coincided with a target peptide during
+/// digestion, see [`DecoyCollision`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecoyCollisions {
+    pub dropped: usize,
+    pub regenerated: usize,
+}
+
+/// Number of proteins digested by [`Parameters::estimate_memory`]
+const MEMORY_ESTIMATE_SAMPLE: usize = 1000;
+
+/// Approximate size of a database before building it, see
+/// [`Parameters::estimate_memory`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    /// Number of target and decoy peptides, including modified forms
+    pub peptides: usize,
+    /// Number of theoretical fragments in the fragment index
+    pub fragments: usize,
+    /// Approximate heap size of the [`IndexedDatabase`], in bytes
+    pub bytes: usize,
+}
+
 #[derive(Deserialize, Default)]
 /// Parameters used for generating the fragment database
 pub struct Builder {
@@ -76,14 +177,39 @@ pub struct Builder {
     /// Minimum ion index to be generated: 1 will remove b1/y1 ions
     /// 2 will remove b1/b2/y1/y2 ions, etc
     pub min_ion_index: Option<usize>,
-    /// Static modifications to add to matching amino acids
-    pub static_mods: Option<HashMap<String, f32>>,
+    /// Minimum ordinal of N-terminal (a/b/c) ions to be generated, e.g. 2 will
+    /// remove b1 ions. Overrides `min_ion_index` for these ions
+    pub min_b_ordinal: Option<usize>,
+    /// Minimum ordinal of C-terminal (x/y/z) ions to be generated, e.g. 2 will
+    /// remove y1 ions. Overrides `min_ion_index` for these ions
+    pub min_y_ordinal: Option<usize>,
+    /// Do not index peptides generating fewer than this many theoretical
+    /// fragments within the fragment m/z window
+    pub min_fragments: Option<usize>,
+    /// Static modifications to add to matching amino acids. Multiple
+    /// modifications on the same site are summed
+    pub static_mods: Option<HashMap<String, crate::modification::StaticMass>>,
     /// Variable modifications to add to matching amino acids
     pub variable_mods: Option<HashMap<String, crate::modification::ValueOrVec>>,
+    /// Search pyroglutamate formation on peptide N-terminal Q and E as
+    /// variable modifications (`^Q` and `^E`)
+    pub pyro_glu: Option<bool>,
     /// Limit number of variable modifications on a peptide
     pub max_variable_mods: Option<usize>,
+    /// Groups of variable modifications that are never applied to the same
+    /// residue, e.g. `[["^Q", "^"]]`
+    pub exclusive_mods: Option<Vec<Vec<String>>>,
+    /// Isotopic label channels (e.g. light/medium/heavy dimethyl). Each channel
+    /// is a set of static modifications, and every peptide is generated once per channel
+    pub label_channels: Option<Vec<HashMap<String, f32>>>,
     /// Use this prefix for decoy proteins
     pub decoy_tag: Option<String>,
+    /// Prefix decoy protein accessions with this in output files, e.g. `DECOY_`.
+    /// Defaults to `decoy_tag`
+    pub decoy_prefix: Option<String>,
+    /// Protein accessions starting with this tag are entrapment sequences,
+    /// used to validate FDR control. See [`IndexedDatabase::is_entrapment`]
+    pub entrapment_tag: Option<String>,
 
     pub generate_decoys: Option<bool>,
     /// Path to fasta database
@@ -91,12 +217,25 @@ pub struct Builder {
 
     /// Trying shuffle strategy for decoys instead of reversing
     pub shuffle_decoys: Option<bool>,
+    /// How to handle decoys coinciding with a target sequence
+    pub decoy_collisions: Option<DecoyCollision>,
     pub keep_ends: Option<bool>,
+    /// Seed for all randomized steps (e.g. shuffled decoys)
+    pub seed: Option<u64>,
+    /// Build the fragment index from chunks of this many peptides, spilling
+    /// sorted chunks to temporary files and merging them, to bound peak memory
+    pub index_chunk_size: Option<usize>,
 }
 
 impl Builder {
     pub fn make_parameters(self) -> Parameters {
         let bucket_size = self.bucket_size.unwrap_or(8192).next_power_of_two();
+        let decoy_tag = self.decoy_tag.unwrap_or_else(|| "rev_".into());
+        let min_ion_index = self.min_ion_index.unwrap_or(2);
+        let mut variable_mods = validate_var_mods(self.variable_mods);
+        if self.pyro_glu.unwrap_or(false) {
+            add_pyro_glu(&mut variable_mods);
+        }
         Parameters {
             bucket_size,
             fragment_min_mz: self.fragment_min_mz.unwrap_or(150.0),
@@ -104,16 +243,34 @@ impl Builder {
             peptide_min_mass: self.peptide_min_mass.unwrap_or(500.0),
             peptide_max_mass: self.peptide_max_mass.unwrap_or(5000.0),
             ion_kinds: self.ion_kinds.unwrap_or(vec![Kind::B, Kind::Y]),
-            min_ion_index: self.min_ion_index.unwrap_or(2),
-            decoy_tag: self.decoy_tag.unwrap_or_else(|| "rev_".into()),
+            min_ion_index,
+            min_b_ordinal: self.min_b_ordinal.unwrap_or(min_ion_index + 1),
+            min_y_ordinal: self.min_y_ordinal.unwrap_or(min_ion_index + 1),
+            min_fragments: self.min_fragments.unwrap_or(0),
+            decoy_prefix: self.decoy_prefix.unwrap_or_else(|| decoy_tag.clone()),
+            decoy_tag,
+            entrapment_tag: self.entrapment_tag,
             enzyme: self.enzyme.unwrap_or_default(),
             static_mods: validate_mods(self.static_mods),
-            variable_mods: validate_var_mods(self.variable_mods),
+            variable_mods,
             max_variable_mods: self.max_variable_mods.map(|x| x.max(1)).unwrap_or(2),
+            exclusive_mods: validate_exclusive_mods(self.exclusive_mods),
+            label_channels: self
+                .label_channels
+                .map(|channels| {
+                    channels
+                        .into_iter()
+                        .map(|channel| validate_mods(Some(channel)))
+                        .collect()
+                })
+                .unwrap_or_default(),
             generate_decoys: self.generate_decoys.unwrap_or(true),
             fasta: self.fasta.expect("A fasta file must be provided!"),
             shuffle_decoys: self.shuffle_decoys.unwrap_or(false),
+            decoy_collisions: self.decoy_collisions.unwrap_or_default(),
             keep_ends: self.keep_ends.unwrap_or(false),
+            seed: self.seed.unwrap_or(DEFAULT_SEED),
+            index_chunk_size: self.index_chunk_size,
         }
     }
 
@@ -132,20 +289,101 @@ pub struct Parameters {
     pub peptide_max_mass: f32,
     pub ion_kinds: Vec<Kind>,
     pub min_ion_index: usize,
+    pub min_b_ordinal: usize,
+    pub min_y_ordinal: usize,
+    pub min_fragments: usize,
     pub static_mods: HashMap<ModificationSpecificity, f32>,
     pub variable_mods: HashMap<ModificationSpecificity, Vec<f32>>,
     pub max_variable_mods: usize,
+    pub exclusive_mods: Vec<Vec<ModificationSpecificity>>,
+    pub label_channels: Vec<HashMap<ModificationSpecificity, f32>>,
     pub decoy_tag: String,
+    pub decoy_prefix: String,
+    pub entrapment_tag: Option<String>,
     pub generate_decoys: bool,
     pub fasta: String,
     pub shuffle_decoys: bool,
+    pub decoy_collisions: DecoyCollision,
     pub keep_ends: bool,
+    pub seed: u64,
+    pub index_chunk_size: Option<usize>,
 }
 
 impl Parameters {
+    /// Random number generator for randomized operations on `peptide`.
+    ///
+    /// Digestion runs in parallel, so rather than sharing a single generator
+    /// (which would make results depend on thread scheduling), each peptide
+    /// gets a generator seeded from `seed` and the peptide itself
+    fn rng_for(&self, peptide: &Peptide) -> StdRng {
+        let mut hasher = FnvHasher::default();
+        peptide.sequence.hash(&mut hasher);
+        for m in &peptide.modifications {
+            m.to_bits().hash(&mut hasher);
+        }
+        peptide.nterm.map(f32::to_bits).hash(&mut hasher);
+        peptide.cterm.map(f32::to_bits).hash(&mut hasher);
+        peptide.label_channel.hash(&mut hasher);
+        StdRng::seed_from_u64(self.seed ^ hasher.finish())
+    }
+
+    /// Generate a decoy for `peptide`, either by shuffling or reversing it. If
+    /// the decoy coincides with a target sequence and `decoy_collisions` is
+    /// [`DecoyCollision::Regenerate`], shuffled decoys are reshuffled
+    fn decoy(
+        &self,
+        peptide: &Peptide,
+        targets: &DashSet<Vec<u8>, FnvBuildHasher>,
+        regenerated: &AtomicUsize,
+    ) -> Peptide {
+        if !self.shuffle_decoys {
+            return peptide.reverse(Some(self.keep_ends));
+        }
+
+        let mut rng = self.rng_for(peptide);
+        let decoy = peptide.shuffle(Some(!self.keep_ends), &mut rng);
+        if self.decoy_collisions == DecoyCollision::Regenerate
+            && targets.contains(&decoy.sequence[..])
+        {
+            if let Some(decoy) = (0..MAX_DECOY_RESHUFFLES)
+                .map(|_| peptide.shuffle(Some(!self.keep_ends), &mut rng))
+                .find(|decoy| !targets.contains(&decoy.sequence[..]))
+            {
+                regenerated.fetch_add(1, atomic::Ordering::Relaxed);
+                return decoy;
+            }
+        }
+        decoy
+    }
+
     pub fn digest(&self, fasta: &Fasta) -> Vec<Peptide> {
+        self.digest_with_collisions(fasta).0
+    }
+
+    /// Digest `fasta`, additionally returning the number of decoy peptides
+    /// that coincided with a target sequence, see [`DecoyCollision`]
+    pub fn digest_with_collisions(&self, fasta: &Fasta) -> (Vec<Peptide>, DecoyCollisions) {
         log::trace!("digesting fasta");
-        let enzyme = self.enzyme.clone().into();
+        let enzyme: EnzymeParameters = self.enzyme.clone().into();
+        if enzyme.enyzme.is_none() {
+            let count = fasta
+                .targets
+                .iter()
+                .map(|(_, sequence)| enzyme.nonspecific_digest_count(sequence.len()))
+                .sum::<usize>();
+            log::info!(
+                "non-specific digest of peptides with {}-{} residues: {} peptides before modifications and decoys",
+                enzyme.min_len,
+                enzyme.max_len,
+                count
+            );
+            if count > NONSPECIFIC_DIGEST_WARNING {
+                log::warn!(
+                    "non-specific digest generates {} peptides - consider narrowing `min_len` and `max_len`, the peptide mass range, or the number of variable modifications to limit memory usage",
+                    count
+                );
+            }
+        }
         // Generate all tryptic peptide sequences, including reversed (decoy)
         // and missed cleavages, if applicable.
         let digests = fasta.digest(&enzyme);
@@ -156,6 +394,18 @@ impl Parameters {
             .flat_map(|(a, b)| b.iter().map(|b| (*a, *b)))
             .collect::<Vec<_>>();
 
+        // Each label channel behaves like an additional set of static mods - channel
+        // specific masses take precedence over regular static mods on the same site
+        let channels = self
+            .label_channels
+            .iter()
+            .map(|channel| {
+                let mut static_mods = self.static_mods.clone();
+                static_mods.extend(channel.iter().map(|(k, v)| (*k, *v)));
+                static_mods
+            })
+            .collect::<Vec<_>>();
+
         let targets: DashSet<_, FnvBuildHasher> = DashSet::default();
         digests
             .par_iter()
@@ -165,13 +415,43 @@ impl Parameters {
             });
 
         log::trace!("modifying peptides");
+        let dropped = AtomicUsize::new(0);
+        let regenerated = AtomicUsize::new(0);
         let mut target_decoys = digests
             .into_par_iter()
             .map(Peptide::try_from)
             .filter_map(Result::ok)
             .flat_map_iter(|peptide| {
-                peptide
-                    .apply(&mods, &self.static_mods, self.max_variable_mods)
+                let modified = match channels.is_empty() {
+                    true => peptide.apply_within(
+                        &mods,
+                        &self.static_mods,
+                        self.max_variable_mods,
+                        &self.exclusive_mods,
+                        self.peptide_min_mass..=self.peptide_max_mass,
+                    ),
+                    false => channels
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(channel, static_mods)| {
+                            peptide
+                                .clone()
+                                .apply_within(
+                                    &mods,
+                                    static_mods,
+                                    self.max_variable_mods,
+                                    &self.exclusive_mods,
+                                    self.peptide_min_mass..=self.peptide_max_mass,
+                                )
+                                .into_iter()
+                                .map(move |mut peptide| {
+                                    peptide.label_channel = Some(channel as u8);
+                                    peptide
+                                })
+                        })
+                        .collect(),
+                };
+                modified
                     .into_iter()
                     .filter(|peptide| {
                         peptide.monoisotopic >= self.peptide_min_mass
@@ -179,25 +459,19 @@ impl Parameters {
                     })
                     .flat_map(|peptide| {
                         if self.generate_decoys {
-                            if self.shuffle_decoys {
-                                if self.keep_ends {
-                                    vec![peptide.shuffle(Some(false)), peptide].into_iter()
-                                }
-                                else {
-                                    vec![peptide.shuffle(Some(true)), peptide].into_iter()
-                                }
-                            } else {
-                                if self.keep_ends {
-                                    vec![peptide.reverse(Some(true)), peptide].into_iter()
-                                } else {
-                                vec![peptide.reverse(Some(false)), peptide].into_iter()
-                            }
-                        }
-                    } else {
+                            let decoy = self.decoy(&peptide, &targets, &regenerated);
+                            vec![decoy, peptide].into_iter()
+                        } else {
                             vec![peptide].into_iter()
                         }
                     })
-                    .filter(|peptide| !peptide.decoy || !targets.contains(&(peptide.sequence[..])))
+                    .filter(|peptide| {
+                        let collision = peptide.decoy && targets.contains(&(peptide.sequence[..]));
+                        if collision {
+                            dropped.fetch_add(1, atomic::Ordering::Relaxed);
+                        }
+                        !collision
+                    })
             })
             .collect::<Vec<_>>();
 
@@ -214,63 +488,221 @@ impl Parameters {
                 && remove.modifications == keep.modifications
                 && remove.nterm == keep.nterm
                 && remove.cterm == keep.cterm
+                && remove.label_channel == keep.label_channel
             {
                 keep.proteins.extend(remove.proteins.iter().cloned());
+                keep.enzymatic_termini = keep.enzymatic_termini.max(remove.enzymatic_termini);
                 true
             } else {
                 false
             }
         });
 
-        target_decoys
-            .par_iter_mut()
-            .for_each(|peptide| peptide.proteins.sort_unstable());
+        target_decoys.par_iter_mut().for_each(|peptide| {
+            peptide.proteins.sort_unstable();
+            peptide.proteins.dedup();
+        });
+
+        let collisions = DecoyCollisions {
+            dropped: dropped.into_inner(),
+            regenerated: regenerated.into_inner(),
+        };
+        if collisions != DecoyCollisions::default() {
+            log::info!(
+                "{} decoy sequences coincided with a target: {} dropped, {} regenerated",
+                collisions.dropped + collisions.regenerated,
+                collisions.dropped,
+                collisions.regenerated
+            );
+        }
+
+        (target_decoys, collisions)
+    }
+
+    /// Estimate the number of peptides and fragments, and the memory needed
+    /// for the [`IndexedDatabase`] built from `fasta`, without building it.
+    ///
+    /// Up to 1000 evenly spaced proteins are digested and fragmented with the
+    /// current settings, and the counts are extrapolated by the number of
+    /// residues. Peptides shared between proteins are deduplicated within the
+    /// sample only, so the estimate errs on the high side for redundant
+    /// databases. Building the index transiently requires up to one additional
+    /// copy of the fragment array (8 bytes per fragment)
+    pub fn estimate_memory(&self, fasta: &Fasta) -> MemoryEstimate {
+        let residues = |fasta: &Fasta| {
+            fasta
+                .targets
+                .iter()
+                .map(|(_, sequence)| sequence.len())
+                .sum::<usize>()
+        };
+        let stride = (fasta.targets.len() / MEMORY_ESTIMATE_SAMPLE).max(1);
+        let mut sample = fasta.clone();
+        sample.targets = fasta.targets.iter().step_by(stride).cloned().collect();
+        if residues(&sample) == 0 {
+            return MemoryEstimate::default();
+        }
+        let scale = residues(fasta) as f64 / residues(&sample) as f64;
+
+        let peptides = self.digest(&sample);
+        let excluded = AtomicUsize::new(0);
+        let mut counts = vec![0; peptides.len()];
+        let fragments = self
+            .fragments(&peptides, 0..peptides.len(), &excluded, &mut counts)
+            .len();
+
+        // Peptides, along with their mass and fragment count
+        let peptide_bytes = peptides
+            .iter()
+            .map(|peptide| {
+                std::mem::size_of::<Peptide>()
+                    + std::mem::size_of::<f32>()
+                    + std::mem::size_of::<u16>()
+                    + peptide.sequence.len()
+                    + peptide.modifications.len() * std::mem::size_of::<f32>()
+                    + peptide.proteins.len() * std::mem::size_of::<std::sync::Arc<String>>()
+            })
+            .sum::<usize>();
+        // Fragments, along with the minimum m/z of each bucket
+        let fragment_bytes = fragments * std::mem::size_of::<Theoretical>()
+            + fragments / self.bucket_size * std::mem::size_of::<f32>();
 
-        target_decoys
+        MemoryEstimate {
+            peptides: (peptides.len() as f64 * scale).round() as usize,
+            fragments: (fragments as f64 * scale).round() as usize,
+            bytes: ((peptide_bytes + fragment_bytes) as f64 * scale).round() as usize,
+        }
     }
 
-    // pub fn build(self) -> Result<IndexedDatabase, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// Digest `fasta` and build the fragment index. Panics if the database
+    /// cannot be generated, see [`Parameters::try_build`]
     pub fn build(self, fasta: Fasta) -> IndexedDatabase {
+        self.try_build(fasta)
+            .unwrap_or_else(|err| panic!("failed to build database: {}", err))
+    }
+
+    /// Digest `fasta` and build the fragment index, returning an error if more
+    /// peptides are generated than can be addressed by a [`PeptideIx`]
+    pub fn try_build(self, fasta: Fasta) -> Result<IndexedDatabase, DatabaseError> {
         let target_decoys = self.digest(&fasta);
+        check_peptide_count(target_decoys.len())?;
+        let (fragments, min_value, fragment_count) =
+            self.index(&target_decoys, 0..target_decoys.len());
+
+        Ok(IndexedDatabase {
+            masses: peptide_masses(&target_decoys),
+            decoys: decoy_bitset(&target_decoys),
+            peptides: target_decoys,
+            fragments,
+            min_value,
+            fragment_count,
+            bucket_size: self.bucket_size,
+            potential_mods: self.potential_mods(),
+            ion_kinds: self.ion_kinds,
+            generate_decoys: self.generate_decoys,
+            decoy_tag: self.decoy_tag,
+            decoy_prefix: self.decoy_prefix,
+            entrapment_tag: self.entrapment_tag,
+        })
+    }
+
+    /// Digest `fasta`, and split the resulting peptides into `partitions`
+    /// contiguous precursor mass ranges. Fragment ions are only generated
+    /// for one partition at a time (see [`PartitionedDatabase::load`]), bounding
+    /// the peak memory usage of the fragment index. Panics if the database
+    /// cannot be generated, see [`Parameters::try_build_partitioned`]
+    pub fn build_partitioned(self, fasta: Fasta, partitions: usize) -> PartitionedDatabase {
+        self.try_build_partitioned(fasta, partitions)
+            .unwrap_or_else(|err| panic!("failed to build database: {}", err))
+    }
+
+    /// Fallible version of [`Parameters::build_partitioned`], returning an
+    /// error if more peptides are generated than can be addressed by a [`PeptideIx`]
+    pub fn try_build_partitioned(
+        self,
+        fasta: Fasta,
+        partitions: usize,
+    ) -> Result<PartitionedDatabase, DatabaseError> {
+        let target_decoys = self.digest(&fasta);
+        check_peptide_count(target_decoys.len())?;
+
+        // Peptides are sorted by mass, so equally sized chunks of peptides
+        // correspond to contiguous (and roughly equally sized) mass ranges
+        let partitions = partitions.max(1);
+        let chunk_size = ((target_decoys.len() + partitions - 1) / partitions).max(1);
+        let partitions = (0..target_decoys.len())
+            .step_by(chunk_size)
+            .map(|start| start..(start + chunk_size).min(target_decoys.len()))
+            .collect();
+
+        let database = IndexedDatabase {
+            fragment_count: vec![0; target_decoys.len()],
+            masses: peptide_masses(&target_decoys),
+            decoys: decoy_bitset(&target_decoys),
+            peptides: target_decoys,
+            fragments: Vec::default(),
+            min_value: Vec::default(),
+            bucket_size: self.bucket_size,
+            potential_mods: self.potential_mods(),
+            ion_kinds: self.ion_kinds.clone(),
+            generate_decoys: self.generate_decoys,
+            decoy_tag: self.decoy_tag.clone(),
+            decoy_prefix: self.decoy_prefix.clone(),
+            entrapment_tag: self.entrapment_tag.clone(),
+        };
+
+        Ok(PartitionedDatabase {
+            parameters: self,
+            database,
+            partitions,
+            loaded: None,
+        })
+    }
+
+    fn potential_mods(&self) -> Vec<(ModificationSpecificity, f32)> {
+        self.variable_mods
+            .iter()
+            .flat_map(|(a, b)| b.iter().map(|b| (*a, *b)))
+            .collect()
+    }
+
+    /// Generate, sort and bucket theoretical fragments for `peptides[range]`,
+    /// returning the fragments, the minimum fragment m/z of each bucket, and
+    /// the number of fragments generated for each peptide in `range`
+    fn index(
+        &self,
+        peptides: &[Peptide],
+        range: Range<usize>,
+    ) -> (Vec<Theoretical>, Vec<f32>, Vec<u16>) {
         log::trace!("generating fragments");
 
-        // Finally, perform in silico digest for our target sequences
-        // Note that multiple charge states are actually handled by
-        // [`SpectrumProcessor`] or during scoring - all theoretical
-        // fragments are monoisotopic/uncharged
-        let mut fragments = target_decoys
-            .par_iter()
-            .enumerate()
-            .flat_map_iter(|(idx, peptide)| {
-                // Generate both B and Y ions, then filter down to make sure that
-                // theoretical fragments are within the search space
-                self.ion_kinds
-                    .iter()
-                    .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate())
-                    .filter(|(ion_idx, ion)| {
-                        // Don't store b1, b2, y1, y2 ions for preliminary scoring
-                        let ion_idx_filter = match ion.kind {
-                            Kind::A | Kind::B | Kind::C => (ion_idx + 1) > self.min_ion_index,
-                            Kind::X | Kind::Y | Kind::Z => {
-                                peptide.sequence.len().saturating_sub(1) - ion_idx
-                                    > self.min_ion_index
-                            }
-                        };
-                        ion_idx_filter
-                            && ion.monoisotopic_mass >= self.fragment_min_mz
-                            && ion.monoisotopic_mass <= self.fragment_max_mz
-                    })
-                    .map(move |(_, ion)| Theoretical {
-                        peptide_index: PeptideIx(idx as u32),
-                        fragment_mz: ion.monoisotopic_mass,
-                    })
-            })
-            .collect::<Vec<_>>();
+        let excluded = AtomicUsize::new(0);
+        let mut counts = vec![0; range.len()];
+        let mut fragments = match self.index_chunk_size {
+            Some(chunk_size) => self
+                .sorted_fragments_external(
+                    peptides,
+                    range.clone(),
+                    chunk_size,
+                    &excluded,
+                    &mut counts,
+                )
+                .unwrap_or_else(|err| {
+                    log::warn!("external fragment sort failed, sorting in memory: {}", err);
+                    excluded.store(0, atomic::Ordering::Relaxed);
+                    self.sorted_fragments(peptides, range, &excluded, &mut counts)
+                }),
+            None => self.sorted_fragments(peptides, range, &excluded, &mut counts),
+        };
+        if self.min_fragments > 0 {
+            log::info!(
+                "excluded {} peptides with fewer than {} theoretical fragments",
+                excluded.into_inner(),
+                self.min_fragments
+            );
+        }
         log::trace!("finalizing index");
 
-        // Sort all of our theoretical fragments by m/z, from low to high
-        fragments.par_sort_unstable_by(|a, b| a.fragment_mz.total_cmp(&b.fragment_mz));
-
         // Now, we bucket all of our theoretical fragments, and within each bucket
         // sort by precursor m/z - and save the minimum *fragment* m/z in a separate
         // vector so that we can perform an efficient binary search to reduce
@@ -305,33 +737,325 @@ impl Parameters {
         // and within Bucket 1, we can perform another binary search to find fragments
         // matching our desired precursor m/z tolerance
 
-        let min_value = fragments
-            .par_chunks_mut(self.bucket_size)
-            .map(|chunk| {
-                // There should always be at least one item in the chunk!
-                //  we know the chunk is already sorted by fragment_mz too, so this is minimum value
-                let min = chunk[0].fragment_mz;
-                chunk.par_sort_unstable_by(|a, b| a.peptide_index.cmp(&b.peptide_index));
-                min
+        let min_value = bucket_fragments(&mut fragments, self.bucket_size);
+
+        (fragments, min_value, counts)
+    }
+
+    /// Generate theoretical fragments for `peptides[range]`, in arbitrary order.
+    /// The number of fragments of each peptide is written to `counts`, which
+    /// must have the same length as `range`. Peptides with fewer than
+    /// `min_fragments` fragments are skipped, and counted in `excluded`
+    fn fragments(
+        &self,
+        peptides: &[Peptide],
+        range: Range<usize>,
+        excluded: &AtomicUsize,
+        counts: &mut [u16],
+    ) -> Vec<Theoretical> {
+        // Finally, perform in silico digest for our target sequences
+        // Note that multiple charge states are actually handled by
+        // [`SpectrumProcessor`] or during scoring - all theoretical
+        // fragments are monoisotopic/uncharged
+        let offset = range.start;
+        peptides[range]
+            .par_iter()
+            .zip(counts.par_iter_mut())
+            .enumerate()
+            .flat_map_iter(|(idx, (peptide, count))| {
+                // Generate both B and Y ions, then filter down to make sure that
+                // theoretical fragments are within the search space
+                let ions = move || {
+                    self.ion_kinds
+                        .iter()
+                        .flat_map(move |kind| IonSeries::new(peptide, *kind).enumerate())
+                        .filter(move |(ion_idx, ion)| {
+                            // Don't store low-ordinal ions (by default b1, b2, y1, y2)
+                            // for preliminary scoring
+                            let ion_idx_filter = match ion.kind {
+                                Kind::A | Kind::B | Kind::C => ion_idx + 1 >= self.min_b_ordinal,
+                                Kind::X | Kind::Y | Kind::Z => {
+                                    peptide.sequence.len().saturating_sub(1) - ion_idx
+                                        >= self.min_y_ordinal
+                                }
+                            };
+                            ion_idx_filter
+                                && ion.monoisotopic_mass >= self.fragment_min_mz
+                                && ion.monoisotopic_mass <= self.fragment_max_mz
+                        })
+                };
+                // Counting regenerates the (cheap) ion series, rather than
+                // collecting every peptide's fragments into a temporary Vec
+                let n = ions().count();
+                *count = n.min(u16::MAX as usize) as u16;
+                let keep = match n < self.min_fragments {
+                    true => {
+                        excluded.fetch_add(1, atomic::Ordering::Relaxed);
+                        0
+                    }
+                    false => n,
+                };
+                ions().take(keep).map(move |(_, ion)| Theoretical {
+                    peptide_index: PeptideIx((offset + idx) as u32),
+                    fragment_mz: ion.monoisotopic_mass,
+                })
             })
-            .collect::<Vec<_>>();
+            .collect()
+    }
 
-        let potential_mods = self
-            .variable_mods
+    /// Generate theoretical fragments for `peptides[range]`, sorted by m/z
+    fn sorted_fragments(
+        &self,
+        peptides: &[Peptide],
+        range: Range<usize>,
+        excluded: &AtomicUsize,
+        counts: &mut [u16],
+    ) -> Vec<Theoretical> {
+        let mut fragments = self.fragments(peptides, range, excluded, counts);
+        // Sort all of our theoretical fragments by m/z, from low to high
+        fragments.par_sort_unstable_by(fragment_order);
+        fragments
+    }
+
+    /// Generate theoretical fragments for `peptides[range]`, sorted by m/z,
+    /// using an external merge sort: fragments are generated for `chunk_size`
+    /// peptides at a time, sorted, and written to a temporary file. The sorted
+    /// runs are then merged into the final fragment array, which is allocated
+    /// exactly once.
+    ///
+    /// Peak memory usage is the final fragment array plus a single chunk,
+    /// rather than the (over-allocated) unsorted fragment array
+    fn sorted_fragments_external(
+        &self,
+        peptides: &[Peptide],
+        range: Range<usize>,
+        chunk_size: usize,
+        excluded: &AtomicUsize,
+        counts: &mut [u16],
+    ) -> std::io::Result<Vec<Theoretical>> {
+        let chunk_size = chunk_size.max(1);
+        let mut runs = Vec::new();
+        for start in range.clone().step_by(chunk_size) {
+            let end = (start + chunk_size).min(range.end);
+            let counts = &mut counts[start - range.start..end - range.start];
+            let mut fragments = self.fragments(peptides, start..end, excluded, counts);
+            fragments.par_sort_unstable_by(fragment_order);
+            runs.push(SortedRun::write(&fragments)?);
+        }
+
+        let total = runs.iter().map(|run| run.len).sum();
+        let mut readers = runs
             .iter()
-            .flat_map(|(a, b)| b.iter().map(|b| (*a, *b)))
-            .collect::<Vec<(ModificationSpecificity, f32)>>();
+            .map(SortedRun::open)
+            .collect::<std::io::Result<Vec<_>>>()?;
 
-        IndexedDatabase {
-            peptides: target_decoys,
-            fragments,
-            min_value,
-            bucket_size: self.bucket_size,
-            ion_kinds: self.ion_kinds,
-            generate_decoys: self.generate_decoys,
-            potential_mods,
-            decoy_tag: self.decoy_tag,
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (run, reader) in readers.iter_mut().enumerate() {
+            if let Some(fragment) = reader.next_fragment()? {
+                heap.push(Reverse(MergeEntry { fragment, run }));
+            }
+        }
+
+        let mut fragments = Vec::with_capacity(total);
+        while let Some(Reverse(MergeEntry { fragment, run })) = heap.pop() {
+            fragments.push(fragment);
+            if let Some(fragment) = readers[run].next_fragment()? {
+                heap.push(Reverse(MergeEntry { fragment, run }));
+            }
+        }
+        Ok(fragments)
+    }
+}
+
+/// Order theoretical fragments by m/z, breaking ties by peptide index so that
+/// in-memory, external and merged sorts produce identical fragment arrays,
+/// independent of thread scheduling. Fragments that compare equal are
+/// indistinguishable, so an unstable sort is still deterministic
+pub(crate) fn fragment_order(a: &Theoretical, b: &Theoretical) -> Ordering {
+    a.fragment_mz
+        .total_cmp(&b.fragment_mz)
+        .then_with(|| a.peptide_index.cmp(&b.peptide_index))
+}
+
+/// A sorted run of theoretical fragments, spilled to a temporary file. The
+/// file is removed when the run is dropped
+struct SortedRun {
+    path: PathBuf,
+    len: usize,
+}
+
+/// Sequential reader over the fragments of a [`SortedRun`]
+struct SortedRunReader {
+    reader: BufReader<File>,
+    remaining: usize,
+}
+
+/// Head of a [`SortedRun`] during the k-way merge
+struct MergeEntry {
+    fragment: Theoretical,
+    run: usize,
+}
+
+impl SortedRun {
+    fn write(fragments: &[Theoretical]) -> std::io::Result<Self> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "sage-fragments-{}-{}.bin",
+            std::process::id(),
+            RUNS.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        // Construct the run before creating the file, so that it is cleaned up on error
+        let run = SortedRun {
+            path,
+            len: fragments.len(),
+        };
+        let mut writer = BufWriter::new(File::create(&run.path)?);
+        for fragment in fragments {
+            writer.write_all(&fragment.peptide_index.0.to_le_bytes())?;
+            writer.write_all(&fragment.fragment_mz.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(run)
+    }
+
+    fn open(&self) -> std::io::Result<SortedRunReader> {
+        Ok(SortedRunReader {
+            reader: BufReader::new(File::open(&self.path)?),
+            remaining: self.len,
+        })
+    }
+}
+
+impl Drop for SortedRun {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl SortedRunReader {
+    fn next_fragment(&mut self) -> std::io::Result<Option<Theoretical>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let mut buf = [0u8; 8];
+        self.reader.read_exact(&mut buf)?;
+        let (peptide_index, fragment_mz) = buf.split_at(4);
+        Ok(Some(Theoretical {
+            peptide_index: PeptideIx(u32::from_le_bytes(peptide_index.try_into().unwrap())),
+            fragment_mz: f32::from_le_bytes(fragment_mz.try_into().unwrap()),
+        }))
+    }
+}
+
+impl PartialEq for MergeEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeEntry {}
+
+impl PartialOrd for MergeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        fragment_order(&self.fragment, &other.fragment).then_with(|| self.run.cmp(&other.run))
+    }
+}
+
+/// Sort each bucket of `fragments` (which must already be sorted by fragment m/z)
+/// by peptide index, returning the minimum fragment m/z of each bucket
+pub(crate) fn bucket_fragments(fragments: &mut [Theoretical], bucket_size: usize) -> Vec<f32> {
+    fragments
+        .par_chunks_mut(bucket_size)
+        .map(|chunk| {
+            // There should always be at least one item in the chunk!
+            //  we know the chunk is already sorted by fragment_mz too, so this is minimum value
+            let min = chunk[0].fragment_mz;
+            chunk.par_sort_unstable_by(|a, b| {
+                a.peptide_index
+                    .cmp(&b.peptide_index)
+                    .then_with(|| a.fragment_mz.total_cmp(&b.fragment_mz))
+            });
+            min
+        })
+        .collect::<Vec<_>>()
+}
+
+/// A database where all peptides are kept in memory, but the fragment index
+/// is only built for a single precursor mass partition at a time.
+///
+/// Peptide indices are shared across partitions, so [`crate::scoring::Feature`]s
+/// generated by searching any partition refer to the same peptides.
+pub struct PartitionedDatabase {
+    parameters: Parameters,
+    database: IndexedDatabase,
+    partitions: Vec<Range<usize>>,
+    loaded: Option<usize>,
+}
+
+impl PartitionedDatabase {
+    /// Number of precursor mass partitions
+    pub fn len(&self) -> usize {
+        self.partitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    /// Return the (inclusive) range of peptide monoisotopic masses in `partition`
+    pub fn mass_range(&self, partition: usize) -> (f32, f32) {
+        let range = &self.partitions[partition];
+        (
+            self.database.peptides[range.start].monoisotopic,
+            self.database.peptides[range.end - 1].monoisotopic,
+        )
+    }
+
+    /// Build the fragment index for `partition`, replacing the fragment index
+    /// of any previously loaded partition. This is a no-op if `partition`
+    /// is already loaded
+    pub fn load(&mut self, partition: usize) -> &IndexedDatabase {
+        if self.loaded != Some(partition) {
+            // Release the previous partition before building the next one
+            self.database.fragments = Vec::default();
+            self.database.min_value = Vec::default();
+
+            let range = self.partitions[partition].clone();
+            let (fragments, min_value, counts) = self
+                .parameters
+                .index(&self.database.peptides, range.clone());
+            self.database.fragments = fragments;
+            self.database.min_value = min_value;
+            self.database.fragment_count[range].copy_from_slice(&counts);
+            self.loaded = Some(partition);
         }
+        &self.database
+    }
+
+    /// Currently loaded partition, if any
+    pub fn loaded(&self) -> Option<usize> {
+        self.loaded
+    }
+
+    /// Consume the partitioned database, returning an [`IndexedDatabase`]
+    /// containing all peptides, and the fragments of the currently loaded partition
+    pub fn into_inner(self) -> IndexedDatabase {
+        self.database
+    }
+}
+
+impl std::ops::Deref for PartitionedDatabase {
+    type Target = IndexedDatabase;
+
+    fn deref(&self) -> &Self::Target {
+        &self.database
     }
 }
 
@@ -352,19 +1076,200 @@ pub struct Theoretical {
     pub fragment_mz: f32,
 }
 
+/// Owned, fully annotated version of a [`Theoretical`] fragment matched by
+/// [`IndexedQuery::page_search_owned`]. Does not borrow the database, so it can
+/// be stored or sent across threads
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct FragmentMatch {
+    pub peptide_index: PeptideIx,
+    /// Neutral fragment mass (no charge)
+    pub fragment_mz: f32,
+    pub kind: Kind,
+    /// Charge state the experimental peak was searched at
+    pub charge: u8,
+    /// Ion ordinal, e.g. 3 for both b3 and y3
+    pub ordinal: i32,
+}
+
+/// Candidate peptide returned by [`IndexedDatabase::query_mass`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MassMatch {
+    pub peptide_index: PeptideIx,
+    /// Number of query fragments matching a theoretical fragment of this peptide
+    pub matched_fragments: u16,
+}
+
+/// Protein mapping of a peptide, see [`IndexedDatabase::peptide_redundancy`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeptideRedundancy {
+    pub peptide_index: PeptideIx,
+    /// Number of proteins containing this peptide
+    pub num_proteins: usize,
+    /// Does this peptide map to at most `max_proteins` proteins? Degenerate
+    /// (shared) peptides cannot uniquely identify a protein
+    pub unique: bool,
+}
+
+/// Reusable buffers for [`IndexedDatabase::query_mass_with`] and
+/// [`IndexedDatabase::search_spectrum`], avoiding per-query allocations when
+/// searching many spectra in a loop. Use one instance per thread
+#[derive(Clone, Debug, Default)]
+pub struct SearchScratch {
+    /// Matched fragments of each candidate, relative to the first candidate
+    pub(crate) counts: Vec<u16>,
+    /// Index (+1) of the last query fragment matched by each candidate
+    last_fragment: Vec<u32>,
+    matches: Vec<MassMatch>,
+}
+
+impl SearchScratch {
+    /// Total capacity of all buffers, in number of elements
+    pub fn capacity(&self) -> usize {
+        self.counts.capacity() + self.last_fragment.capacity() + self.matches.capacity()
+    }
+}
+
 pub struct IndexedDatabase {
     pub peptides: Vec<Peptide>,
+    /// Monoisotopic mass of each peptide (indexed by [`PeptideIx`]), and
+    /// therefore sorted, see [`IndexedDatabase::peptide_masses`]
+    pub(crate) masses: Vec<f32>,
+    /// Packed bitset flagging decoy peptides (indexed by [`PeptideIx`]), see
+    /// [`IndexedDatabase::is_decoy`]
+    pub decoys: Vec<u64>,
     pub fragments: Vec<Theoretical>,
     pub ion_kinds: Vec<Kind>,
     pub min_value: Vec<f32>,
+    /// Number of theoretical fragments generated for each peptide (indexed by
+    /// [`PeptideIx`]), i.e. those within the fragment m/z range and passing
+    /// `min_b_ordinal`/`min_y_ordinal`, e.g. for normalizing the number of matched fragments.
+    /// This includes peptides excluded from the index by `min_fragments`.
+    /// For a [`PartitionedDatabase`], only peptides of partitions that have
+    /// been loaded are counted.
+    ///
+    /// These counts are exposed for library users only: [`crate::scoring::Scorer`]
+    /// does not use them, since full scoring regenerates every ion (at each
+    /// fragment charge) regardless of the fragment index filters
+    pub fragment_count: Vec<u16>,
     /// Keep a list of potential (AA, mass) modifications for RT prediction
     pub potential_mods: Vec<(ModificationSpecificity, f32)>,
     pub bucket_size: usize,
     pub generate_decoys: bool,
     pub decoy_tag: String,
+    pub decoy_prefix: String,
+    /// Protein accessions starting with this tag are entrapment sequences
+    pub entrapment_tag: Option<String>,
 }
 
 impl IndexedDatabase {
+    /// Protein accessions of `peptide`, separated by `;`. Decoy accessions are
+    /// reported with [`IndexedDatabase::decoy_prefix`], both for internally
+    /// generated decoys and for decoys supplied in the FASTA (which are expected
+    /// to start with `decoy_tag`). All output writers should use this method,
+    /// so that decoys are consistently recognizable by downstream tools
+    pub fn proteins(&self, peptide: &Peptide) -> String {
+        if !peptide.decoy {
+            return peptide.proteins(&self.decoy_tag, self.generate_decoys);
+        }
+        peptide
+            .proteins
+            .iter()
+            .map(|accession| {
+                if self.generate_decoys {
+                    format!("{}{}", self.decoy_prefix, accession)
+                } else if let Some(accession) = accession.strip_prefix(self.decoy_tag.as_str()) {
+                    format!("{}{}", self.decoy_prefix, accession)
+                } else {
+                    accession.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Is `peptide` exclusively derived from entrapment proteins, i.e. proteins
+    /// with an accession starting with `entrapment_tag`? Peptides shared with
+    /// proteins of the original database are not considered entrapment.
+    /// This applies to decoys as well, which retain the accessions of the
+    /// proteins they were generated from
+    pub fn is_entrapment(&self, peptide: &Peptide) -> bool {
+        match &self.entrapment_tag {
+            Some(tag) => {
+                !peptide.proteins.is_empty()
+                    && peptide
+                        .proteins
+                        .iter()
+                        .all(|accession| accession.starts_with(tag.as_str()))
+            }
+            None => false,
+        }
+    }
+
+    /// Indices of all peptides with a monoisotopic mass between `lo` and `hi`
+    /// (inclusive). Peptides are sorted by mass, so candidates for a precursor
+    /// window can be found by binary search, without touching the fragment index.
+    ///
+    /// The returned range can be used to index [`IndexedDatabase::peptides`],
+    /// or converted into [`PeptideIx`] values
+    pub fn peptides_in_mass_window(&self, lo: f32, hi: f32) -> std::ops::Range<usize> {
+        let (left, right) = binary_search_slice(
+            &self.peptides,
+            |p, bounds| p.monoisotopic.total_cmp(bounds),
+            lo,
+            hi,
+        );
+
+        // `binary_search_slice` returns the widest range, trim it to the window
+        let slice = &self.peptides[left..right];
+        let start = left + slice.partition_point(|p| p.monoisotopic < lo);
+        let end = left + slice.partition_point(|p| p.monoisotopic <= hi);
+        start..end.max(start)
+    }
+
+    /// Number of proteins each of `peptides` maps to, flagging peptides shared
+    /// by more than `max_proteins` proteins as non-unique. Results are sorted
+    /// by the number of proteins (descending), and then by peptide index
+    pub fn peptide_redundancy(
+        &self,
+        peptides: &[PeptideIx],
+        max_proteins: usize,
+    ) -> Vec<PeptideRedundancy> {
+        let mut redundancy = peptides
+            .iter()
+            .map(|&peptide_index| {
+                let num_proteins = self[peptide_index].proteins.len();
+                PeptideRedundancy {
+                    peptide_index,
+                    num_proteins,
+                    unique: num_proteins <= max_proteins,
+                }
+            })
+            .collect::<Vec<_>>();
+        redundancy.sort_unstable_by(|a, b| {
+            b.num_proteins
+                .cmp(&a.num_proteins)
+                .then_with(|| a.peptide_index.cmp(&b.peptide_index))
+        });
+        redundancy.dedup_by_key(|r| r.peptide_index);
+        redundancy
+    }
+
+    /// All theoretical fragment ions of the peptide at `ix`, for each of the
+    /// ion kinds the database was built with, regenerated from the peptide
+    /// rather than looked up in the (globally sorted) fragment index.
+    ///
+    /// Unlike [`IndexedDatabase::fragments`], this includes ions that were
+    /// excluded from the index for preliminary scoring (e.g. b1/b2, or those
+    /// outside of the fragment m/z range). Ions are neutral masses, ordered by
+    /// kind and then ordinal
+    pub fn fragments_for(&self, ix: PeptideIx) -> Vec<Ion> {
+        let peptide = &self[ix];
+        self.ion_kinds
+            .iter()
+            .flat_map(|kind| IonSeries::new(peptide, *kind))
+            .collect()
+    }
+
     /// Create a new [`IndexedQuery`] for a specific [`ProcessedSpectrum`]
     ///
     /// All matches returned by the query will be within the specified tolerance
@@ -394,14 +1299,399 @@ impl IndexedDatabase {
         }
     }
 
-    pub fn size(&self) -> usize {
-        self.fragments.len()
+    /// Find candidate peptides for a precursor `neutral_mass` and a list of
+    /// singly charged `fragments` (m/z), without constructing a
+    /// [`crate::spectrum::ProcessedSpectrum`].
+    ///
+    /// Returns all peptides within `precursor_tol` that match at least one
+    /// fragment within `fragment_tol`, sorted by the number of matched fragments
+    /// (descending), and then by peptide index. See
+    /// [`IndexedDatabase::query_mass_with`] for searching many queries without
+    /// allocating new buffers for each of them
+    pub fn query_mass(
+        &self,
+        neutral_mass: f32,
+        fragments: &[f32],
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> Vec<MassMatch> {
+        let mut scratch = SearchScratch::default();
+        self.query_mass_with(
+            &mut scratch,
+            neutral_mass,
+            fragments,
+            precursor_tol,
+            fragment_tol,
+        )
+        .to_vec()
+    }
+
+    /// Same as [`IndexedDatabase::query_mass`], but reuses the buffers of
+    /// `scratch`, which are cleared before searching. Once the buffers have
+    /// grown to fit the largest query, no further allocations are performed
+    pub fn query_mass_with<'s>(
+        &self,
+        scratch: &'s mut SearchScratch,
+        neutral_mass: f32,
+        fragments: &[f32],
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> &'s [MassMatch] {
+        let masses = fragments.iter().map(|mz| mz - PROTON);
+        self.search_scratch(scratch, neutral_mass, masses, precursor_tol, fragment_tol)
+    }
+
+    /// Search the peaks of `spectrum` against all peptides matching its first
+    /// precursor at `charge`, reusing the buffers of `scratch`. Results are
+    /// ordered as for [`IndexedDatabase::query_mass`]. Peaks are searched as
+    /// singly charged fragments, and spectra without a precursor have no matches
+    pub fn search_spectrum<'s>(
+        &self,
+        scratch: &'s mut SearchScratch,
+        spectrum: &ProcessedSpectrum,
+        charge: u8,
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> &'s [MassMatch] {
+        let precursor = match spectrum.precursors.first() {
+            Some(precursor) => precursor,
+            None => {
+                scratch.matches.clear();
+                return &scratch.matches;
+            }
+        };
+        let neutral_mass = precursor.neutral_mz() * charge as f32;
+        let masses = spectrum.peaks.iter().map(|peak| peak.mass);
+        self.search_scratch(scratch, neutral_mass, masses, precursor_tol, fragment_tol)
+    }
+
+    /// View of this database restricted to target peptides, see [`DatabaseView`]
+    pub fn targets_only(&self) -> DatabaseView<'_> {
+        DatabaseView {
+            db: self,
+            decoy: false,
+        }
+    }
+
+    /// View of this database restricted to decoy peptides, see [`DatabaseView`]
+    pub fn decoys_only(&self) -> DatabaseView<'_> {
+        DatabaseView {
+            db: self,
+            decoy: true,
+        }
+    }
+
+    fn search_scratch<'s, I: Iterator<Item = f32>>(
+        &self,
+        scratch: &'s mut SearchScratch,
+        neutral_mass: f32,
+        fragment_masses: I,
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> &'s [MassMatch] {
+        let query = self.query(neutral_mass, precursor_tol, fragment_tol);
+        // Candidates are a contiguous range of peptides, so matches can be
+        // counted in a dense buffer rather than a map
+        let potential = query.pre_idx_hi - query.pre_idx_lo + 1;
+        scratch.counts.clear();
+        scratch.counts.resize(potential, 0);
+        scratch.last_fragment.clear();
+        scratch.last_fragment.resize(potential, 0);
+        scratch.matches.clear();
+
+        for (fragment, mass) in fragment_masses.enumerate() {
+            for frag in query.page_search(mass) {
+                let idx = frag.peptide_index.0 as usize - query.pre_idx_lo;
+                // Count each fragment at most once per peptide, even if it
+                // matches multiple theoretical ions
+                if scratch.last_fragment[idx] != fragment as u32 + 1 {
+                    scratch.last_fragment[idx] = fragment as u32 + 1;
+                    scratch.counts[idx] += 1;
+                }
+            }
+        }
+
+        scratch.matches.extend(
+            scratch
+                .counts
+                .iter()
+                .enumerate()
+                .filter(|(_, &count)| count > 0)
+                .map(|(idx, &matched_fragments)| MassMatch {
+                    peptide_index: PeptideIx((query.pre_idx_lo + idx) as u32),
+                    matched_fragments,
+                }),
+        );
+        scratch.matches.sort_unstable_by(|a, b| {
+            b.matched_fragments
+                .cmp(&a.matched_fragments)
+                .then_with(|| a.peptide_index.cmp(&b.peptide_index))
+        });
+        &scratch.matches
+    }
+
+    /// Merge `other` into this database, e.g. to search a main FASTA and a
+    /// spike-in standard that were indexed separately.
+    ///
+    /// Peptides from both databases are re-sorted by mass, and identical peptides
+    /// are combined (with the union of their proteins). Every [`PeptideIx`]
+    /// referring to either of the source databases is invalidated by merging.
+    /// Panics if the databases cannot be merged, see [`IndexedDatabase::try_merge`]
+    pub fn merge(self, other: IndexedDatabase) -> IndexedDatabase {
+        self.try_merge(other)
+            .unwrap_or_else(|err| panic!("failed to merge databases: {}", err))
+    }
+
+    /// Merge `other` into this database, see [`IndexedDatabase::merge`].
+    /// Returns an error if the merged database contains more peptides than
+    /// can be addressed by a [`PeptideIx`]
+    pub fn try_merge(self, other: IndexedDatabase) -> Result<IndexedDatabase, DatabaseError> {
+        if self.bucket_size != other.bucket_size {
+            log::warn!(
+                "merging databases with different bucket sizes ({} and {}), using {}",
+                self.bucket_size,
+                other.bucket_size,
+                self.bucket_size
+            );
+        }
+        if self.decoy_tag != other.decoy_tag
+            || self.decoy_prefix != other.decoy_prefix
+            || self.generate_decoys != other.generate_decoys
+        {
+            log::warn!(
+                "merging databases with different decoy settings, using `{}`",
+                self.decoy_tag
+            );
+        }
+        if self.entrapment_tag != other.entrapment_tag {
+            log::warn!(
+                "merging databases with different entrapment tags, using {:?}",
+                self.entrapment_tag
+            );
+        }
+
+        let sizes = [self.peptides.len(), other.peptides.len()];
+
+        // Keep track of the source database and original index of each peptide
+        let mut peptides = self
+            .peptides
+            .into_iter()
+            .enumerate()
+            .map(|(idx, peptide)| (peptide, 0, idx))
+            .chain(
+                other
+                    .peptides
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, peptide)| (peptide, 1, idx)),
+            )
+            .collect::<Vec<_>>();
+
+        // This is equivalent to a stable sort
+        peptides.par_sort_unstable_by(|a, b| {
+            a.0.monoisotopic
+                .total_cmp(&b.0.monoisotopic)
+                .then_with(|| a.0.initial_sort(&b.0))
+                .then_with(|| a.1.cmp(&b.1))
+        });
+
+        // Map original peptide indices to merged indices. Fragments of peptides
+        // that were combined with an identical peptide are dropped (`None`)
+        let mut remap = [vec![None; sizes[0]], vec![None; sizes[1]]];
+        let mut merged: Vec<Peptide> = Vec::with_capacity(peptides.len());
+        let counts = [self.fragment_count, other.fragment_count];
+        let mut fragment_count = Vec::with_capacity(peptides.len());
+        for (peptide, source, idx) in peptides {
+            if let Some(keep) = merged.last_mut() {
+                if keep.sequence == peptide.sequence
+                    && keep.modifications == peptide.modifications
+                    && keep.nterm == peptide.nterm
+                    && keep.cterm == peptide.cterm
+                    && keep.decoy == peptide.decoy
+                    && keep.label_channel == peptide.label_channel
+                {
+                    keep.proteins.extend(peptide.proteins);
+                    keep.proteins.sort_unstable();
+                    keep.proteins.dedup();
+                    continue;
+                }
+            }
+            remap[source][idx] = Some(PeptideIx(merged.len() as u32));
+            merged.push(peptide);
+            fragment_count.push(counts[source].get(idx).copied().unwrap_or_default());
+        }
+
+        check_peptide_count(merged.len())?;
+
+        let mut fragments =
+            self.fragments
+                .into_par_iter()
+                .filter_map(|frag| remap[0][frag.peptide_index.0 as usize].map(|ix| (frag, ix)))
+                .chain(other.fragments.into_par_iter().filter_map(|frag| {
+                    remap[1][frag.peptide_index.0 as usize].map(|ix| (frag, ix))
+                }))
+                .map(|(frag, peptide_index)| Theoretical {
+                    peptide_index,
+                    fragment_mz: frag.fragment_mz,
+                })
+                .collect::<Vec<_>>();
+
+        fragments.par_sort_unstable_by(fragment_order);
+        let min_value = bucket_fragments(&mut fragments, self.bucket_size);
+
+        let mut ion_kinds = self.ion_kinds;
+        for kind in other.ion_kinds {
+            if !ion_kinds.contains(&kind) {
+                ion_kinds.push(kind);
+            }
+        }
+        let mut potential_mods = self.potential_mods;
+        for m in other.potential_mods {
+            if !potential_mods.contains(&m) {
+                potential_mods.push(m);
+            }
+        }
+
+        Ok(IndexedDatabase {
+            masses: peptide_masses(&merged),
+            decoys: decoy_bitset(&merged),
+            peptides: merged,
+            fragments,
+            ion_kinds,
+            min_value,
+            fragment_count,
+            potential_mods,
+            bucket_size: self.bucket_size,
+            generate_decoys: self.generate_decoys,
+            decoy_tag: self.decoy_tag,
+            decoy_prefix: self.decoy_prefix,
+            entrapment_tag: self.entrapment_tag,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.fragments.len()
     }
 
     pub fn buckets(&self) -> &[f32] {
         &self.min_value
     }
 
+    /// Monoisotopic masses of all peptides, sorted in ascending order and
+    /// indexed by [`PeptideIx`]. This allows external code to select candidate
+    /// peptides by mass (e.g. with [`binary_search_slice`]) without touching
+    /// [`IndexedDatabase::peptides`]
+    pub fn peptide_masses(&self) -> &[f32] {
+        &self.masses
+    }
+
+    /// Is the peptide at `ix` a decoy? This is a single bit lookup, and does
+    /// not touch [`IndexedDatabase::peptides`]
+    #[inline]
+    pub fn is_decoy(&self, ix: PeptideIx) -> bool {
+        let ix = ix.0 as usize;
+        self.decoys[ix / 64] & (1 << (ix % 64)) != 0
+    }
+
+    /// Check the invariants of the fragment index that the query path relies
+    /// on: peptides are sorted by mass, fragments are sorted by m/z across
+    /// buckets, each bucket is sorted by peptide index (and thus precursor
+    /// mass), `min_value` holds the minimum fragment m/z of each bucket, and
+    /// all peptide indices are valid
+    pub fn verify(&self) -> Result<(), IntegrityError> {
+        if let Some(index) = self
+            .peptides
+            .windows(2)
+            .position(|w| w[0].monoisotopic > w[1].monoisotopic)
+        {
+            return Err(IntegrityError::UnsortedPeptides { index: index + 1 });
+        }
+
+        if self.masses.len() != self.peptides.len() {
+            return Err(IntegrityError::PeptideMassMismatch {
+                index: self.masses.len().min(self.peptides.len()),
+            });
+        }
+        if let Some(index) = self
+            .peptides
+            .iter()
+            .zip(&self.masses)
+            .position(|(peptide, mass)| peptide.monoisotopic.to_bits() != mass.to_bits())
+        {
+            return Err(IntegrityError::PeptideMassMismatch { index });
+        }
+
+        if self.decoys.len() != (self.peptides.len() + 63) / 64 {
+            return Err(IntegrityError::DecoyMismatch {
+                index: (self.decoys.len() * 64).min(self.peptides.len()),
+            });
+        }
+        if let Some(index) = self
+            .peptides
+            .iter()
+            .enumerate()
+            .position(|(idx, peptide)| peptide.decoy != self.is_decoy(PeptideIx(idx as u32)))
+        {
+            return Err(IntegrityError::DecoyMismatch { index });
+        }
+
+        if let Some((fragment, frag)) = self
+            .fragments
+            .iter()
+            .enumerate()
+            .find(|(_, frag)| frag.peptide_index.0 as usize >= self.peptides.len())
+        {
+            return Err(IntegrityError::PeptideIndexOutOfRange {
+                fragment,
+                peptide_index: frag.peptide_index,
+            });
+        }
+
+        if self.bucket_size == 0 {
+            return Err(IntegrityError::BucketCount {
+                expected: 0,
+                observed: self.min_value.len(),
+            });
+        }
+        let expected = (self.fragments.len() + self.bucket_size - 1) / self.bucket_size;
+        if self.min_value.len() != expected {
+            return Err(IntegrityError::BucketCount {
+                expected,
+                observed: self.min_value.len(),
+            });
+        }
+
+        let mut prev_max = f32::MIN;
+        for (bucket, (chunk, &min_value)) in self
+            .fragments
+            .chunks(self.bucket_size)
+            .zip(&self.min_value)
+            .enumerate()
+        {
+            if chunk
+                .windows(2)
+                .any(|w| w[0].peptide_index > w[1].peptide_index)
+            {
+                return Err(IntegrityError::UnsortedBucket { bucket });
+            }
+            let (min, max) = chunk.iter().fold((f32::MAX, f32::MIN), |(min, max), frag| {
+                (min.min(frag.fragment_mz), max.max(frag.fragment_mz))
+            });
+            if min != min_value {
+                return Err(IntegrityError::MinValueMismatch {
+                    bucket,
+                    expected: min,
+                    observed: min_value,
+                });
+            }
+            if min < prev_max {
+                return Err(IntegrityError::OverlappingBuckets { bucket });
+            }
+            prev_max = max;
+        }
+        Ok(())
+    }
+
     pub fn serialize(&self) {
         use std::io::Write;
         let mut wtr = std::io::BufWriter::new(std::fs::File::create("fragments.bin").unwrap());
@@ -418,7 +1708,7 @@ impl IndexedDatabase {
                 wtr,
                 "{},{},{},{}",
                 fragment,
-                fragment.proteins(&self.decoy_tag, self.generate_decoys),
+                self.proteins(fragment),
                 fragment.monoisotopic,
                 fragment.decoy
             )
@@ -428,6 +1718,263 @@ impl IndexedDatabase {
     }
 }
 
+/// Violated invariant of an [`IndexedDatabase`], see [`IndexedDatabase::verify`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntegrityError {
+    /// Peptide at `index` is lighter than the preceding peptide
+    UnsortedPeptides { index: usize },
+    /// `masses` does not match the monoisotopic mass of the peptide at `index`
+    PeptideMassMismatch { index: usize },
+    /// `decoys` does not match the decoy flag of the peptide at `index`
+    DecoyMismatch { index: usize },
+    /// Fragment refers to a peptide that does not exist
+    PeptideIndexOutOfRange {
+        fragment: usize,
+        peptide_index: PeptideIx,
+    },
+    /// `min_value` does not contain exactly one entry per bucket
+    BucketCount { expected: usize, observed: usize },
+    /// Fragments within a bucket are not sorted by peptide index
+    UnsortedBucket { bucket: usize },
+    /// `min_value` does not match the minimum fragment m/z of a bucket
+    MinValueMismatch {
+        bucket: usize,
+        expected: f32,
+        observed: f32,
+    },
+    /// A bucket contains fragments with a lower m/z than the preceding bucket
+    OverlappingBuckets { bucket: usize },
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::UnsortedPeptides { index } => {
+                write!(f, "peptides are not sorted by mass at index {}", index)
+            }
+            IntegrityError::PeptideMassMismatch { index } => {
+                write!(f, "peptide mass does not match peptide at index {}", index)
+            }
+            IntegrityError::DecoyMismatch { index } => {
+                write!(f, "decoy flag does not match peptide at index {}", index)
+            }
+            IntegrityError::PeptideIndexOutOfRange {
+                fragment,
+                peptide_index,
+            } => write!(
+                f,
+                "fragment {} refers to nonexistent peptide {}",
+                fragment, peptide_index.0
+            ),
+            IntegrityError::BucketCount { expected, observed } => write!(
+                f,
+                "expected {} fragment buckets, found {} bucket minimums",
+                expected, observed
+            ),
+            IntegrityError::UnsortedBucket { bucket } => {
+                write!(f, "bucket {} is not sorted by peptide index", bucket)
+            }
+            IntegrityError::MinValueMismatch {
+                bucket,
+                expected,
+                observed,
+            } => write!(
+                f,
+                "minimum m/z of bucket {} is {}, but min_value is {}",
+                bucket, expected, observed
+            ),
+            IntegrityError::OverlappingBuckets { bucket } => write!(
+                f,
+                "bucket {} overlaps the m/z range of the preceding bucket",
+                bucket
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Maximum number of peptides in a database. Peptides are addressed by a
+/// [`PeptideIx`], and `u32::MAX` is reserved for [`PeptideIx::default`]
+pub const MAX_PEPTIDES: usize = u32::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatabaseError {
+    /// More than [`MAX_PEPTIDES`] peptides were generated
+    TooManyPeptides { count: usize },
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::TooManyPeptides { count } => write!(
+                f,
+                "generated {} peptides, but at most {} are supported - consider using \
+                 fewer variable modifications, missed cleavages or a fully enzymatic digest",
+                count, MAX_PEPTIDES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+/// Monoisotopic masses of `peptides`, see [`IndexedDatabase::peptide_masses`]
+pub(crate) fn peptide_masses(peptides: &[Peptide]) -> Vec<f32> {
+    peptides
+        .iter()
+        .map(|peptide| peptide.monoisotopic)
+        .collect()
+}
+
+/// Packed decoy flags of `peptides`, see [`IndexedDatabase::is_decoy`]
+pub(crate) fn decoy_bitset(peptides: &[Peptide]) -> Vec<u64> {
+    let mut bits = vec![0u64; (peptides.len() + 63) / 64];
+    for (idx, peptide) in peptides.iter().enumerate() {
+        if peptide.decoy {
+            bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+    bits
+}
+
+/// Ensure that `count` peptides can be addressed by a [`PeptideIx`], rather
+/// than silently truncating peptide indices
+pub(crate) fn check_peptide_count(count: usize) -> Result<(), DatabaseError> {
+    match count > MAX_PEPTIDES {
+        true => Err(DatabaseError::TooManyPeptides { count }),
+        false => Ok(()),
+    }
+}
+
+/// Write the in-silico peptide table of `db` as TSV, e.g. to check digestion
+/// and modification settings before starting a search. Columns are the
+/// (modified) peptide, its monoisotopic neutral mass, target/decoy label
+/// (1/-1), proteins (see [`IndexedDatabase::proteins`]), missed cleavages,
+/// number of enzymatic termini, and number of theoretical fragments (see
+/// [`IndexedDatabase::fragment_count`])
+pub fn write_peptide_table<W: Write>(db: &IndexedDatabase, mut writer: W) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "peptide\tcalcmass\tlabel\tproteins\tmissed_cleavages\tenzymatic_termini\tfragments"
+    )?;
+    for (idx, peptide) in db.peptides.iter().enumerate() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            peptide,
+            peptide.monoisotopic,
+            if peptide.decoy { -1 } else { 1 },
+            db.proteins(peptide),
+            peptide.missed_cleavages,
+            peptide.enzymatic_termini,
+            db.fragment_count.get(idx).copied().unwrap_or_default(),
+        )?;
+    }
+    writer.flush()
+}
+
+/// Target-only or decoy-only view of an [`IndexedDatabase`], e.g. for separate
+/// target and decoy searches, or for inspecting decoy score distributions.
+///
+/// The view shares the peptides and fragment index of the underlying database,
+/// so [`PeptideIx`] values are valid for both. Queries are performed against
+/// the full index, and peptides outside of the view are filtered out
+#[derive(Copy, Clone)]
+pub struct DatabaseView<'db> {
+    db: &'db IndexedDatabase,
+    decoy: bool,
+}
+
+impl<'db> DatabaseView<'db> {
+    /// The underlying (unfiltered) database
+    pub fn database(&self) -> &'db IndexedDatabase {
+        self.db
+    }
+
+    /// Is the peptide at `ix` part of this view?
+    pub fn contains(&self, ix: PeptideIx) -> bool {
+        self.db.is_decoy(ix) == self.decoy
+    }
+
+    /// All peptides in this view, alongside their index into the database
+    pub fn peptides(&self) -> impl Iterator<Item = (PeptideIx, &'db Peptide)> + '_ {
+        self.db
+            .peptides
+            .iter()
+            .enumerate()
+            .filter(move |(_, peptide)| peptide.decoy == self.decoy)
+            .map(|(ix, peptide)| (PeptideIx(ix as u32), peptide))
+    }
+
+    /// Number of peptides in this view
+    pub fn len(&self) -> usize {
+        self.peptides().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peptides().next().is_none()
+    }
+
+    /// Same as [`IndexedDatabase::query_mass`], restricted to this view
+    pub fn query_mass(
+        &self,
+        neutral_mass: f32,
+        fragments: &[f32],
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> Vec<MassMatch> {
+        let mut scratch = SearchScratch::default();
+        self.query_mass_with(
+            &mut scratch,
+            neutral_mass,
+            fragments,
+            precursor_tol,
+            fragment_tol,
+        )
+        .to_vec()
+    }
+
+    /// Same as [`IndexedDatabase::query_mass_with`], restricted to this view
+    pub fn query_mass_with<'s>(
+        &self,
+        scratch: &'s mut SearchScratch,
+        neutral_mass: f32,
+        fragments: &[f32],
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> &'s [MassMatch] {
+        self.db.query_mass_with(
+            scratch,
+            neutral_mass,
+            fragments,
+            precursor_tol,
+            fragment_tol,
+        );
+        self.retain(scratch)
+    }
+
+    /// Same as [`IndexedDatabase::search_spectrum`], restricted to this view
+    pub fn search_spectrum<'s>(
+        &self,
+        scratch: &'s mut SearchScratch,
+        spectrum: &ProcessedSpectrum,
+        charge: u8,
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> &'s [MassMatch] {
+        self.db
+            .search_spectrum(scratch, spectrum, charge, precursor_tol, fragment_tol);
+        self.retain(scratch)
+    }
+
+    /// Drop matches outside of this view, preserving their order
+    fn retain<'s>(&self, scratch: &'s mut SearchScratch) -> &'s [MassMatch] {
+        scratch.matches.retain(|m| self.contains(m.peptide_index));
+        &scratch.matches
+    }
+}
+
 impl std::ops::Index<PeptideIx> for IndexedDatabase {
     type Output = Peptide;
 
@@ -446,6 +1993,38 @@ pub struct IndexedQuery<'d> {
 }
 
 impl<'d> IndexedQuery<'d> {
+    /// Search for a specified `fragment_mz` within the database, returning owned
+    /// [`FragmentMatch`]es annotated with ion kind and ordinal. `charge` is the
+    /// charge state that the experimental peak was multiplied by to obtain
+    /// `fragment_mz`, and is carried through to the results.
+    ///
+    /// This is slower than [`IndexedQuery::page_search`], since the ion series
+    /// of each matched peptide must be regenerated to recover the annotations
+    pub fn page_search_owned(&self, fragment_mz: f32, charge: u8) -> Vec<FragmentMatch> {
+        self.page_search(fragment_mz)
+            .filter_map(|frag| {
+                let peptide = &self.db[frag.peptide_index];
+                self.db
+                    .ion_kinds
+                    .iter()
+                    .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate())
+                    .find(|(_, ion)| ion.monoisotopic_mass == frag.fragment_mz)
+                    .map(|(idx, ion)| FragmentMatch {
+                        peptide_index: frag.peptide_index,
+                        fragment_mz: frag.fragment_mz,
+                        kind: ion.kind,
+                        charge,
+                        ordinal: match ion.kind {
+                            Kind::A | Kind::B | Kind::C => idx as i32 + 1,
+                            Kind::X | Kind::Y | Kind::Z => {
+                                peptide.sequence.len().saturating_sub(1) as i32 - idx as i32
+                            }
+                        },
+                    })
+            })
+            .collect()
+    }
+
     /// Search for a specified `fragment_mz` within the database
     pub fn page_search(&self, fragment_mz: f32) -> impl Iterator<Item = &Theoretical> {
         let (fragment_lo, fragment_hi) = self.fragment_tol.bounds(fragment_mz);
@@ -544,6 +2123,7 @@ where
 
 #[cfg(test)]
 mod test {
+    use fnv::FnvHashSet;
     use std::sync::Arc;
 
     use super::*;
@@ -614,16 +2194,26 @@ mod test {
             peptide_max_mass: 5000.0,
             ion_kinds: vec![Kind::B, Kind::Y],
             min_ion_index: 2,
+            min_b_ordinal: 3,
+            min_y_ordinal: 3,
+            min_fragments: 0,
             static_mods: HashMap::default(),
             variable_mods: [(ModificationSpecificity::ProteinN(None), vec![42.0])]
                 .into_iter()
                 .collect(),
             max_variable_mods: 2,
+            exclusive_mods: Vec::new(),
+            label_channels: Vec::default(),
             decoy_tag: "rev_".into(),
+            decoy_prefix: "rev_".into(),
+            entrapment_tag: None,
             generate_decoys: false,
             fasta: "none".into(),
             shuffle_decoys: false,
+            decoy_collisions: DecoyCollision::Drop,
             keep_ends: true,
+            seed: DEFAULT_SEED,
+            index_chunk_size: None,
         };
 
         let peptides = params.digest(&fasta);
@@ -652,4 +2242,1253 @@ mod test {
             vec!["sp|AAAAA".to_string().into()]
         );
     }
+
+    #[test]
+    fn duplicate_proteins() {
+        let fasta = r#"
+        >sp|AAAAA
+        MEWKLEQSMREQALLKAQLTQLK
+        >sp|BBBBB
+        MEWKLEQSMREQALLKAQLTQLK
+        >sp|AAAAA
+        MEWKLEQSMREQALLKAQLTQLK
+        >sp|CCCCC
+        PEPTIDEK
+        "#;
+
+        let fasta = Fasta::parse(fasta.into(), "rev_", false);
+
+        // The exact duplicate entry of sp|AAAAA is dropped
+        assert_eq!(fasta.targets.len(), 3);
+        assert_eq!(
+            fasta.duplicate_sequences(),
+            vec![vec![
+                Arc::new("sp|AAAAA".to_string()),
+                Arc::new("sp|BBBBB".to_string())
+            ]]
+        );
+
+        let mut params = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        params.generate_decoys = false;
+        let database = params.build(fasta);
+
+        let shared = database
+            .peptides
+            .iter()
+            .filter(|p| p.sequence.as_ref() != b"PEPTIDEK")
+            .collect::<Vec<_>>();
+        assert!(!shared.is_empty());
+        for peptide in shared {
+            assert_eq!(database.proteins(peptide), "sp|AAAAA;sp|BBBBB");
+        }
+    }
+
+    #[test]
+    fn label_channels() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMR".into(), "rev_", false);
+
+        let channel = |mass: f32| {
+            [
+                (ModificationSpecificity::PeptideN(None), mass),
+                (ModificationSpecificity::Residue(b'K'), mass),
+            ]
+            .into_iter()
+            .collect::<HashMap<_, _>>()
+        };
+
+        let params = Parameters {
+            bucket_size: 128,
+            enzyme: EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(6),
+                max_len: Some(10),
+                ..Default::default()
+            },
+            fragment_min_mz: 100.0,
+            fragment_max_mz: 1000.0,
+            peptide_min_mass: 150.0,
+            peptide_max_mass: 5000.0,
+            ion_kinds: vec![Kind::B, Kind::Y],
+            min_ion_index: 2,
+            min_b_ordinal: 3,
+            min_y_ordinal: 3,
+            min_fragments: 0,
+            static_mods: [(ModificationSpecificity::Residue(b'K'), 100.0)]
+                .into_iter()
+                .collect(),
+            variable_mods: HashMap::default(),
+            max_variable_mods: 2,
+            exclusive_mods: Vec::new(),
+            label_channels: vec![channel(28.0), channel(36.0)],
+            decoy_tag: "rev_".into(),
+            decoy_prefix: "rev_".into(),
+            entrapment_tag: None,
+            generate_decoys: false,
+            fasta: "none".into(),
+            shuffle_decoys: false,
+            decoy_collisions: DecoyCollision::Drop,
+            keep_ends: true,
+            seed: DEFAULT_SEED,
+            index_chunk_size: None,
+        };
+
+        let peptides = params.digest(&fasta);
+        let mut observed = peptides
+            .iter()
+            .map(|p| (p.label_channel, p.to_string()))
+            .collect::<Vec<_>>();
+        observed.sort();
+
+        assert_eq!(
+            observed,
+            vec![
+                (Some(0), "[+28]-EQALLK[+28]".to_string()),
+                (Some(0), "[+28]-LEQSMR".to_string()),
+                (Some(1), "[+36]-EQALLK[+36]".to_string()),
+                (Some(1), "[+36]-LEQSMR".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pyro_glu() {
+        let fasta = Fasta::parse(">sp|AAAAA\nQALLKEQSMRLEQSMR".into(), "rev_", false);
+        let params = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(5),
+                ..Default::default()
+            }),
+            peptide_min_mass: Some(150.0),
+            variable_mods: Some(
+                serde_json::from_value(serde_json::json!({ "^Q": -17.026549 })).unwrap(),
+            ),
+            pyro_glu: Some(true),
+            generate_decoys: Some(false),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        // Masses that are already configured are not duplicated
+        assert_eq!(
+            params.variable_mods[&ModificationSpecificity::PeptideN(Some(b'Q'))],
+            vec![-17.026549]
+        );
+        assert_eq!(
+            params.variable_mods[&ModificationSpecificity::PeptideN(Some(b'E'))],
+            vec![-18.010565]
+        );
+
+        let mut observed = params
+            .digest(&fasta)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        observed.sort();
+
+        // Only applied to N-terminal Q/E, unmodified forms are kept
+        assert_eq!(
+            observed,
+            vec![
+                "EQSMR",
+                "E[-18.010565]QSMR",
+                "LEQSMR",
+                "QALLK",
+                "Q[-17.026548]ALLK",
+            ]
+        );
+    }
+
+    #[test]
+    fn partitioned_database() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        let full = parameters.clone().build(fasta.clone());
+        let mut partitioned = parameters.build_partitioned(fasta, 3);
+        assert_eq!(partitioned.len(), 3);
+        assert_eq!(partitioned.peptides.len(), full.peptides.len());
+
+        let mut fragments = Vec::new();
+        let mut prev_hi = 0.0;
+        for partition in 0..partitioned.len() {
+            let (lo, hi) = partitioned.mass_range(partition);
+            assert!(lo >= prev_hi && lo <= hi);
+            prev_hi = hi;
+
+            let db = partitioned.load(partition);
+            assert!(db.fragments.iter().all(|frag| {
+                let mass = db[frag.peptide_index].monoisotopic;
+                mass >= lo && mass <= hi
+            }));
+            fragments.extend(db.fragments.iter().copied());
+        }
+
+        // Together, all partitions must contain exactly the same fragments
+        let key = |a: &Theoretical, b: &Theoretical| {
+            a.peptide_index
+                .cmp(&b.peptide_index)
+                .then(a.fragment_mz.total_cmp(&b.fragment_mz))
+        };
+        let mut expected = full.fragments.clone();
+        expected.sort_by(key);
+        fragments.sort_by(key);
+        assert_eq!(fragments, expected);
+    }
+
+    #[test]
+    fn external_fragment_sort() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            bucket_size: Some(8),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        let in_memory = parameters.clone().build(fasta.clone());
+        let external = Parameters {
+            index_chunk_size: Some(3),
+            ..parameters
+        }
+        .build(fasta);
+
+        assert!(in_memory.fragments.len() > 8);
+        assert_eq!(external.fragments, in_memory.fragments);
+        assert_eq!(external.min_value, in_memory.min_value);
+    }
+
+    #[test]
+    fn deterministic_fragment_order() {
+        // Leucine/isoleucine variants share every fragment m/z
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nPEPTLDEKPEPTIDEKLESLIEKLESLLEKIESLIEKVLHSGPRVIHSGPR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            bucket_size: Some(8),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        let a = parameters.clone().build(fasta.clone());
+        for _ in 0..4 {
+            let b = parameters.clone().build(fasta.clone());
+            assert_eq!(a.fragments, b.fragments);
+            assert_eq!(a.min_value, b.min_value);
+        }
+
+        // Within each bucket, fragments are ordered by peptide, then m/z
+        for chunk in a.fragments.chunks(8) {
+            assert!(chunk
+                .windows(2)
+                .all(|w| (w[0].peptide_index, w[0].fragment_mz)
+                    <= (w[1].peptide_index, w[1].fragment_mz)));
+        }
+    }
+
+    #[test]
+    fn memory_estimate() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR\n>sp|BBBBB\nMKLESLIEKRPEPTIDEKR"
+                .into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        // Every protein is sampled, so counts are exact
+        let estimate = parameters.estimate_memory(&fasta);
+        let database = parameters.clone().build(fasta);
+        assert_eq!(estimate.peptides, database.peptides.len());
+        assert_eq!(estimate.fragments, database.fragments.len());
+        assert!(estimate.bytes > estimate.fragments * std::mem::size_of::<Theoretical>());
+        assert!(estimate.bytes > estimate.peptides * std::mem::size_of::<Peptide>());
+
+        let empty = Fasta::parse(String::new(), "rev_", true);
+        assert_eq!(
+            parameters.estimate_memory(&empty),
+            MemoryEstimate::default()
+        );
+    }
+
+    #[test]
+    fn peptide_count_limit() {
+        assert_eq!(check_peptide_count(0), Ok(()));
+        assert_eq!(check_peptide_count(MAX_PEPTIDES), Ok(()));
+        // The largest index must not collide with `PeptideIx::default()`
+        assert!(PeptideIx((MAX_PEPTIDES - 1) as u32) != PeptideIx::default());
+        let err = check_peptide_count(MAX_PEPTIDES + 1).unwrap_err();
+        assert_eq!(
+            err,
+            DatabaseError::TooManyPeptides {
+                count: MAX_PEPTIDES + 1
+            }
+        );
+        assert!(err.to_string().contains("4294967296 peptides"));
+    }
+
+    #[test]
+    fn verify_integrity() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            bucket_size: Some(8),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        assert!(db.min_value.len() > 2);
+        assert_eq!(db.verify(), Ok(()));
+
+        let corrupt = |f: &dyn Fn(&mut IndexedDatabase)| {
+            let mut db = IndexedDatabase {
+                peptides: db.peptides.clone(),
+                masses: db.masses.clone(),
+                decoys: db.decoys.clone(),
+                fragments: db.fragments.clone(),
+                ion_kinds: db.ion_kinds.clone(),
+                min_value: db.min_value.clone(),
+                fragment_count: db.fragment_count.clone(),
+                potential_mods: db.potential_mods.clone(),
+                bucket_size: db.bucket_size,
+                generate_decoys: db.generate_decoys,
+                decoy_tag: db.decoy_tag.clone(),
+                decoy_prefix: db.decoy_prefix.clone(),
+                entrapment_tag: db.entrapment_tag.clone(),
+            };
+            f(&mut db);
+            db.verify()
+        };
+
+        assert!(matches!(
+            corrupt(&|db| {
+                let n = db.peptides.len();
+                db.peptides.swap(0, n - 1)
+            }),
+            Err(IntegrityError::UnsortedPeptides { index: 1 })
+        ));
+        assert!(matches!(
+            corrupt(&|db| db.masses[2] += 1.0),
+            Err(IntegrityError::PeptideMassMismatch { index: 2 })
+        ));
+        assert!(matches!(
+            corrupt(&|db| {
+                db.masses.pop();
+            }),
+            Err(IntegrityError::PeptideMassMismatch { .. })
+        ));
+        assert!(matches!(
+            corrupt(&|db| db.decoys[0] ^= 1),
+            Err(IntegrityError::DecoyMismatch { index: 0 })
+        ));
+        assert!(matches!(
+            corrupt(&|db| db.fragments[3].peptide_index = PeptideIx(u32::MAX)),
+            Err(IntegrityError::PeptideIndexOutOfRange { fragment: 3, .. })
+        ));
+        assert!(matches!(
+            corrupt(&|db| {
+                db.min_value.pop();
+            }),
+            Err(IntegrityError::BucketCount { .. })
+        ));
+        assert!(matches!(
+            corrupt(&|db| db.fragments[8..16].reverse()),
+            Err(IntegrityError::UnsortedBucket { bucket: 1 })
+        ));
+        assert!(matches!(
+            corrupt(&|db| db.min_value[1] += 1.0),
+            Err(IntegrityError::MinValueMismatch { bucket: 1, .. })
+        ));
+        assert!(matches!(
+            corrupt(&|db| {
+                let (a, b) = db.fragments.split_at_mut(8);
+                std::mem::swap(&mut a[0], &mut b[0]);
+                db.min_value[0] = db.fragments[..8]
+                    .iter()
+                    .map(|frag| frag.fragment_mz)
+                    .fold(f32::MAX, f32::min);
+                db.min_value[1] = db.fragments[8..16]
+                    .iter()
+                    .map(|frag| frag.fragment_mz)
+                    .fold(f32::MAX, f32::min);
+                db.fragments[..8].sort_by_key(|frag| frag.peptide_index);
+                db.fragments[8..16].sort_by_key(|frag| frag.peptide_index);
+            }),
+            Err(IntegrityError::OverlappingBuckets { bucket: 1 })
+        ));
+    }
+
+    #[test]
+    fn min_fragments() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR".into(),
+            "rev_",
+            false,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        let counts = |db: &IndexedDatabase| {
+            let mut counts = vec![0; db.peptides.len()];
+            for frag in &db.fragments {
+                counts[frag.peptide_index.0 as usize] += 1;
+            }
+            counts
+        };
+
+        let all = counts(&parameters.clone().build(fasta.clone()));
+        let filtered = counts(
+            &Parameters {
+                min_fragments: 8,
+                ..parameters
+            }
+            .build(fasta),
+        );
+
+        assert!(all.iter().any(|&n| n > 0 && n < 8));
+        for (all, filtered) in all.into_iter().zip(filtered) {
+            match all < 8 {
+                true => assert_eq!(filtered, 0),
+                false => assert_eq!(filtered, all),
+            }
+        }
+    }
+
+    #[test]
+    fn fragment_count() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKPEPTIDEKVLHSGPRAVLDMR".into(),
+            "rev_",
+            false,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+
+        let db = parameters.clone().build(fasta.clone());
+        let mut indexed = vec![0u16; db.peptides.len()];
+        for frag in &db.fragments {
+            indexed[frag.peptide_index.0 as usize] += 1;
+        }
+        assert!(indexed.iter().all(|&n| n > 0));
+        assert_eq!(db.fragment_count, indexed);
+
+        // External sorting and partitioning produce identical counts
+        let external = Parameters {
+            index_chunk_size: Some(3),
+            ..parameters.clone()
+        }
+        .build(fasta.clone());
+        assert_eq!(external.fragment_count, indexed);
+
+        let mut partitioned = parameters.clone().build_partitioned(fasta.clone(), 3);
+        assert!(partitioned.fragment_count.iter().all(|&n| n == 0));
+        for partition in 0..partitioned.len() {
+            partitioned.load(partition);
+        }
+        assert_eq!(partitioned.fragment_count, indexed);
+
+        // Peptides excluded from the index are still counted
+        let filtered = Parameters {
+            min_fragments: 8,
+            ..parameters
+        }
+        .build(fasta);
+        assert_eq!(filtered.fragment_count, indexed);
+    }
+
+    #[test]
+    fn peptide_table() {
+        let fasta = Fasta::parse(">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLK".into(), "rev_", true);
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let mut table = Vec::new();
+        write_peptide_table(&db, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "peptide\tcalcmass\tlabel\tproteins\tmissed_cleavages\tenzymatic_termini\tfragments"
+        );
+        assert_eq!(lines.len(), db.peptides.len() + 1);
+
+        let row = |sequence: &str| {
+            let idx = db
+                .peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence.as_bytes())
+                .unwrap();
+            lines[idx + 1].split('\t').collect::<Vec<_>>()
+        };
+        let target = row("AQLTQLK");
+        assert_eq!(target[0], "AQLTQLK");
+        assert!((target[1].parse::<f32>().unwrap() - 800.4756).abs() < 0.001);
+        assert_eq!(target[2..6], ["1", "sp|AAAAA", "0", "2"]);
+        assert!(target[6].parse::<u16>().unwrap() > 0);
+
+        let decoy = row("KLQTLQA");
+        assert_eq!(decoy[2..4], ["-1", "rev_sp|AAAAA"]);
+    }
+
+    #[test]
+    fn stacked_static_mods() {
+        let build = |static_mods: serde_json::Value| {
+            let fasta = Fasta::parse(">sp|AAAAA\nLEQSMRACLTQLK".into(), "rev_", false);
+            Builder {
+                fasta: Some("none".into()),
+                static_mods: Some(serde_json::from_value(static_mods).unwrap()),
+                ..Default::default()
+            }
+            .make_parameters()
+            .build(fasta)
+        };
+
+        let stacked = build(serde_json::json!({ "C": [57.0215, 10.0], "K": 8.0142 }));
+        let single = build(serde_json::json!({ "C": 67.0215, "K": 8.0142 }));
+
+        let ix = stacked
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"ACLTQLK")
+            .unwrap();
+        assert!((stacked.peptides[ix].modifications[1] - 67.0215).abs() < 1E-4);
+        assert_eq!(stacked.peptides[ix].modifications[6], 8.0142);
+
+        let stacked = stacked.fragments_for(PeptideIx(ix as u32));
+        let single = single.fragments_for(PeptideIx(ix as u32));
+        assert_eq!(stacked.len(), single.len());
+        for (a, b) in stacked.iter().zip(&single) {
+            assert_eq!(a.kind, b.kind);
+            assert!((a.monoisotopic_mass - b.monoisotopic_mass).abs() < 1E-3);
+        }
+    }
+
+    #[test]
+    fn merge_databases() {
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let main = parameters.clone().build(Fasta::parse(
+            ">sp|MAIN\nEQALLKLEQSMRAPEPTIDEKGLVHSGTR".into(),
+            "rev_",
+            true,
+        ));
+        let spike = parameters.build(Fasta::parse(
+            ">sp|SPIKE\nVLHSGAKAPEPTIDEKGGAAVLDMR".into(),
+            "rev_",
+            true,
+        ));
+        let (main_peptides, spike_peptides) = (main.peptides.len(), spike.peptides.len());
+
+        let merged = main.try_merge(spike).unwrap();
+        assert_eq!(merged.verify(), Ok(()));
+        // APEPTIDEK (and its decoy) is present in both databases
+        assert_eq!(merged.peptides.len(), main_peptides + spike_peptides - 2);
+        assert!(merged
+            .peptides
+            .windows(2)
+            .all(|w| w[0].monoisotopic <= w[1].monoisotopic));
+
+        let find = |s: &str| {
+            merged
+                .peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == s.as_bytes() && !p.decoy)
+                .map(|ix| PeptideIx(ix as u32))
+                .unwrap()
+        };
+        let shared = find("APEPTIDEK");
+        assert_eq!(
+            merged[shared].proteins,
+            vec![
+                Arc::new("sp|MAIN".to_string()),
+                Arc::new("sp|SPIKE".to_string())
+            ]
+        );
+
+        // Every peptide from either source database can be found by querying
+        // its own fragments, and all fragments point to the correct peptide
+        for sequence in ["EQALLK", "GLVHSGTR", "APEPTIDEK", "VLHSGAK", "GGAAVLDMR"] {
+            let ix = find(sequence);
+            let peptide = &merged[ix];
+            let query = merged.query(
+                peptide.monoisotopic,
+                Tolerance::Ppm(-5.0, 5.0),
+                Tolerance::Ppm(-5.0, 5.0),
+            );
+            let ions = IonSeries::new(peptide, Kind::Y)
+                .map(|ion| ion.monoisotopic_mass)
+                .filter(|mass| *mass >= merged.min_value[0])
+                .collect::<Vec<_>>();
+            let matches = ions
+                .iter()
+                .flat_map(|mass| query.page_search(*mass))
+                .filter(|frag| frag.peptide_index == ix)
+                .count();
+            assert!(matches > 0, "{}", sequence);
+        }
+        for frag in &merged.fragments {
+            let peptide = &merged[frag.peptide_index];
+            assert!(IonSeries::new(peptide, Kind::B)
+                .chain(IonSeries::new(peptide, Kind::Y))
+                .any(|ion| ion.monoisotopic_mass == frag.fragment_mz));
+        }
+    }
+
+    #[test]
+    fn peptide_max_mass_cutoff() {
+        // Both tryptic peptides are within `max_len`, but the second one is too heavy
+        let heavy = format!("{}K", "W".repeat(30));
+        let fasta = Fasta::parse(format!(">sp|AAAAA\nEQALLKLEQSMR{}", heavy), "rev_", false);
+
+        let mut params = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        params.generate_decoys = false;
+        params.enzyme.max_len = Some(50);
+        params.peptide_max_mass = 5000.0;
+
+        let database = params.build(fasta);
+        let sequences = database
+            .peptides
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        assert!(sequences.contains(&"LEQSMR".to_string()));
+        assert!(!sequences.contains(&heavy));
+        assert!(database.peptides.iter().all(|p| p.monoisotopic <= 5000.0));
+        assert!(database
+            .fragments
+            .iter()
+            .all(|frag| (frag.peptide_index.0 as usize) < database.peptides.len()));
+    }
+
+    #[test]
+    fn peptides_in_mass_window() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKPEPTIDEKGGGGRMAGICK".into(),
+            "rev_",
+            false,
+        );
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let masses = database
+            .peptides
+            .iter()
+            .map(|p| p.monoisotopic)
+            .collect::<Vec<_>>();
+        assert!(masses.len() > 4);
+
+        for (lo, hi) in [
+            (0.0, 10000.0),
+            (masses[1], masses[3]),
+            (masses[1] + 0.001, masses[3] - 0.001),
+            (masses.last().unwrap() + 1.0, 10000.0),
+            (0.0, 1.0),
+            (masses[3], masses[1]),
+        ] {
+            let range = database.peptides_in_mass_window(lo, hi);
+            let expected = masses
+                .iter()
+                .enumerate()
+                .filter(|(_, &m)| m >= lo && m <= hi)
+                .map(|(idx, _)| idx)
+                .collect::<Vec<_>>();
+            assert_eq!(range.collect::<Vec<_>>(), expected, "{} {}", lo, hi);
+        }
+
+        // External candidate selection over the sorted mass slice
+        assert_eq!(database.peptide_masses(), masses.as_slice());
+        let (left, right) = binary_search_slice(
+            database.peptide_masses(),
+            |mass, bounds| mass.total_cmp(bounds),
+            masses[1],
+            masses[3],
+        );
+        assert!(left <= 1 && right >= 3);
+    }
+
+    #[test]
+    fn shuffled_decoys_reproducible() {
+        let build = |seed| {
+            let fasta = Fasta::parse(
+                ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+                "rev_",
+                false,
+            );
+            Builder {
+                fasta: Some("none".into()),
+                shuffle_decoys: Some(true),
+                seed,
+                ..Default::default()
+            }
+            .make_parameters()
+            .build(fasta)
+        };
+
+        let a = build(None);
+        let b = build(Some(DEFAULT_SEED));
+        let c = build(Some(1));
+        assert!(a.peptides.iter().any(|p| p.decoy));
+        assert!(a.peptides == b.peptides);
+        assert_eq!(a.fragments, b.fragments);
+        assert_eq!(a.min_value, b.min_value);
+        assert!(a.peptides != c.peptides);
+    }
+
+    #[test]
+    fn decoy_collisions() {
+        let digest = |sequence: &str, shuffle, decoy_collisions, seed| {
+            let fasta = Fasta::parse(format!(">sp|AAAAA\n{sequence}"), "rev_", false);
+            Builder {
+                fasta: Some("none".into()),
+                shuffle_decoys: Some(shuffle),
+                keep_ends: Some(!shuffle),
+                decoy_collisions: Some(decoy_collisions),
+                seed: Some(seed),
+                ..Default::default()
+            }
+            .make_parameters()
+            .digest_with_collisions(&fasta)
+        };
+
+        // Reversing the internal residues of this peptide reproduces the target
+        for policy in [DecoyCollision::Drop, DecoyCollision::Regenerate] {
+            let (peptides, collisions) = digest("AAAAAAAK", false, policy, DEFAULT_SEED);
+            assert_eq!(peptides.len(), 1);
+            assert!(!peptides[0].decoy);
+            assert_eq!(collisions.dropped, 1);
+            assert_eq!(collisions.regenerated, 0);
+        }
+
+        // Shuffling the internal residues reproduces the target 1 in 6 times
+        let mut collided = 0;
+        for seed in 0..50 {
+            let (_, dropped) = digest("AAAAAAGK", true, DecoyCollision::Drop, seed);
+            let (peptides, regenerated) =
+                digest("AAAAAAGK", true, DecoyCollision::Regenerate, seed);
+            assert_eq!(peptides.len(), 2);
+            assert_ne!(peptides[0].sequence, peptides[1].sequence);
+            assert_eq!(regenerated.dropped, 0);
+            assert_eq!(dropped.dropped, regenerated.regenerated);
+            collided += dropped.dropped;
+        }
+        assert!(collided > 0);
+    }
+
+    #[test]
+    fn modified_decoys() {
+        // Residue and modification of every site, including the termini
+        let sites = |p: &Peptide| {
+            let mut sites = p
+                .sequence
+                .iter()
+                .zip(&p.modifications)
+                .map(|(r, m)| (*r, m.to_bits()))
+                .collect::<Vec<_>>();
+            sites.sort_unstable();
+            (sites, p.nterm.map(f32::to_bits), p.cterm.map(f32::to_bits))
+        };
+
+        for shuffle in [false, true] {
+            let fasta = Fasta::parse(
+                ">sp|AAAAA\nQEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGCR".into(),
+                "rev_",
+                false,
+            );
+            let parameters = serde_json::from_value::<Builder>(serde_json::json!({
+                "fasta": "none",
+                "shuffle_decoys": shuffle,
+                "static_mods": { "C": 57.0215, "^": 229.1629, "$R": 10.0083 },
+                "variable_mods": { "M": 15.9949, "^Q": -17.0265 },
+            }))
+            .unwrap()
+            .make_parameters();
+            let peptides = parameters.digest(&fasta);
+
+            let targets = peptides
+                .iter()
+                .filter(|p| !p.decoy)
+                .map(sites)
+                .collect::<Vec<_>>();
+            let decoys = peptides.iter().filter(|p| p.decoy).collect::<Vec<_>>();
+            assert!(decoys
+                .iter()
+                .any(|p| p.modifications.iter().any(|&m| m != 0.0)));
+
+            for decoy in decoys {
+                // Modifications move with their residues, so every decoy carries
+                // exactly the residues and modifications of a target peptide
+                assert!(targets.contains(&sites(decoy)), "{}", decoy);
+
+                // ... and has the same neutral mass as that target
+                let mass = decoy
+                    .sequence
+                    .iter()
+                    .zip(&decoy.modifications)
+                    .map(|(r, m)| crate::mass::monoisotopic(*r) + m)
+                    .sum::<f32>()
+                    + crate::mass::H2O
+                    + decoy.nterm.unwrap_or_default()
+                    + decoy.cterm.unwrap_or_default();
+                assert!((decoy.monoisotopic - mass).abs() < 1E-3, "{}", decoy);
+                let target = peptides
+                    .iter()
+                    .find(|p| !p.decoy && sites(p) == sites(decoy))
+                    .unwrap();
+                assert!((decoy.monoisotopic - target.monoisotopic).abs() < 1E-3);
+            }
+        }
+    }
+
+    #[test]
+    fn fragments_for_peptide() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        for (idx, peptide) in database.peptides.iter().enumerate() {
+            let ix = PeptideIx(idx as u32);
+            let ions = database.fragments_for(ix);
+            assert_eq!(ions.len(), (peptide.sequence.len() - 1) * 2);
+
+            // Every indexed fragment of this peptide must be regenerated
+            for frag in database.fragments.iter().filter(|f| f.peptide_index == ix) {
+                assert!(ions
+                    .iter()
+                    .any(|ion| (ion.monoisotopic_mass - frag.fragment_mz).abs() < 1E-4));
+            }
+        }
+    }
+
+    #[test]
+    fn page_search_owned() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let idx = database
+            .peptides
+            .iter()
+            .position(|p| p.to_string() == "AQLTQLK")
+            .expect("AQLTQLK is in the database");
+        let peptide = &database.peptides[idx];
+
+        // y4 ion of AQLTQLK, searched as if it were a doubly charged peak
+        let y4 = IonSeries::new(peptide, Kind::Y).nth(2).unwrap();
+        let query = database.query(
+            peptide.monoisotopic,
+            Tolerance::Ppm(-10.0, 10.0),
+            Tolerance::Ppm(-10.0, 10.0),
+        );
+
+        let borrowed = query.page_search(y4.monoisotopic_mass).count();
+        let matches = query.page_search_owned(y4.monoisotopic_mass, 2);
+        assert_eq!(matches.len(), borrowed);
+
+        // Owned results can outlive the query and be sent to another thread
+        let matches = std::thread::spawn(move || matches).join().unwrap();
+        assert_eq!(
+            matches,
+            vec![FragmentMatch {
+                peptide_index: PeptideIx(idx as u32),
+                fragment_mz: y4.monoisotopic_mass,
+                kind: Kind::Y,
+                charge: 2,
+                ordinal: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn nonspecific_digest() {
+        let sequence = "MADEEKLPPGWEKRMSRSSGRVYYFNHITNASQWERPSGNW";
+        let fasta = Fasta::parse(format!(">sp|AAAAA\n{sequence}"), "rev_", false);
+        let database = Builder {
+            fasta: Some("none".into()),
+            enzyme: Some(EnzymeBuilder {
+                cleave_at: Some("".into()),
+                min_len: Some(8),
+                max_len: Some(10),
+                ..Default::default()
+            }),
+            peptide_min_mass: Some(0.0),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let targets = database
+            .peptides
+            .iter()
+            .filter(|p| !p.decoy)
+            .map(|p| p.to_string())
+            .collect::<FnvHashSet<_>>();
+        let expected = (8..=10)
+            .flat_map(|len| sequence.as_bytes().windows(len))
+            .map(|window| String::from_utf8(window.to_vec()).unwrap())
+            .collect::<FnvHashSet<_>>();
+        assert_eq!(targets, expected);
+        assert!(database.peptides.iter().all(|p| p.enzymatic_termini == 0));
+
+        // Decoys are generated for non-specific peptides, but never coincide with a target
+        let decoys = database.peptides.iter().filter(|p| p.decoy).count();
+        assert!(decoys > 0 && decoys <= targets.len());
+        assert!(database
+            .peptides
+            .iter()
+            .filter(|p| p.decoy)
+            .all(|p| !targets.contains(&p.to_string())));
+
+        // Every peptide is present in the fragment index
+        assert!(database.fragment_count.iter().all(|&count| count > 0));
+        let ix = database
+            .peptides
+            .iter()
+            .position(|p| p.to_string() == "NHITNASQW")
+            .unwrap();
+        let peptide = &database.peptides[ix];
+        let fragments = IonSeries::new(peptide, Kind::Y)
+            .map(|ion| ion.monoisotopic_mass + PROTON)
+            .collect::<Vec<_>>();
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let matches = database.query_mass(peptide.monoisotopic, &fragments, tol, tol);
+        assert_eq!(matches[0].peptide_index, PeptideIx(ix as u32));
+    }
+
+    #[test]
+    fn min_ion_ordinals() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let fragment_count = |builder: Builder| {
+            let database = Builder {
+                fasta: Some("none".into()),
+                fragment_min_mz: Some(0.0),
+                ..builder
+            }
+            .make_parameters()
+            .build(fasta.clone());
+            let idx = database
+                .peptides
+                .iter()
+                .position(|p| p.to_string() == "AQLTQLK")
+                .unwrap();
+            database.fragment_count[idx]
+        };
+
+        // 6 b- and 6 y-ions, of which b1/b2/y1/y2 are removed by default
+        assert_eq!(fragment_count(Builder::default()), 8);
+        assert_eq!(
+            fragment_count(Builder {
+                min_ion_index: Some(0),
+                ..Default::default()
+            }),
+            12
+        );
+        assert_eq!(
+            fragment_count(Builder {
+                min_b_ordinal: Some(2),
+                min_y_ordinal: Some(1),
+                ..Default::default()
+            }),
+            11
+        );
+    }
+
+    #[test]
+    fn query_mass() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let idx = database
+            .peptides
+            .iter()
+            .position(|p| p.to_string() == "AQLTQLK")
+            .expect("AQLTQLK is in the database");
+        let peptide = &database.peptides[idx];
+
+        // All y-ions and a noise peak, as singly charged m/z values
+        let fragments = IonSeries::new(peptide, Kind::Y)
+            .map(|ion| ion.monoisotopic_mass + PROTON)
+            .chain(std::iter::once(1234.5))
+            .collect::<Vec<_>>();
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+
+        let matches = database.query_mass(peptide.monoisotopic, &fragments, tol, tol);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].peptide_index, PeptideIx(idx as u32));
+        // y1 and y2 are excluded from the index by `min_y_ordinal`
+        assert_eq!(matches[0].matched_fragments, 4);
+
+        // No candidates outside of the precursor tolerance
+        assert!(database
+            .query_mass(peptide.monoisotopic + 10.0, &fragments, tol, tol)
+            .is_empty());
+    }
+
+    #[test]
+    fn peptide_redundancy() {
+        let fasta = Fasta::parse(
+            ">sp|A\nPEPTIDEKLESLIEK\n>sp|B\nPEPTIDEKAQLTQLK\n>sp|C\nPEPTIDEK".into(),
+            "rev_",
+            false,
+        );
+        let database = Builder {
+            fasta: Some("none".into()),
+            generate_decoys: Some(false),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let ix = |sequence: &str| {
+            let idx = database
+                .peptides
+                .iter()
+                .position(|p| p.to_string() == sequence)
+                .unwrap();
+            PeptideIx(idx as u32)
+        };
+
+        let peptides = [ix("LESLIEK"), ix("PEPTIDEK"), ix("AQLTQLK"), ix("PEPTIDEK")];
+        let redundancy = database.peptide_redundancy(&peptides, 1);
+        assert_eq!(redundancy.len(), 3);
+        assert_eq!(
+            redundancy[0],
+            PeptideRedundancy {
+                peptide_index: ix("PEPTIDEK"),
+                num_proteins: 3,
+                unique: false,
+            }
+        );
+        assert!(redundancy[1..]
+            .iter()
+            .all(|r| r.num_proteins == 1 && r.unique));
+
+        assert!(database
+            .peptide_redundancy(&peptides, 3)
+            .iter()
+            .all(|r| r.unique));
+    }
+
+    #[test]
+    fn search_scratch() {
+        use crate::spectrum::{Peak, Precursor};
+
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", true);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+
+        let mut scratch = SearchScratch::default();
+        for (idx, peptide) in database.peptides.iter().enumerate() {
+            let peaks = IonSeries::new(peptide, Kind::B)
+                .chain(IonSeries::new(peptide, Kind::Y))
+                .map(|ion| Peak {
+                    mass: ion.monoisotopic_mass,
+                    intensity: 100.0,
+                })
+                .collect::<Vec<_>>();
+            let spectrum = ProcessedSpectrum {
+                level: 2,
+                precursors: vec![Precursor {
+                    mz: peptide.mz(2),
+                    ..Default::default()
+                }],
+                peaks,
+                ..Default::default()
+            };
+
+            let matches = database.search_spectrum(&mut scratch, &spectrum, 2, tol, tol);
+            assert_eq!(matches[0].peptide_index, PeptideIx(idx as u32));
+
+            // Reusing the scratch buffers yields the same results as allocating
+            let fragments = spectrum
+                .peaks
+                .iter()
+                .map(|peak| peak.mass + PROTON)
+                .collect::<Vec<_>>();
+            let expected = database.query_mass(peptide.monoisotopic, &fragments, tol, tol);
+            let reused =
+                database.query_mass_with(&mut scratch, peptide.monoisotopic, &fragments, tol, tol);
+            assert_eq!(reused, expected.as_slice());
+        }
+
+        let empty = ProcessedSpectrum::default();
+        assert!(database
+            .search_spectrum(&mut scratch, &empty, 2, tol, tol)
+            .is_empty());
+    }
+
+    #[test]
+    fn target_decoy_views() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", true);
+        let database = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let tol = Tolerance::Da(-100.0, 100.0);
+
+        let targets = database.targets_only();
+        let decoys = database.decoys_only();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(decoys.len(), 3);
+        assert!(targets
+            .peptides()
+            .all(|(ix, p)| !p.decoy && targets.contains(ix)));
+        assert!(decoys
+            .peptides()
+            .all(|(ix, p)| p.decoy && !targets.contains(ix)));
+
+        let mut scratch = SearchScratch::default();
+        for peptide in &database.peptides {
+            let fragments = IonSeries::new(peptide, Kind::Y)
+                .map(|ion| ion.monoisotopic_mass + PROTON)
+                .collect::<Vec<_>>();
+
+            // Together, both views return the same matches as the full database
+            let all = database.query_mass(peptide.monoisotopic, &fragments, tol, tol);
+            let target = targets.query_mass(peptide.monoisotopic, &fragments, tol, tol);
+            let decoy = decoys
+                .query_mass_with(&mut scratch, peptide.monoisotopic, &fragments, tol, tol)
+                .to_vec();
+            assert!(target.iter().all(|m| !database[m.peptide_index].decoy));
+            assert!(decoy.iter().all(|m| database[m.peptide_index].decoy));
+            assert_eq!(target.len() + decoy.len(), all.len());
+            assert!(all
+                .iter()
+                .filter(|m| !database[m.peptide_index].decoy)
+                .eq(target.iter()));
+        }
+    }
+
+    #[test]
+    fn decoy_bitset() {
+        let fasta = Fasta::parse(
+            ">sp|VAT1\nMSDEREVAEAATGEDASSPPPKTEAASDPQHPAASEGAAAAAASPPLLRCLVLTGFGGYDKVKLQSRPAAPPAPGPGQLTLRLRACGLNFADLMARQGLYDRLPPLPVTPGMEGAGVVIAVGEGVSDRKAGDRVMVLNRSGMWQEEVTVPSVQTFLIPEAMTFEEAAALLVNYITAYMVLFDFGNLQPGHSVLVHMAAGGVGMAAVQLCRTVENVTVFGTASASKHEALKENGVTHPIDYHTTDYVDEIKKISPKGVDIVMDPLGGSDTAKGYNLLKPMGKVVTYGMANLLTGPKRNLMALARTWWNQFSVTALQLLQANRAVCGFHLGYLDGEVELVSGVVARLLALYNQGHIKPHIDSVWPFEKVADAMKQMQEKKNVGKVLLVPGPEKEN".into(),
+            "rev_",
+            true,
+        );
+        let database = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(2),
+                ..Default::default()
+            }),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        // Spans several words of the bitset
+        assert!(database.peptides.len() > 128);
+        assert_eq!(database.decoys.len(), (database.peptides.len() + 63) / 64);
+        for (idx, peptide) in database.peptides.iter().enumerate() {
+            assert_eq!(database.is_decoy(PeptideIx(idx as u32)), peptide.decoy);
+        }
+        assert!(database.peptides.iter().any(|p| p.decoy));
+        assert!(database.peptides.iter().any(|p| !p.decoy));
+    }
+
+    #[test]
+    fn decoy_prefix() {
+        let build = |fasta: &str, generate_decoys: bool| {
+            Builder {
+                fasta: Some("none".into()),
+                decoy_prefix: Some("DECOY_".into()),
+                generate_decoys: Some(generate_decoys),
+                ..Default::default()
+            }
+            .make_parameters()
+            .build(Fasta::parse(fasta.into(), "rev_", generate_decoys))
+        };
+
+        // Internally generated decoys
+        let database = build(">sp|AAAAA\nEQALLKLEQSMR", true);
+        let decoys = database
+            .peptides
+            .iter()
+            .filter(|p| p.decoy)
+            .map(|p| database.proteins(p))
+            .collect::<Vec<_>>();
+        assert!(!decoys.is_empty());
+        assert!(decoys.iter().all(|p| p == "DECOY_sp|AAAAA"));
+
+        // Decoys supplied in the FASTA have `decoy_tag` replaced
+        let database = build(
+            ">sp|AAAAA\nEQALLKLEQSMR\n>rev_sp|AAAAA\nRMSQELKLLAQE",
+            false,
+        );
+        let (decoys, targets): (Vec<_>, Vec<_>) = database.peptides.iter().partition(|p| p.decoy);
+        assert!(!decoys.is_empty());
+        assert!(decoys
+            .iter()
+            .all(|p| database.proteins(p) == "DECOY_sp|AAAAA"));
+        assert!(targets.iter().all(|p| database.proteins(p) == "sp|AAAAA"));
+    }
 }
@@ -15,14 +15,26 @@ pub struct Digest {
     pub decoy: bool,
     /// Semi-enzymatic?
     pub semi_enzymatic: bool,
+    /// Non-enzymatic N-terminus generated by ragged N-terminal digestion
+    /// (e.g. signal peptide removal)
+    pub ragged_nterm: bool,
     /// Cleaved peptide sequence
     pub sequence: String,
     /// Protein accession
     pub protein: Arc<String>,
     /// Missed cleavages
     pub missed_cleavages: u8,
+    /// Number of termini produced by enzymatic cleavage: 2 for fully enzymatic,
+    /// 1 for semi-enzymatic, and 0 for non-specific digests
+    pub enzymatic_termini: u8,
     /// Is this an N-terminal peptide of the protein?
     pub position: Position,
+    /// Index of the first residue of the peptide within the protein (0-based).
+    /// If a peptide occurs multiple times in a protein, this is the first occurrence
+    pub start: usize,
+    /// Index one past the last residue of the peptide within the protein, i.e.
+    /// `protein[start..end]` is the peptide sequence
+    pub end: usize,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
@@ -54,10 +66,14 @@ impl Digest {
         Digest {
             decoy: true,
             semi_enzymatic: self.semi_enzymatic,
+            ragged_nterm: self.ragged_nterm,
             protein: self.protein.clone(),
             sequence: sequence.into_iter().collect(),
             missed_cleavages: self.missed_cleavages,
+            enzymatic_termini: self.enzymatic_termini,
             position: self.position,
+            start: self.start,
+            end: self.end,
         }
     }
 }
@@ -86,6 +102,9 @@ pub struct EnzymeParameters {
     pub min_len: usize,
     /// Inclusive
     pub max_len: usize,
+    /// Generate peptides with non-enzymatic N-termini starting within the
+    /// first N residues of each protein (0 to disable)
+    pub ragged_nterm: usize,
     pub enyzme: Option<Enzyme>,
 }
 
@@ -95,12 +114,75 @@ pub struct Enzyme {
     pub skip_suffix: Option<char>,
     // Regex for matching cleavage sites
     regex: Regex,
+    // Is `regex` a user-defined motif? If so, cleavage sites may overlap, and
+    // the first capture group (if any) defines the cleavage site
+    motif: bool,
     // Cleave at c-terminal?
     pub c_terminal: bool,
     // Semi-enzymatic cleavage?
     pub semi_enzymatic: bool,
 }
 
+/// A named enzyme with a well-established cleavage rule. All presets cleave
+/// C-terminal to the cleavage site
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EnzymePreset {
+    pub name: &'static str,
+    /// Amino acids to cleave after
+    pub cleave_at: &'static str,
+    /// Do not cleave if the cleavage site is followed by this amino acid
+    pub restrict: Option<char>,
+}
+
+/// Built-in enzyme presets, selectable by name, see [`EnzymePreset::find`]
+pub const ENZYME_PRESETS: [EnzymePreset; 5] = [
+    EnzymePreset {
+        name: "trypsin",
+        cleave_at: "KR",
+        restrict: Some('P'),
+    },
+    EnzymePreset {
+        name: "arg-c",
+        cleave_at: "R",
+        restrict: Some('P'),
+    },
+    // Glu-C (V8 protease) in bicarbonate buffer
+    EnzymePreset {
+        name: "glu-c",
+        cleave_at: "E",
+        restrict: None,
+    },
+    // Glu-C (V8 protease) in phosphate buffer also cleaves after aspartate
+    EnzymePreset {
+        name: "glu-c-phosphate",
+        cleave_at: "DE",
+        restrict: None,
+    },
+    EnzymePreset {
+        name: "chymotrypsin",
+        cleave_at: "FYWL",
+        restrict: Some('P'),
+    },
+];
+
+impl EnzymePreset {
+    /// Find a preset by name, ignoring case and separators, i.e. `"Glu-C"`,
+    /// `"glu_c"` and `"GluC"` are equivalent
+    pub fn find(name: &str) -> Option<Self> {
+        let normalize = |name: &str| {
+            name.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect::<String>()
+        };
+        let name = normalize(name);
+        ENZYME_PRESETS
+            .iter()
+            .find(|preset| normalize(preset.name) == name)
+            .copied()
+    }
+}
+
 #[derive(Clone)]
 pub struct DigestSite {
     // Range defining cleavage position
@@ -136,6 +218,7 @@ impl Enzyme {
             "" => None,
             "$" => Some(Enzyme {
                 regex: Regex::new("$").unwrap(),
+                motif: false,
                 skip_suffix: None,
                 // Allowing this to be set to false could cause unexpected behavior
                 c_terminal: true,
@@ -144,6 +227,7 @@ impl Enzyme {
             }),
             _ => Some(Enzyme {
                 regex: Regex::new(&format!("[{}]", cleave.replace('?', ""))).unwrap(),
+                motif: false,
                 skip_suffix,
                 c_terminal,
                 semi_enzymatic,
@@ -151,14 +235,68 @@ impl Enzyme {
         }
     }
 
+    /// Create an enzyme cleaving at a user-defined regex `motif`, e.g. `M` for
+    /// CNBr or `(D)P` for cleavage between aspartate and proline. If the motif
+    /// contains a capture group, cleavage occurs C-terminal (or N-terminal) to
+    /// the first group, rather than to the whole match. Returns an error if
+    /// `motif` is not a valid regex
+    pub fn motif(
+        motif: &str,
+        skip_suffix: Option<char>,
+        c_terminal: bool,
+        semi_enzymatic: bool,
+    ) -> Result<Self, regex::Error> {
+        Ok(Enzyme {
+            regex: Regex::new(motif)?,
+            motif: true,
+            skip_suffix,
+            c_terminal,
+            semi_enzymatic,
+        })
+    }
+
+    /// Cleavage positions of a user-defined motif. Unlike single residue
+    /// cleavage rules, motif matches may overlap (e.g. `KK` in `KKK`)
+    fn motif_cuts(&self, sequence: &str) -> Vec<usize> {
+        let mut cuts = Vec::new();
+        let mut start = 0;
+        while let Some(caps) = self.regex.captures_at(sequence, start) {
+            let whole = caps.get(0).expect("capture group 0 is always present");
+            let site = caps.get(1).unwrap_or(whole);
+            cuts.push(match self.c_terminal {
+                true => site.end(),
+                false => site.start(),
+            });
+            start = whole.start() + 1;
+            if start > sequence.len() {
+                break;
+            }
+        }
+        cuts.sort_unstable();
+        cuts.dedup();
+        cuts
+    }
+
     pub fn cleavage_sites(&self, sequence: &str) -> Vec<DigestSite> {
+        // Single residue rules (e.g. `[KR]` for trypsin) only need `find_iter`,
+        // which the regex crate accelerates with a vectorized byte search - this
+        // is faster than scanning residues by hand. Only user-defined motifs pay
+        // for capture groups and overlapping matches
+        let cuts = match self.motif {
+            true => self.motif_cuts(sequence),
+            false => self
+                .regex
+                .find_iter(sequence)
+                .map(|mat| match self.c_terminal {
+                    true => mat.end(),
+                    false => mat.start(),
+                })
+                .collect(),
+        };
+
         let mut sites = Vec::new();
         let mut left = 0;
-        for mat in self.regex.find_iter(sequence) {
-            let right = match self.c_terminal {
-                true => mat.end(),
-                false => mat.start(),
-            };
+        for right in cuts {
             if let Some(skip) = self.skip_suffix {
                 if right < sequence.len() && sequence[right..].starts_with(skip) {
                     continue;
@@ -181,6 +319,15 @@ impl Enzyme {
 }
 
 impl EnzymeParameters {
+    /// Number of peptides generated by a non-specific digest of a protein with
+    /// `len` residues, i.e. all substrings with a length within `min_len..=max_len`
+    /// (before removing duplicate sequences)
+    pub fn nonspecific_digest_count(&self, len: usize) -> usize {
+        (self.min_len.max(1)..=self.max_len)
+            .map(|window| (len + 1).saturating_sub(window))
+            .sum()
+    }
+
     pub fn cleavage_sites(&self, sequence: &str) -> Vec<DigestSite> {
         match &self.enyzme {
             Some(enzyme) => enzyme.cleavage_sites(sequence),
@@ -230,6 +377,16 @@ impl EnzymeParameters {
         }
     }
 
+    /// Number of enzymatic termini of a digest produced at a (semi-enzymatic or
+    /// fully enzymatic) cleavage site
+    fn enzymatic_termini(&self, semi_enzymatic: bool) -> u8 {
+        match (&self.enyzme, semi_enzymatic) {
+            (None, _) => 0,
+            (Some(_), true) => 1,
+            (Some(_), false) => 2,
+        }
+    }
+
     fn semi_enzymatic_sites(&self, sites: &mut Vec<DigestSite>) -> Vec<DigestSite> {
         let mut semi_enzymatic_sites = Vec::new();
         for site in sites.iter_mut() {
@@ -302,13 +459,49 @@ impl EnzymeParameters {
                 digests.push(Digest {
                     sequence: sequence.into(),
                     missed_cleavages: site.missed_cleavages,
+                    enzymatic_termini: self.enzymatic_termini(site.semi_enzymatic),
                     decoy: false,
                     semi_enzymatic: site.semi_enzymatic,
+                    ragged_nterm: false,
                     position,
+                    start,
+                    end,
                     protein: protein.clone(),
                 });
             }
         }
+
+        // Semi-enzymatic digests already contain all N-terminally ragged peptides
+        if self.ragged_nterm > 0 && self.enyzme.is_some() && !self.is_semi_enzymatic() {
+            for site in sites.iter() {
+                let end = site.site.end;
+                // Non-enzymatic N-termini within the first `ragged_nterm` residues
+                for start in site.site.start + 1..self.ragged_nterm.min(end) {
+                    let sequence = match sequence.get(start..end) {
+                        Some(sequence) => sequence,
+                        None => continue,
+                    };
+                    let len = sequence.len();
+                    if len >= self.min_len && len <= self.max_len && seen.insert(sequence) {
+                        digests.push(Digest {
+                            sequence: sequence.into(),
+                            missed_cleavages: site.missed_cleavages,
+                            enzymatic_termini: self.enzymatic_termini(true),
+                            decoy: false,
+                            semi_enzymatic: true,
+                            ragged_nterm: true,
+                            position: match end == n {
+                                true => Position::Cterm,
+                                false => Position::Internal,
+                            },
+                            start,
+                            end,
+                            protein: protein.clone(),
+                        });
+                    }
+                }
+            }
+        }
         digests
     }
 }
@@ -326,17 +519,25 @@ mod test {
             Digest {
                 decoy: false,
                 semi_enzymatic: false,
+                ragged_nterm: false,
                 sequence: "MADEEK".into(),
                 missed_cleavages: 0,
+                enzymatic_termini: 2,
                 position: Position::Nterm,
+                start: 0,
+                end: 6,
                 protein: Arc::new(String::default()),
             },
             Digest {
                 decoy: false,
                 semi_enzymatic: false,
+                ragged_nterm: false,
                 sequence: "MADEEK".into(),
                 missed_cleavages: 0,
+                enzymatic_termini: 2,
                 position: Position::Nterm,
+                start: 0,
+                end: 6,
                 protein: Arc::new(String::default()),
             },
         ];
@@ -349,17 +550,25 @@ mod test {
             Digest {
                 decoy: false,
                 semi_enzymatic: false,
+                ragged_nterm: false,
                 sequence: "MADEEK".into(),
                 missed_cleavages: 0,
+                enzymatic_termini: 2,
                 position: Position::Nterm,
+                start: 0,
+                end: 6,
                 protein: Arc::new(String::default()),
             },
             Digest {
                 decoy: false,
                 semi_enzymatic: false,
+                ragged_nterm: false,
                 sequence: "MADEEK".into(),
                 missed_cleavages: 0,
+                enzymatic_termini: 2,
                 position: Position::Internal,
+                start: 0,
+                end: 6,
                 protein: Arc::new(String::default()),
             },
         ];
@@ -384,6 +593,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -396,6 +606,88 @@ mod test {
         );
     }
 
+    #[test]
+    fn enzyme_presets() {
+        // Human serum albumin (P02768), residues 25-94
+        let sequence = "DAHKSEVAHRFKDLGEENFKALVLIAFAQYLQQCPFEDHVKLVNEVTEFAKTCVADESAENCDKSLHTLF";
+        let digest = |name: &str| {
+            let preset = EnzymePreset::find(name).unwrap();
+            EnzymeParameters {
+                min_len: 1,
+                max_len: 100,
+                missed_cleavages: 0,
+                ragged_nterm: 0,
+                enyzme: Enzyme::new(preset.cleave_at, preset.restrict, true, false),
+            }
+            .digest(sequence, Arc::default())
+            .into_iter()
+            .map(|d| d.sequence)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            digest("Arg-C"),
+            vec![
+                "DAHKSEVAHR",
+                "FKDLGEENFKALVLIAFAQYLQQCPFEDHVKLVNEVTEFAKTCVADESAENCDKSLHTLF"
+            ]
+        );
+        assert_eq!(
+            digest("glu-c"),
+            vec![
+                "DAHKSE",
+                "VAHRFKDLGE",
+                "E",
+                "NFKALVLIAFAQYLQQCPFE",
+                "DHVKLVNE",
+                "VTE",
+                "FAKTCVADE",
+                "SAE",
+                "NCDKSLHTLF"
+            ]
+        );
+        // Repeated peptides ("D", "E") are only reported once
+        assert_eq!(
+            digest("glu_c_phosphate"),
+            vec![
+                "D",
+                "AHKSE",
+                "VAHRFKD",
+                "LGE",
+                "E",
+                "NFKALVLIAFAQYLQQCPFE",
+                "HVKLVNE",
+                "VTE",
+                "FAKTCVAD",
+                "SAE",
+                "NCD",
+                "KSLHTLF"
+            ]
+        );
+        // No cleavage between F and P
+        assert_eq!(
+            digest("chymotrypsin"),
+            vec![
+                "DAHKSEVAHRF",
+                "KDL",
+                "GEENF",
+                "KAL",
+                "VL",
+                "IAF",
+                "AQY",
+                "L",
+                "QQCPF",
+                "EDHVKL",
+                "VNEVTEF",
+                "AKTCVADESAENCDKSL",
+                "HTL",
+                "F"
+            ]
+        );
+        assert_eq!(EnzymePreset::find("Trypsin"), Some(ENZYME_PRESETS[0]));
+        assert_eq!(EnzymePreset::find("pepsin"), None);
+    }
+
     #[test]
     fn trypsin_missed_cleavage() {
         let sequence = "MADEEKLPPGWEKRMSRSSGRVYYFNHITNASQWERPSGN";
@@ -417,6 +709,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 1,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -454,6 +747,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 2,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -482,6 +776,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", None, true, false),
         };
 
@@ -503,6 +798,7 @@ mod test {
             min_len: 1,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("D", None, false, false),
         };
 
@@ -532,6 +828,7 @@ mod test {
             min_len: 1,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("FYWL", None, true, false),
         };
 
@@ -558,6 +855,7 @@ mod test {
             min_len: 5,
             max_len: 5,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: None,
         };
 
@@ -570,6 +868,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn nonspecific_digest_count() {
+        let params = EnzymeParameters {
+            min_len: 5,
+            max_len: 7,
+            missed_cleavages: 0,
+            ragged_nterm: 0,
+            enyzme: None,
+        };
+        let sequence = "MADEEKLPPGWEKRMSRSSGRVYYFNHITNASQWERPSGNW";
+        assert_eq!(
+            params.nonspecific_digest_count(sequence.len()),
+            params.cleavage_sites(sequence).len()
+        );
+        assert_eq!(params.nonspecific_digest_count(6), 2 + 1);
+        assert_eq!(params.nonspecific_digest_count(4), 0);
+    }
+
     #[test]
     fn nonspecific_digest_5_7() {
         let sequence = "MADEEKLPPGWEKRMSRSSGRVYYFNHITNASQWERPSGNW";
@@ -587,6 +903,7 @@ mod test {
             min_len: 5,
             max_len: 7,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("", None, true, false),
         };
 
@@ -608,6 +925,7 @@ mod test {
             min_len: 0,
             max_len: usize::MAX,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("$", None, true, false),
         };
 
@@ -629,6 +947,7 @@ mod test {
             min_len: 2,
             max_len: usize::MAX,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", None, true, false),
         };
 
@@ -652,6 +971,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
@@ -702,6 +1022,7 @@ mod test {
             min_len: 3,
             max_len: 50,
             missed_cleavages: 1,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
@@ -732,6 +1053,33 @@ mod test {
         }
     }
 
+    #[test]
+    fn enzymatic_termini() {
+        let sequence = "MADEEKLPPGWEK";
+        let mut tryp = EnzymeParameters {
+            min_len: 3,
+            max_len: 50,
+            missed_cleavages: 1,
+            ragged_nterm: 0,
+            enyzme: Enzyme::new("KR", None, true, true),
+        };
+
+        for digest in tryp.digest(sequence, Arc::default()) {
+            let expected = match digest.sequence.as_str() {
+                "MADEEK" | "LPPGWEK" | "MADEEKLPPGWEK" => 2,
+                _ => 1,
+            };
+            assert_eq!(digest.enzymatic_termini, expected, "{}", digest.sequence);
+        }
+
+        tryp.enyzme = None;
+        tryp.max_len = 5;
+        assert!(tryp
+            .digest(sequence, Arc::default())
+            .iter()
+            .all(|digest| digest.enzymatic_termini == 0));
+    }
+
     /// Helper struct for generation of random sequences of valid amino acids
     #[derive(Clone, Debug)]
     struct RandomSequence {
@@ -758,6 +1106,7 @@ mod test {
             min_len: 3,
             max_len: 50,
             missed_cleavages: 2,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
@@ -780,4 +1129,157 @@ mod test {
             assert!(digest.missed_cleavages <= 2);
         }
     }
+
+    #[test]
+    fn ragged_nterm() {
+        let sequence = "MSLLKPEPTIDEKVLAR";
+        let tryp = EnzymeParameters {
+            min_len: 3,
+            max_len: 50,
+            missed_cleavages: 0,
+            ragged_nterm: 4,
+            enyzme: Enzyme::new("KR", Some('P'), true, false),
+        };
+
+        let expected = vec![
+            ("MSLLKPEPTIDEK".into(), Position::Nterm, false),
+            ("VLAR".into(), Position::Cterm, false),
+            ("SLLKPEPTIDEK".into(), Position::Internal, true),
+            ("LLKPEPTIDEK".into(), Position::Internal, true),
+            ("LKPEPTIDEK".into(), Position::Internal, true),
+        ];
+
+        let digests = tryp.digest(sequence, Arc::default());
+        assert!(digests.iter().all(|d| d.ragged_nterm == d.semi_enzymatic));
+        assert_eq!(
+            digests
+                .into_iter()
+                .map(|d| (d.sequence, d.position, d.ragged_nterm))
+                .collect::<Vec<(String, _, _)>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn digest_coordinates() {
+        let sequence = "MSLLKPEPTIDEKVLARGGAAK";
+        let tryp = EnzymeParameters {
+            min_len: 3,
+            max_len: 50,
+            missed_cleavages: 1,
+            ragged_nterm: 0,
+            enyzme: Enzyme::new("KR", Some('P'), true, false),
+        };
+
+        let mut coordinates = tryp
+            .digest(sequence, Arc::default())
+            .into_iter()
+            .map(|d| (d.start, d.end, d.sequence))
+            .collect::<Vec<(_, _, String)>>();
+        coordinates.sort();
+        assert_eq!(
+            coordinates,
+            vec![
+                (0, 13, "MSLLKPEPTIDEK".into()),
+                (0, 17, "MSLLKPEPTIDEKVLAR".into()),
+                (13, 17, "VLAR".into()),
+                (13, 22, "VLARGGAAK".into()),
+                (17, 22, "GGAAK".into()),
+            ]
+        );
+
+        // Semi-enzymatic, ragged and non-specific digests
+        for enzyme in [
+            Enzyme::new("KR", Some('P'), true, false),
+            Enzyme::new("KR", Some('P'), true, true),
+            Enzyme::new("", None, true, false),
+        ] {
+            let params = EnzymeParameters {
+                min_len: 3,
+                max_len: 8,
+                missed_cleavages: 0,
+                ragged_nterm: 4,
+                enyzme: enzyme,
+            };
+            for digest in params.digest(sequence, Arc::default()) {
+                assert_eq!(&sequence[digest.start..digest.end], digest.sequence);
+                let reversed = digest.reverse();
+                assert_eq!((reversed.start, reversed.end), (digest.start, digest.end));
+            }
+        }
+    }
+
+    #[test]
+    fn residue_rules_match_motifs() {
+        // Built-in single residue rules skip the motif engine, but must cleave
+        // at exactly the same sites as the equivalent motif
+        let sequences = ["MKPRPEPTIDEKKRAAPK", "KRKRP", "PEPTIDE", "RPEPKPDEKR", ""];
+        for (cleave, skip, c_terminal) in [
+            ("KR", Some('P'), true),
+            ("K", None, true),
+            ("R", Some('P'), true),
+            ("DE", None, false),
+            ("FWYL", Some('P'), true),
+        ] {
+            let builtin = Enzyme::new(cleave, skip, c_terminal, false).unwrap();
+            let motif = Enzyme::motif(&format!("[{}]", cleave), skip, c_terminal, false).unwrap();
+            for sequence in sequences {
+                let sites = |enzyme: &Enzyme| {
+                    enzyme
+                        .cleavage_sites(sequence)
+                        .into_iter()
+                        .map(|s| s.site)
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(sites(&builtin), sites(&motif), "{} {}", cleave, sequence);
+            }
+        }
+    }
+
+    #[test]
+    fn motif_digest() {
+        let digest = |enzyme: Enzyme, sequence: &str| {
+            EnzymeParameters {
+                min_len: 1,
+                max_len: 50,
+                missed_cleavages: 0,
+                ragged_nterm: 0,
+                enyzme: Some(enzyme),
+            }
+            .digest(sequence, Arc::default())
+            .into_iter()
+            .map(|d| d.sequence)
+            .collect::<Vec<_>>()
+        };
+
+        // CNBr, C-terminal to methionine
+        assert_eq!(
+            digest(Enzyme::motif("M", None, true, false).unwrap(), "AMGKMPR"),
+            vec!["AM", "GKM", "PR"]
+        );
+
+        // Acid cleavage between D and P, using a capture group for the site
+        assert_eq!(
+            digest(
+                Enzyme::motif("(D)P", None, true, false).unwrap(),
+                "AADPKDDPDA"
+            ),
+            vec!["AAD", "PKDD", "PDA"]
+        );
+        assert_eq!(
+            digest(
+                Enzyme::motif("D(P)", None, false, false).unwrap(),
+                "AADPKDDPDA"
+            ),
+            vec!["AAD", "PKDD", "PDA"]
+        );
+
+        // Overlapping motif matches are all cleaved
+        assert_eq!(
+            digest(Enzyme::motif("(K)K", None, true, false).unwrap(), "AKKKR"),
+            vec!["AK", "K", "KR"]
+        );
+
+        assert!(Enzyme::motif("(D", None, true, false).is_err());
+    }
 }
@@ -1,4 +1,5 @@
 use crate::enzyme::{Digest, EnzymeParameters};
+use fnv::{FnvHashMap, FnvHashSet};
 use rayon::prelude::*;
 use std::sync::Arc;
 
@@ -13,6 +14,10 @@ pub struct Fasta {
 
 impl Fasta {
     // Parse a string into a fasta database
+    //
+    // Entries that are exact duplicates of a previous entry (same accession and
+    // sequence) are skipped. Identical sequences under different accessions are
+    // kept - peptides shared between them are assigned to all accessions
     pub fn parse<S: Into<String>>(contents: String, decoy_tag: S, generate_decoys: bool) -> Fasta {
         let decoy_tag = decoy_tag.into();
 
@@ -48,11 +53,67 @@ impl Fasta {
             }
         }
 
-        Fasta {
+        let mut seen = FnvHashSet::default();
+        let total = targets.len();
+        targets.retain(|(acc, seq)| seen.insert((acc.clone(), seq.clone())));
+        if targets.len() < total {
+            log::warn!(
+                "skipped {} duplicate FASTA entries with identical accessions and sequences",
+                total - targets.len()
+            );
+        }
+
+        let fasta = Fasta {
             targets,
             decoy_tag,
             generate_decoys,
+        };
+
+        let duplicates = fasta.duplicate_sequences();
+        if !duplicates.is_empty() {
+            log::info!(
+                "{} FASTA sequences are shared by multiple accessions, e.g. {}",
+                duplicates.len(),
+                duplicates[0]
+                    .iter()
+                    .map(|acc| acc.as_str())
+                    .collect::<Vec<_>>()
+                    .join(";")
+            );
+        }
+        let mut accessions = FnvHashSet::default();
+        let reused = fasta
+            .targets
+            .iter()
+            .filter(|(acc, _)| !accessions.insert(acc.clone()))
+            .count();
+        if reused > 0 {
+            log::warn!(
+                "{} FASTA accessions are used for multiple different sequences",
+                reused
+            );
+        }
+
+        fasta
+    }
+
+    /// Groups of accessions (in FASTA order) that have identical protein
+    /// sequences
+    pub fn duplicate_sequences(&self) -> Vec<Vec<Arc<String>>> {
+        let mut groups: FnvHashMap<&str, Vec<Arc<String>>> = FnvHashMap::default();
+        let mut order = Vec::new();
+        for (acc, seq) in &self.targets {
+            let group = groups.entry(seq.as_str()).or_default();
+            if group.is_empty() {
+                order.push(seq.as_str());
+            }
+            group.push(acc.clone());
         }
+        order
+            .into_iter()
+            .filter_map(|seq| groups.remove(seq))
+            .filter(|group| group.len() > 1)
+            .collect()
     }
 
     pub fn digest(&self, enzyme: &EnzymeParameters) -> Vec<Digest> {
@@ -10,6 +10,7 @@ use crate::ml::kde::Estimator;
 use crate::scoring::Feature;
 use fnv::FnvHashMap;
 use rayon::prelude::*;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::hash::BuildHasher;
 
@@ -155,9 +156,9 @@ pub fn picked_peptide(db: &IndexedDatabase, features: &mut [Feature]) -> usize {
 pub fn picked_protein(db: &IndexedDatabase, features: &mut [Feature]) -> usize {
     let mut map: FnvHashMap<_, Competition<String>> = FnvHashMap::default();
     for feat in features.iter() {
-        let decoy = db[feat.peptide_idx].decoy;
+        let decoy = db.is_decoy(feat.peptide_idx);
         let entry = map.entry(&db[feat.peptide_idx].proteins).or_default();
-        let proteins = db[feat.peptide_idx].proteins(&db.decoy_tag, db.generate_decoys);
+        let proteins = db.proteins(&db[feat.peptide_idx]);
         match decoy {
             true => {
                 entry.reverse = entry.reverse.max(feat.discriminant_score);
@@ -173,13 +174,109 @@ pub fn picked_protein(db: &IndexedDatabase, features: &mut [Feature]) -> usize {
     let (scores, passing) = Competition::assign_q_value(map, 0.01);
 
     features.par_iter_mut().for_each(|feat| {
-        let proteins = db[feat.peptide_idx].proteins(&db.decoy_tag, db.generate_decoys);
+        let proteins = db.proteins(&db[feat.peptide_idx]);
         feat.protein_q = scores[&proteins];
     });
 
     passing
 }
 
+/// Entrapment identifications passing an FDR threshold, used to validate
+/// decoy-based FDR estimates. Entrapment proteins (e.g. from an unrelated
+/// organism) are tagged with `entrapment_tag`, see [`IndexedDatabase::is_entrapment`],
+/// and any target identification of an entrapment peptide is a false discovery
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Entrapment {
+    /// Passing target identifications from the original database
+    pub targets: usize,
+    /// Passing target identifications from entrapment proteins
+    pub entrapment: usize,
+    /// Number of entrapment target peptides per original target peptide in the database
+    pub ratio: f64,
+}
+
+impl Entrapment {
+    /// Count rank 1 target PSMs with a spectrum-level q-value at or below `threshold`
+    pub fn psms(db: &IndexedDatabase, features: &[Feature], threshold: f32) -> Self {
+        let passing = features
+            .iter()
+            .filter(|feat| feat.rank == 1 && feat.spectrum_q <= threshold)
+            .map(|feat| feat.peptide_idx);
+        Self::count(db, passing)
+    }
+
+    /// Count unique target peptides with a peptide-level q-value at or below `threshold`
+    pub fn peptides(db: &IndexedDatabase, features: &[Feature], threshold: f32) -> Self {
+        let mut passing = features
+            .iter()
+            .filter(|feat| feat.peptide_q <= threshold)
+            .map(|feat| feat.peptide_idx)
+            .collect::<Vec<_>>();
+        passing.sort_unstable();
+        passing.dedup();
+        Self::count(db, passing)
+    }
+
+    fn count<I: IntoIterator<Item = PeptideIx>>(db: &IndexedDatabase, passing: I) -> Self {
+        let (targets, entrapment) = db.peptides.iter().filter(|peptide| !peptide.decoy).fold(
+            (0usize, 0usize),
+            |(t, e), peptide| match db.is_entrapment(peptide) {
+                true => (t, e + 1),
+                false => (t + 1, e),
+            },
+        );
+        let mut counts = Entrapment {
+            ratio: match targets {
+                0 => 0.0,
+                _ => entrapment as f64 / targets as f64,
+            },
+            ..Default::default()
+        };
+        for ix in passing {
+            let peptide = &db[ix];
+            if peptide.decoy {
+                continue;
+            }
+            match db.is_entrapment(peptide) {
+                true => counts.entrapment += 1,
+                false => counts.targets += 1,
+            }
+        }
+        counts
+    }
+
+    /// Fraction of passing target identifications that are entrapment
+    pub fn identification_rate(&self) -> f64 {
+        match self.targets + self.entrapment {
+            0 => 0.0,
+            n => self.entrapment as f64 / n as f64,
+        }
+    }
+
+    /// Combined entrapment estimate of the false discovery proportion,
+    /// `entrapment * (1 + 1/ratio) / (targets + entrapment)`, which accounts
+    /// for false discoveries among the original targets that go unnoticed
+    pub fn estimated_fdp(&self) -> f64 {
+        if self.entrapment == 0 || self.ratio <= 0.0 {
+            return 0.0;
+        }
+        (self.identification_rate() * (1.0 + 1.0 / self.ratio)).min(1.0)
+    }
+}
+
+impl std::fmt::Display for Entrapment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entrapment of {} targets ({:.2}%), estimated FDP {:.2}%",
+            self.entrapment,
+            self.targets + self.entrapment,
+            self.identification_rate() * 100.0,
+            self.estimated_fdp() * 100.0
+        )
+    }
+}
+
 pub fn picked_precursor(
     peaks: &mut FnvHashMap<(PrecursorId, bool), (crate::lfq::Peak, Vec<f64>)>,
 ) -> usize {
@@ -240,3 +337,83 @@ pub fn picked_precursor(
     });
     passing
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+
+    #[test]
+    fn entrapment() {
+        let fasta = Fasta::parse(
+            ">sp|TARGET\nEQALLKLEQSMRAQLTQLKGGGGRMAGICK\n\
+             >entrap_sp|OTHER\nVLHSGCRMEWKLEQSMRLESLIEK"
+                .into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            entrapment_tag: Some("entrap_".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let find = |sequence: &[u8], decoy: bool| {
+            let ix = db
+                .peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence && p.decoy == decoy)
+                .unwrap_or_else(|| panic!("{}", String::from_utf8_lossy(sequence)));
+            PeptideIx(ix as u32)
+        };
+        assert!(!db.is_entrapment(&db[find(b"AQLTQLK", false)]));
+        // Shared between an original and an entrapment protein
+        assert!(!db.is_entrapment(&db[find(b"LEQSMR", false)]));
+        assert!(db.is_entrapment(&db[find(b"LESLIEK", false)]));
+        // Decoys of entrapment proteins are entrapment decoys
+        let decoy = db
+            .peptides
+            .iter()
+            .position(|p| p.decoy && db.is_entrapment(p))
+            .map(|ix| PeptideIx(ix as u32))
+            .unwrap();
+
+        let feature = |ix, q| Feature {
+            peptide_idx: ix,
+            spectrum_q: q,
+            peptide_q: q,
+            rank: 1,
+            ..Default::default()
+        };
+        let features = vec![
+            feature(find(b"AQLTQLK", false), 0.001),
+            feature(find(b"AQLTQLK", false), 0.001),
+            feature(find(b"LEQSMR", false), 0.005),
+            feature(find(b"LESLIEK", false), 0.008),
+            feature(decoy, 0.009),
+            feature(find(b"VLHSGCR", false), 0.5),
+            // Lower ranked PSMs are not counted
+            Feature {
+                rank: 2,
+                ..feature(find(b"LESLIEK", false), 0.001)
+            },
+        ];
+
+        let psms = Entrapment::psms(&db, &features, 0.01);
+        assert_eq!((psms.targets, psms.entrapment), (3, 1));
+        assert!((psms.identification_rate() - 0.25).abs() < 1E-9);
+        assert!(psms.ratio > 0.0);
+        assert!(psms.estimated_fdp() > psms.identification_rate());
+
+        let peptides = Entrapment::peptides(&db, &features, 0.01);
+        assert_eq!((peptides.targets, peptides.entrapment), (2, 1));
+        assert_eq!(peptides.ratio, psms.ratio);
+
+        let none = Entrapment::psms(&db, &features, 0.0);
+        assert_eq!(none.identification_rate(), 0.0);
+        assert_eq!(none.estimated_fdp(), 0.0);
+    }
+}
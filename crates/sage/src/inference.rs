@@ -0,0 +1,181 @@
+//! Parsimonious protein inference
+//!
+//! Proteins with identical sets of identified peptides are indistinguishable,
+//! and are combined into protein groups. The minimal set of groups explaining
+//! all identified peptides is then approximated by greedy set cover: the group
+//! explaining the most unexplained peptides is selected, until every peptide
+//! is explained. Each peptide is assigned to the first selected group
+//! containing it (i.e. as a "razor" peptide)
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::scoring::Feature;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A group of indistinguishable proteins in the minimal protein set
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProteinGroup {
+    /// Protein accessions, sorted
+    pub proteins: Vec<Arc<String>>,
+    /// All identified peptides mapping to these proteins, sorted
+    pub peptides: Vec<PeptideIx>,
+    /// Peptides assigned to this group by set cover, i.e. those that were not
+    /// already explained by a previously selected group, sorted
+    pub assigned: Vec<PeptideIx>,
+}
+
+/// Infer the minimal set of protein groups explaining all target peptides of
+/// `features` with a peptide-level q-value of at most `max_q`.
+///
+/// Groups are returned in the order they were selected, so groups explaining
+/// the most peptides come first. Ties are broken by the total number of
+/// peptides, and then by accession, so the result is deterministic
+pub fn parsimony(db: &IndexedDatabase, features: &[Feature], max_q: f32) -> Vec<ProteinGroup> {
+    let peptides = features
+        .iter()
+        .filter(|feat| feat.label == 1 && feat.peptide_q <= max_q)
+        .map(|feat| feat.peptide_idx)
+        .collect::<FnvHashSet<_>>();
+
+    let mut protein_peptides: FnvHashMap<&Arc<String>, Vec<PeptideIx>> = FnvHashMap::default();
+    for &ix in &peptides {
+        for protein in &db[ix].proteins {
+            protein_peptides.entry(protein).or_default().push(ix);
+        }
+    }
+
+    // Combine proteins with identical peptide sets
+    let mut groups: BTreeMap<Vec<PeptideIx>, Vec<Arc<String>>> = BTreeMap::new();
+    for (protein, mut peptides) in protein_peptides {
+        peptides.sort_unstable();
+        groups.entry(peptides).or_default().push(protein.clone());
+    }
+    let mut groups = groups
+        .into_iter()
+        .map(|(peptides, mut proteins)| {
+            proteins.sort_unstable();
+            ProteinGroup {
+                proteins,
+                peptides,
+                assigned: Vec::new(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut explained = FnvHashSet::default();
+    let mut selected = Vec::new();
+    while explained.len() < peptides.len() {
+        let unexplained = |group: &ProteinGroup| {
+            group
+                .peptides
+                .iter()
+                .filter(|ix| !explained.contains(*ix))
+                .count()
+        };
+        let best = groups
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| {
+                unexplained(a)
+                    .cmp(&unexplained(b))
+                    .then_with(|| a.peptides.len().cmp(&b.peptides.len()))
+                    .then_with(|| b.proteins.cmp(&a.proteins))
+            })
+            .map(|(idx, _)| idx);
+
+        let mut group = match best {
+            Some(idx) => groups.swap_remove(idx),
+            None => break,
+        };
+        group.assigned = group
+            .peptides
+            .iter()
+            .copied()
+            .filter(|ix| explained.insert(*ix))
+            .collect();
+        selected.push(group);
+    }
+    selected
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+
+    #[test]
+    fn greedy_set_cover() {
+        let fasta = Fasta::parse(
+            ">sp|A\nLESLIEKAQLTQLKVLHSGPR\n\
+             >sp|B\nVLHSGPRGGAAVLDMR\n\
+             >sp|C\nGGAAVLDMR\n\
+             >sp|D\nVLHSGPRLESLIEKAQLTQLK\n\
+             >sp|E\nAQLTQLK\n\
+             >sp|F\nELVISLIK"
+                .into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            generate_decoys: Some(false),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let ix = |sequence: &str| {
+            db.peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence.as_bytes())
+                .map(|ix| PeptideIx(ix as u32))
+                .unwrap()
+        };
+        let psm = |sequence: &str, peptide_q: f32| Feature {
+            peptide_idx: ix(sequence),
+            label: 1,
+            peptide_q,
+            ..Default::default()
+        };
+        let features = vec![
+            psm("LESLIEK", 0.0),
+            psm("AQLTQLK", 0.0),
+            psm("VLHSGPR", 0.0),
+            psm("VLHSGPR", 0.0),
+            psm("GGAAVLDMR", 0.005),
+            // Not confidently identified
+            psm("ELVISLIK", 0.5),
+        ];
+
+        let groups = parsimony(&db, &features, 0.01);
+        let accessions = |group: &ProteinGroup| {
+            group
+                .proteins
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+        };
+        let sorted = |mut ixs: Vec<PeptideIx>| {
+            ixs.sort_unstable();
+            ixs
+        };
+
+        // A and D are indistinguishable, and explain 3 peptides. B explains
+        // the remaining peptide, while C and E are subsumed
+        assert_eq!(groups.len(), 2);
+        assert_eq!(accessions(&groups[0]), vec!["sp|A", "sp|D"]);
+        assert_eq!(
+            groups[0].assigned,
+            sorted(vec![ix("LESLIEK"), ix("AQLTQLK"), ix("VLHSGPR")])
+        );
+        assert_eq!(accessions(&groups[1]), vec!["sp|B"]);
+        assert_eq!(
+            groups[1].peptides,
+            sorted(vec![ix("VLHSGPR"), ix("GGAAVLDMR")])
+        );
+        assert_eq!(groups[1].assigned, vec![ix("GGAAVLDMR")]);
+
+        assert!(parsimony(&db, &features, -1.0).is_empty());
+    }
+}
@@ -14,6 +14,61 @@ pub enum Kind {
     Z,
 }
 
+/// Relative weight of each ion kind during scoring, e.g. to emphasize y-ions
+/// for HCD or c/z-ions for ETD spectra. Unspecified kinds default to 1.0
+///
+/// `position` additionally up-weights fragments from cleavages near the middle
+/// of the peptide, which tend to be more abundant than those near the termini.
+/// A fragment from a central cleavage is weighted by `1 + position`, and the
+/// bonus decreases linearly towards either terminus. The default of 0.0 gives
+/// uniform weights
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IonWeights {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub position: f32,
+}
+
+impl Default for IonWeights {
+    fn default() -> Self {
+        Self {
+            a: 1.0,
+            b: 1.0,
+            c: 1.0,
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+            position: 0.0,
+        }
+    }
+}
+
+impl IonWeights {
+    pub fn weight(&self, kind: Kind) -> f32 {
+        match kind {
+            Kind::A => self.a,
+            Kind::B => self.b,
+            Kind::C => self.c,
+            Kind::X => self.x,
+            Kind::Y => self.y,
+            Kind::Z => self.z,
+        }
+    }
+
+    /// Weight of a fragment of `kind`, generated by cleavage after residue
+    /// `idx` (as enumerated by [`IonSeries`]) of a peptide with `len` residues
+    pub fn fragment_weight(&self, kind: Kind, idx: usize, len: usize) -> f32 {
+        // Relative position of the cleavage site, 0 at either terminus, 1 in the middle
+        let centrality = 1.0 - ((2 * (idx + 1)) as f32 / len as f32 - 1.0).abs();
+        self.weight(kind) * (1.0 + self.position * centrality)
+    }
+}
+
 /// Theoretical B/Y ion
 #[derive(Copy, Clone, Debug)]
 pub struct Ion {
@@ -144,6 +199,29 @@ mod test {
         check_within(ions!(&peptide, Kind::Z, 1.0), &expected_z);
     }
 
+    #[test]
+    fn positional_weights() {
+        let uniform = IonWeights::default();
+        assert!((0..9).all(|idx| uniform.fragment_weight(Kind::B, idx, 10) == 1.0));
+
+        let weights = IonWeights {
+            y: 2.0,
+            position: 0.5,
+            ..Default::default()
+        };
+        // Central cleavage of a 10 residue peptide
+        assert!((weights.fragment_weight(Kind::B, 4, 10) - 1.5).abs() < 1E-6);
+        assert!((weights.fragment_weight(Kind::Y, 4, 10) - 3.0).abs() < 1E-6);
+        // Terminal cleavages are only weighted by ion kind
+        assert!((weights.fragment_weight(Kind::B, 0, 10) - 1.1).abs() < 1E-6);
+        assert!((weights.fragment_weight(Kind::Y, 8, 10) - 2.2).abs() < 1E-6);
+        // Symmetric around the middle of the peptide
+        assert_eq!(
+            weights.fragment_weight(Kind::B, 2, 10),
+            weights.fragment_weight(Kind::B, 6, 10)
+        );
+    }
+
     #[test]
     fn iterate_b_ions() {
         let peptide = peptide("PEPTIDE");
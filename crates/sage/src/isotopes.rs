@@ -49,9 +49,127 @@ pub fn peptide_isotopes(carbons: u16, sulfurs: u16) -> [f32; 3] {
     [c[0], c[1], c[2]]
 }
 
+/// Estimate the index of the most abundant isotope in the precursor isotopic
+/// envelope of a peptide with (neutral) monoisotopic `mass`, using the averagine
+/// model. For large peptides, the instrument may select this peak rather than
+/// the monoisotopic peak, resulting in a +n isotope error that grows with mass
+pub fn most_abundant_isotope(mass: f32) -> u8 {
+    // Expected number of heavy isotopes per averagine residue (C4.9384 H7.7583
+    // N1.3577 O1.4773 S0.0417, 111.1254 Da), dominated by 13C
+    const HEAVY_PER_RESIDUE: f32 =
+        4.9384 * 0.0107 + 7.7583 * 0.000115 + 1.3577 * 0.00364 + 1.4773 * 0.00038;
+    let lambda = mass.max(0.0) / 111.1254 * HEAVY_PER_RESIDUE;
+    // The mode of a poisson distribution is floor(lambda)
+    lambda.floor().min(u8::MAX as f32) as u8
+}
+
+/// Elements tracked in elemental compositions, in the order C, H, N, O, S
+const ELEMENT_MASSES: [f32; 5] = [12.0, 1.007825, 14.003074, 15.994915, 31.97207];
+
+/// Heavy isotopes of each element (in the order of [`ELEMENT_MASSES`]) as
+/// (nominal mass shift, abundance relative to the lightest isotope)
+const HEAVY_ISOTOPES: [&[(usize, f32)]; 5] = [
+    &[(1, 0.0107 / 0.9893)],
+    &[(1, 0.000115 / 0.999885)],
+    &[(1, 0.00364 / 0.99636)],
+    &[(1, 0.00038 / 0.99757), (2, 0.00205 / 0.99757)],
+    &[(1, 0.0075 / 0.9499), (2, 0.0425 / 0.9499)],
+];
+
+/// Elemental composition of an averagine residue (111.1254 Da)
+const AVERAGINE: [f32; 5] = [4.9384, 7.7583, 1.3577, 1.4773, 0.0417];
+const AVERAGINE_MASS: f32 = 111.1254;
+
+/// Elemental composition (C, H, N, O, S) of an amino acid residue, i.e.
+/// without water
+const fn residue_formula(aa: u8) -> Option<[f32; 5]> {
+    let formula = match aa {
+        b'G' => [2.0, 3.0, 1.0, 1.0, 0.0],
+        b'A' => [3.0, 5.0, 1.0, 1.0, 0.0],
+        b'S' => [3.0, 5.0, 1.0, 2.0, 0.0],
+        b'P' => [5.0, 7.0, 1.0, 1.0, 0.0],
+        b'V' => [5.0, 9.0, 1.0, 1.0, 0.0],
+        b'T' => [4.0, 7.0, 1.0, 2.0, 0.0],
+        b'C' => [3.0, 5.0, 1.0, 1.0, 1.0],
+        b'L' | b'I' => [6.0, 11.0, 1.0, 1.0, 0.0],
+        b'N' => [4.0, 6.0, 2.0, 2.0, 0.0],
+        b'D' => [4.0, 5.0, 1.0, 3.0, 0.0],
+        b'Q' => [5.0, 8.0, 2.0, 2.0, 0.0],
+        b'K' => [6.0, 12.0, 2.0, 1.0, 0.0],
+        b'E' => [5.0, 7.0, 1.0, 3.0, 0.0],
+        b'M' => [5.0, 9.0, 1.0, 1.0, 1.0],
+        b'H' => [6.0, 7.0, 3.0, 1.0, 0.0],
+        b'F' => [9.0, 9.0, 1.0, 1.0, 0.0],
+        b'R' => [6.0, 12.0, 4.0, 1.0, 0.0],
+        b'Y' => [9.0, 9.0, 1.0, 2.0, 0.0],
+        b'W' => [11.0, 10.0, 2.0, 1.0, 0.0],
+        b'O' => [12.0, 19.0, 3.0, 2.0, 0.0],
+        _ => return None,
+    };
+    Some(formula)
+}
+
+/// Estimate the elemental composition of a peptide with (neutral) monoisotopic
+/// `mass`, including all modifications. The composition of unmodified residues
+/// and water is exact, while any remaining mass (modifications, or residues
+/// with an unknown composition) is approximated by averagine
+pub fn peptide_formula(sequence: &[u8], mass: f32) -> [f32; 5] {
+    // H2O
+    let mut formula = [0.0, 2.0, 0.0, 1.0, 0.0];
+    for formula_aa in sequence.iter().filter_map(|&aa| residue_formula(aa)) {
+        formula
+            .iter_mut()
+            .zip(formula_aa)
+            .for_each(|(count, n)| *count += n);
+    }
+    let known = formula
+        .iter()
+        .zip(ELEMENT_MASSES)
+        .map(|(count, mass)| count * mass)
+        .sum::<f32>();
+    let residues = (mass - known) / AVERAGINE_MASS;
+    formula
+        .iter_mut()
+        .zip(AVERAGINE)
+        .for_each(|(count, n)| *count = (*count + n * residues).max(0.0));
+    formula
+}
+
+/// Relative intensities of the first `n` isotopes (M, M+1, M+2, ...) of a
+/// molecule with elemental composition `formula` (C, H, N, O, S), normalized
+/// to the most abundant isotope. The number of each heavy isotope is modeled
+/// as a poisson distribution, and isotopes are binned by nominal mass shift
+pub fn isotope_distribution(formula: &[f32; 5], n: usize) -> Vec<f32> {
+    let mut dist = vec![0.0; n];
+    if let Some(first) = dist.first_mut() {
+        *first = 1.0;
+    }
+    for (count, isotopes) in formula.iter().zip(HEAVY_ISOTOPES) {
+        for &(shift, abundance) in isotopes {
+            let lambda = count * abundance;
+            // Poisson probabilities of 0, 1, 2, ... heavy atoms, unnormalized
+            // since the distribution is scaled to the most abundant isotope
+            let mut poisson = vec![0.0; n];
+            let mut p = 1.0;
+            for (k, idx) in (0..n).step_by(shift).enumerate() {
+                poisson[idx] = p;
+                p *= lambda / (k + 1) as f32;
+            }
+            dist = (0..n)
+                .map(|i| (0..=i).map(|j| dist[j] * poisson[i - j]).sum())
+                .collect();
+        }
+    }
+    let max = dist.iter().copied().fold(0.0, f32::max);
+    if max > 0.0 {
+        dist.iter_mut().for_each(|val| *val /= max);
+    }
+    dist
+}
+
 #[cfg(test)]
 mod tests {
-    use super::peptide_isotopes;
+    use super::{isotope_distribution, most_abundant_isotope, peptide_formula, peptide_isotopes};
 
     #[test]
     fn smoke_isotopes() {
@@ -63,4 +181,31 @@ mod tests {
 
         assert!(matched, "{:?} {:?}", iso, expected);
     }
+
+    #[test]
+    fn most_abundant() {
+        assert_eq!(most_abundant_isotope(800.0), 0);
+        assert_eq!(most_abundant_isotope(1500.0), 0);
+        assert_eq!(most_abundant_isotope(2500.0), 1);
+        assert_eq!(most_abundant_isotope(4000.0), 2);
+        assert_eq!(most_abundant_isotope(6000.0), 3);
+    }
+
+    #[test]
+    fn elemental_composition() {
+        // Angiotensin II, C50H71N13O12
+        let formula = peptide_formula(b"DRVYIHPF", 1045.5345);
+        let expected = [50.0, 71.0, 13.0, 12.0, 0.0];
+        assert!(formula
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (a - b).abs() < 0.01));
+
+        // Unknown residues and modifications are approximated by averagine
+        let formula = peptide_formula(b"DRVYIHPF", 1045.5345 + 111.1254);
+        assert!((formula[0] - 54.9384).abs() < 0.01);
+
+        assert_eq!(isotope_distribution(&expected, 0), Vec::<f32>::new());
+        assert_eq!(isotope_distribution(&[0.0; 5], 2), vec![1.0, 0.0]);
+    }
 }
@@ -21,6 +21,9 @@ const K_WIDTH: usize = 10;
 const GRID_SIZE: usize = 100;
 /// Number of isotopes to search for
 const N_ISOTOPES: usize = 3;
+/// Maximum difference, in volts, between FAIMS compensation voltages that are
+/// considered to be the same
+pub(crate) const FAIMS_CV_TOL: f32 = 0.5;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PeakScoringStrategy {
@@ -72,9 +75,23 @@ pub struct PrecursorRange {
     pub isotope: usize,
     pub peptide: PeptideIx,
     pub file_id: usize,
+    /// FAIMS compensation voltage of the identifying PSM at this charge (or
+    /// of the best PSM of the peptide, if this charge state was not identified)
+    /// - only MS1 spectra acquired at the same CV are used for quantification
+    pub faims_cv: Option<f32>,
     pub decoy: bool,
 }
 
+impl PrecursorRange {
+    /// Non-FAIMS spectra and precursors match any compensation voltage
+    pub fn same_faims_cv(&self, faims_cv: Option<f32>) -> bool {
+        match (self.faims_cv, faims_cv) {
+            (Some(a), Some(b)) => (a - b).abs() <= FAIMS_CV_TOL,
+            _ => true,
+        }
+    }
+}
+
 /// Create a data structure analogous to [`IndexedDatabase`] - instaed of
 /// storing fragment masses binned by precursor mass, store MS1 precursors
 /// binned by RT - This should enable rapid quantification as well
@@ -91,10 +108,14 @@ pub fn build_feature_map(
     features: &[Feature],
 ) -> FeatureMap {
     let map: DashMap<PeptideIx, PrecursorRange, fnv::FnvBuildHasher> = DashMap::default();
+    // Charge states of a peptide can be transmitted at different FAIMS CVs
+    let cvs: DashMap<(PeptideIx, u8), Option<f32>, fnv::FnvBuildHasher> = DashMap::default();
     features
         .iter()
         .filter(|feat| feat.peptide_q <= 0.01 && feat.label == 1)
         .for_each(|feat| {
+            cvs.entry((feat.peptide_idx, feat.charge))
+                .or_insert(feat.faims_cv);
             // `features` is sorted by confidence, so just take the first entry
             if !map.contains_key(&feat.peptide_idx) {
                 // let mass = if feat.isotope_error > 0.0 || feat.delta_mass >= settings.ppm_tolerance * 3.0 {
@@ -112,6 +133,7 @@ pub fn build_feature_map(
                         charge: feat.charge,
                         isotope: 0,
                         file_id: feat.file_id,
+                        faims_cv: feat.faims_cv,
                         decoy: false,
                     },
                 );
@@ -122,7 +144,12 @@ pub fn build_feature_map(
     let mut ranges = map
         .into_par_iter()
         .flat_map_iter(|(_, range)| {
+            let cvs = &cvs;
             (precursor_charge.0..=precursor_charge.1).flat_map(move |charge| {
+                let faims_cv = cvs
+                    .get(&(range.peptide, charge))
+                    .map(|cv| *cv)
+                    .unwrap_or(range.faims_cv);
                 (0..N_ISOTOPES).flat_map(move |isotope| {
                     let mass = (range.mass_lo + isotope as f32 * NEUTRON) / charge as f32;
                     let (mass_lo, mass_hi) =
@@ -134,6 +161,7 @@ pub fn build_feature_map(
                         mass_hi,
                         charge,
                         isotope,
+                        faims_cv,
                         decoy: false,
                         ..range
                     };
@@ -225,7 +253,10 @@ impl FeatureMap {
                 let query = self.rt_slice(rt, RT_TOL);
 
                 for peak in &spectrum.peaks {
-                    for entry in query.mass_lookup(peak.mass) {
+                    for entry in query
+                        .mass_lookup(peak.mass)
+                        .filter(|entry| entry.same_faims_cv(spectrum.faims_cv))
+                    {
                         let id = match self.settings.combine_charge_states {
                             true => PrecursorId::Combined(entry.peptide),
                             false => PrecursorId::Charged((entry.peptide, entry.charge)),
@@ -656,3 +687,39 @@ impl<'a> Query<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn faims_cv_per_charge() {
+        let feature = |charge: u8, faims_cv: f32| Feature {
+            peptide_idx: PeptideIx(0),
+            label: 1,
+            charge,
+            calcmass: 1000.0,
+            faims_cv: Some(faims_cv),
+            ..Default::default()
+        };
+        // The best PSM of the peptide is doubly charged
+        let features = [feature(2, -45.0), feature(3, -60.0)];
+        let map = build_feature_map(LfqSettings::default(), (2, 4), &features);
+
+        let cv = |charge: u8| {
+            let cvs = map
+                .ranges
+                .iter()
+                .filter(|range| range.charge == charge)
+                .map(|range| range.faims_cv)
+                .collect::<Vec<_>>();
+            assert_eq!(cvs.len(), 2 * N_ISOTOPES);
+            assert!(cvs.iter().all(|&cv| cv == cvs[0]));
+            cvs[0]
+        };
+        assert_eq!(cv(2), Some(-45.0));
+        assert_eq!(cv(3), Some(-60.0));
+        // Unidentified charge states use the CV of the best PSM
+        assert_eq!(cv(4), Some(-45.0));
+    }
+}
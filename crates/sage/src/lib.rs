@@ -1,15 +1,25 @@
+pub mod annotation;
+pub mod calibration;
+pub mod cluster;
+pub mod crosslink;
 pub mod database;
 pub mod enzyme;
 pub mod fasta;
 pub mod fdr;
 pub mod heap;
+pub mod inference;
 pub mod ion_series;
 pub mod isotopes;
 pub mod lfq;
+pub mod library;
 pub mod mass;
+pub mod mbr;
 pub mod ml;
 pub mod modification;
+pub mod open_search;
 pub mod peptide;
+pub mod pipeline;
+pub mod properties;
 pub mod scoring;
 pub mod spectrum;
 pub mod tmt;
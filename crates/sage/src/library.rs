@@ -0,0 +1,603 @@
+//! Spectral library search
+//!
+//! Instead of generating theoretical fragments from a FASTA digest, a
+//! [`SpectralLibrary`] indexes empirical fragment ions (with measured
+//! intensities) of each library peptide. The fragment index has the same
+//! structure as for in silico fragments, so candidates are retrieved and
+//! scored by a regular [`Scorer`], and then re-ranked by the correlation of
+//! observed and library fragment intensities
+
+use crate::database::{
+    bucket_fragments, check_peptide_count, fragment_order, DatabaseError, IndexedDatabase,
+    Parameters, PeptideIx, Theoretical,
+};
+use crate::ion_series::IonSeries;
+use crate::mass::{Tolerance, PROTON};
+use crate::peptide::{Peptide, PeptideError};
+use crate::scoring::{Feature, Scorer};
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use fnv::FnvHashMap;
+use rayon::prelude::*;
+use std::sync::Arc;
+
+/// Maximum difference (in Da) between a library fragment and a theoretical
+/// ion of the target peptide for the fragment to be annotated, and thus
+/// transferred to the decoy peptide
+const ANNOTATION_TOLERANCE: f32 = 0.02;
+
+/// Minimum number of library fragments required to calculate a correlation
+const MIN_CORRELATION_FRAGMENTS: usize = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LibraryFragment {
+    /// Neutral fragment mass (no charge)
+    pub mass: f32,
+    pub charge: u8,
+    pub intensity: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct LibraryEntry {
+    pub peptide: Peptide,
+    pub fragments: Vec<LibraryFragment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LibraryError {
+    /// A required column is not present in the header
+    MissingColumn(&'static str),
+    /// A value in `column` on (1-based) `line` could not be parsed
+    InvalidValue {
+        line: usize,
+        column: &'static str,
+    },
+    Peptide(PeptideError),
+}
+
+impl std::fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LibraryError::MissingColumn(column) => {
+                write!(f, "spectral library is missing column `{}`", column)
+            }
+            LibraryError::InvalidValue { line, column } => {
+                write!(f, "invalid `{}` value on line {}", column, line)
+            }
+            LibraryError::Peptide(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for LibraryError {}
+
+/// Find the index of the first column of `header` matching one of `names`
+fn column(header: &[&str], names: &[&'static str]) -> Result<usize, LibraryError> {
+    header
+        .iter()
+        .position(|col| names.contains(col))
+        .ok_or(LibraryError::MissingColumn(names[0]))
+}
+
+/// Convert a modified sequence to the representation understood by
+/// [`Peptide::from_modified_string`]. DIA-NN style sequences, such as
+/// `_(UniMod:1)PEPM(UniMod:35)K_`, are converted to `n[UniMod:1]PEPM[UniMod:35]K`
+fn normalize_sequence(sequence: &str) -> String {
+    let sequence = sequence
+        .trim_matches('_')
+        .replace('(', "[")
+        .replace(')', "]");
+    match sequence.starts_with('[') {
+        true => format!("n{}", sequence),
+        false => sequence,
+    }
+}
+
+/// Parse a tab-separated spectral library with one row per fragment ion, as
+/// exported by e.g. DIA-NN or Spectronaut. The following columns are used:
+/// * `ModifiedPeptide` (or `FullUniModPeptideName`, `ModifiedSequence`)
+/// * `ProductMz` (or `FragmentMz`)
+/// * `LibraryIntensity` (or `RelativeIntensity`)
+/// * `FragmentCharge` (optional, default: 1)
+/// * `ProteinName` (or `ProteinId`, optional), separated by `;`
+///
+/// Fragments of the same modified peptide (e.g. from multiple precursor
+/// charge states) are combined into a single entry
+pub fn parse_tsv(contents: &str) -> Result<Vec<LibraryEntry>, LibraryError> {
+    let mut lines = contents.lines();
+    let header = lines
+        .next()
+        .unwrap_or_default()
+        .split('\t')
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    let peptide_col = column(
+        &header,
+        &[
+            "ModifiedPeptide",
+            "FullUniModPeptideName",
+            "ModifiedSequence",
+        ],
+    )?;
+    let mz_col = column(&header, &["ProductMz", "FragmentMz"])?;
+    let intensity_col = column(&header, &["LibraryIntensity", "RelativeIntensity"])?;
+    let charge_col = column(&header, &["FragmentCharge"]).ok();
+    let protein_col = column(&header, &["ProteinName", "ProteinId"]).ok();
+
+    let mut index: FnvHashMap<String, usize> = FnvHashMap::default();
+    let mut entries: Vec<LibraryEntry> = Vec::new();
+    for (line, row) in lines.enumerate() {
+        if row.trim().is_empty() {
+            continue;
+        }
+        // Line numbers are 1-based, and the header is the first line
+        let line = line + 2;
+        let fields = row.split('\t').map(str::trim).collect::<Vec<_>>();
+        let field = |col: usize, name: &'static str| {
+            fields
+                .get(col)
+                .copied()
+                .ok_or(LibraryError::InvalidValue { line, column: name })
+        };
+        let invalid = |column: &'static str| LibraryError::InvalidValue { line, column };
+
+        let mz = field(mz_col, "ProductMz")?
+            .parse::<f32>()
+            .map_err(|_| invalid("ProductMz"))?;
+        let intensity = field(intensity_col, "LibraryIntensity")?
+            .parse::<f32>()
+            .map_err(|_| invalid("LibraryIntensity"))?;
+        let charge = match charge_col {
+            Some(col) => field(col, "FragmentCharge")?
+                .parse::<u8>()
+                .map_err(|_| invalid("FragmentCharge"))?
+                .max(1),
+            None => 1,
+        };
+        let fragment = LibraryFragment {
+            mass: (mz - PROTON) * charge as f32,
+            charge,
+            intensity,
+        };
+
+        let sequence = field(peptide_col, "ModifiedPeptide")?;
+        match index.get(sequence) {
+            Some(&ix) => entries[ix].fragments.push(fragment),
+            None => {
+                let mut peptide = Peptide::from_modified_string(&normalize_sequence(sequence))
+                    .map_err(LibraryError::Peptide)?;
+                if let Some(proteins) = protein_col.and_then(|col| fields.get(col)) {
+                    peptide.proteins = proteins
+                        .split(';')
+                        .filter(|protein| !protein.is_empty())
+                        .map(|protein| Arc::new(protein.to_string()))
+                        .collect();
+                }
+                index.insert(sequence.to_string(), entries.len());
+                entries.push(LibraryEntry {
+                    peptide,
+                    fragments: vec![fragment],
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Generate a decoy library entry by reversing the peptide sequence. Library
+/// fragments are annotated with theoretical ions of the target peptide, and
+/// their intensities are transferred to the same ions of the decoy peptide.
+/// Fragments that cannot be annotated are dropped
+fn decoy(parameters: &Parameters, entry: &LibraryEntry) -> Option<LibraryEntry> {
+    let peptide = entry.peptide.reverse(Some(parameters.keep_ends));
+    if peptide.sequence == entry.peptide.sequence {
+        return None;
+    }
+
+    let fragments = entry
+        .fragments
+        .iter()
+        .filter_map(|fragment| {
+            parameters.ion_kinds.iter().find_map(|kind| {
+                let idx = IonSeries::new(&entry.peptide, *kind).position(|ion| {
+                    (ion.monoisotopic_mass - fragment.mass).abs() <= ANNOTATION_TOLERANCE
+                })?;
+                let ion = IonSeries::new(&peptide, *kind).nth(idx)?;
+                Some(LibraryFragment {
+                    mass: ion.monoisotopic_mass,
+                    ..*fragment
+                })
+            })
+        })
+        .collect();
+
+    Some(LibraryEntry { peptide, fragments })
+}
+
+/// Pearson correlation of `a` and `b`, or 0 if either has no variance
+fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    match var_a > 0.0 && var_b > 0.0 {
+        true => cov / (var_a * var_b).sqrt(),
+        false => 0.0,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LibraryMatch {
+    pub feature: Feature,
+    /// Pearson correlation of library and observed fragment intensities
+    pub correlation: f32,
+}
+
+/// An [`IndexedDatabase`] built from spectral library entries, alongside the
+/// library fragments (with intensities) of each peptide
+pub struct SpectralLibrary {
+    database: IndexedDatabase,
+    /// Library spectrum (fragments with intensities) of each peptide, indexed
+    /// by [`PeptideIx`]
+    spectra: Vec<Vec<LibraryFragment>>,
+}
+
+impl SpectralLibrary {
+    /// Index the fragments of library `entries`. Peptide mass, fragment m/z and
+    /// decoy settings of `parameters` are respected, but all enzyme,
+    /// modification and ion generation settings are ignored. Decoys are
+    /// generated by reversing library peptides. Panics if the library cannot
+    /// be indexed, see [`SpectralLibrary::try_build`]
+    pub fn build(parameters: Parameters, entries: Vec<LibraryEntry>) -> Self {
+        Self::try_build(parameters, entries)
+            .unwrap_or_else(|err| panic!("failed to build spectral library: {}", err))
+    }
+
+    /// Index the fragments of library `entries`, see [`SpectralLibrary::build`].
+    /// Returns an error if more peptides (including decoys) are present than
+    /// can be addressed by a [`PeptideIx`]
+    pub fn try_build(
+        parameters: Parameters,
+        entries: Vec<LibraryEntry>,
+    ) -> Result<Self, DatabaseError> {
+        let mut entries = entries
+            .into_iter()
+            .filter(|entry| {
+                entry.peptide.monoisotopic >= parameters.peptide_min_mass
+                    && entry.peptide.monoisotopic <= parameters.peptide_max_mass
+            })
+            .collect::<Vec<_>>();
+
+        if parameters.generate_decoys {
+            let decoys = entries
+                .par_iter()
+                .filter(|entry| !entry.peptide.decoy)
+                .filter_map(|entry| decoy(&parameters, entry))
+                .collect::<Vec<_>>();
+            entries.extend(decoys);
+        }
+
+        entries.par_sort_unstable_by(|a, b| {
+            a.peptide
+                .monoisotopic
+                .total_cmp(&b.peptide.monoisotopic)
+                .then_with(|| a.peptide.initial_sort(&b.peptide))
+        });
+
+        let (peptides, spectra): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.fragments.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+                (entry.peptide, entry.fragments)
+            })
+            .unzip();
+        check_peptide_count(peptides.len())?;
+
+        let in_range = |fragment: &&LibraryFragment| {
+            fragment.mass >= parameters.fragment_min_mz
+                && fragment.mass <= parameters.fragment_max_mz
+        };
+        let mut fragments = spectra
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(idx, fragments)| {
+                fragments
+                    .iter()
+                    .filter(in_range)
+                    .map(move |fragment| Theoretical {
+                        peptide_index: PeptideIx(idx as u32),
+                        fragment_mz: fragment.mass,
+                    })
+            })
+            .collect::<Vec<_>>();
+        fragments.par_sort_unstable_by(fragment_order);
+        let min_value = bucket_fragments(&mut fragments, parameters.bucket_size);
+
+        let fragment_count = spectra
+            .iter()
+            .map(|fragments| {
+                fragments
+                    .iter()
+                    .filter(in_range)
+                    .count()
+                    .min(u16::MAX as usize) as u16
+            })
+            .collect();
+
+        let database = IndexedDatabase {
+            masses: crate::database::peptide_masses(&peptides),
+            decoys: crate::database::decoy_bitset(&peptides),
+            peptides,
+            fragments,
+            ion_kinds: parameters.ion_kinds,
+            min_value,
+            fragment_count,
+            potential_mods: Vec::new(),
+            bucket_size: parameters.bucket_size,
+            generate_decoys: parameters.generate_decoys,
+            decoy_tag: parameters.decoy_tag,
+            decoy_prefix: parameters.decoy_prefix,
+            entrapment_tag: parameters.entrapment_tag,
+        };
+
+        Ok(SpectralLibrary { database, spectra })
+    }
+
+    /// Library fragments of the peptide at `ix`, sorted by mass
+    pub fn library_fragments(&self, ix: PeptideIx) -> &[LibraryFragment] {
+        &self.spectra[ix.0 as usize]
+    }
+
+    /// Pearson correlation between the library intensities of the peptide at
+    /// `ix` and the intensities of the most intense peaks of `spectrum` within
+    /// `fragment_tol` (0 for unmatched fragments)
+    pub fn correlation(
+        &self,
+        ix: PeptideIx,
+        spectrum: &ProcessedSpectrum,
+        fragment_tol: Tolerance,
+    ) -> f32 {
+        let fragments = self.library_fragments(ix);
+        if fragments.len() < MIN_CORRELATION_FRAGMENTS {
+            return 0.0;
+        }
+        let (library, observed): (Vec<f32>, Vec<f32>) = fragments
+            .iter()
+            .map(|fragment| {
+                // Experimental peaks are multipled by charge, therefore theoretical are divided
+                let mz = fragment.mass / fragment.charge as f32;
+                let observed = select_most_intense_peak(&spectrum.peaks, mz, fragment_tol, None)
+                    .map(|peak| peak.intensity)
+                    .unwrap_or_default();
+                (fragment.intensity, observed)
+            })
+            .unzip();
+        pearson(&library, &observed)
+    }
+
+    /// Score `spectrum` using `scorer`, which must have been created for this
+    /// library, and re-rank the reported PSMs by intensity correlation.
+    ///
+    /// Only the `report_psms` best candidates (by hyperscore) are re-ranked,
+    /// so this should be set higher than the desired number of PSMs
+    pub fn score(&self, scorer: &Scorer, spectrum: &ProcessedSpectrum) -> Vec<LibraryMatch> {
+        assert!(
+            std::ptr::eq(scorer.db, &self.database),
+            "scorer was not created for this spectral library"
+        );
+        let mut matches = scorer
+            .score(spectrum)
+            .into_iter()
+            .map(|feature| LibraryMatch {
+                correlation: self.correlation(feature.peptide_idx, spectrum, scorer.fragment_tol),
+                feature,
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| {
+            b.correlation
+                .total_cmp(&a.correlation)
+                .then_with(|| b.feature.hyperscore.total_cmp(&a.feature.hyperscore))
+        });
+        for (rank, m) in matches.iter_mut().enumerate() {
+            m.feature.rank = rank as u32 + 1;
+        }
+        matches
+    }
+}
+
+impl std::ops::Deref for SpectralLibrary {
+    type Target = IndexedDatabase;
+
+    fn deref(&self) -> &Self::Target {
+        &self.database
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::ion_series::Kind;
+    use crate::spectrum::{Peak, Precursor};
+
+    /// Tab-separated library rows for the b and y ions of `sequence`, with
+    /// intensities given by `intensity(ion_index)`
+    fn rows(sequence: &str, intensity: impl Fn(usize) -> f32) -> String {
+        let peptide = Peptide::from_modified_string(sequence).unwrap();
+        [Kind::B, Kind::Y]
+            .iter()
+            .flat_map(|kind| IonSeries::new(&peptide, *kind).collect::<Vec<_>>())
+            .enumerate()
+            .map(|(idx, ion)| {
+                format!(
+                    "{}\t{}\t1\t{}\tsp|P1\n",
+                    sequence,
+                    ion.monoisotopic_mass + PROTON,
+                    intensity(idx)
+                )
+            })
+            .collect()
+    }
+
+    fn library() -> SpectralLibrary {
+        let tsv = format!(
+            "ModifiedPeptide\tProductMz\tFragmentCharge\tLibraryIntensity\tProteinName\n{}{}",
+            rows("LESLIEK", |idx| (idx % 4 + 1) as f32 * 10.0),
+            rows("PEPTIDEK", |_| 10.0),
+        );
+        let entries = parse_tsv(&tsv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].fragments.len(), 12);
+        assert_eq!(entries[0].peptide.proteins, vec![Arc::new("sp|P1".into())]);
+
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        SpectralLibrary::build(parameters, entries)
+    }
+
+    #[test]
+    fn parse_diann_sequences() {
+        let tsv = "ModifiedPeptide\tProductMz\tLibraryIntensity\n\
+                   _(UniMod:1)AAM(UniMod:35)K_\t300.0\t1.0\n\
+                   _(UniMod:1)AAM(UniMod:35)K_\t400.0\t2.0\n";
+        let entries = parse_tsv(tsv).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].peptide.nterm, Some(42.010565));
+        assert_eq!(
+            entries[0].peptide.modifications,
+            vec![0.0, 0.0, 15.994915, 0.0]
+        );
+        assert_eq!(entries[0].fragments[1].mass, 400.0 - PROTON);
+
+        assert_eq!(
+            parse_tsv("ModifiedPeptide\tLibraryIntensity\n").unwrap_err(),
+            LibraryError::MissingColumn("ProductMz")
+        );
+        assert_eq!(
+            parse_tsv("ModifiedPeptide\tProductMz\tLibraryIntensity\nPEPTIDE\tfoo\t1\n")
+                .unwrap_err(),
+            LibraryError::InvalidValue {
+                line: 2,
+                column: "ProductMz"
+            }
+        );
+    }
+
+    #[test]
+    fn build_library() {
+        let library = library();
+        assert_eq!(library.peptides.len(), 4);
+        assert_eq!(library.verify(), Ok(()));
+        assert!(library
+            .peptides
+            .windows(2)
+            .all(|w| w[0].monoisotopic <= w[1].monoisotopic));
+
+        // Decoy fragments are the reversed peptide's ions, with the same intensities
+        let decoy = library.peptides.iter().position(|p| p.decoy).unwrap();
+        let decoy = PeptideIx(decoy as u32);
+        let mut expected = [Kind::B, Kind::Y]
+            .iter()
+            .flat_map(|kind| IonSeries::new(&library[decoy], *kind))
+            .map(|ion| ion.monoisotopic_mass)
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| a.total_cmp(b));
+        let observed = library
+            .library_fragments(decoy)
+            .iter()
+            .map(|fragment| fragment.mass)
+            .collect::<Vec<_>>();
+        assert_eq!(observed, expected);
+        assert_eq!(
+            library.fragment_count[decoy.0 as usize] as usize,
+            library
+                .fragments
+                .iter()
+                .filter(|f| f.peptide_index == decoy)
+                .count()
+        );
+    }
+
+    #[test]
+    fn library_search() {
+        let library = library();
+        let target = library
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"LESLIEK" && !p.decoy)
+            .unwrap();
+        let target = PeptideIx(target as u32);
+
+        // Observed intensities are proportional to library intensities
+        let mut peaks = library
+            .library_fragments(target)
+            .iter()
+            .map(|fragment| Peak {
+                mass: fragment.mass,
+                intensity: fragment.intensity * 3.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: library[target].monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let fragment_tol = Tolerance::Ppm(-10.0, 10.0);
+        assert!((library.correlation(target, &spectrum, fragment_tol) - 1.0).abs() < 1E-4);
+
+        let scorer = Scorer {
+            db: &library,
+            precursor_tol: Tolerance::Da(-500.0, 500.0),
+            fragment_tol,
+            min_matched_peaks: 2,
+            min_consecutive_ions: 0,
+            min_score: None,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            averagine_isotope_errors: false,
+            averagine_precursor_correction: false,
+            isotope_error_penalty: 0.0,
+            precursor_offsets: &[],
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            precursor_charge_limits: (1, u8::MAX),
+            max_fragment_charge: Some(1),
+            exclude_above_precursor_mz: false,
+            dedup_fragment_charges: false,
+            fragment_charge_offset: 1,
+            intact_mass_tol: None,
+            min_fragment_mass: 150.0,
+            max_fragment_mass: 2000.0,
+            min_scored_fragment_mz: None,
+            chimera: false,
+            report_psms: 4,
+            ion_weights: Default::default(),
+            activation_ion_kinds: false,
+            peak_selection: Default::default(),
+            ranking_score: Default::default(),
+            wide_window: false,
+            annotate_matches: false,
+        };
+        let matches = library.score(&scorer, &spectrum);
+        assert!(matches.len() > 1);
+        assert_eq!(matches[0].feature.peptide_idx, target);
+        assert_eq!(matches[0].feature.rank, 1);
+        assert!(matches[0].correlation > matches[1].correlation);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{iter::Sum, ops::Mul};
+use std::{iter::Sum, ops::Mul, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
@@ -7,6 +7,30 @@ pub const PROTON: f32 = 1.0072764;
 pub const NEUTRON: f32 = 1.00335;
 pub const NH3: f32 = 17.026548;
 
+/// Charge carrier of a precursor ion, e.g. [M+zH]z+ or [M+zNa]z+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Adduct {
+    #[default]
+    Proton,
+    Sodium,
+    Ammonium,
+    /// Negative mode, [M-zH]z-
+    Deprotonation,
+}
+
+impl Adduct {
+    /// Mass added to the neutral molecule per charge
+    pub const fn mass(&self) -> f32 {
+        match self {
+            Adduct::Proton => PROTON,
+            Adduct::Sodium => 22.989221,
+            Adduct::Ammonium => 18.033826,
+            Adduct::Deprotonation => -PROTON,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
 #[serde(rename_all = "lowercase")]
 pub enum Tolerance {
@@ -38,6 +62,79 @@ impl Tolerance {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InvalidTolerance {
+    Empty,
+    MissingUnit(String),
+    UnknownUnit(String),
+    InvalidValue(String),
+}
+
+impl std::fmt::Display for InvalidTolerance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidTolerance::Empty => write!(f, "empty tolerance string"),
+            InvalidTolerance::MissingUnit(s) => {
+                write!(f, "tolerance `{}` is missing a unit (ppm, da, th)", s)
+            }
+            InvalidTolerance::UnknownUnit(s) => {
+                write!(f, "unknown tolerance unit `{}`, expected ppm, da, or th", s)
+            }
+            InvalidTolerance::InvalidValue(s) => write!(f, "invalid tolerance value `{}`", s),
+        }
+    }
+}
+
+impl std::error::Error for InvalidTolerance {}
+
+impl FromStr for Tolerance {
+    type Err = InvalidTolerance;
+
+    /// Parse a tolerance from a value and unit, e.g. `"10 ppm"` or `"0.02 da"`.
+    ///
+    /// A single value is treated as a symmetric window (`"10 ppm"` is `Ppm(-10, 10)`),
+    /// while two comma-separated values are used as-is for an asymmetric window
+    /// (`"-0.5,+2.5 da"` is `Da(-0.5, 2.5)`). Th (m/z) is accepted as an alias for Da.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(InvalidTolerance::Empty);
+        }
+
+        let split = s
+            .rfind(|c: char| c.is_ascii_digit() || c == '.')
+            .map(|idx| idx + 1)
+            .ok_or_else(|| InvalidTolerance::InvalidValue(s.into()))?;
+        let (values, unit) = s.split_at(split);
+        let unit = unit.trim();
+        if unit.is_empty() {
+            return Err(InvalidTolerance::MissingUnit(s.into()));
+        }
+
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| InvalidTolerance::InvalidValue(v.trim().into()))
+        };
+
+        let (lo, hi) = match values.split_once(',') {
+            Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+            None => {
+                let v = parse(values)?.abs();
+                (-v, v)
+            }
+        };
+
+        match unit.to_ascii_lowercase().as_str() {
+            "ppm" => Ok(Tolerance::Ppm(lo, hi)),
+            "da" | "th" => Ok(Tolerance::Da(lo, hi)),
+            _ => Err(InvalidTolerance::UnknownUnit(unit.into())),
+        }
+    }
+}
+
 impl Mul<f32> for Tolerance {
     type Output = Tolerance;
 
@@ -68,6 +165,121 @@ pub const fn monoisotopic(aa: u8) -> f32 {
     }
 }
 
+/// Mass of carbon monoxide, which is lost from a residue when forming its immonium ion
+pub const CO: f32 = 27.994915;
+
+/// m/z of the (singly charged) immonium ion of residue `aa`, i.e. the residue
+/// mass - CO + H+, or `None` if `aa` is not a known residue
+pub fn immonium(aa: u8) -> Option<f32> {
+    match monoisotopic(aa) {
+        mass if mass > 0.0 => Some(mass - CO + PROTON),
+        _ => None,
+    }
+}
+
+/// m/z of (singly charged) diagnostic ions for modified residues and labels,
+/// see [`crate::spectrum::ProcessedSpectrum::contains_ion`]
+pub const DIAGNOSTIC_IONS: [(&str, f32); 7] = [
+    ("Phospho (pY immonium)", 216.04203),
+    ("Acetyl (acetyl-K immonium - NH3)", 126.09134),
+    ("HexNAc oxonium", 204.08665),
+    ("HexNAc oxonium fragment", 138.05495),
+    ("Hex oxonium", 163.0601),
+    ("TMT6plex tag", 230.17021),
+    ("TMTpro tag", 305.21442),
+];
+
+/// Reference monoisotopic residue masses, calculated from elemental compositions
+const REFERENCE_RESIDUES: [(u8, f64); 22] = [
+    (b'A', 71.037114),
+    (b'C', 103.009185),
+    (b'D', 115.026943),
+    (b'E', 129.042593),
+    (b'F', 147.068414),
+    (b'G', 57.021464),
+    (b'H', 137.058912),
+    (b'I', 113.084064),
+    (b'K', 128.094963),
+    (b'L', 113.084064),
+    (b'M', 131.040485),
+    (b'N', 114.042927),
+    (b'O', 237.147727),
+    (b'P', 97.052764),
+    (b'Q', 128.058578),
+    (b'R', 156.101111),
+    (b'S', 87.032028),
+    (b'T', 101.047679),
+    (b'U', 150.953636),
+    (b'V', 99.068414),
+    (b'W', 186.079313),
+    (b'Y', 163.063329),
+];
+
+/// Reference monoisotopic (neutral) peptide masses
+const REFERENCE_PEPTIDES: [(&str, &str, f64); 3] = [
+    ("[Glu1]-Fibrinopeptide B", "EGVNDNEEGFFSAR", 1569.6696),
+    ("Angiotensin II", "DRVYIHPF", 1045.5345),
+    ("Bradykinin", "RPPGFSPFR", 1059.5614),
+];
+
+/// Maximum allowed deviation from a reference mass, in Da
+const VALIDATION_TOLERANCE: f64 = 0.001;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidMass {
+    pub name: String,
+    pub expected: f64,
+    pub observed: f32,
+}
+
+impl std::fmt::Display for InvalidMass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "mass of {} is {}, expected {} (+/- {} Da)",
+            self.name, self.observed, self.expected, VALIDATION_TOLERANCE
+        )
+    }
+}
+
+impl std::error::Error for InvalidMass {}
+
+fn check_mass<S: Into<String>>(name: S, expected: f64, observed: f32) -> Result<(), InvalidMass> {
+    if (observed as f64 - expected).abs() <= VALIDATION_TOLERANCE {
+        Ok(())
+    } else {
+        Err(InvalidMass {
+            name: name.into(),
+            expected,
+            observed,
+        })
+    }
+}
+
+/// Self-check of the mass tables: residue masses, [`PROTON`], [`H2O`], [`NH3`],
+/// [`NEUTRON`] and a few well-known peptides must match reference values within
+/// 1 mDa. Guards against accidental regressions to the mass tables.
+pub fn validate() -> Result<(), InvalidMass> {
+    check_mass("proton", 1.007276, PROTON)?;
+    check_mass("H2O", 18.010565, H2O)?;
+    check_mass("NH3", 17.026549, NH3)?;
+    check_mass("13C-12C", 1.003355, NEUTRON)?;
+
+    for (aa, expected) in REFERENCE_RESIDUES {
+        check_mass(
+            format!("residue {}", aa as char),
+            expected,
+            monoisotopic(aa),
+        )?;
+    }
+
+    for (name, sequence, expected) in REFERENCE_PEPTIDES {
+        let observed = sequence.bytes().map(monoisotopic).sum::<f32>() + H2O;
+        check_mass(name, expected, observed)?;
+    }
+    Ok(())
+}
+
 pub const fn composition(aa: u8) -> Composition {
     match aa {
         b'A' => Composition::new(3, 2, 0),
@@ -124,7 +336,7 @@ impl Sum for Composition {
 mod test {
     use crate::mass::monoisotopic;
 
-    use super::{Tolerance, VALID_AA};
+    use super::{immonium, validate, InvalidTolerance, Tolerance, DIAGNOSTIC_IONS, VALID_AA};
 
     #[test]
     fn smoke() {
@@ -133,6 +345,31 @@ mod test {
         }
     }
 
+    #[test]
+    fn validate_masses() {
+        assert_eq!(validate(), Ok(()));
+    }
+
+    #[test]
+    fn immonium_ions() {
+        for (aa, expected) in [
+            (b'L', 86.0964),
+            (b'F', 120.0808),
+            (b'Y', 136.0757),
+            (b'H', 110.0714),
+            (b'W', 159.0917),
+            (b'P', 70.0651),
+        ] {
+            assert!((immonium(aa).unwrap() - expected).abs() < 1E-3);
+        }
+        assert_eq!(immonium(b'X'), None);
+        assert_eq!(immonium(b'l'), None);
+
+        // pY immonium ion is the Y immonium ion + HPO3
+        let (_, py) = DIAGNOSTIC_IONS[0];
+        assert!((immonium(b'Y').unwrap() + 79.9663 - py).abs() < 1E-3);
+    }
+
     #[test]
     fn tolerances() {
         assert_eq!(
@@ -148,4 +385,31 @@ mod test {
             (999.95, 1000.05)
         );
     }
+
+    #[test]
+    fn parse_tolerances() {
+        assert_eq!("10 ppm".parse(), Ok(Tolerance::Ppm(-10.0, 10.0)));
+        assert_eq!("0.02 da".parse(), Ok(Tolerance::Da(-0.02, 0.02)));
+        assert_eq!("0.5Th".parse(), Ok(Tolerance::Da(-0.5, 0.5)));
+        assert_eq!("-0.5,+2.5 da".parse(), Ok(Tolerance::Da(-0.5, 2.5)));
+        assert_eq!("-10, 20 PPM".parse(), Ok(Tolerance::Ppm(-10.0, 20.0)));
+
+        assert_eq!("".parse::<Tolerance>(), Err(InvalidTolerance::Empty));
+        assert_eq!(
+            "10".parse::<Tolerance>(),
+            Err(InvalidTolerance::MissingUnit("10".into()))
+        );
+        assert_eq!(
+            "10 mmu".parse::<Tolerance>(),
+            Err(InvalidTolerance::UnknownUnit("mmu".into()))
+        );
+        assert_eq!(
+            "ten ppm".parse::<Tolerance>(),
+            Err(InvalidTolerance::InvalidValue("ten ppm".into()))
+        );
+        assert_eq!(
+            "1,x2 da".parse::<Tolerance>(),
+            Err(InvalidTolerance::InvalidValue("x2".into()))
+        );
+    }
 }
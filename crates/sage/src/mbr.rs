@@ -0,0 +1,359 @@
+//! Match-between-runs: transfer confident identifications from a source run
+//! to a target run in which the peptide was not identified
+//!
+//! 1) Peptides confidently identified in both runs are used as anchors to fit
+//!    a LOESS curve mapping source retention times to target retention times
+//! 2) For each peptide only identified in the source run, the expected RT in
+//!    the target run is predicted from the curve
+//! 3) An MS1 extracted ion chromatogram is built at the m/z of the peptide in
+//!    the target run, within an RT window around the predicted RT. The apex
+//!    and integrated intensity of the chromatogram are reported
+
+use crate::database::PeptideIx;
+use crate::lfq::FAIMS_CV_TOL;
+use crate::mass::Tolerance;
+use crate::scoring::Feature;
+use crate::spectrum::ProcessedSpectrum;
+use fnv::FnvHashMap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Only PSMs passing this spectrum-level q-value are used as anchors, or
+/// transferred to other runs
+const MBR_Q_VALUE: f32 = 0.01;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct MbrSettings {
+    /// Fraction of anchors used for each local regression
+    pub span: f32,
+    /// Minimum number of peptides identified in both runs required to align them
+    pub min_anchors: usize,
+    /// Half-width of the window (in minutes) around the predicted RT used to
+    /// extract MS1 signal
+    pub rt_tolerance: f32,
+    /// MS1 mass tolerance (in ppm) used to extract MS1 signal
+    pub ppm_tolerance: f32,
+}
+
+impl Default for MbrSettings {
+    fn default() -> Self {
+        Self {
+            span: 0.3,
+            min_anchors: 10,
+            rt_tolerance: 1.0,
+            ppm_tolerance: 10.0,
+        }
+    }
+}
+
+/// Non-linear mapping of retention times from one run to another, fitted by
+/// LOESS (locally weighted linear regression with a tricube kernel)
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtAlignment {
+    /// Distinct source RTs of the anchors, in ascending order
+    x: Vec<f64>,
+    /// Fitted target RT at each of `x`
+    y: Vec<f64>,
+}
+
+impl RtAlignment {
+    /// Fit an alignment to `(source RT, target RT)` anchor pairs, using the
+    /// nearest `span` fraction of anchors for each local regression. Returns
+    /// `None` if there are no anchors
+    pub fn fit(anchors: &[(f32, f32)], span: f32) -> Option<Self> {
+        let mut anchors = anchors
+            .iter()
+            .map(|&(x, y)| (x as f64, y as f64))
+            .filter(|(x, y)| x.is_finite() && y.is_finite())
+            .collect::<Vec<_>>();
+        if anchors.is_empty() {
+            return None;
+        }
+        anchors.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let n = anchors.len();
+        let k = ((span as f64 * n as f64).ceil() as usize).clamp(2.min(n), n);
+
+        let mut x: Vec<f64> = Vec::with_capacity(n);
+        let mut y: Vec<f64> = Vec::with_capacity(n);
+        let mut lo = 0;
+        for &(x0, _) in &anchors {
+            if x.last() == Some(&x0) {
+                continue;
+            }
+            // Slide the window of `k` nearest neighbours along the sorted anchors
+            while lo + k < n && anchors[lo + k].0 - x0 < x0 - anchors[lo].0 {
+                lo += 1;
+            }
+            let window = &anchors[lo..lo + k];
+            let dist = (x0 - window[0].0).max(window[k - 1].0 - x0);
+
+            let (mut sw, mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for &(xi, yi) in window {
+                let w = match dist > 0.0 {
+                    true => (1.0 - ((xi - x0).abs() / dist).powi(3)).powi(3),
+                    false => 1.0,
+                };
+                sw += w;
+                sx += w * xi;
+                sy += w * yi;
+                sxx += w * xi * xi;
+                sxy += w * xi * yi;
+            }
+
+            let mean_x = sx / sw;
+            let mean_y = sy / sw;
+            let var = sxx / sw - mean_x * mean_x;
+            let fitted = match var > f64::EPSILON {
+                true => mean_y + (sxy / sw - mean_x * mean_y) / var * (x0 - mean_x),
+                false => mean_y,
+            };
+            x.push(x0);
+            y.push(fitted);
+        }
+
+        Some(Self { x, y })
+    }
+
+    /// Predict the target RT for a source RT. Predictions between anchors
+    /// are linearly interpolated, and predictions outside of the anchors are
+    /// extrapolated from the closest segment of the curve
+    pub fn predict(&self, rt: f32) -> f32 {
+        let rt = rt as f64;
+        let n = self.x.len();
+        if n == 1 {
+            return (self.y[0] + (rt - self.x[0])) as f32;
+        }
+        let idx = self.x.partition_point(|&x| x < rt).clamp(1, n - 1);
+        let (x0, x1) = (self.x[idx - 1], self.x[idx]);
+        let (y0, y1) = (self.y[idx - 1], self.y[idx]);
+        (y0 + (y1 - y0) / (x1 - x0) * (rt - x0)) as f32
+    }
+}
+
+/// MS1 signal extracted for a peptide transferred to another run
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub peptide_idx: PeptideIx,
+    /// The run that the peptide was transferred to
+    pub file_id: usize,
+    pub charge: u8,
+    /// Target RT predicted from the RT of the source PSM
+    pub predicted_rt: f32,
+    /// RT of the most intense point of the extracted ion chromatogram
+    pub apex_rt: f32,
+    pub apex_intensity: f32,
+    /// Trapezoidal integral of the extracted ion chromatogram
+    pub area: f32,
+    /// Number of MS1 spectra with signal at the peptide m/z
+    pub points: usize,
+}
+
+/// Best confident target PSM for each peptide in a run
+fn confident_psms(features: &[Feature], file_id: usize) -> FnvHashMap<PeptideIx, &Feature> {
+    let mut psms: FnvHashMap<PeptideIx, &Feature> = FnvHashMap::default();
+    for feat in features {
+        if feat.file_id != file_id || feat.label != 1 || feat.spectrum_q > MBR_Q_VALUE {
+            continue;
+        }
+        psms.entry(feat.peptide_idx)
+            .and_modify(|best| {
+                if feat.discriminant_score > best.discriminant_score {
+                    *best = feat;
+                }
+            })
+            .or_insert(feat);
+    }
+    psms
+}
+
+/// Align retention times of the `source` run to the `target` run, using
+/// peptides confidently identified in both runs as anchors. Returns `None` if
+/// there are fewer than `settings.min_anchors` shared peptides
+pub fn align_runs(
+    features: &[Feature],
+    source: usize,
+    target: usize,
+    settings: &MbrSettings,
+) -> Option<RtAlignment> {
+    let source = confident_psms(features, source);
+    let target = confident_psms(features, target);
+    let anchors = source
+        .iter()
+        .filter_map(|(peptide, s)| target.get(peptide).map(|t| (s.rt, t.rt)))
+        .collect::<Vec<_>>();
+
+    if anchors.len() < settings.min_anchors.max(1) {
+        return None;
+    }
+    RtAlignment::fit(&anchors, settings.span)
+}
+
+/// Extract the MS1 signal at `mz` (peak masses of processed MS1 spectra are
+/// m/z values minus a proton, and `mz` uses the same convention) within
+/// `settings.rt_tolerance` of `rt`. `spectra` must be MS1 spectra of a single
+/// run, sorted by scan start time. Returns `(apex RT, apex intensity, area,
+/// points)`, or `None` if there is no signal
+pub fn extract_ms1(
+    spectra: &[&ProcessedSpectrum],
+    mz: f32,
+    rt: f32,
+    faims_cv: Option<f32>,
+    settings: &MbrSettings,
+) -> Option<(f32, f32, f32, usize)> {
+    let (mz_lo, mz_hi) = Tolerance::Ppm(-settings.ppm_tolerance, settings.ppm_tolerance).bounds(mz);
+    let rt_lo = rt - settings.rt_tolerance;
+    let rt_hi = rt + settings.rt_tolerance;
+
+    let start = spectra.partition_point(|s| s.scan_start_time < rt_lo);
+    let xic = spectra[start..]
+        .iter()
+        .take_while(|s| s.scan_start_time <= rt_hi)
+        .filter(|s| match (s.faims_cv, faims_cv) {
+            (Some(a), Some(b)) => (a - b).abs() <= FAIMS_CV_TOL,
+            _ => true,
+        })
+        .map(|s| {
+            let lo = s.peaks.partition_point(|peak| peak.mass < mz_lo);
+            let intensity = s.peaks[lo..]
+                .iter()
+                .take_while(|peak| peak.mass <= mz_hi)
+                .map(|peak| peak.intensity)
+                .fold(0.0f32, f32::max);
+            (s.scan_start_time, intensity)
+        })
+        .collect::<Vec<_>>();
+
+    let points = xic.iter().filter(|(_, int)| *int > 0.0).count();
+    if points == 0 {
+        return None;
+    }
+
+    let (apex_rt, apex_intensity) = xic.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    let area = xic
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0) * (w[0].1 + w[1].1) / 2.0)
+        .sum::<f32>();
+
+    Some((apex_rt, apex_intensity, area, points))
+}
+
+/// Transfer peptides confidently identified in the `source` run, but not in
+/// the `target` run, by aligning retention times of both runs and extracting
+/// MS1 signal in the target run at the predicted RT.
+///
+/// `spectra` may contain spectra from any number of runs - only MS1 spectra
+/// of the target run are used. Returns an empty vector if the runs could not
+/// be aligned
+pub fn match_between_runs(
+    features: &[Feature],
+    spectra: &[ProcessedSpectrum],
+    source: usize,
+    target: usize,
+    settings: &MbrSettings,
+) -> Vec<Transfer> {
+    let alignment = match align_runs(features, source, target, settings) {
+        Some(alignment) => alignment,
+        None => return Vec::new(),
+    };
+
+    let mut ms1 = spectra
+        .iter()
+        .filter(|s| s.level == 1 && s.file_id == target)
+        .collect::<Vec<_>>();
+    ms1.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+
+    let identified = confident_psms(features, target);
+    let mut transfers = confident_psms(features, source)
+        .into_par_iter()
+        .filter(|(peptide, _)| !identified.contains_key(peptide))
+        .filter_map(|(peptide, feat)| {
+            let predicted_rt = alignment.predict(feat.rt);
+            let mz = feat.calcmass / feat.charge as f32;
+            let (apex_rt, apex_intensity, area, points) =
+                extract_ms1(&ms1, mz, predicted_rt, feat.faims_cv, settings)?;
+            Some(Transfer {
+                peptide_idx: peptide,
+                file_id: target,
+                charge: feat.charge,
+                predicted_rt,
+                apex_rt,
+                apex_intensity,
+                area,
+                points,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    transfers.sort_by_key(|t| t.peptide_idx);
+    transfers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spectrum::Peak;
+
+    #[test]
+    fn loess_alignment() {
+        // Non-linear (but monotonic) shift between runs
+        let f = |x: f32| 1.1 * x + 2.0 + 0.5 * (x / 10.0).sin();
+        let anchors = (0..100)
+            .map(|i| i as f32 * 0.6)
+            .map(|x| (x, f(x)))
+            .collect::<Vec<_>>();
+
+        let alignment = RtAlignment::fit(&anchors, 0.2).unwrap();
+        for x in [1.0, 17.3, 30.0, 45.5, 59.0] {
+            assert!((alignment.predict(x) - f(x)).abs() < 0.05, "{}", x);
+        }
+
+        // Extrapolation beyond the last anchor
+        assert!((alignment.predict(65.0) - f(65.0)).abs() < 0.5);
+
+        let single = RtAlignment::fit(&[(10.0, 12.0)], 0.3).unwrap();
+        assert_eq!(single.predict(11.0), 13.0);
+        assert_eq!(RtAlignment::fit(&[], 0.3), None);
+    }
+
+    #[test]
+    fn extract_signal() {
+        let mz = 500.0;
+        let spectra = (0..50)
+            .map(|i| {
+                let rt = 10.0 + i as f32 * 0.1;
+                // Gaussian elution profile with apex at 12.0 min
+                let intensity = 1E6 * (-((rt - 12.0) / 0.2).powi(2)).exp();
+                ProcessedSpectrum {
+                    level: 1,
+                    scan_start_time: rt,
+                    peaks: vec![
+                        Peak {
+                            mass: 499.9,
+                            intensity: 1E7,
+                        },
+                        Peak {
+                            mass: mz + 0.001,
+                            intensity,
+                        },
+                    ],
+                    ..Default::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        let spectra = spectra.iter().collect::<Vec<_>>();
+
+        let settings = MbrSettings::default();
+        let (apex_rt, apex_intensity, area, points) =
+            extract_ms1(&spectra, mz, 12.25, None, &settings).unwrap();
+        assert!((apex_rt - 12.0).abs() < 1E-3);
+        assert!((apex_intensity - 1E6).abs() < 1.0);
+        assert!(area > 0.0);
+        assert_eq!(points, 20);
+
+        // No signal at a different m/z
+        assert_eq!(extract_ms1(&spectra, 600.0, 12.0, None, &settings), None);
+        // ... or outside of the RT window
+        assert_eq!(extract_ms1(&spectra, mz, 20.0, None, &settings), None);
+    }
+}
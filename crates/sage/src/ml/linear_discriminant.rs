@@ -16,7 +16,7 @@ use crate::mass::Tolerance;
 use crate::scoring::Feature;
 
 // Declare, so that we have compile time checking of matrix dimensions
-const FEATURES: usize = 20;
+const FEATURES: usize = 21;
 const FEATURE_NAMES: [&str; FEATURES] = [
     "rank",
     "charge",
@@ -38,6 +38,7 @@ const FEATURE_NAMES: [&str; FEATURES] = [
     "ims",
     "sqrt(delta_rt_model)",
     "sqrt(delta_ims_model)",
+    "ln1p(abs(delta_rt_external))",
 ];
 
 struct Features<'a>(&'a [f64]);
@@ -131,7 +132,9 @@ pub fn score_psms(scores: &mut [Feature], precursor_tol: Tolerance) -> Option<()
 
     let mass_error = match precursor_tol {
         Tolerance::Ppm(_, _) => |feat: &Feature| feat.delta_mass as f64,
-        Tolerance::Da(_, _) => |feat: &Feature| (feat.expmass - feat.calcmass) as f64,
+        Tolerance::Da(_, _) => {
+            |feat: &Feature| (feat.expmass - feat.calcmass - feat.precursor_offset) as f64
+        }
     };
 
     let (bw_adjust, bin_size) = match precursor_tol {
@@ -179,13 +182,35 @@ pub fn score_psms(scores: &mut [Feature], precursor_tol: Tolerance) -> Option<()
                 (perc.ims as f64),
                 (perc.delta_rt_model as f64).clamp(0.001, 0.999).sqrt(),
                 (perc.delta_ims_model as f64).clamp(0.001, 0.999).sqrt(),
+                // PSMs without an external RT prediction are not penalized
+                (perc.delta_rt_external.unwrap_or(0.0).abs() as f64).ln_1p(),
             ];
             x
         })
         .collect::<Vec<_>>();
 
     let features = Matrix::new(features, scores.len(), FEATURES);
-    let lda = LinearDiscriminantAnalysis::train(&features, &decoys)?;
+
+    // PSMs propagated from a cluster representative duplicate the features of
+    // the representative, so they are scored, but not used for training
+    let lda = match scores.iter().any(|feat| feat.propagated) {
+        false => LinearDiscriminantAnalysis::train(&features, &decoys)?,
+        true => {
+            let training = (0..scores.len())
+                .filter(|&row| !scores[row].propagated)
+                .collect::<Vec<_>>();
+            let subset = Matrix::new(
+                training
+                    .iter()
+                    .flat_map(|&row| features.row(row))
+                    .collect::<Vec<_>>(),
+                training.len(),
+                FEATURES,
+            );
+            let decoys = training.iter().map(|&row| decoys[row]).collect::<Vec<_>>();
+            LinearDiscriminantAnalysis::train(&subset, &decoys)?
+        }
+    };
     if !lda.eigenvector.iter().all(|f| f.is_finite()) {
         log::error!(
             "linear model eigenvector includes NaN: this likely indicates a bug, please report!"
@@ -10,8 +10,12 @@ use crate::peptide::Peptide;
 use crate::scoring::Feature;
 use rayon::prelude::*;
 
-/// Try to fit a retention time prediction model
+/// Try to fit an ion mobility prediction model. PSMs without an ion mobility
+/// measurement (`ims == 0.0`, e.g. non-timsTOF data) are ignored
 pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<()> {
+    if !features.iter().any(|feat| feat.ims > 0.0) {
+        return None;
+    }
     // Training LR might fail - not enough values, or r-squared is < 0.7
     let lr = match MobilityModel::fit(db, features) {
         Some(lr) => lr,
@@ -20,16 +24,31 @@ pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<()> {
             return None;
         }
     };
-    features.par_iter_mut().for_each(|feat| {
-        // LR can sometimes predict crazy values - clamp predicted RT
-        let ims = lr.predict_peptide(db, feat);
-        let bounded = ims.clamp(0.0, 2.0) as f32;
-        feat.predicted_ims = bounded;
+    features
+        .par_iter_mut()
+        .filter(|feat| feat.ims > 0.0)
+        .for_each(|feat| {
+            // LR can sometimes predict crazy values - clamp predicted RT
+            let ims = lr.predict_peptide(db, feat);
+            let bounded = ims.clamp(0.0, 2.0) as f32;
+            feat.predicted_ims = bounded;
 
-        feat.delta_ims_model = (feat.ims - bounded).abs();
-    });
+            feat.delta_ims_model = (feat.ims - bounded).abs();
+        });
     Some(())
 }
+
+/// Remove PSMs whose observed ion mobility differs from the predicted ion
+/// mobility (see [`predict`]) by more than `max_delta_ims`, returning the
+/// number of removed PSMs. PSMs without an ion mobility measurement or
+/// prediction are always retained
+pub fn filter(features: &mut Vec<Feature>, max_delta_ims: f32) -> usize {
+    let before = features.len();
+    features.retain(|feat| {
+        feat.ims <= 0.0 || feat.predicted_ims <= 0.0 || feat.delta_ims_model <= max_delta_ims
+    });
+    before - features.len()
+}
 pub struct MobilityModel {
     beta: Vec<f64>,
     map: [usize; 26],
@@ -158,7 +177,7 @@ impl MobilityModel {
 
         let ims = training_set
             .par_iter()
-            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01)
+            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01 && feat.ims > 0.0)
             .map(|psm| psm.ims as f64)
             .collect::<Vec<f64>>();
 
@@ -169,7 +188,7 @@ impl MobilityModel {
 
         let features = training_set
             .par_iter()
-            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01)
+            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01 && feat.ims > 0.0)
             .flat_map_iter(|psm| Self::embed(&db[psm.peptide_idx], &psm.charge, &map))
             .collect::<Vec<_>>();
 
@@ -292,4 +311,25 @@ mod test {
         assert_eq!(w_cterm_counts, vec![0.0, 1.0, 0.0, 0.0,], "W C-term counts");
         assert_eq!(i_cterm_counts, vec![0.0, 0.0, 0.0, 0.0,], "I C-term counts");
     }
+
+    #[test]
+    fn filter_by_delta_ims() {
+        let feature = |ims: f32, predicted_ims: f32| Feature {
+            ims,
+            predicted_ims,
+            delta_ims_model: (ims - predicted_ims).abs(),
+            ..Default::default()
+        };
+        let mut features = vec![
+            feature(0.9, 0.92),
+            feature(0.9, 1.1),
+            // No ion mobility measurement
+            feature(0.0, 0.0),
+            // Observed, but not predicted
+            feature(1.2, 0.0),
+        ];
+        assert_eq!(filter(&mut features, 0.05), 1);
+        assert_eq!(features.len(), 3);
+        assert!(features.iter().all(|feat| feat.predicted_ims != 1.1));
+    }
 }
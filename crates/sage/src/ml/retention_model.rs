@@ -9,6 +9,8 @@ use crate::mass::VALID_AA;
 use crate::peptide::Peptide;
 use crate::scoring::Feature;
 use rayon::prelude::*;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
 
 /// Try to fit a retention time prediction model
 pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<()> {
@@ -23,6 +25,42 @@ pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<()> {
     });
     Some(())
 }
+
+/// Source of externally predicted retention times, e.g. from a deep learning
+/// model trained outside of Sage
+pub trait RetentionTimePredictor {
+    /// Predicted retention time of `peptide`, in the same unit as the
+    /// retention time of the spectra it is matched to
+    fn predict_rt(&self, peptide: &Peptide) -> Option<f32>;
+}
+
+/// Predicted retention times keyed by modified sequence, as returned by
+/// [`Peptide::to_modified_string`]
+impl<S: BuildHasher> RetentionTimePredictor for HashMap<String, f32, S> {
+    fn predict_rt(&self, peptide: &Peptide) -> Option<f32> {
+        self.get(&peptide.to_modified_string()).copied()
+    }
+}
+
+/// Calculate the difference between observed and externally predicted
+/// retention time for each PSM. PSMs whose peptide has no prediction are left
+/// without a `delta_rt_external` value. Returns the number of PSMs with a
+/// prediction
+pub fn predict_external<P>(db: &IndexedDatabase, features: &mut [Feature], predictor: &P) -> usize
+where
+    P: RetentionTimePredictor + Sync,
+{
+    features
+        .par_iter_mut()
+        .map(|feat| {
+            feat.delta_rt_external = predictor
+                .predict_rt(&db[feat.peptide_idx])
+                .map(|predicted| feat.rt - predicted);
+            feat.delta_rt_external.is_some() as usize
+        })
+        .sum()
+}
+
 pub struct RetentionModel {
     beta: Vec<f64>,
     map: [usize; 26],
@@ -116,3 +154,52 @@ impl RetentionModel {
             .fold(0.0f64, |sum, (x, y)| sum + x * y)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Builder, PeptideIx};
+    use crate::fasta::Fasta;
+
+    #[test]
+    fn external_predictions() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            static_mods: Some([("M".to_string(), 15.9949.into())].into_iter().collect()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let find = |sequence: &[u8]| {
+            db.peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence)
+                .map(|ix| PeptideIx(ix as u32))
+                .unwrap()
+        };
+        let feature = |peptide_idx, rt| Feature {
+            peptide_idx,
+            rt,
+            ..Default::default()
+        };
+        let mut features = vec![
+            feature(find(b"AQLTQLK"), 21.5),
+            feature(find(b"LEQSMR"), 12.0),
+            feature(find(b"EQALLK"), 30.0),
+        ];
+
+        let predictions: HashMap<String, f32> = [
+            ("AQLTQLK".to_string(), 20.0),
+            ("LEQSM[+15.9949]R".to_string(), 14.0),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(predict_external(&db, &mut features, &predictions), 2);
+        assert_eq!(features[0].delta_rt_external, Some(1.5));
+        assert_eq!(features[1].delta_rt_external, Some(-2.0));
+        assert_eq!(features[2].delta_rt_external, None);
+    }
+}
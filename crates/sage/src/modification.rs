@@ -103,13 +103,64 @@ impl FromStr for ModificationSpecificity {
     }
 }
 
-pub fn validate_mods(input: Option<HashMap<String, f32>>) -> HashMap<ModificationSpecificity, f32> {
+impl ModificationSpecificity {
+    /// Parse a modification string, expanding terminal modifications that are
+    /// conditional on a set of residues (e.g. `$KR`: peptide C-terminus, if the
+    /// terminal residue is K or R) into one specificity per residue
+    pub fn parse_set(s: &str) -> Result<Vec<Self>, InvalidModification> {
+        match s.chars().next() {
+            Some(prefix @ ('^' | '$' | '[' | ']')) if s.len() > 2 => s[1..]
+                .chars()
+                .map(|residue| {
+                    if VALID_AA.contains(&(residue as u8)) {
+                        format!("{prefix}{residue}").parse()
+                    } else {
+                        Err(InvalidModification::InvalidResidue(residue))
+                    }
+                })
+                .collect(),
+            _ => s.parse().map(|m| vec![m]),
+        }
+    }
+}
+
+/// One or more static modifications on the same site. Multiple modifications
+/// stack, i.e. their masses are summed
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum StaticMass {
+    Single(f32),
+    Stacked(Vec<f32>),
+}
+
+impl StaticMass {
+    /// Combined mass of all modifications
+    pub fn mass(&self) -> f32 {
+        match self {
+            StaticMass::Single(mass) => *mass,
+            StaticMass::Stacked(masses) => masses.iter().sum(),
+        }
+    }
+}
+
+impl From<f32> for StaticMass {
+    fn from(mass: f32) -> Self {
+        StaticMass::Single(mass)
+    }
+}
+
+pub fn validate_mods<M: Into<StaticMass>>(
+    input: Option<HashMap<String, M>>,
+) -> HashMap<ModificationSpecificity, f32> {
     let mut output = HashMap::new();
     if let Some(input) = input {
         for (s, mass) in input {
-            match ModificationSpecificity::from_str(&s) {
-                Ok(m) => {
-                    output.insert(m, mass);
+            match ModificationSpecificity::parse_set(&s) {
+                Ok(specificities) => {
+                    let mass = mass.into().mass();
+                    for m in specificities {
+                        output.insert(m, mass);
+                    }
                 }
                 Err(InvalidModification::Empty) => {
                     log::error!("Invalid modification string: empty")
@@ -132,9 +183,11 @@ pub fn validate_var_mods(
     let mut output = HashMap::new();
     if let Some(input) = input {
         for (s, mass) in input {
-            match ModificationSpecificity::from_str(&s) {
-                Ok(m) => {
-                    output.insert(m, mass.data);
+            match ModificationSpecificity::parse_set(&s) {
+                Ok(specificities) => {
+                    for m in specificities {
+                        output.insert(m, mass.data.clone());
+                    }
                 }
                 Err(InvalidModification::Empty) => {
                     log::error!("Skipping invalid modification string: empty")
@@ -154,6 +207,112 @@ pub fn validate_var_mods(
     output
 }
 
+/// Add pyroglutamate formation on peptide N-terminal glutamine (-17.0265)
+/// and glutamic acid (-18.0106) to `variable_mods`, i.e. `^Q` and `^E`.
+/// Masses already configured for these sites are kept
+pub fn add_pyro_glu(variable_mods: &mut HashMap<ModificationSpecificity, Vec<f32>>) {
+    for (resi, name) in [(b'Q', "Gln->pyro-Glu"), (b'E', "Glu->pyro-Glu")] {
+        let mass = named_modification(name).expect("built-in modification");
+        let masses = variable_mods
+            .entry(ModificationSpecificity::PeptideN(Some(resi)))
+            .or_default();
+        if !masses.iter().any(|m| (m - mass).abs() < 1E-4) {
+            masses.push(mass);
+        }
+    }
+}
+
+/// Parse groups of mutually exclusive variable modifications, see
+/// [`crate::peptide::Peptide::apply_exclusive`]. Each group is a list of
+/// modification strings, using the same syntax as `variable_mods` keys
+pub fn validate_exclusive_mods(
+    input: Option<Vec<Vec<String>>>,
+) -> Vec<Vec<ModificationSpecificity>> {
+    let mut output = Vec::new();
+    for group in input.unwrap_or_default() {
+        let mut specificities = Vec::new();
+        for s in group {
+            match ModificationSpecificity::parse_set(&s) {
+                Ok(parsed) => specificities.extend(parsed),
+                Err(InvalidModification::Empty) => {
+                    log::error!("Skipping invalid exclusive modification string: empty")
+                }
+                Err(InvalidModification::InvalidResidue(c)) => {
+                    log::error!(
+                        "Skipping invalid exclusive modification string: unrecognized residue ({})",
+                        c
+                    )
+                }
+                Err(InvalidModification::TooLong(s)) => {
+                    log::error!(
+                        "Skipping invalid exclusive modification string: {} is too long",
+                        s
+                    )
+                }
+            }
+        }
+        if specificities.len() > 1 {
+            output.push(specificities);
+        }
+    }
+    output
+}
+
+/// Monoisotopic delta masses of commonly used modifications, by Unimod name
+/// and accession
+const NAMED_MODIFICATIONS: &[(&str, u32, f32)] = &[
+    ("Acetyl", 1, 42.010565),
+    ("Amidated", 2, -0.984016),
+    ("Carbamidomethyl", 4, 57.021464),
+    ("Carbamyl", 5, 43.005814),
+    ("Deamidated", 7, 0.984016),
+    ("GlyGly", 121, 114.04293),
+    ("Methyl", 34, 14.01565),
+    ("Dimethyl", 36, 28.0313),
+    ("Trimethyl", 37, 42.04695),
+    ("Oxidation", 35, 15.994915),
+    ("Dioxidation", 425, 31.989829),
+    ("Phospho", 21, 79.96633),
+    ("Gln->pyro-Glu", 28, -17.026549),
+    ("Glu->pyro-Glu", 27, -18.010565),
+    ("iTRAQ4plex", 214, 144.10207),
+    ("iTRAQ8plex", 730, 304.20536),
+    ("TMT", 738, 224.15248),
+    ("TMT6plex", 737, 229.16293),
+    ("TMTpro", 2016, 304.20715),
+    ("Label:13C(6)", 188, 6.020129),
+    ("Label:13C(6)15N(2)", 259, 8.014199),
+    ("Label:13C(6)15N(4)", 267, 10.008269),
+];
+
+/// Look up the monoisotopic delta mass of a modification by (case-insensitive)
+/// Unimod name, e.g. "Oxidation", or accession, e.g. "UNIMOD:35"
+pub fn named_modification(name: &str) -> Option<f32> {
+    let accession = name
+        .get(..7)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("UNIMOD:"))
+        .and_then(|_| name[7..].parse::<u32>().ok());
+
+    NAMED_MODIFICATIONS
+        .iter()
+        .find(|(n, id, _)| match accession {
+            Some(accession) => *id == accession,
+            None => n.eq_ignore_ascii_case(name),
+        })
+        .map(|(_, _, mass)| *mass)
+}
+
+/// Unimod name of the commonly used modification with a delta mass closest
+/// to `mass`, if it is within `tolerance` Da
+pub fn closest_named_modification(mass: f32, tolerance: f32) -> Option<&'static str> {
+    NAMED_MODIFICATIONS
+        .iter()
+        .map(|(name, _, delta)| (*name, (delta - mass).abs()))
+        .filter(|(_, error)| *error <= tolerance)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(name, _)| name)
+}
+
 #[derive(Default)]
 pub struct ValueOrVec {
     data: Vec<f32>,
@@ -226,5 +385,37 @@ mod test {
             "Z".parse::<ModificationSpecificity>(),
             Err(InvalidResidue('Z'))
         );
+        assert_eq!(
+            ModificationSpecificity::parse_set("$KR"),
+            Ok(vec![PeptideC(Some(b'K')), PeptideC(Some(b'R'))])
+        );
+        assert_eq!(
+            ModificationSpecificity::parse_set("^Q"),
+            Ok(vec![PeptideN(Some(b'Q'))])
+        );
+        assert_eq!(
+            ModificationSpecificity::parse_set("$KZ"),
+            Err(InvalidResidue('Z'))
+        );
+        assert_eq!(
+            ModificationSpecificity::parse_set("KRH"),
+            Err(TooLong("KRH".into()))
+        );
+    }
+
+    #[test]
+    fn named_modifications() {
+        assert_eq!(named_modification("Oxidation"), Some(15.994915));
+        assert_eq!(named_modification("oxidation"), Some(15.994915));
+        assert_eq!(named_modification("UNIMOD:35"), Some(15.994915));
+        assert_eq!(named_modification("unimod:21"), Some(79.96633));
+        assert_eq!(named_modification("Gln->pyro-Glu"), Some(-17.026549));
+        assert_eq!(named_modification("UNIMOD:999999"), None);
+        assert_eq!(named_modification("Foo"), None);
+
+        assert_eq!(closest_named_modification(15.99, 0.01), Some("Oxidation"));
+        assert_eq!(closest_named_modification(42.04, 0.05), Some("Trimethyl"));
+        assert_eq!(closest_named_modification(42.02, 0.05), Some("Acetyl"));
+        assert_eq!(closest_named_modification(0.0, 0.01), None);
     }
 }
@@ -0,0 +1,149 @@
+//! Precursor delta mass histograms for open searches
+//!
+//! After a wide precursor tolerance search, common modifications show up as
+//! peaks in the distribution of experimental - calculated precursor masses,
+//! e.g. +15.9949 for oxidation or +79.9663 for phosphorylation
+
+use crate::modification::closest_named_modification;
+use crate::scoring::Feature;
+use serde::Serialize;
+
+/// Histogram of precursor delta masses (experimental - calculated, in Da),
+/// using bins of `bin_width` Da centered on multiples of `bin_width`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeltaMassHistogram {
+    pub bin_width: f32,
+    /// Index of the first bin, i.e. the first bin is centered on
+    /// `offset * bin_width`
+    pub offset: i64,
+    pub counts: Vec<usize>,
+}
+
+/// A local maximum of a [`DeltaMassHistogram`]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeltaMassPeak {
+    /// Delta mass at the center of the bin
+    pub mass: f32,
+    pub count: usize,
+    /// Unimod name of a known modification with a matching delta mass
+    pub modification: Option<&'static str>,
+}
+
+impl DeltaMassHistogram {
+    /// Bin the delta masses of rank 1 target PSMs in `features`. PSMs should
+    /// generally be filtered by q-value first
+    pub fn new(features: &[Feature], bin_width: f32) -> Self {
+        assert!(bin_width > 0.0, "bin width must be positive");
+        let bins = features
+            .iter()
+            .filter(|feat| feat.rank == 1 && feat.label == 1)
+            .map(|feat| ((feat.expmass - feat.calcmass) / bin_width).round() as i64)
+            .collect::<Vec<_>>();
+
+        let (lo, hi) = match (bins.iter().min(), bins.iter().max()) {
+            (Some(lo), Some(hi)) => (*lo, *hi),
+            _ => {
+                return DeltaMassHistogram {
+                    bin_width,
+                    offset: 0,
+                    counts: Vec::new(),
+                }
+            }
+        };
+
+        let mut counts = vec![0; (hi - lo) as usize + 1];
+        for bin in bins {
+            counts[(bin - lo) as usize] += 1;
+        }
+        DeltaMassHistogram {
+            bin_width,
+            offset: lo,
+            counts,
+        }
+    }
+
+    /// Delta mass at the center of bin `idx`
+    pub fn center(&self, idx: usize) -> f32 {
+        (self.offset + idx as i64) as f32 * self.bin_width
+    }
+
+    /// Bins with at least `min_count` PSMs that are local maxima of the
+    /// histogram, sorted by decreasing count. Peaks are annotated with a known
+    /// modification if its delta mass is within `tolerance` Da of the bin center
+    pub fn peaks(&self, min_count: usize, tolerance: f32) -> Vec<DeltaMassPeak> {
+        let mut peaks = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(idx, &count)| {
+                // Plateaus are reported once, at their first bin
+                let left = idx.checked_sub(1).map(|i| self.counts[i]).unwrap_or(0);
+                let right = self.counts.get(idx + 1).copied().unwrap_or(0);
+                count >= min_count.max(1) && count > left && count >= right
+            })
+            .map(|(idx, &count)| {
+                let mass = self.center(idx);
+                DeltaMassPeak {
+                    mass,
+                    count,
+                    modification: closest_named_modification(mass, tolerance),
+                }
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by_key(|peak| std::cmp::Reverse(peak.count));
+        peaks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delta_mass_histogram() {
+        let psm = |delta: f32, label| Feature {
+            expmass: 1000.0 + delta,
+            calcmass: 1000.0,
+            label,
+            rank: 1,
+            ..Default::default()
+        };
+        let mut features = vec![
+            psm(0.0, 1),
+            psm(0.001, 1),
+            psm(-0.002, 1),
+            psm(15.992, 1),
+            psm(15.993, 1),
+            psm(79.966, 1),
+            psm(79.967, 1),
+            psm(79.966, 1),
+            psm(79.968, 1),
+            psm(42.5, 1),
+            // Decoys and lower ranked PSMs are ignored
+            psm(42.5, -1),
+            psm(42.5, -1),
+        ];
+        features.push(Feature {
+            rank: 2,
+            ..psm(42.5, 1)
+        });
+
+        let histogram = DeltaMassHistogram::new(&features, 0.01);
+        assert_eq!(histogram.counts.iter().sum::<usize>(), 10);
+        assert_eq!(histogram.center(0), 0.0);
+        assert!((histogram.center(histogram.counts.len() - 1) - 79.97).abs() < 1E-4);
+
+        let peaks = histogram.peaks(2, 0.01);
+        assert_eq!(peaks.len(), 3);
+        assert_eq!(peaks[0].count, 4);
+        assert!((peaks[0].mass - 79.97).abs() < 1E-4);
+        assert_eq!(peaks[0].modification, Some("Phospho"));
+        assert_eq!(peaks[1].count, 3);
+        assert_eq!(peaks[1].mass, 0.0);
+        assert_eq!(peaks[1].modification, None);
+        assert_eq!(peaks[2].count, 2);
+        assert_eq!(peaks[2].modification, Some("Oxidation"));
+
+        assert!(DeltaMassHistogram::new(&[], 0.01).peaks(1, 0.01).is_empty());
+    }
+}
@@ -2,12 +2,13 @@ use std::cmp::Ordering;
 use std::{collections::HashMap, fmt::Debug, sync::Arc};
 
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::Rng;
 
-use crate::modification::ModificationSpecificity;
+use crate::modification::{named_modification, ModificationSpecificity};
 use crate::{
     enzyme::{Digest, Position},
-    mass::{monoisotopic, H2O},
+    isotopes::{isotope_distribution, peptide_formula},
+    mass::{monoisotopic, H2O, NEUTRON, PROTON},
 };
 use fnv::FnvHashSet;
 use itertools::Itertools;
@@ -21,14 +22,24 @@ pub struct Peptide {
     pub nterm: Option<f32>,
     /// Modification on peptide C-terminus
     pub cterm: Option<f32>,
+    /// Is `nterm` a protein N-terminal (rather than peptide N-terminal) modification?
+    pub protein_nterm: bool,
+    /// Is `cterm` a protein C-terminal (rather than peptide C-terminal) modification?
+    pub protein_cterm: bool,
     /// Monoisotopic mass, inclusive of N/C-terminal mods
     pub monoisotopic: f32,
     /// Number of missed cleavages for this sequence
     pub missed_cleavages: u8,
     /// Is this a semi-enzymatic peptide?
     pub semi_enzymatic: bool,
+    /// Number of enzymatic termini (0, 1 or 2)
+    pub enzymatic_termini: u8,
+    /// Was this peptide generated by ragged N-terminal digestion?
+    pub ragged_nterm: bool,
     /// Where is this peptide located in the protein?
     pub position: Position,
+    /// Isotopic label channel this peptide was generated for, if any
+    pub label_channel: Option<u8>,
 
     pub proteins: Vec<Arc<String>>,
 }
@@ -66,9 +77,13 @@ impl Debug for Peptide {
             )
             .field("nterm", &self.nterm)
             .field("cterm", &self.cterm)
+            .field("protein_nterm", &self.protein_nterm)
+            .field("protein_cterm", &self.protein_cterm)
             .field("monoisotopic", &self.monoisotopic)
             .field("missed_cleavages", &self.missed_cleavages)
+            .field("enzymatic_termini", &self.enzymatic_termini)
             .field("position", &self.position)
+            .field("label_channel", &self.label_channel)
             .finish()
     }
 }
@@ -81,6 +96,26 @@ impl Peptide {
         }
     }
 
+    /// Theoretical precursor m/z of this peptide (including all modifications)
+    /// at `charge`, i.e. [M+zH]z+. A charge of 0 is treated as 1
+    pub fn mz(&self, charge: u8) -> f32 {
+        let charge = charge.max(1) as f32;
+        (self.monoisotopic + charge * PROTON) / charge
+    }
+
+    /// Theoretical precursor isotope envelope of this peptide (including all
+    /// modifications), as (neutral mass, relative intensity) pairs for the
+    /// first `max_isotopes` isotopes. Intensities are relative to the most
+    /// abundant isotope, see [`crate::isotopes::isotope_distribution`]
+    pub fn isotope_distribution(&self, max_isotopes: usize) -> Vec<(f32, f32)> {
+        let formula = peptide_formula(&self.sequence, self.monoisotopic);
+        isotope_distribution(&formula, max_isotopes)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, intensity)| (self.monoisotopic + idx as f32 * NEUTRON, intensity))
+            .collect()
+    }
+
     pub fn proteins(&self, decoy_tag: &str, generate_decoys: bool) -> String {
         if self.decoy {
             self.proteins
@@ -148,6 +183,18 @@ impl Peptide {
                     self.cterm = self.cterm.or(Some(0.0)).map(|x| x + mass);
                 }
             }
+            Site::ProteinNterm => {
+                if self.nterm.is_none() {
+                    self.nterm = Some(mass);
+                    self.protein_nterm = true;
+                }
+            }
+            Site::ProteinCterm => {
+                if self.cterm.is_none() {
+                    self.cterm = Some(mass);
+                    self.protein_cterm = true;
+                }
+            }
             Site::Sequence(index) => {
                 if self.modifications[index as usize] == 0.0 {
                     self.modifications[index as usize] += mass;
@@ -156,6 +203,15 @@ impl Peptide {
         }
     }
 
+    /// Index of the residue modified at `site`
+    fn site_residue(&self, site: Site) -> usize {
+        match site {
+            Site::Nterm | Site::ProteinNterm => 0,
+            Site::Cterm | Site::ProteinCterm => self.sequence.len().saturating_sub(1),
+            Site::Sequence(index) => index as usize,
+        }
+    }
+
     fn push_resi(&self, acc: &mut Vec<(Site, f32)>, target: ModificationSpecificity, mass: f32) {
         match (target, self.position) {
             (ModificationSpecificity::PeptideN(None), _) => acc.push((Site::Nterm, mass)),
@@ -174,7 +230,7 @@ impl Peptide {
                 ))
             }
             (ModificationSpecificity::ProteinN(None), Position::Nterm | Position::Full) => {
-                acc.push((Site::Nterm, mass))
+                acc.push((Site::ProteinNterm, mass))
             }
             (ModificationSpecificity::ProteinN(Some(resi)), Position::Nterm | Position::Full)
                 if resi == *self.sequence.first().unwrap_or(&0) =>
@@ -182,7 +238,7 @@ impl Peptide {
                 acc.push((Site::Sequence(0), mass))
             }
             (ModificationSpecificity::ProteinC(None), Position::Cterm | Position::Full) => {
-                acc.push((Site::Cterm, mass))
+                acc.push((Site::ProteinCterm, mass))
             }
             (ModificationSpecificity::ProteinC(Some(resi)), Position::Cterm | Position::Full)
                 if resi == *self.sequence.last().unwrap_or(&0) =>
@@ -228,7 +284,7 @@ impl Peptide {
                 )
             }
             (ModificationSpecificity::ProteinN(None), Position::Nterm | Position::Full) => {
-                self.apply_site(Site::Nterm, mass)
+                self.apply_site(Site::ProteinNterm, mass)
             }
             (ModificationSpecificity::ProteinN(Some(resi)), Position::Nterm | Position::Full)
                 if resi == *self.sequence.first().unwrap_or(&0) =>
@@ -236,7 +292,7 @@ impl Peptide {
                 self.apply_site(Site::Sequence(0), mass)
             }
             (ModificationSpecificity::ProteinC(None), Position::Cterm | Position::Full) => {
-                self.apply_site(Site::Cterm, mass)
+                self.apply_site(Site::ProteinCterm, mass)
             }
             (ModificationSpecificity::ProteinC(Some(resi)), Position::Cterm | Position::Full)
                 if resi == *self.sequence.last().unwrap_or(&0) =>
@@ -258,35 +314,124 @@ impl Peptide {
     }
 
     /// Apply variable modifications, then static modifications to a peptide
+    ///
+    /// Only a single modification is applied to each site. Static mods are
+    /// applied in a fixed order (see [`static_mod_order`]), so that when several
+    /// of them target the same site, the most specific one always wins
     pub fn apply(
+        self,
+        variable_mods: &[(ModificationSpecificity, f32)],
+        static_mods: &HashMap<ModificationSpecificity, f32>,
+        combinations: usize,
+    ) -> Vec<Peptide> {
+        self.apply_exclusive(variable_mods, static_mods, combinations, &[])
+    }
+
+    /// Same as [`Peptide::apply`], but variable modifications with
+    /// specificities in the same group of `exclusive` are never applied to the
+    /// same residue. Terminal modifications are considered to be on the
+    /// terminal residue, e.g. `["^Q", "^"]` prevents combining pyro-glu
+    /// formation of an N-terminal Q with a peptide N-terminal modification
+    pub fn apply_exclusive(
+        self,
+        variable_mods: &[(ModificationSpecificity, f32)],
+        static_mods: &HashMap<ModificationSpecificity, f32>,
+        combinations: usize,
+        exclusive: &[Vec<ModificationSpecificity>],
+    ) -> Vec<Peptide> {
+        self.apply_within(
+            variable_mods,
+            static_mods,
+            combinations,
+            exclusive,
+            f32::NEG_INFINITY..=f32::INFINITY,
+        )
+    }
+
+    /// Same as [`Peptide::apply_exclusive`], but modified forms with a
+    /// monoisotopic mass outside of `mass_range` are pruned before they are
+    /// generated. This keeps searches with many variable modifications
+    /// tractable, since most combinations are never materialized.
+    ///
+    /// The mass of each combination is estimated from the masses of its
+    /// modifications, allowing for a small rounding error, so forms very
+    /// close to the bounds may still be returned
+    pub fn apply_within(
         mut self,
         variable_mods: &[(ModificationSpecificity, f32)],
         static_mods: &HashMap<ModificationSpecificity, f32>,
         combinations: usize,
+        exclusive: &[Vec<ModificationSpecificity>],
+        mass_range: std::ops::RangeInclusive<f32>,
     ) -> Vec<Peptide> {
+        let static_mods = static_mod_order(static_mods);
         if variable_mods.is_empty() {
-            for (target, mass) in static_mods {
+            for (target, mass) in &static_mods {
                 self.static_mods(*target, *mass);
             }
             self.monoisotopic += self.modification_mass();
             vec![self]
         } else {
             let mut mods = Vec::new();
+            // Specificity of the variable mod that generated each entry of `mods`
+            let mut targets = Vec::new();
             for (residue, mass) in variable_mods.iter() {
                 self.push_resi(&mut mods, *residue, *mass);
+                targets.resize(mods.len(), *residue);
             }
 
+            // Static mods that would be applied to each site of the unmodified
+            // peptide. A variable mod replaces the static mod on its site
+            let mut fixed = self.clone();
+            for (target, mass) in &static_mods {
+                fixed.static_mods(*target, *mass);
+            }
+            let static_mass = fixed.modification_mass();
+            let static_at = |site: Site| match site {
+                Site::Nterm | Site::ProteinNterm => fixed.nterm.unwrap_or(0.0),
+                Site::Cterm | Site::ProteinCterm => fixed.cterm.unwrap_or(0.0),
+                Site::Sequence(index) => fixed.modifications[index as usize],
+            };
+            let (lo, hi) = (
+                mass_range.start() - MASS_RANGE_SLACK,
+                mass_range.end() + MASS_RANGE_SLACK,
+            );
+            let in_range = |mass: f32| mass >= lo && mass <= hi;
+
             let mut modified = Vec::new();
-            modified.push(self.clone());
+            if in_range(self.monoisotopic + static_mass) {
+                modified.push(self.clone());
+            }
 
             for n in 1..=combinations {
-                'next: for combination in mods.iter().combinations(n).filter(no_duplicates) {
+                'next: for indices in (0..mods.len()).combinations(n) {
+                    let combination = indices.iter().map(|&i| &mods[i]).collect::<Vec<_>>();
+                    if !no_duplicates(&combination) {
+                        continue;
+                    }
                     let mut set = FnvHashSet::default();
                     for (site, _) in &combination {
                         if !set.insert(*site) {
                             continue 'next;
                         }
                     }
+                    if !exclusive.is_empty()
+                        && indices.iter().tuple_combinations().any(|(&a, &b)| {
+                            self.site_residue(mods[a].0) == self.site_residue(mods[b].0)
+                                && exclusive.iter().any(|group| {
+                                    group.contains(&targets[a]) && group.contains(&targets[b])
+                                })
+                        })
+                    {
+                        continue;
+                    }
+                    let delta = combination
+                        .iter()
+                        .map(|(site, mass)| mass - static_at(*site))
+                        .sum::<f32>();
+                    if !in_range(self.monoisotopic + static_mass + delta) {
+                        continue;
+                    }
                     let mut peptide = self.clone();
                     for (site, mass) in combination {
                         peptide.apply_site(*site, *mass);
@@ -297,7 +442,7 @@ impl Peptide {
 
             // Apply static mods to all peptides
             for peptide in modified.iter_mut() {
-                for (target, mass) in static_mods {
+                for (target, mass) in &static_mods {
                     peptide.static_mods(*target, *mass);
                 }
                 peptide.monoisotopic += peptide.modification_mass();
@@ -334,19 +479,20 @@ impl Peptide {
         pep
     }
 
-    pub fn shuffle(&self, keep_ends: Option<bool>) -> Peptide {
+    /// Shuffle the sequence (and modifications) of this peptide to generate a
+    /// decoy. All randomness is drawn from `rng`, so that decoys are reproducible
+    pub fn shuffle<R: Rng>(&self, keep_ends: Option<bool>, rng: &mut R) -> Peptide {
         let mut pep = self.clone();
         pep.decoy = !pep.decoy;
         let n = pep.sequence.len();
         if n > 1 {
             let mut s = Vec::from(pep.sequence.as_ref());
             let mut m = pep.modifications.clone();
-            let mut rng = thread_rng();
 
             if keep_ends.unwrap_or(true) {
                 if n > 2 {
                     let mut indices: Vec<usize> = (1..n-1).collect();
-                    indices.shuffle(&mut rng);
+                    indices.shuffle(rng);
 
                     let mut s_shuffled = s.clone();
                     let mut m_shuffled = m.clone();
@@ -359,7 +505,7 @@ impl Peptide {
                 }
             } else {
                 let mut indices: Vec<usize> = (0..n).collect();
-                indices.shuffle(&mut rng);
+                indices.shuffle(rng);
 
                 let mut s_shuffled = s.clone();
                 let mut m_shuffled = m.clone();
@@ -378,13 +524,39 @@ impl Peptide {
     }
 }
 
+/// Allowed rounding error (in Da) of the estimated mass of modified forms, see
+/// [`Peptide::apply_within`]
+const MASS_RANGE_SLACK: f32 = 0.01;
+
+/// Sort static modifications into the order they are applied in: protein
+/// terminal mods, then peptide terminal mods, then residue mods. Ties are broken
+/// by the ordering of [`ModificationSpecificity`], so the result never depends
+/// on `HashMap` iteration order
+fn static_mod_order(
+    static_mods: &HashMap<ModificationSpecificity, f32>,
+) -> Vec<(ModificationSpecificity, f32)> {
+    let mut ordered = static_mods
+        .iter()
+        .map(|(target, mass)| (*target, *mass))
+        .collect::<Vec<_>>();
+    ordered.sort_unstable_by_key(|(target, _)| {
+        let precedence = match target {
+            ModificationSpecificity::ProteinN(_) | ModificationSpecificity::ProteinC(_) => 0,
+            ModificationSpecificity::PeptideN(_) | ModificationSpecificity::PeptideC(_) => 1,
+            ModificationSpecificity::Residue(_) => 2,
+        };
+        (precedence, *target)
+    });
+    ordered
+}
+
 fn no_duplicates(combination: &Vec<&(Site, f32)>) -> bool {
     let mut n = 0;
     let mut c = 0;
     for (site, _) in combination {
         match site {
-            Site::Nterm => n += 1,
-            Site::Cterm => c += 1,
+            Site::Nterm | Site::ProteinNterm => n += 1,
+            Site::Cterm | Site::ProteinCterm => c += 1,
             _ => {}
         }
     }
@@ -396,14 +568,55 @@ fn no_duplicates(combination: &Vec<&(Site, f32)>) -> bool {
 enum Site {
     Nterm,
     Cterm,
+    ProteinNterm,
+    ProteinCterm,
     Sequence(u32),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PeptideError {
     InvalidSequence(String),
+    /// A modified-sequence string could not be parsed, at byte offset `position`
+    MalformedSequence {
+        sequence: String,
+        position: usize,
+        reason: &'static str,
+    },
+    /// A bracketed modification is neither a delta mass nor a known
+    /// modification name
+    UnknownModification {
+        sequence: String,
+        modification: String,
+    },
 }
 
+impl std::fmt::Display for PeptideError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeptideError::InvalidSequence(s) => write!(f, "invalid peptide sequence: {}", s),
+            PeptideError::MalformedSequence {
+                sequence,
+                position,
+                reason,
+            } => write!(
+                f,
+                "malformed peptide sequence {} at position {}: {}",
+                sequence, position, reason
+            ),
+            PeptideError::UnknownModification {
+                sequence,
+                modification,
+            } => write!(
+                f,
+                "unknown modification [{}] in peptide sequence {}",
+                modification, sequence
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PeptideError {}
+
 impl TryFrom<Digest> for Peptide {
     type Error = PeptideError;
 
@@ -430,16 +643,199 @@ impl TryFrom<Digest> for Peptide {
             monoisotopic: mass,
             nterm: None,
             cterm: None,
+            protein_nterm: false,
+            protein_cterm: false,
             missed_cleavages: value.missed_cleavages,
             semi_enzymatic: value.semi_enzymatic,
+            enzymatic_termini: value.enzymatic_termini,
+            ragged_nterm: value.ragged_nterm,
+            label_channel: None,
             proteins: vec![value.protein],
         })
     }
 }
 
-impl std::fmt::Display for Peptide {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Peptide {
+    /// Does this peptide end in one of `residues`? Peptides at the protein
+    /// C-terminus legitimately lack a cleavage site, and always pass
+    pub fn cterm_cleaved(&self, residues: &[u8]) -> bool {
+        matches!(self.position, Position::Cterm | Position::Full)
+            || self
+                .sequence
+                .last()
+                .map(|residue| residues.contains(residue))
+                .unwrap_or(false)
+    }
+
+    /// Does this peptide have a tryptic C-terminus (K or R), or lie at the
+    /// protein C-terminus?
+    pub fn is_tryptic_cterm(&self) -> bool {
+        self.cterm_cleaved(b"KR")
+    }
+
+    /// Canonical modified-sequence representation of this peptide, e.g.
+    /// `n[+229.1629]PEPT[+79.9663]IDEK`.
+    ///
+    /// N- and C-terminal modifications are written as `n[...]` and `c[...]`,
+    /// or as `pn[...]` and `pc[...]` if they are protein-terminal modifications,
+    /// and residue modifications (static and variable) directly follow the
+    /// modified residue. Delta masses always have an explicit sign and four
+    /// decimal places, so the string is stable and can be parsed back with
+    /// [`Peptide::from_modified_string`]
+    pub fn to_modified_string(&self) -> String {
+        let mut s = String::with_capacity(self.sequence.len() * 2);
+        if let Some(m) = self.nterm.filter(|m| *m != 0.0) {
+            let prefix = if self.protein_nterm { "pn" } else { "n" };
+            s.push_str(&format!("{}[{:+.4}]", prefix, m));
+        }
+        for (c, m) in self.sequence.iter().zip(self.modifications.iter()) {
+            s.push(*c as char);
+            if *m != 0.0 {
+                s.push_str(&format!("[{:+.4}]", m));
+            }
+        }
+        if let Some(m) = self.cterm.filter(|m| *m != 0.0) {
+            let prefix = if self.protein_cterm { "pc" } else { "c" };
+            s.push_str(&format!("{}[{:+.4}]", prefix, m));
+        }
+        s
+    }
+
+    /// Parse a peptide from a modified-sequence string, such as the
+    /// representation produced by [`Peptide::to_modified_string`].
+    ///
+    /// Modifications are enclosed in brackets directly following the modified
+    /// residue, and may be given either as a delta mass (`M[+15.9949]`) or as
+    /// a Unimod name or accession (`M[Oxidation]`, `M[UNIMOD:35]`). Terminal
+    /// modifications are written as an `n[...]` prefix and a `c[...]` suffix,
+    /// or `pn[...]` and `pc[...]` for protein-terminal modifications.
+    /// The returned peptide has no protein assignments, and is not a decoy
+    pub fn from_modified_string(s: &str) -> Result<Peptide, PeptideError> {
+        let malformed = |position: usize, reason: &'static str| PeptideError::MalformedSequence {
+            sequence: s.to_string(),
+            position,
+            reason,
+        };
+
+        // Parse the bracketed modification starting at byte offset `start`,
+        // returning the delta mass and the offset following the closing bracket
+        let bracket = |start: usize| -> Result<(f32, usize), PeptideError> {
+            let len = s[start..]
+                .find(']')
+                .ok_or_else(|| malformed(start, "unclosed bracket"))?;
+            let inner = &s[start + 1..start + len];
+            if inner.is_empty() {
+                return Err(malformed(start, "empty modification"));
+            }
+            let mass = inner
+                .parse::<f32>()
+                .ok()
+                .or_else(|| named_modification(inner))
+                .ok_or_else(|| PeptideError::UnknownModification {
+                    sequence: s.to_string(),
+                    modification: inner.to_string(),
+                })?;
+            Ok((mass, start + len + 1))
+        };
+
+        let mut nterm = None;
+        let mut cterm = None;
+        let mut protein_nterm = false;
+        let mut protein_cterm = false;
+        let mut sequence = String::with_capacity(s.len());
+        let mut modifications: Vec<f32> = Vec::with_capacity(s.len());
+
+        let bytes = s.as_bytes();
+        let mut idx = 0;
+        if s.starts_with("pn") || s.starts_with('n') {
+            protein_nterm = s.starts_with('p');
+            let open = if protein_nterm { 2 } else { 1 };
+            if bytes.get(open) != Some(&b'[') {
+                return Err(malformed(open, "expected '[' after N-terminal 'n'"));
+            }
+            let (mass, next) = bracket(open)?;
+            nterm = Some(mass);
+            idx = next;
+        }
+
+        while idx < bytes.len() {
+            match bytes[idx] {
+                b'c' | b'p' => {
+                    protein_cterm = bytes[idx] == b'p';
+                    if protein_cterm && bytes.get(idx + 1) != Some(&b'c') {
+                        return Err(malformed(idx + 1, "expected 'c' after 'p'"));
+                    }
+                    let open = if protein_cterm { idx + 2 } else { idx + 1 };
+                    if bytes.get(open) != Some(&b'[') {
+                        return Err(malformed(open, "expected '[' after C-terminal 'c'"));
+                    }
+                    let (mass, next) = bracket(open)?;
+                    if next != bytes.len() {
+                        return Err(malformed(next, "C-terminal modification must come last"));
+                    }
+                    cterm = Some(mass);
+                    idx = next;
+                }
+                b'[' => {
+                    let (mass, next) = bracket(idx)?;
+                    match modifications.last_mut() {
+                        Some(m) if *m == 0.0 => *m = mass,
+                        Some(_) => return Err(malformed(idx, "residue is already modified")),
+                        None => return Err(malformed(idx, "modification without a residue")),
+                    }
+                    idx = next;
+                }
+                b']' => return Err(malformed(idx, "unopened bracket")),
+                c if c.is_ascii() && monoisotopic(c) != 0.0 => {
+                    sequence.push(c as char);
+                    modifications.push(0.0);
+                    idx += 1;
+                }
+                _ => return Err(malformed(idx, "invalid amino acid")),
+            }
+        }
+
+        if sequence.is_empty() {
+            return Err(malformed(idx, "no amino acids"));
+        }
+
+        let digest = Digest {
+            sequence,
+            position: Position::Full,
+            ..Default::default()
+        };
+        let mut peptide = Peptide::try_from(digest)?;
+        peptide.proteins.clear();
+        peptide.modifications = modifications;
+        peptide.nterm = nterm;
+        peptide.cterm = cterm;
+        peptide.protein_nterm = protein_nterm;
+        peptide.protein_cterm = protein_cterm;
+        peptide.monoisotopic += peptide.modification_mass();
+        Ok(peptide)
+    }
+}
+
+impl Peptide {
+    /// Format the peptide as its [`Display`](std::fmt::Display) representation,
+    /// but additionally mark protein-terminal modifications with a `pn`/`pc`
+    /// prefix, e.g. `pn[+42.0106]-MPEPTIDEK` or `PEPTIDEK-pc[-0.984]`
+    pub fn to_protein_terminal_string(&self) -> String {
+        let mut s = String::with_capacity(self.sequence.len() * 2);
+        // Writing to a `String` never fails
+        let _ = self.write_sequence(&mut s, true);
+        s
+    }
+
+    fn write_sequence<W: std::fmt::Write>(
+        &self,
+        f: &mut W,
+        protein_terminal: bool,
+    ) -> std::fmt::Result {
         if let Some(m) = self.nterm {
+            if protein_terminal && self.protein_nterm {
+                write!(f, "pn")?;
+            }
             write!(f, "[{:+}]-", m)?;
         }
         for (c, m) in self.sequence.iter().zip(self.modifications.iter()) {
@@ -450,12 +846,22 @@ impl std::fmt::Display for Peptide {
             }
         }
         if let Some(m) = self.cterm {
-            write!(f, "-[{:+}]", m)?;
+            write!(f, "-")?;
+            if protein_terminal && self.protein_cterm {
+                write!(f, "pc")?;
+            }
+            write!(f, "[{:+}]", m)?;
         }
         Ok(())
     }
 }
 
+impl std::fmt::Display for Peptide {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_sequence(f, false)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::enzyme::{Enzyme, EnzymeParameters};
@@ -483,6 +889,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 0,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -617,6 +1024,100 @@ mod test {
         assert_eq!(peptides, expected);
     }
 
+    #[test]
+    fn variable_mods_within_mass_range() {
+        use ModificationSpecificity::*;
+        let variable_mods = [
+            (PeptideN(None), 42.0106),
+            (PeptideN(Some(b'Q')), -17.0265),
+            (Residue(b'M'), 15.9949),
+            (Residue(b'C'), 10.0),
+            (Residue(b'K'), 114.0429),
+        ];
+        // Static mods on sites that are also variably modified are replaced
+        let static_mods = [
+            (PeptideN(None), 229.1629),
+            (Residue(b'C'), 57.0215),
+            (Residue(b'K'), 229.1629),
+        ]
+        .into();
+        let peptide = Peptide::try_from(Digest {
+            sequence: "QCMGCMK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let all = peptide
+            .clone()
+            .apply(&variable_mods, &static_mods, 3)
+            .into_iter()
+            .map(|p| (p.to_string(), p.monoisotopic))
+            .collect::<Vec<_>>();
+        let masses = all.iter().map(|(_, mass)| *mass).collect::<Vec<_>>();
+        let lo = masses.iter().copied().fold(f32::MAX, f32::min);
+        let hi = masses.iter().copied().fold(f32::MIN, f32::max);
+
+        for (min, max) in [(lo, hi), (lo + 50.0, hi - 50.0), (hi + 1.0, hi + 2.0)] {
+            let within = peptide
+                .clone()
+                .apply_within(&variable_mods, &static_mods, 3, &[], min..=max)
+                .into_iter()
+                .map(|p| (p.to_string(), p.monoisotopic))
+                .collect::<Vec<_>>();
+            let expected = all
+                .iter()
+                .filter(|(_, mass)| *mass >= min && *mass <= max)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(within, expected);
+        }
+    }
+
+    #[test]
+    fn exclusive_variable_mods() {
+        use ModificationSpecificity::*;
+        let variable_mods = [
+            (PeptideN(None), 42.),
+            (PeptideN(Some(b'Q')), -17.),
+            (Residue(b'Q'), 1.),
+            (Residue(b'K'), 114.),
+        ];
+        let peptide = Peptide::try_from(Digest {
+            sequence: "QGQGK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let generate = |exclusive: &[Vec<ModificationSpecificity>]| {
+            peptide
+                .clone()
+                .apply_exclusive(&variable_mods, &HashMap::default(), 2, exclusive)
+                .into_iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let all = generate(&[]);
+        assert!(all.contains(&"[+42]-Q[-17]GQGK".to_string()));
+        assert_eq!(all, var_mod_sequence(&peptide, &variable_mods, 2));
+
+        // The N-terminal mod and pyro-glu both modify the first residue
+        let exclusive = generate(&[vec![PeptideN(None), PeptideN(Some(b'Q'))]]);
+        assert!(!exclusive.contains(&"[+42]-Q[-17]GQGK".to_string()));
+        assert!(exclusive.contains(&"[+42]-QGQ[+1]GK".to_string()));
+        assert!(exclusive.contains(&"Q[-17]GQGK[+114]".to_string()));
+        assert_eq!(exclusive.len(), all.len() - 1);
+
+        // ... as does a residue mod on the N-terminal Q, but not on the internal Q
+        let exclusive = generate(&[vec![PeptideN(None), Residue(b'Q')]]);
+        assert!(!exclusive.contains(&"[+42]-Q[+1]GQGK".to_string()));
+        assert!(exclusive.contains(&"[+42]-QGQ[+1]GK".to_string()));
+        assert_eq!(exclusive.len(), all.len() - 1);
+
+        // Residue mods on different residues are never mutually exclusive
+        let exclusive = generate(&[vec![Residue(b'Q'), Residue(b'K')]]);
+        assert_eq!(exclusive, all);
+    }
+
     #[test]
     fn test_variable_mods_cterm() {
         use ModificationSpecificity::*;
@@ -675,6 +1176,7 @@ mod test {
             missed_cleavages: 0,
             min_len: 3,
             max_len: 30,
+            ragged_nterm: 0,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -768,4 +1270,309 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn static_mods_applied_in_fixed_order() {
+        use ModificationSpecificity::*;
+        let peptide = Peptide::try_from(Digest {
+            sequence: "QPEPTIDEK".into(),
+            position: Position::Nterm,
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Protein N-term mods take precedence over peptide N-term mods, which
+        // take precedence over residue mods on the same site
+        for _ in 0..16 {
+            let static_mods: HashMap<_, _> = [
+                (PeptideN(None), 229.1629),
+                (ProteinN(None), 42.0106),
+                (Residue(b'Q'), 10.0),
+                (PeptideN(Some(b'Q')), -17.0265),
+                (Residue(b'K'), 229.1629),
+            ]
+            .into_iter()
+            .collect();
+
+            let modified = peptide.clone().apply(&[], &static_mods, 2).remove(0);
+            assert_eq!(modified.nterm, Some(42.0106));
+            assert_eq!(modified.modifications[0], -17.0265);
+            assert_eq!(modified.modifications[8], 229.1629);
+            let expected = peptide.monoisotopic + 42.0106 - 17.0265 + 229.1629;
+            assert!((modified.monoisotopic - expected).abs() < 1E-3);
+        }
+    }
+
+    #[test]
+    fn conditional_cterm_static_mods() {
+        // C-terminal mod only applied if the terminal residue is K or R
+        let static_mods = crate::modification::validate_mods(Some(
+            [("$KR".to_string(), 28.0313)].into_iter().collect(),
+        ));
+        assert_eq!(static_mods.len(), 2);
+
+        for (sequence, expected) in [("PEPTIDEK", Some(28.0313)), ("PEPTIDEF", None)] {
+            let peptide = Peptide::try_from(Digest {
+                sequence: sequence.into(),
+                ..Default::default()
+            })
+            .unwrap();
+            let modified = peptide.clone().apply(&[], &static_mods, 0).remove(0);
+            assert_eq!(
+                modified.modifications[7],
+                expected.unwrap_or_default(),
+                "{sequence}"
+            );
+            let delta = modified.monoisotopic - peptide.monoisotopic;
+            assert!((delta - expected.unwrap_or_default()).abs() < 1E-3);
+        }
+    }
+
+    #[test]
+    fn modified_string_round_trip() {
+        use ModificationSpecificity::*;
+        let peptide = Peptide::try_from(Digest {
+            sequence: "PEPTIDEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut static_mods = HashMap::new();
+        static_mods.insert(PeptideN(None), 229.1629);
+        static_mods.insert(Residue(b'K'), 229.1629);
+        let variable_mods = [(Residue(b'T'), 79.9663), (PeptideC(None), -0.9840)];
+
+        let peptides = peptide.apply(&variable_mods, &static_mods, 2);
+        let strings = peptides
+            .iter()
+            .map(|p| p.to_modified_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            strings,
+            vec![
+                "n[+229.1629]PEPTIDEK[+229.1629]",
+                "n[+229.1629]PEPT[+79.9663]IDEK[+229.1629]",
+                "n[+229.1629]PEPTIDEK[+229.1629]c[-0.9840]",
+                "n[+229.1629]PEPT[+79.9663]IDEK[+229.1629]c[-0.9840]",
+            ]
+        );
+
+        for (peptide, s) in peptides.iter().zip(strings.iter()) {
+            let parsed = Peptide::from_modified_string(s).unwrap();
+            assert_eq!(parsed.sequence, peptide.sequence);
+            assert_eq!(parsed.modifications, peptide.modifications);
+            assert_eq!(parsed.nterm, peptide.nterm);
+            assert_eq!(parsed.cterm, peptide.cterm);
+            assert!((parsed.monoisotopic - peptide.monoisotopic).abs() < 1E-3);
+            assert_eq!(&parsed.to_modified_string(), s);
+        }
+
+        for invalid in [
+            "",
+            "n[+1.0]",
+            "PEP[+1.0",
+            "[+1.0]PEP",
+            "PEP[x]",
+            "PEPc[+1]K",
+            "PEPZ",
+        ] {
+            assert!(
+                Peptide::from_modified_string(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn tryptic_cterm() {
+        let digest = |sequence: &str, position| {
+            Peptide::try_from(Digest {
+                sequence: sequence.into(),
+                position,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        assert!(digest("PEPTIDEK", Position::Internal).is_tryptic_cterm());
+        assert!(digest("PEPTIDER", Position::Nterm).is_tryptic_cterm());
+        assert!(!digest("PEPTIDEA", Position::Internal).is_tryptic_cterm());
+        assert!(!digest("PEPTIDEA", Position::Nterm).is_tryptic_cterm());
+        assert!(digest("PEPTIDEA", Position::Cterm).is_tryptic_cterm());
+        assert!(digest("PEPTIDEA", Position::Full).is_tryptic_cterm());
+
+        // Decoys keep their C-terminal residue
+        let decoy = digest("PEPTIDEK", Position::Internal).reverse(None);
+        assert!(decoy.is_tryptic_cterm());
+
+        assert!(digest("PEPTIDEF", Position::Internal).cterm_cleaved(b"FWY"));
+        assert!(!digest("PEPTIDEK", Position::Internal).cterm_cleaved(b"FWY"));
+    }
+
+    #[test]
+    fn protein_terminal_modifications() {
+        use ModificationSpecificity::*;
+        let digest = |sequence: &str, position| {
+            Peptide::try_from(Digest {
+                sequence: sequence.into(),
+                position,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let static_mods = HashMap::default();
+        let variable_mods = [(ProteinN(None), 42.0106), (PeptideN(None), 43.0058)];
+
+        // Protein N-terminal acetylation is only considered for peptides at the
+        // protein N-terminus, and only one N-terminal modification is applied
+        let peptides = digest("MPEPTIDEK", Position::Nterm).apply(&variable_mods, &static_mods, 2);
+        assert_eq!(
+            peptides
+                .iter()
+                .map(|p| p.to_modified_string())
+                .collect::<Vec<_>>(),
+            vec!["MPEPTIDEK", "pn[+42.0106]MPEPTIDEK", "n[+43.0058]MPEPTIDEK"]
+        );
+        assert!(peptides[1].protein_nterm);
+        assert!(!peptides[2].protein_nterm);
+        assert_eq!(peptides[1].to_string(), "[+42.0106]-MPEPTIDEK");
+        assert_eq!(
+            peptides[1].to_protein_terminal_string(),
+            "pn[+42.0106]-MPEPTIDEK"
+        );
+        assert_eq!(peptides[2].to_string(), "[+43.0058]-MPEPTIDEK");
+        assert_eq!(
+            peptides[2].to_protein_terminal_string(),
+            "[+43.0058]-MPEPTIDEK"
+        );
+
+        let peptides =
+            digest("MPEPTIDEK", Position::Internal).apply(&variable_mods, &static_mods, 2);
+        assert!(peptides.iter().all(|p| !p.protein_nterm));
+
+        let static_mods = [(ProteinC(None), -0.984)].into_iter().collect();
+        let peptide = digest("PEPTIDEK", Position::Cterm)
+            .apply(&[], &static_mods, 2)
+            .remove(0);
+        assert!(peptide.protein_cterm);
+        assert_eq!(peptide.to_modified_string(), "PEPTIDEKpc[-0.9840]");
+        assert_eq!(peptide.to_string(), "PEPTIDEK-[-0.984]");
+        assert_eq!(peptide.to_protein_terminal_string(), "PEPTIDEK-pc[-0.984]");
+
+        for s in [
+            "pn[+42.0106]MPEPTIDEK",
+            "PEPTIDEKpc[-0.9840]",
+            "pn[Acetyl]PEPTIDEKc[-0.9840]",
+        ] {
+            let parsed = Peptide::from_modified_string(s).unwrap();
+            assert_eq!(parsed.protein_nterm, s.starts_with('p'));
+            assert_eq!(parsed.protein_cterm, s.contains("pc"));
+        }
+        assert!(Peptide::from_modified_string("pn+42.0106]PEPTIDEK").is_err());
+        assert!(Peptide::from_modified_string("PEPTIDEKp[+1.0]").is_err());
+    }
+
+    #[test]
+    fn parse_modified_string() {
+        let delta = Peptide::from_modified_string("n[+42.0106]M[+15.9949]PEPTIDEK").unwrap();
+        let named = Peptide::from_modified_string("n[Acetyl]M[Oxidation]PEPTIDEK").unwrap();
+        let accession = Peptide::from_modified_string("n[UNIMOD:1]M[UNIMOD:35]PEPTIDEK").unwrap();
+        let unmodified = Peptide::from_modified_string("MPEPTIDEK").unwrap();
+
+        for peptide in [&delta, &named, &accession] {
+            assert_eq!(peptide.sequence.as_ref(), b"MPEPTIDEK");
+            assert_eq!(peptide.modifications[1..], [0.0; 8]);
+            assert!((peptide.modifications[0] - 15.9949).abs() < 1E-3);
+            assert!((peptide.nterm.unwrap() - 42.0106).abs() < 1E-3);
+            assert!((peptide.monoisotopic - unmodified.monoisotopic - 58.0055).abs() < 1E-3);
+        }
+        assert!((unmodified.monoisotopic - 1058.4954).abs() < 1E-3);
+
+        assert_eq!(
+            Peptide::from_modified_string("PEPT[Foo]IDE"),
+            Err(PeptideError::UnknownModification {
+                sequence: "PEPT[Foo]IDE".into(),
+                modification: "Foo".into()
+            })
+        );
+        assert_eq!(
+            Peptide::from_modified_string("PEPT[+79.97IDE"),
+            Err(PeptideError::MalformedSequence {
+                sequence: "PEPT[+79.97IDE".into(),
+                position: 4,
+                reason: "unclosed bracket"
+            })
+        );
+        assert_eq!(
+            Peptide::from_modified_string("PEPTiDE")
+                .unwrap_err()
+                .to_string(),
+            "malformed peptide sequence PEPTiDE at position 4: invalid amino acid"
+        );
+    }
+
+    #[test]
+    fn precursor_mz() {
+        let peptide = Peptide::try_from(Digest {
+            sequence: "PEPTIDEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!((peptide.mz(1) - (peptide.monoisotopic + PROTON)).abs() < 1E-4);
+        assert!((peptide.mz(2) - (peptide.monoisotopic / 2.0 + PROTON)).abs() < 1E-4);
+        assert!((peptide.mz(3) - 310.1589).abs() < 1E-3);
+
+        // Modifications, including terminal ones, shift the precursor m/z
+        let mut static_mods = HashMap::new();
+        static_mods.insert(ModificationSpecificity::PeptideN(None), 42.0106);
+        let modified = peptide.clone().apply(&[], &static_mods, 0).remove(0);
+        assert!((modified.mz(2) - peptide.mz(2) - 21.0053).abs() < 1E-3);
+    }
+
+    #[test]
+    fn isotope_distribution() {
+        let peptide = |sequence: &str| {
+            Peptide::try_from(Digest {
+                sequence: sequence.into(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let close = |observed: Vec<(f32, f32)>, expected: &[f32], tol: f32| {
+            observed.len() == expected.len()
+                && observed
+                    .iter()
+                    .zip(expected)
+                    .all(|((_, a), b)| (a - b).abs() <= tol)
+        };
+
+        // Angiotensin II, C50H71N13O12
+        let angiotensin = peptide("DRVYIHPF").isotope_distribution(4);
+        assert!(close(
+            angiotensin.clone(),
+            &[1.0, 0.601, 0.2023, 0.0492],
+            0.01
+        ));
+        assert_eq!(angiotensin[0].0, peptide("DRVYIHPF").monoisotopic);
+        assert!((angiotensin[2].0 - angiotensin[0].0 - 2.0 * NEUTRON).abs() < 1E-4);
+
+        // Sulfur-rich, C68H112N20O20S5
+        assert!(close(
+            peptide("MEWKMEQSMRCCK").isotope_distribution(6),
+            &[1.0, 0.8685, 0.6377, 0.3337, 0.1499, 0.0571],
+            0.01
+        ));
+
+        // Modifications are approximated by averagine
+        let mut static_mods = HashMap::new();
+        static_mods.insert(ModificationSpecificity::Residue(b'C'), 57.021464);
+        let carbamidomethyl = peptide("MEWKMEQSMRCCK")
+            .apply(&[], &static_mods, 0)
+            .remove(0)
+            .isotope_distribution(3);
+        // C72H118N22O22S5
+        assert!(close(carbamidomethyl, &[1.0, 0.9205, 0.6881], 0.02));
+    }
 }
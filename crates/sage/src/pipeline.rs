@@ -0,0 +1,621 @@
+//! A single high-level entry point for embedding Sage in other applications
+//!
+//! [`SearchPipeline`] wraps database generation, parallel search of processed
+//! spectra, and FDR control (spectrum, peptide and protein level q-values).
+//! For finer control - partitioned databases, retention time prediction,
+//! quantification, etc - use the lower level modules directly.
+//!
+//! [`SearchPipeline::search_two_pass`] performs a narrow search followed by an
+//! open (wide precursor tolerance) search of the spectra that were not
+//! confidently identified in the first pass
+//!
+//! [`SearchPipeline::search_summarized`] additionally returns a
+//! [`SearchSummary`] of identification counts, e.g. as a sanity check of the
+//! search settings
+//!
+//! [`SearchPipeline::search_controls`] searches scrambled control spectra,
+//! which should yield (almost) no confident identifications
+
+use crate::database::{IndexedDatabase, Parameters, SearchScratch};
+use crate::fasta::Fasta;
+use crate::mass::Tolerance;
+use crate::scoring::{Feature, Scorer, ScoringSettings};
+use crate::spectrum::{ControlSpectrum, ProcessedSpectrum};
+use fnv::{FnvHashSet, FnvHasher};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// PSMs, peptides and proteins are counted as identified in a [`SearchSummary`]
+/// if they pass this q-value
+const SUMMARY_Q_VALUE: f32 = 0.01;
+
+/// Search settings used by [`SearchPipeline`]. Defaults match those of the
+/// command line interface
+#[derive(Clone, Debug)]
+pub struct SearchSettings {
+    /// Settings of the [`Scorer`] used to search each spectrum
+    pub scoring: ScoringSettings,
+    /// Only search MS2 spectra with at least this many peaks
+    pub min_peaks: usize,
+    /// Skip MS2 spectra with a chimericity above this value, see
+    /// [`crate::spectrum::assign_chimericity`]
+    pub max_chimericity: Option<f32>,
+}
+
+impl SearchSettings {
+    pub fn new(precursor_tol: Tolerance, fragment_tol: Tolerance) -> Self {
+        Self {
+            scoring: ScoringSettings::new(precursor_tol, fragment_tol),
+            min_peaks: 15,
+            max_chimericity: None,
+        }
+    }
+}
+
+pub struct SearchPipeline {
+    pub database: IndexedDatabase,
+    pub settings: SearchSettings,
+    min_fragment_mass: f32,
+    max_fragment_mass: f32,
+}
+
+impl SearchPipeline {
+    /// Generate a database from an already parsed `fasta`
+    pub fn new(parameters: Parameters, fasta: Fasta, settings: SearchSettings) -> Self {
+        let min_fragment_mass = parameters.fragment_min_mz;
+        let max_fragment_mass = parameters.fragment_max_mz;
+        Self {
+            database: parameters.build(fasta),
+            settings,
+            min_fragment_mass,
+            max_fragment_mass,
+        }
+    }
+
+    /// Read the FASTA file at `parameters.fasta`, and generate a database from it
+    pub fn from_fasta_path(
+        parameters: Parameters,
+        settings: SearchSettings,
+    ) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(&parameters.fasta)?;
+        let fasta = Fasta::parse(
+            contents,
+            parameters.decoy_tag.clone(),
+            parameters.generate_decoys,
+        );
+        Ok(Self::new(parameters, fasta, settings))
+    }
+
+    /// Create a [`Scorer`] for the database and settings of this pipeline, e.g.
+    /// for searching spectra without performing FDR control
+    pub fn scorer(&self) -> Scorer<'_> {
+        self.scorer_with(&self.settings)
+    }
+
+    fn scorer_with<'a>(&'a self, settings: &'a SearchSettings) -> Scorer<'a> {
+        settings.scoring.scorer(
+            &self.database,
+            self.min_fragment_mass,
+            self.max_fragment_mass,
+        )
+    }
+
+    /// Search all MS2 spectra in parallel, and assign spectrum, peptide and
+    /// protein level q-values to the resulting PSMs.
+    ///
+    /// PSMs are returned sorted by discriminant score, in descending order
+    pub fn search(&self, spectra: &[ProcessedSpectrum]) -> Vec<Feature> {
+        self.search_with(&self.settings, &spectra.iter().collect::<Vec<_>>())
+    }
+
+    /// Search all MS2 spectra as in [`SearchPipeline::search`], and summarize
+    /// the results
+    pub fn search_summarized(
+        &self,
+        spectra: &[ProcessedSpectrum],
+    ) -> (Vec<Feature>, SearchSummary) {
+        let start = Instant::now();
+        let features = self.search(spectra);
+        let summary = SearchSummary::new(&self.database, spectra, &features, start.elapsed());
+        (features, summary)
+    }
+
+    /// Search control spectra, obtained by scrambling `spectra` (see
+    /// [`ProcessedSpectrum::scrambled`]), and summarize the results.
+    ///
+    /// Control spectra should not match any peptide, so the identification
+    /// rate of the summary should be close to zero - a high rate points to a
+    /// problem with scoring or FDR control. Spectra that cannot be scrambled
+    /// are skipped. Randomized spectra are reproducible for a given `seed`
+    pub fn search_controls(
+        &self,
+        spectra: &[ProcessedSpectrum],
+        control: ControlSpectrum,
+        seed: u64,
+    ) -> (Vec<Feature>, SearchSummary) {
+        let controls = spectra
+            .par_iter()
+            .filter_map(|spec| {
+                let mut hasher = FnvHasher::default();
+                spec.id.hash(&mut hasher);
+                spec.file_id.hash(&mut hasher);
+                let mut rng = StdRng::seed_from_u64(seed ^ hasher.finish());
+                spec.scrambled(control, &mut rng)
+            })
+            .collect::<Vec<_>>();
+        let (features, summary) = self.search_summarized(&controls);
+        log::info!("control search: {}", summary);
+        (features, summary)
+    }
+
+    /// Two-pass search: first search all spectra using the settings of this
+    /// pipeline, then search only those spectra without a target PSM at
+    /// `spectrum_q <= q_threshold` again using `open` settings (e.g. a wide
+    /// precursor tolerance).
+    ///
+    /// FDR is controlled separately within each pass, since the score
+    /// distributions of narrow and open searches differ. Returns the first
+    /// pass PSMs of confidently identified spectra, followed by all PSMs
+    /// of the second pass
+    pub fn search_two_pass(
+        &self,
+        spectra: &[ProcessedSpectrum],
+        open: &SearchSettings,
+        q_threshold: f32,
+    ) -> Vec<Feature> {
+        let mut features = self.search(spectra);
+        // Spectrum ids are only unique within a file
+        let first_pass = features
+            .iter()
+            .filter(|feat| feat.label == 1 && feat.spectrum_q <= q_threshold)
+            .map(|feat| (feat.file_id, feat.spec_id.as_str()))
+            .collect::<FnvHashSet<_>>();
+
+        let (identified, remainder): (Vec<_>, Vec<_>) = spectra
+            .iter()
+            .partition(|spec| first_pass.contains(&(spec.file_id, spec.id.as_str())));
+        log::info!(
+            "{} spectra identified in first pass, searching remaining {} spectra",
+            identified.len(),
+            remainder.len()
+        );
+
+        let identified = identified
+            .into_iter()
+            .map(|spec| (spec.file_id, spec.id.as_str()))
+            .collect::<FnvHashSet<_>>();
+        features.retain(|feat| identified.contains(&(feat.file_id, feat.spec_id.as_str())));
+        features.extend(self.search_with(open, &remainder));
+        features
+    }
+
+    fn search_with(
+        &self,
+        settings: &SearchSettings,
+        spectra: &[&ProcessedSpectrum],
+    ) -> Vec<Feature> {
+        let scorer = self.scorer_with(settings);
+        let mut features: Vec<Feature> = spectra
+            .par_iter()
+            .filter(|spec| spec.level == 2 && spec.peaks.len() >= settings.min_peaks)
+            .filter(|spec| match (spec.chimericity, settings.max_chimericity) {
+                (Some(chimericity), Some(max)) => chimericity <= max,
+                _ => true,
+            })
+            .map_init(SearchScratch::default, |scratch, spec| {
+                scorer.score_with(scratch, spec)
+            })
+            .flatten_iter()
+            .collect();
+
+        if crate::ml::linear_discriminant::score_psms(&mut features, settings.scoring.precursor_tol)
+            .is_none()
+        {
+            log::warn!("linear model fitting failed, falling back to heuristic discriminant score");
+            features.par_iter_mut().for_each(|feat| {
+                feat.discriminant_score = (-feat.poisson as f32).ln_1p() + feat.longest_y_pct / 3.0
+            });
+        }
+        features.par_sort_unstable_by(|a, b| b.discriminant_score.total_cmp(&a.discriminant_score));
+        crate::ml::qvalue::spectrum_q_value(&mut features);
+        crate::fdr::picked_peptide(&self.database, &mut features);
+        crate::fdr::picked_protein(&self.database, &mut features);
+        features
+    }
+}
+
+/// Identification counts of a search, at 1% FDR
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchSummary {
+    /// Number of MS2 spectra
+    pub spectra: usize,
+    /// Number of spectra with at least one candidate PSM (target or decoy)
+    pub spectra_with_candidates: usize,
+    /// Number of rank 1 target PSMs with `spectrum_q <= 0.01`
+    pub psms: usize,
+    /// Number of unique target peptides with `peptide_q <= 0.01`
+    pub peptides: usize,
+    /// Number of unique target protein groups with `protein_q <= 0.01`
+    pub proteins: usize,
+    /// Median absolute precursor mass error (ppm) of identified PSMs, or
+    /// `None` if there are none
+    pub median_ppm_error: Option<f32>,
+    /// Wall clock time spent searching, including FDR control
+    pub elapsed: Duration,
+}
+
+impl SearchSummary {
+    /// Summarize `features`, which must have been assigned q-values, as
+    /// obtained by searching `spectra` against `db`
+    pub fn new(
+        db: &IndexedDatabase,
+        spectra: &[ProcessedSpectrum],
+        features: &[Feature],
+        elapsed: Duration,
+    ) -> Self {
+        let spectra_with_candidates = features
+            .iter()
+            .map(|feat| feat.spec_id.as_str())
+            .collect::<FnvHashSet<_>>()
+            .len();
+
+        let targets = features.iter().filter(|feat| feat.label == 1);
+        let mut errors = targets
+            .clone()
+            .filter(|feat| feat.rank == 1 && feat.spectrum_q <= SUMMARY_Q_VALUE)
+            .map(|feat| feat.delta_mass)
+            .collect::<Vec<_>>();
+        let peptides = targets
+            .clone()
+            .filter(|feat| feat.peptide_q <= SUMMARY_Q_VALUE)
+            .map(|feat| feat.peptide_idx)
+            .collect::<FnvHashSet<_>>()
+            .len();
+        let proteins = targets
+            .filter(|feat| feat.protein_q <= SUMMARY_Q_VALUE)
+            .map(|feat| &db[feat.peptide_idx].proteins)
+            .collect::<FnvHashSet<_>>()
+            .len();
+
+        errors.sort_unstable_by(|a, b| a.total_cmp(b));
+        let mid = errors.len() / 2;
+        let median_ppm_error = match errors.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((errors[mid - 1] + errors[mid]) / 2.0),
+            _ => Some(errors[mid]),
+        };
+
+        SearchSummary {
+            spectra: spectra.iter().filter(|spec| spec.level == 2).count(),
+            spectra_with_candidates,
+            psms: errors.len(),
+            peptides,
+            proteins,
+            median_ppm_error,
+            elapsed,
+        }
+    }
+
+    /// Fraction of MS2 spectra identified at 1% FDR
+    pub fn identification_rate(&self) -> f32 {
+        match self.spectra {
+            0 => 0.0,
+            n => self.psms as f32 / n as f32,
+        }
+    }
+}
+
+impl std::fmt::Display for SearchSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} MS2 spectra ({} with candidates), {} PSMs ({:.1}%), {} peptides, {} proteins at 1% FDR",
+            self.spectra,
+            self.spectra_with_candidates,
+            self.psms,
+            self.identification_rate() * 100.0,
+            self.peptides,
+            self.proteins,
+        )?;
+        if let Some(ppm) = self.median_ppm_error {
+            write!(f, ", median precursor error {:.2} ppm", ppm)?;
+        }
+        write!(f, ", searched in {:.1}s", self.elapsed.as_secs_f32())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::ion_series::{IonSeries, Kind};
+    use crate::mass::PROTON;
+    use crate::spectrum::{Peak, Precursor};
+
+    /// Spectrum containing all b and y ions of `peptide`, with a precursor
+    /// shifted by `delta` Da
+    fn spectrum(id: &str, peptide: &crate::peptide::Peptide, delta: f32) -> ProcessedSpectrum {
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        ProcessedSpectrum {
+            level: 2,
+            id: id.into(),
+            precursors: vec![Precursor {
+                mz: (peptide.monoisotopic + delta) / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn search_pipeline() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let settings = SearchSettings {
+            min_peaks: 4,
+            ..SearchSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+        };
+        let pipeline = SearchPipeline::new(parameters, fasta, settings);
+
+        let targets = ["EQALLK", "LEQSMR", "AQLTQLK", "VLHSGR"];
+        let spectra = targets
+            .iter()
+            .enumerate()
+            .map(|(scan, sequence)| {
+                let peptide = pipeline
+                    .database
+                    .peptides
+                    .iter()
+                    .find(|p| p.sequence.as_ref() == sequence.as_bytes())
+                    .unwrap();
+                spectrum(&format!("scan={}", scan), peptide, 0.0)
+            })
+            .collect::<Vec<_>>();
+
+        let psms = pipeline.search(&spectra);
+        assert_eq!(psms.len(), targets.len());
+        for (scan, sequence) in targets.iter().enumerate() {
+            let psm = psms
+                .iter()
+                .find(|psm| psm.spec_id == format!("scan={}", scan))
+                .unwrap();
+            assert_eq!(pipeline.database[psm.peptide_idx].to_string(), *sequence);
+            assert_eq!(psm.label, 1);
+            assert_eq!(psm.rank, 1);
+            // Without any decoy PSMs, q-values are as low as they can be:
+            // 1 / #targets, due to the +1 decoy correction
+            assert_eq!(psm.spectrum_q, 1.0 / targets.len() as f32);
+        }
+    }
+
+    #[test]
+    fn search_summary() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let settings = SearchSettings {
+            min_peaks: 4,
+            ..SearchSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+        };
+        let pipeline = SearchPipeline::new(parameters, fasta, settings);
+        let ix = pipeline
+            .database
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"AQLTQLK")
+            .unwrap();
+
+        // An MS1 spectrum, and an MS2 spectrum without candidates
+        let spectra = vec![
+            spectrum("scan=1", &pipeline.database.peptides[ix], 0.0),
+            ProcessedSpectrum {
+                level: 1,
+                ..spectrum("scan=2", &pipeline.database.peptides[ix], 0.0)
+            },
+            spectrum("scan=3", &pipeline.database.peptides[ix], 5000.0),
+        ];
+
+        let (mut psms, summary) = pipeline.search_summarized(&spectra);
+        assert_eq!(summary.spectra, 2);
+        assert_eq!(summary.spectra_with_candidates, 1);
+        // A single target PSM is not enough to pass 1% FDR
+        assert_eq!(summary.psms, 0);
+        assert_eq!(summary.median_ppm_error, None);
+
+        for feat in psms.iter_mut() {
+            feat.spectrum_q = 0.0;
+            feat.peptide_q = 0.0;
+            feat.protein_q = 0.0;
+        }
+        let summary = SearchSummary::new(&pipeline.database, &spectra, &psms, summary.elapsed);
+        assert_eq!(summary.psms, 1);
+        assert_eq!(summary.peptides, 1);
+        assert_eq!(summary.proteins, 1);
+        assert!(summary.median_ppm_error.unwrap() < 1.0);
+        assert_eq!(summary.identification_rate(), 0.5);
+        assert!(summary
+            .to_string()
+            .starts_with("2 MS2 spectra (1 with candidates), 1 PSMs (50.0%)"));
+    }
+
+    #[test]
+    fn min_score() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let settings = SearchSettings {
+            min_peaks: 4,
+            scoring: ScoringSettings {
+                report_psms: 5,
+                ..ScoringSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+            },
+            ..SearchSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+        };
+        let mut pipeline = SearchPipeline::new(parameters, fasta, settings);
+        let ix = pipeline
+            .database
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"AQLTQLK")
+            .unwrap();
+        let spectra = vec![spectrum("scan=1", &pipeline.database.peptides[ix], 0.0)];
+
+        let scorer = pipeline.scorer();
+        let all = scorer.score(&spectra[0]);
+        let best = all[0].hyperscore;
+
+        pipeline.settings.scoring.min_score = Some(best);
+        let filtered = pipeline.scorer().score(&spectra[0]);
+        assert!(!filtered.is_empty());
+        assert!(filtered.iter().all(|feat| feat.hyperscore >= best));
+        // Delta scores are still relative to all candidates
+        assert_eq!(filtered[0].delta_next, all[0].delta_next);
+
+        pipeline.settings.scoring.min_score = Some(best + 1.0);
+        assert!(pipeline.scorer().score(&spectra[0]).is_empty());
+        assert!(pipeline.search(&spectra).is_empty());
+    }
+
+    #[test]
+    fn two_pass_search() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let narrow = SearchSettings {
+            min_peaks: 4,
+            ..SearchSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+        };
+        let mut open = narrow.clone();
+        open.scoring.precursor_tol = Tolerance::Da(-100.0, 100.0);
+        let pipeline = SearchPipeline::new(parameters, fasta, narrow);
+
+        let position = |sequence: &[u8]| {
+            pipeline
+                .database
+                .peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence)
+                .unwrap()
+        };
+        let (a, b) = (position(b"AQLTQLK"), position(b"EQALLK"));
+
+        // The second spectrum carries an unknown modification on the precursor
+        let spectra = vec![
+            spectrum("scan=1", &pipeline.database.peptides[a], 0.0),
+            spectrum("scan=2", &pipeline.database.peptides[b], 79.9663),
+        ];
+        assert!(pipeline
+            .search(&spectra)
+            .iter()
+            .all(|feat| feat.peptide_idx.0 as usize != b));
+
+        let psms = pipeline.search_two_pass(&spectra, &open, 1.0);
+        assert_eq!(psms.len(), 2);
+        assert_eq!(psms[0].spec_id, "scan=1");
+        assert_eq!(psms[0].peptide_idx.0 as usize, a);
+        assert_eq!(psms[1].spec_id, "scan=2");
+        assert_eq!(psms[1].peptide_idx.0 as usize, b);
+        assert!((psms[1].expmass - psms[1].calcmass - 79.9663).abs() < 0.01);
+
+        // A spectrum of another file sharing the id of an identified spectrum
+        // is still searched in the second pass
+        let mut other = spectrum("scan=1", &pipeline.database.peptides[b], 79.9663);
+        other.file_id = 1;
+        let spectra = vec![spectra[0].clone(), other];
+        let psms = pipeline.search_two_pass(&spectra, &open, 1.0);
+        assert_eq!(psms.len(), 2);
+        assert_eq!((psms[0].file_id, psms[0].peptide_idx.0 as usize), (0, a));
+        assert_eq!((psms[1].file_id, psms[1].peptide_idx.0 as usize), (1, b));
+    }
+
+    #[test]
+    fn control_search() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nEQALLKLEQSMRAQLTQLKMEWKLEQSMRPEPTIDEKVLHSGR".into(),
+            "rev_",
+            true,
+        );
+        let parameters = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        let settings = SearchSettings {
+            min_peaks: 4,
+            ..SearchSettings::new(Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0))
+        };
+        let pipeline = SearchPipeline::new(parameters, fasta, settings);
+        let ix = pipeline
+            .database
+            .peptides
+            .iter()
+            .position(|p| p.sequence.as_ref() == b"AQLTQLK")
+            .unwrap();
+
+        let mut spectra = vec![spectrum("scan=1", &pipeline.database.peptides[ix], 0.0)];
+        spectra.push(ProcessedSpectrum {
+            id: "scan=2".into(),
+            precursors: vec![Precursor {
+                charge: None,
+                ..spectra[0].precursors[0].clone()
+            }],
+            ..spectra[0].clone()
+        });
+        let matched = pipeline.search(&spectra)[0].matched_peaks;
+        assert!(matched >= 10);
+
+        for control in [ControlSpectrum::Reflect, ControlSpectrum::Randomize] {
+            let (psms, summary) = pipeline.search_controls(&spectra, control, 42);
+            assert!(psms.iter().all(|feat| feat.matched_peaks < matched));
+            assert_eq!(summary.psms, 0);
+            match control {
+                // No precursor mass to reflect peaks about without a charge
+                ControlSpectrum::Reflect => assert_eq!(summary.spectra, 1),
+                ControlSpectrum::Randomize => assert_eq!(summary.spectra, 2),
+            }
+        }
+    }
+}
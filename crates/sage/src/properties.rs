@@ -0,0 +1,168 @@
+//! Physicochemical properties of peptides, e.g. for use as additional
+//! rescoring features
+//!
+//! These are pure functions of the (unmodified) peptide sequence, and are not
+//! used during database search
+
+use crate::peptide::Peptide;
+use serde::Serialize;
+
+/// pKa of the free peptide N-terminus (EMBOSS)
+const PKA_NTERM: f32 = 8.6;
+/// pKa of the free peptide C-terminus (EMBOSS)
+const PKA_CTERM: f32 = 3.6;
+
+/// pKa of ionizable side chains (EMBOSS), and whether they are positively
+/// charged when protonated
+fn side_chain_pka(residue: u8) -> Option<(f32, bool)> {
+    match residue {
+        b'C' => Some((8.5, false)),
+        b'D' => Some((3.9, false)),
+        b'E' => Some((4.1, false)),
+        b'H' => Some((6.5, true)),
+        b'K' => Some((10.8, true)),
+        b'R' => Some((12.5, true)),
+        b'Y' => Some((10.1, false)),
+        _ => None,
+    }
+}
+
+/// Kyte-Doolittle hydropathy index
+fn hydropathy(residue: u8) -> Option<f32> {
+    match residue {
+        b'A' => Some(1.8),
+        b'R' => Some(-4.5),
+        b'N' => Some(-3.5),
+        b'D' => Some(-3.5),
+        b'C' => Some(2.5),
+        b'Q' => Some(-3.5),
+        b'E' => Some(-3.5),
+        b'G' => Some(-0.4),
+        b'H' => Some(-3.2),
+        b'I' => Some(4.5),
+        b'L' => Some(3.8),
+        b'K' => Some(-3.9),
+        b'M' => Some(1.9),
+        b'F' => Some(2.8),
+        b'P' => Some(-1.6),
+        b'S' => Some(-0.8),
+        b'T' => Some(-0.7),
+        b'W' => Some(-0.9),
+        b'Y' => Some(-1.3),
+        b'V' => Some(4.2),
+        _ => None,
+    }
+}
+
+/// Fraction of a group with `pka` that is protonated at `ph`
+fn protonated(pka: f32, ph: f32) -> f32 {
+    1.0 / (1.0 + 10f32.powf(ph - pka))
+}
+
+/// Net charge of `sequence` at `ph`
+pub fn net_charge(sequence: &[u8], ph: f32) -> f32 {
+    let termini = protonated(PKA_NTERM, ph) - (1.0 - protonated(PKA_CTERM, ph));
+    sequence
+        .iter()
+        .filter_map(|&residue| side_chain_pka(residue))
+        .fold(termini, |charge, (pka, basic)| match basic {
+            true => charge + protonated(pka, ph),
+            false => charge - (1.0 - protonated(pka, ph)),
+        })
+}
+
+/// Isoelectric point of `sequence`, i.e. the pH at which the net charge is
+/// zero, determined by bisection
+pub fn isoelectric_point(sequence: &[u8]) -> f32 {
+    let (mut lo, mut hi) = (0.0f32, 14.0f32);
+    while hi - lo > 1E-4 {
+        let mid = (lo + hi) / 2.0;
+        // Net charge decreases monotonically with pH
+        if net_charge(sequence, mid) > 0.0 {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Grand average of hydropathy (GRAVY) of `sequence`. Residues without a
+/// Kyte-Doolittle hydropathy value (e.g. X, U) are ignored
+pub fn gravy(sequence: &[u8]) -> f32 {
+    let (sum, n) = sequence
+        .iter()
+        .filter_map(|&residue| hydropathy(residue))
+        .fold((0.0, 0), |(sum, n), h| (sum + h, n + 1));
+    match n {
+        0 => 0.0,
+        n => sum / n as f32,
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+pub struct PeptideProperties {
+    pub length: usize,
+    pub isoelectric_point: f32,
+    pub gravy: f32,
+    /// Number of occurrences of each residue, indexed by `residue - b'A'`
+    pub composition: [u16; 26],
+}
+
+impl PeptideProperties {
+    pub fn new(peptide: &Peptide) -> Self {
+        let mut composition = [0; 26];
+        for residue in peptide.sequence.iter() {
+            if residue.is_ascii_uppercase() {
+                composition[(residue - b'A') as usize] += 1;
+            }
+        }
+        PeptideProperties {
+            length: peptide.sequence.len(),
+            isoelectric_point: isoelectric_point(&peptide.sequence),
+            gravy: gravy(&peptide.sequence),
+            composition,
+        }
+    }
+
+    /// Number of occurrences of `residue` in the peptide
+    pub fn count(&self, residue: u8) -> u16 {
+        match residue.is_ascii_uppercase() {
+            true => self.composition[(residue - b'A') as usize],
+            false => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::enzyme::Digest;
+
+    #[test]
+    fn peptide_properties() {
+        // Only the termini are ionizable, so the pI is halfway between their pKa
+        assert!((isoelectric_point(b"GGG") - 6.1).abs() < 1E-3);
+        assert!(isoelectric_point(b"KKKR") > 10.0);
+        assert!(isoelectric_point(b"DDEE") < 4.0);
+        assert!(net_charge(b"PEPTIDEK", 2.0) > 0.0);
+        assert!(net_charge(b"PEPTIDEK", 12.0) < 0.0);
+
+        assert!((gravy(b"AAA") - 1.8).abs() < 1E-5);
+        assert!((gravy(b"ILVX") - 12.5 / 3.0).abs() < 1E-5);
+        assert_eq!(gravy(b""), 0.0);
+
+        let peptide = Peptide::try_from(Digest {
+            sequence: "LESLIEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let properties = PeptideProperties::new(&peptide);
+        assert_eq!(properties.length, 7);
+        assert_eq!(properties.count(b'L'), 2);
+        assert_eq!(properties.count(b'E'), 2);
+        assert_eq!(properties.count(b'W'), 0);
+        assert!((properties.gravy - gravy(b"LESLIEK")).abs() < 1E-6);
+        assert!(properties.isoelectric_point < 7.0);
+    }
+}
@@ -1,10 +1,12 @@
-use crate::database::{IndexedDatabase, PeptideIx};
+use crate::database::{IndexedDatabase, PeptideIx, SearchScratch, Theoretical};
 use crate::heap::bounded_min_heapify;
-use crate::ion_series::{IonSeries, Kind};
+use crate::ion_series::{IonSeries, IonWeights, Kind};
+use crate::isotopes::most_abundant_isotope;
 use crate::mass::{Tolerance, NEUTRON, PROTON};
-use crate::spectrum::{Precursor, ProcessedSpectrum};
-use serde::Serialize;
-use std::ops::AddAssign;
+use crate::peptide::Peptide;
+use crate::spectrum::{select_peak, PeakSelection, Precursor, ProcessedSpectrum};
+use serde::{Deserialize, Serialize};
+use std::ops::{AddAssign, RangeInclusive};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// Structure to hold temporary scores
@@ -15,12 +17,23 @@ struct Score {
     matched_y: u16,
     summed_b: f32,
     summed_y: f32,
+    /// Matched intensities, scaled by [`IonWeights`]
+    weighted_b: f32,
+    weighted_y: f32,
     longest_b: usize,
     longest_y: usize,
+    complementary_pairs: u16,
+    /// Number of theoretical fragment ions considered for matching
+    theoretical: u16,
     hyperscore: f64,
+    /// Andromeda-style binomial score, see [`binomial_score`]
+    andromeda: f64,
     ppm_difference: f32,
     precursor_charge: u8,
     isotope_error: i8,
+    precursor_offset: u8,
+    /// Does the intact precursor mass match the peptide? See [`Scorer::intact_mass_tol`]
+    intact_mass: bool,
 }
 
 /// Preliminary score - # of matched peaks for each candidate peptide
@@ -30,6 +43,8 @@ struct PreScore {
     peptide: PeptideIx,
     precursor_charge: u8,
     isotope_error: i8,
+    /// Index of the precursor mass offset (see [`Scorer::offset_mass`])
+    precursor_offset: u8,
 }
 
 /// Store preliminary scores & stats for first pass search for a query spectrum
@@ -50,7 +65,7 @@ impl AddAssign<InitialHits> for InitialHits {
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Clone, Debug, Default)]
 /// Features of a candidate peptide spectrum match
 pub struct Feature {
     #[serde(skip_serializing)]
@@ -80,8 +95,19 @@ pub struct Feature {
     pub predicted_rt: f32,
     /// Difference between predicted & observed RT
     pub delta_rt_model: f32,
+    /// Observed minus externally predicted RT, if a prediction was supplied,
+    /// see [`crate::ml::retention_model::predict_external`]
+    pub delta_rt_external: Option<f32>,
     /// Ion mobility
     pub ims: f32,
+    /// FAIMS compensation voltage, if acquired with a FAIMS device
+    pub faims_cv: Option<f32>,
+    /// Ratio of interfering to precursor MS1 intensity within the isolation
+    /// window, see [`crate::spectrum::assign_chimericity`]
+    pub chimericity: Option<f32>,
+    /// Copied from the PSM of a cluster representative, rather than found by
+    /// searching this spectrum, see [`crate::cluster::propagate`]
+    pub propagated: bool,
     /// Predicted ion mobility, if enabled
     pub predicted_ims: f32,
     /// Difference between predicted & observed ion mobility
@@ -90,6 +116,8 @@ pub struct Feature {
     pub delta_mass: f32,
     /// C13 isotope error
     pub isotope_error: f32,
+    /// User-defined precursor mass offset (in Da) that produced this match
+    pub precursor_offset: f32,
     /// Average ppm delta mass for matched fragments
     pub average_ppm: f32,
     /// X!Tandem hyperscore
@@ -106,14 +134,22 @@ pub struct Feature {
     pub longest_y: u32,
     /// Longest y-ion series, divided by peptide length
     pub longest_y_pct: f32,
+    /// Number of cleavage sites where both the b and y ion were matched
+    pub complementary_pairs: u32,
     /// Number of missed cleavages
     pub missed_cleavages: u8,
+    /// Number of enzymatic termini of the matched peptide (0, 1 or 2)
+    pub enzymatic_termini: u8,
+    /// Isotopic label channel of the matched peptide, if label channels are configured
+    pub label_channel: Option<u8>,
     /// Fraction of matched MS2 intensity
     pub matched_intensity_pct: f32,
     /// Number of scored candidates for this spectrum
     pub scored_candidates: u32,
     /// Probability of matching exactly N peaks across all candidates Pr(x=k)
     pub poisson: f64,
+    /// Andromeda-style binomial score, see [`binomial_score`]
+    pub andromeda: f64,
     /// Combined score from linear discriminant analysis, used for FDR calc
     pub discriminant_score: f32,
     /// Posterior error probability for this PSM / local FDR
@@ -142,12 +178,12 @@ pub struct Fragments {
 
 static PSM_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
-fn increment_psm_counter() -> usize {
+pub(crate) fn increment_psm_counter() -> usize {
     PSM_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
 /// Stirling's approximation for log factorial
-fn lnfact(n: u16) -> f64 {
+pub(crate) fn lnfact(n: u16) -> f64 {
     if n == 0 {
         1.0
     } else {
@@ -156,12 +192,119 @@ fn lnfact(n: u16) -> f64 {
     }
 }
 
+/// Probability that a theoretical fragment ion matches a peak in `spectrum`
+/// by chance: the fraction of the spectrum's mass range covered by the
+/// `fragment_tol` windows around each of its peaks
+pub fn random_match_probability(spectrum: &ProcessedSpectrum, fragment_tol: Tolerance) -> f64 {
+    let (first, last) = match (spectrum.peaks.first(), spectrum.peaks.last()) {
+        (Some(first), Some(last)) => (first.mass, last.mass),
+        _ => return 0.0,
+    };
+    let covered = spectrum
+        .peaks
+        .iter()
+        .map(|peak| {
+            let (lo, hi) = fragment_tol.bounds(peak.mass);
+            (hi - lo) as f64
+        })
+        .sum::<f64>();
+    match last - first {
+        span if span > 0.0 => (covered / span as f64).min(1.0),
+        _ => 1.0,
+    }
+}
+
+/// Andromeda-style score: `-10 * log10(P(X >= matched))`, where X follows a
+/// binomial distribution over `theoretical` fragment ions that each match by
+/// chance with probability `p`
+pub fn binomial_score(matched: u32, theoretical: u32, p: f64) -> f64 {
+    let k = matched.min(theoretical);
+    if k == 0 || p >= 1.0 {
+        return 0.0;
+    }
+    let n = theoretical as f64;
+    let p = p.max(f64::MIN_POSITIVE);
+
+    // ln Pr(X = k), with the binomial coefficient accumulated as a sum of logs
+    let ln_choose = (0..k)
+        .map(|i| (n - i as f64).ln() - (i as f64 + 1.0).ln())
+        .sum::<f64>();
+    let ln_pmf = ln_choose + k as f64 * p.ln() + (n - k as f64) * (-p).ln_1p();
+
+    // Sum the upper tail relative to Pr(X = k), using the ratio of successive terms
+    let odds = p / (1.0 - p);
+    let (mut term, mut tail) = (1.0, 1.0);
+    for j in k..theoretical {
+        term *= (n - j as f64) / (j as f64 + 1.0) * odds;
+        tail += term;
+    }
+
+    let score = -10.0 * (ln_pmf + tail.ln()) / std::f64::consts::LN_10;
+    score.max(0.0)
+}
+
+/// Andromeda-style score of `candidate` against `spectrum`, matching singly
+/// charged b- and y-ions within `fragment_tol`. See [`binomial_score`]
+pub fn andromeda_score(
+    spectrum: &ProcessedSpectrum,
+    candidate: &Peptide,
+    fragment_tol: Tolerance,
+) -> f64 {
+    let (theoretical, matched) = [Kind::B, Kind::Y]
+        .iter()
+        .flat_map(|kind| IonSeries::new(candidate, *kind))
+        .fold((0, 0), |(theoretical, matched), ion| {
+            let hit = crate::spectrum::select_most_intense_peak(
+                &spectrum.peaks,
+                ion.monoisotopic_mass,
+                fragment_tol,
+                None,
+            );
+            (theoretical + 1, matched + hit.is_some() as u32)
+        });
+    binomial_score(
+        matched,
+        theoretical,
+        random_match_probability(spectrum, fragment_tol),
+    )
+}
+
+/// Primary score used to rank the candidate peptides of a spectrum
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RankingScore {
+    /// X!Tandem hyperscore
+    #[default]
+    Hyperscore,
+    /// Andromeda-style binomial score, see [`binomial_score`]
+    Andromeda,
+}
+
+impl RankingScore {
+    /// Value of the ranking score for a reported PSM
+    pub fn of(self, feature: &Feature) -> f64 {
+        match self {
+            RankingScore::Hyperscore => feature.hyperscore,
+            RankingScore::Andromeda => feature.andromeda,
+        }
+    }
+
+    fn of_candidate(self, score: &Score) -> f64 {
+        match self {
+            RankingScore::Hyperscore => score.hyperscore,
+            RankingScore::Andromeda => score.andromeda,
+        }
+    }
+}
+
 impl Score {
     /// Calculate the X!Tandem hyperscore
     /// * `fact_table` is a precomputed vector of factorials
     fn hyperscore(&self) -> f64 {
-        let i = (self.summed_b + 1.0) as f64 * (self.summed_y + 1.0) as f64;
-        let score = i.ln() + lnfact(self.matched_b) + lnfact(self.matched_y);
+        let i = (self.weighted_b + 1.0) as f64 * (self.weighted_y + 1.0) as f64;
+        // A matching intact precursor counts as a full-length y-ion
+        let matched_y = self.matched_y + self.intact_mass as u16;
+        let score = i.ln() + lnfact(self.matched_b) + lnfact(matched_y);
         if score.is_finite() {
             score
         } else {
@@ -176,17 +319,81 @@ pub struct Scorer<'db> {
     pub fragment_tol: Tolerance,
     /// What is the minimum number of matched b and y ion peaks to report PSMs for?
     pub min_matched_peaks: u16,
+    /// Minimum length of the longest run of consecutive b or y ions (a
+    /// sequence tag) to report PSMs for. Scattered matches count towards
+    /// `min_matched_peaks`, but not towards this requirement (0 to disable)
+    pub min_consecutive_ions: u16,
+    /// Discard PSMs with a ranking score (see `ranking_score`) below this
+    /// value (`None` to disable).
+    /// Targets and decoys are filtered alike, so FDR estimation is not biased,
+    /// but q-values are only estimated among the remaining PSMs
+    pub min_score: Option<f64>,
     /// Precursor isotope error lower bounds (e.g. -1)
     pub min_isotope_err: i8,
     /// Precursor isotope error upper bounds (e.g. 3)
     pub max_isotope_err: i8,
+    /// Extend the isotope error upper bound to the most abundant isotope of
+    /// the averagine envelope at the precursor mass, for instruments that
+    /// select the most abundant rather than monoisotopic peak of large peptides
+    pub averagine_isotope_errors: bool,
+    /// Assume the reported precursor is the most abundant isotope of the
+    /// averagine envelope, and shift isotope errors (and thus the delta mass,
+    /// e.g. for open searches) to be relative to the estimated monoisotopic
+    /// mass. Takes precedence over `averagine_isotope_errors`
+    pub averagine_precursor_correction: bool,
+    /// Subtract this from the hyperscore of a candidate for each unit of
+    /// (absolute) precursor isotope error, so that exact matches are preferred
+    /// over equally good matches at an isotope error (0.0 to disable)
+    pub isotope_error_penalty: f64,
+    /// Additional neutral mass offsets (in Da) of the precursor relative to the
+    /// peptide, e.g. for adducts or in-source fragments. These are searched
+    /// alongside the unshifted precursor mass (and all isotope errors)
+    pub precursor_offsets: &'db [f32],
+    /// Charge states to search precursors without an annotated charge state at
     pub min_precursor_charge: u8,
     pub max_precursor_charge: u8,
+    /// Never search precursors at charge states outside of this (inclusive)
+    /// range, whether annotated or assumed. Spectra without any charge state
+    /// within the range are skipped entirely
+    pub precursor_charge_limits: (u8, u8),
     pub max_fragment_charge: Option<u8>,
+    /// Ignore fragment ions with an m/z above the precursor m/z. This is
+    /// applied at query time, using the precursor mass of each candidate,
+    /// since the fragment index is shared between all precursor charge states
+    pub exclude_above_precursor_mz: bool,
+    /// Count each fragment ion (kind and ordinal) at most once towards the
+    /// number of matched peaks, even if it is matched at several charge
+    /// states. Intensities of all matched charge states are still summed
+    pub dedup_fragment_charges: bool,
+    /// Only consider fragment ions with a charge of at most the precursor
+    /// charge minus this offset (but always singly charged fragments), e.g.
+    /// 1 (the default) searches fragments up to 2+ for a 3+ precursor
+    pub fragment_charge_offset: u8,
+    /// Treat the intact precursor as an additional matched pseudo-fragment
+    /// when its neutral mass matches the peptide within this (tight) tolerance,
+    /// without isotope errors or precursor offsets
+    pub intact_mass_tol: Option<Tolerance>,
     pub min_fragment_mass: f32,
     pub max_fragment_mass: f32,
+    /// Ignore theoretical fragment ions below this m/z when scoring candidates,
+    /// e.g. to keep reporter and immonium ions out of sequence ion scores
+    /// while they remain in the fragment index. Unlike `min_fragment_mass`,
+    /// this only affects scoring and not the initial fragment index search
+    pub min_scored_fragment_mz: Option<f32>,
     pub chimera: bool,
     pub report_psms: usize,
+    /// Weight matched fragment intensities by ion kind when calculating hyperscore
+    pub ion_weights: IonWeights,
+    /// Only score ion kinds produced by the activation method of each
+    /// spectrum (if known), e.g. b/y ions for HCD and c/z ions for ETD
+    pub activation_ion_kinds: bool,
+    /// Which observed peak to match when several fall within `fragment_tol`
+    /// of a theoretical fragment. Each observed peak is counted for at most
+    /// one theoretical fragment of a candidate
+    pub peak_selection: PeakSelection,
+    /// Primary score used to rank candidates (and calculate `delta_next` and
+    /// `delta_best`). Ties are broken by hyperscore, then as in [`candidate_order`]
+    pub ranking_score: RankingScore,
 
     // Rather than use a fixed precursor tolerance, dynamically alter
     // the precursor tolerance window based on MS2 isolation window and charge
@@ -194,33 +401,267 @@ pub struct Scorer<'db> {
     pub annotate_matches: bool,
 }
 
+/// Owned settings of a [`Scorer`], which can be built for any database with
+/// [`ScoringSettings::scorer`]. Defaults match those of the command line
+/// interface; see [`Scorer`] for the meaning of each field
+#[derive(Clone, Debug)]
+pub struct ScoringSettings {
+    pub precursor_tol: Tolerance,
+    pub fragment_tol: Tolerance,
+    pub min_matched_peaks: u16,
+    pub min_consecutive_ions: u16,
+    pub min_score: Option<f64>,
+    /// Precursor charge states to try, if not reported in the spectrum
+    pub precursor_charge: (u8, u8),
+    pub precursor_charge_limits: (u8, u8),
+    pub isotope_errors: (i8, i8),
+    pub averagine_isotope_errors: bool,
+    pub averagine_precursor_correction: bool,
+    pub isotope_error_penalty: f64,
+    pub precursor_offsets: Vec<f32>,
+    pub max_fragment_charge: Option<u8>,
+    pub exclude_above_precursor_mz: bool,
+    pub dedup_fragment_charges: bool,
+    pub fragment_charge_offset: u8,
+    pub intact_mass_tol: Option<Tolerance>,
+    pub min_scored_fragment_mz: Option<f32>,
+    pub chimera: bool,
+    pub wide_window: bool,
+    pub report_psms: usize,
+    pub ion_weights: IonWeights,
+    pub activation_ion_kinds: bool,
+    pub peak_selection: PeakSelection,
+    pub ranking_score: RankingScore,
+    pub annotate_matches: bool,
+}
+
+impl ScoringSettings {
+    pub fn new(precursor_tol: Tolerance, fragment_tol: Tolerance) -> Self {
+        Self {
+            precursor_tol,
+            fragment_tol,
+            min_matched_peaks: 4,
+            min_consecutive_ions: 0,
+            min_score: None,
+            precursor_charge: (2, 4),
+            precursor_charge_limits: (1, u8::MAX),
+            isotope_errors: (0, 0),
+            averagine_isotope_errors: false,
+            averagine_precursor_correction: false,
+            isotope_error_penalty: 0.0,
+            precursor_offsets: Vec::new(),
+            max_fragment_charge: None,
+            exclude_above_precursor_mz: false,
+            dedup_fragment_charges: false,
+            fragment_charge_offset: 1,
+            intact_mass_tol: None,
+            min_scored_fragment_mz: None,
+            chimera: false,
+            wide_window: false,
+            report_psms: 1,
+            ion_weights: IonWeights::default(),
+            activation_ion_kinds: false,
+            peak_selection: PeakSelection::default(),
+            ranking_score: RankingScore::default(),
+            annotate_matches: false,
+        }
+    }
+
+    /// Build a [`Scorer`] searching `db`, which was generated with fragment
+    /// m/z limits of `min_fragment_mass` and `max_fragment_mass`
+    pub fn scorer<'db>(
+        &'db self,
+        db: &'db IndexedDatabase,
+        min_fragment_mass: f32,
+        max_fragment_mass: f32,
+    ) -> Scorer<'db> {
+        Scorer {
+            db,
+            precursor_tol: self.precursor_tol,
+            fragment_tol: self.fragment_tol,
+            min_matched_peaks: self.min_matched_peaks,
+            min_consecutive_ions: self.min_consecutive_ions,
+            min_score: self.min_score,
+            min_isotope_err: self.isotope_errors.0,
+            max_isotope_err: self.isotope_errors.1,
+            averagine_isotope_errors: self.averagine_isotope_errors,
+            averagine_precursor_correction: self.averagine_precursor_correction,
+            isotope_error_penalty: self.isotope_error_penalty,
+            precursor_offsets: &self.precursor_offsets,
+            min_precursor_charge: self.precursor_charge.0,
+            max_precursor_charge: self.precursor_charge.1,
+            precursor_charge_limits: self.precursor_charge_limits,
+            max_fragment_charge: self.max_fragment_charge,
+            exclude_above_precursor_mz: self.exclude_above_precursor_mz,
+            dedup_fragment_charges: self.dedup_fragment_charges,
+            fragment_charge_offset: self.fragment_charge_offset,
+            intact_mass_tol: self.intact_mass_tol,
+            min_fragment_mass,
+            max_fragment_mass,
+            min_scored_fragment_mz: self.min_scored_fragment_mz,
+            chimera: self.chimera,
+            report_psms: self.report_psms,
+            ion_weights: self.ion_weights,
+            activation_ion_kinds: self.activation_ion_kinds,
+            peak_selection: self.peak_selection,
+            ranking_score: self.ranking_score,
+            wide_window: self.wide_window,
+            annotate_matches: self.annotate_matches,
+        }
+    }
+}
+
 #[inline(always)]
 /// Calculate upper bound (excluded) of the charge state range to use for
 /// searching fragment ions (1..N)
+/// Fragment ions are considered up to charge `precursor_charge - offset`
+/// (typically `precursor_charge - 1`, see [`Scorer::fragment_charge_offset`]).
 /// If user has configured max_fragment_charge, potentially override precursor
 /// charge
-fn max_fragment_charge(max_fragment_charge: Option<u8>, precursor_charge: u8) -> u8 {
-    precursor_charge
+pub(crate) fn max_fragment_charge(
+    max_fragment_charge: Option<u8>,
+    precursor_charge: u8,
+    offset: u8,
+) -> u8 {
+    let bound = precursor_charge.saturating_add(1).saturating_sub(offset);
+    bound
         .min(
             max_fragment_charge
-                .map(|c| c + 1)
-                .unwrap_or(precursor_charge),
+                .map(|c| c.saturating_add(1))
+                .unwrap_or(bound),
         )
         .max(2)
 }
 
 impl<'db> Scorer<'db> {
     pub fn score(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+        self.score_with(&mut SearchScratch::default(), query)
+    }
+
+    /// Same as [`Scorer::score`], but reuses the buffers of `scratch` for the
+    /// preliminary search, avoiding per-spectrum allocations when searching
+    /// many spectra in a loop. Use one instance per thread
+    pub fn score_with(
+        &self,
+        scratch: &mut SearchScratch,
+        query: &ProcessedSpectrum,
+    ) -> Vec<Feature> {
         assert_eq!(
             query.level, 2,
             "internal bug, trying to score a non-MS2 scan!"
         );
         match self.chimera {
-            true => self.score_chimera_fast(query),
-            false => self.score_standard(query),
+            true => self.score_chimera_fast(scratch, query),
+            false => self.score_standard(scratch, query),
+        }
+    }
+
+    /// Isotope errors to consider for a precursor of (neutral) `precursor_mass`
+    fn isotope_errors(&self, precursor_mass: f32) -> RangeInclusive<i8> {
+        let most_abundant = most_abundant_isotope(precursor_mass).min(i8::MAX as u8) as i8;
+        match (
+            self.averagine_precursor_correction,
+            self.averagine_isotope_errors,
+        ) {
+            (true, _) => {
+                self.min_isotope_err.saturating_add(most_abundant)
+                    ..=self.max_isotope_err.saturating_add(most_abundant)
+            }
+            (false, true) => self.min_isotope_err..=self.max_isotope_err.max(most_abundant),
+            (false, false) => self.min_isotope_err..=self.max_isotope_err,
+        }
+    }
+
+    /// Upper bound (excluded) of fragment charge states to match for a
+    /// precursor of `precursor_charge`, see `fragment_charge_offset`
+    fn max_fragment_charge(&self, precursor_charge: u8) -> u8 {
+        max_fragment_charge(
+            self.max_fragment_charge,
+            precursor_charge,
+            self.fragment_charge_offset,
+        )
+    }
+
+    /// Highest (neutral) fragment m/z to match for a precursor of (neutral)
+    /// `precursor_mass` and `precursor_charge`, see `exclude_above_precursor_mz`
+    fn max_fragment_mz(&self, precursor_mass: f32, precursor_charge: u8) -> f32 {
+        match self.exclude_above_precursor_mz {
+            true => precursor_mass / precursor_charge as f32,
+            false => f32::MAX,
+        }
+    }
+
+    /// Indices of all precursor mass offsets to search: 0 is the unshifted
+    /// precursor mass, and `i > 0` refers to `precursor_offsets[i - 1]`
+    fn offset_indices(&self) -> impl Iterator<Item = u8> + '_ {
+        std::iter::once(0).chain(
+            self.precursor_offsets
+                .iter()
+                .take(u8::MAX as usize)
+                .enumerate()
+                .filter(|(_, offset)| **offset != 0.0)
+                .map(|(idx, _)| idx as u8 + 1),
+        )
+    }
+
+    /// Neutral mass (in Da) of the precursor mass offset at index `idx`
+    fn offset_mass(&self, idx: u8) -> f32 {
+        match idx {
+            0 => 0.0,
+            idx => self.precursor_offsets[idx as usize - 1],
         }
     }
 
+    /// Return the lowest and highest (neutral) peptide mass that could be
+    /// matched to any of the precursors of `query`, taking into account
+    /// precursor charge states, isotope errors and precursor tolerance. Useful
+    /// for routing spectra to [`crate::database::PartitionedDatabase`] partitions.
+    ///
+    /// Precursor m/z values are converted to neutral masses for each charge
+    /// state before `precursor_tol` is applied, so tolerances are always in
+    /// neutral mass space
+    pub fn precursor_mass_bounds(&self, query: &ProcessedSpectrum) -> (f32, f32) {
+        if query.precursors.is_empty() {
+            panic!("missing MS1 precursor for {}", query.id);
+        }
+
+        query
+            .precursors
+            .iter()
+            .flat_map(|precursor| {
+                self.precursor_charges(precursor)
+                    .into_iter()
+                    .map(move |precursor_charge| (precursor, precursor_charge))
+            })
+            .flat_map(|(precursor, precursor_charge)| {
+                // Sage operates on masses without protons; [M] instead of [MH+]
+                let mz = precursor.neutral_mz();
+                let precursor_tol = match self.wide_window {
+                    true => {
+                        precursor
+                            .isolation_window
+                            .unwrap_or(Tolerance::Da(-2.4, 2.4))
+                            * precursor_charge as f32
+                    }
+                    false => self.precursor_tol,
+                };
+                let precursor_mass = mz * precursor_charge as f32;
+                self.isotope_errors(precursor_mass)
+                    .flat_map(move |isotope_error| {
+                        self.offset_indices().map(move |offset| {
+                            precursor_tol.bounds(
+                                precursor_mass
+                                    - isotope_error as f32 * NEUTRON
+                                    - self.offset_mass(offset),
+                            )
+                        })
+                    })
+            })
+            .fold((f32::MAX, f32::MIN), |(lo, hi), (a, b)| {
+                (lo.min(a), hi.max(b))
+            })
+    }
+
     /// Perform a k-select and truncation of an [`InitialHits`] list.
     ///
     /// Determine how many candidates to actually calculate hyperscore for.
@@ -245,42 +686,66 @@ impl<'db> Scorer<'db> {
     /// Returned hits are guaranteed to be the top-K hits (see above comment)
     /// from among all potential candidates, but the returned vector is not
     /// in sorted order.
+    #[allow(clippy::too_many_arguments)]
     fn matched_peaks_with_isotope(
         &self,
+        scratch: &mut SearchScratch,
         query: &ProcessedSpectrum,
         precursor_mass: f32,
         precursor_charge: u8,
         precursor_tol: Tolerance,
         isotope_error: i8,
+        precursor_offset: u8,
     ) -> InitialHits {
-        let candidates = self.db.query(
-            precursor_mass - isotope_error as f32 * NEUTRON,
-            precursor_tol,
-            self.fragment_tol,
-        );
-
-        let max_fragment_charge = max_fragment_charge(self.max_fragment_charge, precursor_charge);
-        // Allocate space for all potential candidates - many potential candidates
+        let candidate_mass =
+            precursor_mass - isotope_error as f32 * NEUTRON - self.offset_mass(precursor_offset);
+        let candidates = self
+            .db
+            .query(candidate_mass, precursor_tol, self.fragment_tol);
+        // Upper bound on the fragment m/z of all candidates, for stopping early
+        let max_mz = self.max_fragment_mz(precursor_tol.bounds(candidate_mass).1, precursor_charge);
+
+        let max_fragment_charge = self.max_fragment_charge(precursor_charge);
+        // Only pay for recovering fragment ion kinds if the activation method
+        // actually excludes some of the indexed kinds
+        let filter_kinds = self
+            .db
+            .ion_kinds
+            .iter()
+            .any(|kind| !self.scores_ion_kind(query, *kind));
+        // Count matched peaks of all potential candidates - many potential candidates
         let potential = candidates.pre_idx_hi - candidates.pre_idx_lo + 1;
-        let mut hits = InitialHits {
-            matched_peaks: 0,
-            scored_candidates: 0,
-            preliminary: vec![PreScore::default(); potential],
-        };
-
-        for peak in query.peaks.iter() {
+        scratch.counts.clear();
+        scratch.counts.resize(potential, 0);
+        let mut hits = InitialHits::default();
+
+        // Peaks are sorted by mass, so we can stop at the first one that cannot
+        // match any fragment below the precursor m/z, even at the edge of the
+        // fragment tolerance
+        for peak in query
+            .peaks
+            .iter()
+            .take_while(|peak| self.fragment_tol.bounds(peak.mass).0 <= max_mz)
+        {
             for charge in 1..max_fragment_charge {
                 let mass = peak.mass * charge as f32;
                 for frag in candidates.page_search(mass) {
-                    let idx = frag.peptide_index.0 as usize - candidates.pre_idx_lo;
-                    let sc = &mut hits.preliminary[idx];
-                    if sc.matched == 0 {
-                        hits.scored_candidates += 1;
-                        sc.precursor_charge = precursor_charge;
-                        sc.peptide = frag.peptide_index;
-                        sc.isotope_error = isotope_error;
+                    // Filter on theoretical fragment m/z and peptide mass, like
+                    // `score_candidate`
+                    if self.exclude_above_precursor_mz
+                        && frag.fragment_mz / charge as f32
+                            > self.max_fragment_mz(
+                                self.db.masses[frag.peptide_index.0 as usize],
+                                precursor_charge,
+                            )
+                    {
+                        continue;
                     }
-                    sc.matched += 1;
+                    if filter_kinds && !self.scores_fragment(query, frag) {
+                        continue;
+                    }
+                    let idx = frag.peptide_index.0 as usize - candidates.pre_idx_lo;
+                    scratch.counts[idx] += 1;
                     hits.matched_peaks += 1;
                 }
             }
@@ -289,100 +754,166 @@ impl<'db> Scorer<'db> {
             return hits;
         }
 
+        // Only candidates with matched peaks are kept
+        hits.preliminary.extend(
+            scratch
+                .counts
+                .iter()
+                .enumerate()
+                .filter(|(_, &matched)| matched > 0)
+                .map(|(idx, &matched)| PreScore {
+                    matched,
+                    peptide: PeptideIx((candidates.pre_idx_lo + idx) as u32),
+                    precursor_charge,
+                    isotope_error,
+                    precursor_offset,
+                }),
+        );
+        hits.scored_candidates = hits.preliminary.len();
+
         self.trim_hits(&mut hits);
         hits
     }
 
     fn matched_peaks(
         &self,
+        scratch: &mut SearchScratch,
         query: &ProcessedSpectrum,
         precursor_mass: f32,
         precursor_charge: u8,
         precursor_tol: Tolerance,
     ) -> InitialHits {
-        if self.min_isotope_err != self.max_isotope_err {
-            let mut hits = (self.min_isotope_err..=self.max_isotope_err).fold(
-                InitialHits::default(),
-                |mut hits, isotope| {
+        let isotope_errors = self.isotope_errors(precursor_mass);
+        if isotope_errors.start() != isotope_errors.end() || !self.precursor_offsets.is_empty() {
+            let mut hits = isotope_errors
+                .flat_map(|isotope| self.offset_indices().map(move |offset| (isotope, offset)))
+                .fold(InitialHits::default(), |mut hits, (isotope, offset)| {
                     hits += self.matched_peaks_with_isotope(
+                        scratch,
                         query,
                         precursor_mass,
                         precursor_charge,
                         precursor_tol,
                         isotope,
+                        offset,
                     );
                     hits
-                },
-            );
+                });
             self.trim_hits(&mut hits);
             hits
         } else {
             self.matched_peaks_with_isotope(
+                scratch,
                 query,
                 precursor_mass,
                 precursor_charge,
                 precursor_tol,
+                *isotope_errors.start(),
                 0,
             )
         }
     }
 
-    fn initial_hits(&self, query: &ProcessedSpectrum, precursor: &Precursor) -> InitialHits {
+    /// Charge states to search `precursor` at, restricted to
+    /// `precursor_charge_limits`
+    fn precursor_charges(&self, precursor: &Precursor) -> Vec<u8> {
+        let (lo, hi) = self.precursor_charge_limits;
+        let mut charges = match self.wide_window {
+            true => (self.min_precursor_charge..=self.max_precursor_charge).collect(),
+            false => {
+                precursor.candidate_charges(self.min_precursor_charge, self.max_precursor_charge)
+            }
+        };
+        charges.retain(|charge| (lo..=hi).contains(charge));
+        charges
+    }
+
+    fn initial_hits(
+        &self,
+        scratch: &mut SearchScratch,
+        query: &ProcessedSpectrum,
+        precursor: &Precursor,
+    ) -> InitialHits {
         // Sage operates on masses without protons; [M] instead of [MH+]
-        let mz = precursor.mz - PROTON;
+        let mz = precursor.neutral_mz();
+
+        let charges = self.precursor_charges(precursor);
 
         // Search in wide-window/DIA mode
         if self.wide_window {
-            let mut hits = (self.min_precursor_charge..=self.max_precursor_charge).fold(
-                InitialHits::default(),
-                |mut hits, precursor_charge| {
-                    let precursor_mass = mz * precursor_charge as f32;
-                    let precursor_tol = precursor
-                        .isolation_window
-                        .unwrap_or(Tolerance::Da(-2.4, 2.4))
-                        * precursor_charge as f32;
-                    hits +=
-                        self.matched_peaks(query, precursor_mass, precursor_charge, precursor_tol);
-                    hits
-                },
-            );
+            let mut hits =
+                charges
+                    .into_iter()
+                    .fold(InitialHits::default(), |mut hits, precursor_charge| {
+                        let precursor_mass = mz * precursor_charge as f32;
+                        let precursor_tol = precursor
+                            .isolation_window
+                            .unwrap_or(Tolerance::Da(-2.4, 2.4))
+                            * precursor_charge as f32;
+                        hits += self.matched_peaks(
+                            scratch,
+                            query,
+                            precursor_mass,
+                            precursor_charge,
+                            precursor_tol,
+                        );
+                        hits
+                    });
             self.trim_hits(&mut hits);
             hits
-        } else if let Some(charge) = precursor.charge {
+        } else if let [charge] = charges[..] {
             // Charge state is already annotated for this precusor, only search once
             let precursor_mass = mz * charge as f32;
-            self.matched_peaks(query, precursor_mass, charge, self.precursor_tol)
+            self.matched_peaks(scratch, query, precursor_mass, charge, self.precursor_tol)
         } else {
             // Not all selected ion precursors have charge states annotated -
-            // assume it could be z=2, z=3, z=4 and search all three
-            let mut hits = (self.min_precursor_charge..=self.max_precursor_charge).fold(
-                InitialHits::default(),
-                |mut hits, precursor_charge| {
-                    let precursor_mass = mz * precursor_charge as f32;
-                    hits += self.matched_peaks(
-                        query,
-                        precursor_mass,
-                        precursor_charge,
-                        self.precursor_tol,
-                    );
-                    hits
-                },
-            );
+            // assume it could be z=2, z=3, z=4 and search all three. Likewise,
+            // search all candidates if the charge state is ambiguous. Candidates
+            // from every charge state compete, and the best scoring PSM reports
+            // the charge state that was used to match it
+            let mut hits =
+                charges
+                    .into_iter()
+                    .fold(InitialHits::default(), |mut hits, precursor_charge| {
+                        let precursor_mass = mz * precursor_charge as f32;
+                        hits += self.matched_peaks(
+                            scratch,
+                            query,
+                            precursor_mass,
+                            precursor_charge,
+                            self.precursor_tol,
+                        );
+                        hits
+                    });
             self.trim_hits(&mut hits);
             hits
         }
     }
 
-    /// Score a single [`ProcessedSpectrum`] against the database
-    pub fn score_standard(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
-        let precursor = query.precursors.get(0).unwrap_or_else(|| {
+    /// Score a single [`ProcessedSpectrum`] against the database. Spectra with
+    /// several precursors (e.g. multiple selected ions) are searched at each of
+    /// them, and only the `report_psms` best PSMs are kept, see [`merge_psms`]
+    pub fn score_standard(
+        &self,
+        scratch: &mut SearchScratch,
+        query: &ProcessedSpectrum,
+    ) -> Vec<Feature> {
+        let precursor = query.precursors.first().unwrap_or_else(|| {
             panic!("missing MS1 precursor for {}", query.id);
         });
 
-        let hits = self.initial_hits(query, precursor);
+        let hits = self.initial_hits(scratch, query, precursor);
         let mut features = Vec::with_capacity(self.report_psms);
         self.build_features(query, precursor, &hits, self.report_psms, &mut features);
-        features
+        if query.precursors.len() == 1 {
+            return features;
+        }
+
+        for precursor in &query.precursors[1..] {
+            let hits = self.initial_hits(scratch, query, precursor);
+            self.build_features(query, precursor, &hits, self.report_psms, &mut features);
+        }
+        merge_psms(features, self.report_psms, self.ranking_score)
     }
 
     /// Given a set of [`InitialHits`] against a query spectrum, prepare N=`report_psms`
@@ -395,26 +926,42 @@ impl<'db> Scorer<'db> {
         report_psms: usize,
         features: &mut Vec<Feature>,
     ) {
+        let random_match = random_match_probability(query, self.fragment_tol);
+
+        // Shared across candidates, so that it is only allocated once per query
+        let mut claimed = Vec::with_capacity(query.peaks.len());
         let mut score_vector = hits
             .preliminary
             .iter()
             .filter(|score| score.peptide != PeptideIx::default())
-            .map(|pre| self.score_candidate(query, pre))
+            .map(|pre| self.score_candidate(query, precursor, pre, &mut claimed))
             .filter(|s| (s.0.matched_b + s.0.matched_y) >= self.min_matched_peaks)
+            .filter(|s| s.0.longest_b.max(s.0.longest_y) >= self.min_consecutive_ions as usize)
+            .map(|(mut score, fragments)| {
+                let matched = (score.matched_b + score.matched_y) as u32;
+                score.andromeda = binomial_score(matched, score.theoretical as u32, random_match);
+                (score, fragments)
+            })
             .collect::<Vec<_>>();
 
-        // Hyperscore is our primary score function for PSMs
-        score_vector.sort_by(|a, b| b.0.hyperscore.total_cmp(&a.0.hyperscore));
+        // Hyperscore is our primary score function for PSMs, unless configured otherwise
+        score_vector.sort_by(|a, b| candidate_order(&a.0, &b.0, self.ranking_score));
 
         // Expected value for poisson distribution
         // (average # of matches peaks/peptide candidate)
         let lambda = hits.matched_peaks as f64 / hits.scored_candidates as f64;
 
         // Sage operates on masses without protons; [M] instead of [MH+]
-        let mz = precursor.mz - PROTON;
+        let mz = precursor.neutral_mz();
 
         for idx in 0..report_psms.min(score_vector.len()) {
             let score = score_vector[idx].0;
+            let ranking = self.ranking_score.of_candidate(&score);
+            // Candidates are sorted by ranking score, so all remaining candidates
+            // are below the threshold too
+            if ranking < self.min_score.unwrap_or(f64::NEG_INFINITY) {
+                break;
+            }
             let fragments: Option<Fragments> = score_vector[idx].1.take();
             let psm_id = increment_psm_counter();
 
@@ -423,12 +970,12 @@ impl<'db> Scorer<'db> {
 
             let next = score_vector
                 .get(idx + 1)
-                .map(|score| score.0.hyperscore)
+                .map(|score| self.ranking_score.of_candidate(&score.0))
                 .unwrap_or_default();
 
             let best = score_vector
-                .get(0)
-                .map(|score| score.0.hyperscore)
+                .first()
+                .map(|score| self.ranking_score.of_candidate(&score.0))
                 .expect("we know that index 0 is valid");
 
             // Poisson distribution probability mass function
@@ -441,8 +988,11 @@ impl<'db> Scorer<'db> {
             }
 
             let isotope_error = score.isotope_error as f32 * NEUTRON;
-            let delta_mass = (precursor_mass - peptide.monoisotopic - isotope_error).abs() * 2E6
-                / (precursor_mass - isotope_error + peptide.monoisotopic);
+            let precursor_offset = self.offset_mass(score.precursor_offset);
+            let delta_mass =
+                (precursor_mass - peptide.monoisotopic - isotope_error - precursor_offset).abs()
+                    * 2E6
+                    / (precursor_mass - isotope_error - precursor_offset + peptide.monoisotopic);
 
             // let (num_proteins, proteins) = self.db.assign_proteins(peptide);
 
@@ -465,22 +1015,30 @@ impl<'db> Scorer<'db> {
                     .unwrap()
                     .inverse_ion_mobility
                     .unwrap_or(0.0),
+                faims_cv: query.faims_cv,
+                chimericity: query.chimericity,
+                propagated: false,
                 delta_mass,
                 isotope_error,
+                precursor_offset,
                 average_ppm: score.ppm_difference,
                 hyperscore: score.hyperscore,
-                delta_next: score.hyperscore - next,
-                delta_best: best - score.hyperscore,
+                delta_next: ranking - next,
+                delta_best: best - ranking,
                 matched_peaks: k as u32,
                 matched_intensity_pct: 100.0 * (score.summed_b + score.summed_y)
                     / query.total_ion_current,
                 poisson: poisson.log10(),
+                andromeda: score.andromeda,
                 longest_b: score.longest_b as u32,
                 longest_y: score.longest_y as u32,
                 longest_y_pct: score.longest_y as f32 / (peptide.sequence.len() as f32),
+                complementary_pairs: score.complementary_pairs as u32,
                 peptide_len: peptide.sequence.len(),
                 scored_candidates: hits.scored_candidates as u32,
                 missed_cleavages: peptide.missed_cleavages,
+                enzymatic_termini: peptide.enzymatic_termini,
+                label_channel: peptide.label_channel,
 
                 // Outputs
                 discriminant_score: 0.0,
@@ -492,6 +1050,7 @@ impl<'db> Scorer<'db> {
                 predicted_ims: 0.0,
                 aligned_rt: query.scan_start_time,
                 delta_rt_model: 0.999,
+                delta_rt_external: None,
                 delta_ims_model: 0.999,
                 ms2_intensity: score.summed_b + score.summed_y,
 
@@ -510,41 +1069,44 @@ impl<'db> Scorer<'db> {
             .iter()
             .flat_map(|kind| IonSeries::new(peptide, *kind));
 
-        let max_fragment_charge = max_fragment_charge(self.max_fragment_charge, psm.charge);
+        let max_fragment_charge = self.max_fragment_charge(psm.charge);
 
         // Remove MS2 peaks matched by previous match
-        let mut to_remove = Vec::new();
+        let mut claimed = vec![false; query.peaks.len()];
         for frag in fragments {
             for charge in 1..max_fragment_charge {
                 // Experimental peaks are multipled by charge, therefore theoretical are divided
-                if let Some(peak) = crate::spectrum::select_most_intense_peak(
+                if let Some(idx) = select_peak(
                     &query.peaks,
                     frag.monoisotopic_mass / charge as f32,
                     self.fragment_tol,
-                    None,
+                    self.peak_selection,
+                    Some(&claimed),
                 ) {
-                    to_remove.push(*peak);
+                    claimed[idx] = true;
                 }
             }
         }
 
-        query.peaks = query
-            .peaks
-            .drain(..)
-            .filter(|peak| !to_remove.contains(peak))
-            .collect();
-        query.total_ion_current = query.peaks.iter().map(|peak| peak.intensity).sum::<f32>();
+        let mut claimed = claimed.into_iter();
+        query.peaks.retain(|_| !claimed.next().unwrap_or_default());
+        (query.total_ion_current, query.base_peak_intensity) =
+            crate::spectrum::ion_current(&query.peaks);
     }
 
     /// Return multiple PSMs for each spectra - first is the best match, second PSM is the best match
     /// after all theoretical peaks assigned to the best match are removed, etc
-    pub fn score_chimera_fast(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+    pub fn score_chimera_fast(
+        &self,
+        scratch: &mut SearchScratch,
+        query: &ProcessedSpectrum,
+    ) -> Vec<Feature> {
         let precursor = query.precursors.get(0).unwrap_or_else(|| {
             panic!("missing MS1 precursor for {}", query.id);
         });
 
         let mut query = query.clone();
-        let hits = self.initial_hits(&query, precursor);
+        let hits = self.initial_hits(scratch, &query, precursor);
 
         let mut candidates: Vec<Feature> = Vec::with_capacity(self.report_psms);
 
@@ -564,21 +1126,46 @@ impl<'db> Scorer<'db> {
         candidates
     }
 
+    /// Should ions of `kind` be scored against `query`? If enabled, restrict
+    /// scoring to the ion kinds produced by the spectrum's activation method
+    fn scores_ion_kind(&self, query: &ProcessedSpectrum, kind: Kind) -> bool {
+        match (self.activation_ion_kinds, query.activation) {
+            (true, Some(activation)) => activation.produces(kind),
+            _ => true,
+        }
+    }
+
+    /// Does `fragment` belong to an ion kind that should be scored against
+    /// `query`? The fragment index doesn't record ion kinds, so they are
+    /// recovered by regenerating the ion series of the fragment's peptide
+    fn scores_fragment(&self, query: &ProcessedSpectrum, fragment: &Theoretical) -> bool {
+        let peptide = &self.db[fragment.peptide_index];
+        self.db
+            .ion_kinds
+            .iter()
+            .filter(|kind| self.scores_ion_kind(query, **kind))
+            .flat_map(|kind| IonSeries::new(peptide, *kind))
+            .any(|ion| ion.monoisotopic_mass == fragment.fragment_mz)
+    }
+
     /// Calculate full hyperscore for a given PSM
     fn score_candidate(
         &self,
         query: &ProcessedSpectrum,
+        precursor: &Precursor,
         pre_score: &PreScore,
+        claimed: &mut Vec<bool>,
     ) -> (Score, Option<Fragments>) {
         let mut score = Score {
             peptide: pre_score.peptide,
             precursor_charge: pre_score.precursor_charge,
             isotope_error: pre_score.isotope_error,
+            precursor_offset: pre_score.precursor_offset,
             ..Default::default()
         };
         let peptide = &self.db[score.peptide];
-        let max_fragment_charge =
-            max_fragment_charge(self.max_fragment_charge, score.precursor_charge);
+        let max_fragment_charge = self.max_fragment_charge(score.precursor_charge);
+        let max_mz = self.max_fragment_mz(peptide.monoisotopic, score.precursor_charge);
 
         // Regenerate theoretical ions - initial database search might be
         // using only a subset of all possible ions (e.g. no b1/b2/y1/y2)
@@ -587,39 +1174,76 @@ impl<'db> Scorer<'db> {
             .db
             .ion_kinds
             .iter()
+            .filter(|kind| self.scores_ion_kind(query, **kind))
             .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate());
 
         let mut b_run = Run::default();
         let mut y_run = Run::default();
 
+        // Track which cleavage sites have matched b and y ions. Since theoretical
+        // b_i + y_(n-i) sum to the precursor mass, ions matched at the same index
+        // form a complementary pair
+        let mut b_sites = vec![false; peptide.sequence.len()];
+        let mut y_sites = vec![false; peptide.sequence.len()];
+
         let mut fragments_details = Fragments::default();
 
+        // Each observed peak is matched to at most one theoretical fragment
+        claimed.clear();
+        claimed.resize(query.peaks.len(), false);
+
         for (idx, frag) in fragments {
+            // Has this fragment already been considered (or matched) at a lower charge state?
+            let mut considered = false;
+            let mut matched = false;
             for charge in 1..max_fragment_charge {
                 // Experimental peaks are multipled by charge, therefore theoretical are divided
                 let mz = frag.monoisotopic_mass / charge as f32;
-                if let Some(peak) = crate::spectrum::select_most_intense_peak(
+                if mz > max_mz || mz + PROTON < self.min_scored_fragment_mz.unwrap_or(0.0) {
+                    continue;
+                }
+                if !(self.dedup_fragment_charges && considered) {
+                    score.theoretical += 1;
+                }
+                considered = true;
+                if let Some(peak_idx) = select_peak(
                     &query.peaks,
                     mz,
                     self.fragment_tol,
-                    None,
+                    self.peak_selection,
+                    Some(claimed),
                 ) {
+                    claimed[peak_idx] = true;
+                    let peak = &query.peaks[peak_idx];
                     score.ppm_difference +=
                         peak.intensity * (mz - peak.mass).abs() * 2E6 / (mz + peak.mass);
 
                     let exp_mz = peak.mass + PROTON;
                     let calc_mz = mz + PROTON;
+                    let weight =
+                        self.ion_weights
+                            .fragment_weight(frag.kind, idx, peptide.sequence.len());
+                    let count = !(self.dedup_fragment_charges && matched) as u16;
+                    matched = true;
 
                     match frag.kind {
                         Kind::A | Kind::B | Kind::C => {
-                            score.matched_b += 1;
+                            score.matched_b += count;
                             score.summed_b += peak.intensity;
+                            score.weighted_b += peak.intensity * weight;
                             b_run.matched(idx);
+                            if frag.kind == Kind::B {
+                                b_sites[idx] = true;
+                            }
                         }
                         Kind::X | Kind::Y | Kind::Z => {
-                            score.matched_y += 1;
+                            score.matched_y += count;
                             score.summed_y += peak.intensity;
+                            score.weighted_y += peak.intensity * weight;
                             y_run.matched(idx);
+                            if frag.kind == Kind::Y {
+                                y_sites[idx] = true;
+                            }
                         }
                     }
 
@@ -641,9 +1265,22 @@ impl<'db> Scorer<'db> {
             }
         }
 
-        score.hyperscore = score.hyperscore();
+        score.intact_mass = self
+            .intact_mass_tol
+            .map(|tol| {
+                let precursor_mass = precursor.neutral_mz() * score.precursor_charge as f32;
+                tol.contains(peptide.monoisotopic, precursor_mass)
+            })
+            .unwrap_or(false);
+        score.hyperscore = score.hyperscore()
+            - self.isotope_error_penalty * score.isotope_error.unsigned_abs() as f64;
         score.longest_b = b_run.longest;
         score.longest_y = y_run.longest;
+        score.complementary_pairs = b_sites
+            .iter()
+            .zip(&y_sites)
+            .filter(|(b, y)| **b && **y)
+            .count() as u16;
         score.ppm_difference /= score.summed_b + score.summed_y;
 
         if self.annotate_matches {
@@ -655,6 +1292,81 @@ impl<'db> Scorer<'db> {
     }
 }
 
+/// Rank candidates by decreasing `ranking` score (by default hyperscore), then
+/// hyperscore. Ties (e.g. between peptides differing only by I/L) are broken
+/// deterministically, preferring more matched ions, then the lower [`PeptideIx`]
+/// (i.e. the lighter peptide, or the first in sequence order if masses are
+/// equal), then the lower precursor charge, isotope error and precursor offset.
+/// Target/decoy status is deliberately not used, as favoring either would bias
+/// FDR estimation
+fn candidate_order(a: &Score, b: &Score, ranking: RankingScore) -> std::cmp::Ordering {
+    ranking
+        .of_candidate(b)
+        .total_cmp(&ranking.of_candidate(a))
+        .then_with(|| b.hyperscore.total_cmp(&a.hyperscore))
+        .then_with(|| (b.matched_b + b.matched_y).cmp(&(a.matched_b + a.matched_y)))
+        .then_with(|| a.peptide.cmp(&b.peptide))
+        .then_with(|| a.precursor_charge.cmp(&b.precursor_charge))
+        .then_with(|| a.isotope_error.cmp(&b.isotope_error))
+        .then_with(|| a.precursor_offset.cmp(&b.precursor_offset))
+}
+
+/// Combine PSMs for the same spectrum that were scored separately (e.g. against
+/// different partitions of a [`crate::database::PartitionedDatabase`]).
+///
+/// Only the `report_psms` best PSMs (by `ranking` score, then hyperscore) are
+/// kept for each spectrum, and `rank`, `delta_next` and `delta_best` are
+/// recalculated. Ties are broken as for PSMs scored together, by matched peaks
+/// and then peptide index.
+///
+/// `scored_candidates` and `poisson` are not recalculated: they remain local to
+/// the search that produced each PSM (e.g. only count the candidates of a single
+/// partition), since the preliminary counts of all candidates are not retained
+pub fn merge_psms(
+    mut features: Vec<Feature>,
+    report_psms: usize,
+    ranking: RankingScore,
+) -> Vec<Feature> {
+    features.sort_by(|a, b| {
+        a.file_id
+            .cmp(&b.file_id)
+            .then_with(|| a.spec_id.cmp(&b.spec_id))
+            .then_with(|| ranking.of(b).total_cmp(&ranking.of(a)))
+            .then_with(|| b.hyperscore.total_cmp(&a.hyperscore))
+            .then_with(|| b.matched_peaks.cmp(&a.matched_peaks))
+            .then_with(|| a.peptide_idx.cmp(&b.peptide_idx))
+    });
+
+    let mut start = 0;
+    while start < features.len() {
+        let end = start
+            + features[start..]
+                .iter()
+                .take_while(|feat| {
+                    feat.file_id == features[start].file_id
+                        && feat.spec_id == features[start].spec_id
+                })
+                .count();
+
+        let best = ranking.of(&features[start]);
+        for idx in start..end {
+            let next = match idx + 1 < end {
+                true => ranking.of(&features[idx + 1]),
+                false => 0.0,
+            };
+            let feat = &mut features[idx];
+            let score = ranking.of(feat);
+            feat.rank = (idx - start) as u32 + 1;
+            feat.delta_next = score - next;
+            feat.delta_best = best - score;
+        }
+        start = end;
+    }
+
+    features.retain(|feat| feat.rank as usize <= report_psms);
+    features
+}
+
 /// Maintain information about the longest continous ion ladder for a series
 #[derive(Default)]
 struct Run {
@@ -683,6 +1395,66 @@ impl Run {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::spectrum::Peak;
+
+    /// Database of a single protein with the peptides EQALLK, LEQSMR and AQLTQLK
+    fn test_db() -> IndexedDatabase {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta)
+    }
+
+    fn test_peptide<'a>(db: &'a IndexedDatabase, sequence: &str) -> &'a Peptide {
+        db.peptides
+            .iter()
+            .find(|p| p.sequence.as_ref() == sequence.as_bytes())
+            .unwrap()
+    }
+
+    /// Scorer searching 2+ precursors and singly charged fragments at 10 ppm,
+    /// reporting PSMs with at least one matched peak. Tests override the
+    /// fields under test
+    fn test_scorer(db: &IndexedDatabase) -> Scorer<'_> {
+        Scorer {
+            db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 1,
+            min_consecutive_ions: 0,
+            min_score: None,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            averagine_isotope_errors: false,
+            averagine_precursor_correction: false,
+            isotope_error_penalty: 0.0,
+            precursor_offsets: &[],
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            precursor_charge_limits: (1, u8::MAX),
+            max_fragment_charge: Some(1),
+            exclude_above_precursor_mz: false,
+            dedup_fragment_charges: false,
+            fragment_charge_offset: 1,
+            intact_mass_tol: None,
+            min_fragment_mass: 150.0,
+            max_fragment_mass: 2000.0,
+            min_scored_fragment_mz: None,
+            chimera: false,
+            report_psms: 1,
+            ion_weights: Default::default(),
+            activation_ion_kinds: false,
+            peak_selection: Default::default(),
+            ranking_score: Default::default(),
+            wide_window: false,
+            annotate_matches: false,
+        }
+    }
 
     #[test]
     fn longest_series() {
@@ -705,15 +1477,672 @@ mod tests {
         assert_eq!(run.length, 2);
     }
 
+    #[test]
+    fn binomial() {
+        // P(X >= 10) = 1/1024 and P(X >= 5) = 638/1024 for X ~ B(10, 0.5)
+        assert!((binomial_score(10, 10, 0.5) - 30.103).abs() < 1E-3);
+        assert!((binomial_score(5, 10, 0.5) - 2.0551).abs() < 1E-3);
+        assert_eq!(binomial_score(0, 10, 0.5), 0.0);
+        assert_eq!(binomial_score(5, 10, 1.0), 0.0);
+        assert!(binomial_score(6, 20, 0.01) > binomial_score(5, 20, 0.01));
+        assert!(binomial_score(6, 20, 0.01) > binomial_score(6, 20, 0.05));
+        assert!(binomial_score(8, 8, 0.0).is_finite());
+    }
+
+    #[test]
+    fn andromeda() {
+        use crate::enzyme::Digest;
+
+        let peptide = Peptide::try_from(Digest {
+            sequence: "LESLIEKR".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut peaks = IonSeries::new(&peptide, Kind::Y)
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let spectrum = ProcessedSpectrum {
+            peaks,
+            ..Default::default()
+        };
+
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let p = random_match_probability(&spectrum, tol);
+        assert!(p > 0.0 && p < 1E-3);
+
+        // All 7 y-ions, but none of the b-ions are matched
+        let score = andromeda_score(&spectrum, &peptide, tol);
+        assert!((score - binomial_score(7, 14, p)).abs() < 1E-9);
+        assert!(score > 100.0);
+
+        let other = Peptide::try_from(Digest {
+            sequence: "PEPTIDEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(andromeda_score(&spectrum, &other, tol) < score);
+        assert_eq!(andromeda_score(&Default::default(), &peptide, tol), 0.0);
+    }
+
+    #[test]
+    fn min_scored_fragment_mz() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            fragment_min_mz: Some(100.0),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.sequence.as_ref() == b"AQLTQLK")
+            .unwrap();
+
+        // Backbone y-ions above 150 m/z, and a low-mass (e.g. reporter ion) peak
+        // that coincides with the y1 ion
+        let y_ions = IonSeries::new(peptide, Kind::Y)
+            .map(|ion| ion.monoisotopic_mass)
+            .collect::<Vec<_>>();
+        let low = y_ions.iter().copied().find(|mass| mass + PROTON < 150.0);
+        let backbone = y_ions
+            .iter()
+            .copied()
+            .filter(|mass| mass + PROTON >= 150.0)
+            .collect::<Vec<_>>();
+        assert!(low.is_some());
+        assert_eq!(backbone.len(), 5);
+
+        let spectrum = |masses: &[f32]| {
+            let mut peaks = masses
+                .iter()
+                .map(|&mass| Peak {
+                    mass,
+                    intensity: 100.0,
+                })
+                .collect::<Vec<_>>();
+            peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+            ProcessedSpectrum {
+                level: 2,
+                id: "scan=1".into(),
+                precursors: vec![Precursor {
+                    mz: peptide.monoisotopic / 2.0 + PROTON,
+                    charge: Some(2),
+                    ..Default::default()
+                }],
+                total_ion_current: 100.0 * peaks.len() as f32,
+                peaks,
+                ..Default::default()
+            }
+        };
+        let with_reporter = spectrum(&[backbone.as_slice(), &[low.unwrap()]].concat());
+        let without_reporter = spectrum(&backbone);
+
+        let score = |min_scored_fragment_mz, query: &ProcessedSpectrum| {
+            let scorer = Scorer {
+                min_fragment_mass: 100.0,
+                min_scored_fragment_mz,
+                ..test_scorer(&db)
+            };
+            let feature = scorer.score(query).remove(0);
+            assert_eq!(&db[feature.peptide_idx], peptide);
+            feature
+        };
+
+        // The low-mass peak inflates the score, unless it is excluded from scoring
+        let inflated = score(None, &with_reporter);
+        let baseline = score(None, &without_reporter);
+        assert_eq!(inflated.matched_peaks, 6);
+        assert!(inflated.hyperscore > baseline.hyperscore);
+
+        let excluded = score(Some(150.0), &with_reporter);
+        assert_eq!(excluded.matched_peaks, 5);
+        assert_eq!(
+            excluded.hyperscore,
+            score(Some(150.0), &without_reporter).hyperscore
+        );
+    }
+
+    #[test]
+    fn activation_ion_kinds_preliminary() {
+        use crate::spectrum::Activation;
+
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            ion_kinds: Some(vec![Kind::B, Kind::Y, Kind::C, Kind::Z]),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        // Only c-ions are present in the spectrum
+        let mut peaks = IonSeries::new(peptide, Kind::C)
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let precursor = Precursor {
+            mz: peptide.monoisotopic / 2.0 + PROTON,
+            charge: Some(2),
+            ..Default::default()
+        };
+        let spectrum = |activation| ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![precursor.clone()],
+            total_ion_current: 100.0 * peaks.len() as f32,
+            peaks: peaks.clone(),
+            activation: Some(activation),
+            ..Default::default()
+        };
+
+        let matched = |activation_ion_kinds, activation| {
+            let scorer = Scorer {
+                activation_ion_kinds,
+                ..test_scorer(&db)
+            };
+            scorer
+                .initial_hits(
+                    &mut SearchScratch::default(),
+                    &spectrum(activation),
+                    &precursor,
+                )
+                .matched_peaks
+        };
+
+        assert!(matched(false, Activation::Hcd) > 0);
+        assert_eq!(
+            matched(true, Activation::Etd),
+            matched(false, Activation::Etd)
+        );
+        // c-ions can't be produced by HCD, so they don't count towards the
+        // preliminary score either
+        assert_eq!(matched(true, Activation::Hcd), 0);
+    }
+
+    #[test]
+    fn exclude_above_precursor_mz_preliminary() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        let mut peaks = IonSeries::new(peptide, Kind::B)
+            .chain(IonSeries::new(peptide, Kind::Y))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let precursor = Precursor {
+            mz: peptide.monoisotopic / 2.0 + PROTON,
+            charge: Some(2),
+            ..Default::default()
+        };
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![precursor.clone()],
+            total_ion_current: 100.0 * peaks.len() as f32,
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = |exclude_above_precursor_mz| Scorer {
+            exclude_above_precursor_mz,
+            ..test_scorer(&db)
+        };
+        let preliminary = |scorer: &Scorer| {
+            scorer
+                .initial_hits(&mut SearchScratch::default(), &spectrum, &precursor)
+                .matched_peaks as u32
+        };
+
+        let all = scorer(false);
+        let excluded = scorer(true);
+        let full = |scorer: &Scorer| scorer.score(&spectrum)[0].matched_peaks;
+        assert!(preliminary(&excluded) < preliminary(&all));
+        // Preliminary and full scoring exclude the same fragments (the fragment
+        // index does not contain fragments below `min_fragment_mass`)
+        assert_eq!(
+            preliminary(&all) - preliminary(&excluded),
+            full(&all) - full(&excluded)
+        );
+    }
+
+    #[test]
+    fn multiple_precursors() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        let mut peaks = IonSeries::new(peptide, Kind::B)
+            .chain(IonSeries::new(peptide, Kind::Y))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let precursor = |mz| Precursor {
+            mz,
+            charge: Some(2),
+            ..Default::default()
+        };
+        let mut spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![precursor(1500.0)],
+            total_ion_current: 100.0 * peaks.len() as f32,
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = test_scorer(&db);
+        assert!(scorer.score(&spectrum).is_empty());
+
+        // Only the second selected ion matches the peptide
+        spectrum
+            .precursors
+            .push(precursor(peptide.monoisotopic / 2.0 + PROTON));
+        let features = scorer.score(&spectrum);
+        assert_eq!(features.len(), 1);
+        assert_eq!(db[features[0].peptide_idx].to_string(), "AQLTQLK");
+        assert_eq!(features[0].rank, 1);
+    }
+
+    #[test]
+    fn ranking_score() {
+        let db = test_db();
+        let many = test_peptide(&db, "AQLTQLK");
+        let intense = test_peptide(&db, "EQALLK");
+
+        // Every fragment of AQLTQLK at low intensity, and a few very intense
+        // fragments of EQALLK: hyperscore favors the latter, Andromeda the former
+        let ions = |peptide, intensity| {
+            [Kind::B, Kind::Y]
+                .into_iter()
+                .flat_map(move |kind| IonSeries::new(peptide, kind))
+                .map(move |ion| Peak {
+                    mass: ion.monoisotopic_mass,
+                    intensity,
+                })
+        };
+        let mut peaks = ions(many, 1.0)
+            .chain(ions(intense, 1E8).filter(|peak| (300.0..600.0).contains(&peak.mass)))
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: many.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            total_ion_current: peaks.iter().map(|peak| peak.intensity).sum(),
+            peaks,
+            ..Default::default()
+        };
+
+        let search = |ranking_score| {
+            let scorer = Scorer {
+                precursor_tol: Tolerance::Da(-150.0, 150.0),
+                report_psms: 2,
+                ranking_score,
+                ..test_scorer(&db)
+            };
+            scorer.score(&spectrum)
+        };
+
+        let features = search(RankingScore::Hyperscore);
+        assert_eq!(db[features[0].peptide_idx].to_string(), "EQALLK");
+        assert!(features[0].andromeda < features[1].andromeda);
+        assert_eq!(
+            features[0].delta_next,
+            features[0].hyperscore - features[1].hyperscore
+        );
+
+        let features = search(RankingScore::Andromeda);
+        assert_eq!(db[features[0].peptide_idx].to_string(), "AQLTQLK");
+        assert!(features[0].hyperscore < features[1].hyperscore);
+        assert_eq!(
+            features[0].delta_next,
+            features[0].andromeda - features[1].andromeda
+        );
+
+        // Re-ranking merged PSMs uses the same score
+        let merged = merge_psms(features.clone(), 1, RankingScore::Andromeda);
+        assert_eq!(merged[0].peptide_idx, features[0].peptide_idx);
+        let merged = merge_psms(features, 1, RankingScore::Hyperscore);
+        assert_eq!(db[merged[0].peptide_idx].to_string(), "EQALLK");
+    }
+
+    #[test]
+    fn equal_scoring_candidates() {
+        // Isobaric peptides with identical fragment ions, in either protein order
+        for fasta in [
+            ">sp|AAAAA\nMKLESLIEKR\n>sp|BBBBB\nMKIESLIEKR",
+            ">sp|BBBBB\nMKIESLIEKR\n>sp|AAAAA\nMKLESLIEKR",
+        ] {
+            let db = Builder {
+                fasta: Some("none".into()),
+                ..Default::default()
+            }
+            .make_parameters()
+            .build(Fasta::parse(fasta.into(), "rev_", false));
+            let ix = |sequence: &[u8]| {
+                db.peptides
+                    .iter()
+                    .position(|p| p.sequence.as_ref() == sequence)
+                    .map(|ix| PeptideIx(ix as u32))
+                    .unwrap()
+            };
+            let (first, second) = match ix(b"LESLIEK") < ix(b"IESLIEK") {
+                true => (ix(b"LESLIEK"), ix(b"IESLIEK")),
+                false => (ix(b"IESLIEK"), ix(b"LESLIEK")),
+            };
+
+            let peptide = &db[first];
+            let mut peaks = IonSeries::new(peptide, Kind::B)
+                .chain(IonSeries::new(peptide, Kind::Y))
+                .map(|ion| Peak {
+                    mass: ion.monoisotopic_mass,
+                    intensity: 100.0,
+                })
+                .collect::<Vec<_>>();
+            peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+            let spectrum = ProcessedSpectrum {
+                level: 2,
+                id: "scan=1".into(),
+                precursors: vec![Precursor {
+                    mz: peptide.monoisotopic / 2.0 + PROTON,
+                    charge: Some(2),
+                    ..Default::default()
+                }],
+                total_ion_current: 100.0 * peaks.len() as f32,
+                peaks,
+                ..Default::default()
+            };
+
+            let scorer = Scorer {
+                report_psms: 2,
+                ..test_scorer(&db)
+            };
+            let features = scorer.score(&spectrum);
+            assert_eq!(features.len(), 2);
+            assert_eq!(features[0].hyperscore, features[1].hyperscore);
+            assert_eq!(features[0].peptide_idx, first);
+            assert_eq!(features[1].peptide_idx, second);
+
+            // Merging separately scored PSMs gives the same winner
+            let merged = merge_psms(
+                features.into_iter().rev().collect(),
+                1,
+                RankingScore::Hyperscore,
+            );
+            assert_eq!(merged.len(), 1);
+            assert_eq!(merged[0].peptide_idx, first);
+        }
+    }
+
+    #[test]
+    fn neutral_mass_precursor_window() {
+        use crate::mass::Adduct;
+
+        let db = test_db();
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        let scorer = |precursor_tol| Scorer {
+            precursor_tol,
+            min_precursor_charge: 1,
+            max_precursor_charge: 4,
+            ..test_scorer(&db)
+        };
+        let mut peaks = IonSeries::new(peptide, Kind::Y)
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        // Precursor of charge `z` with a neutral mass offset by `ppm` from the peptide
+        let spectrum = |z: u8, ppm: f32, adduct: Adduct| ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic * (1.0 + ppm / 1E6) / z as f32 + adduct.mass(),
+                charge: Some(z),
+                adduct,
+                ..Default::default()
+            }],
+            peaks: peaks.clone(),
+            ..Default::default()
+        };
+
+        // Tolerances are applied to the neutral mass, after removing the charge
+        // carriers - so the window is the same at every charge state
+        let ppm = scorer(Tolerance::Ppm(-10.0, 10.0));
+        let da = scorer(Tolerance::Da(-0.5, 0.5));
+        for z in 1..=4 {
+            for adduct in [Adduct::Proton, Adduct::Sodium] {
+                let query = spectrum(z, 0.0, adduct);
+                let (lo, hi) = ppm.precursor_mass_bounds(&query);
+                assert!((lo - peptide.monoisotopic * (1.0 - 10E-6)).abs() < 1E-3);
+                assert!((hi - peptide.monoisotopic * (1.0 + 10E-6)).abs() < 1E-3);
+                let (lo, hi) = da.precursor_mass_bounds(&query);
+                assert!((lo - (peptide.monoisotopic - 0.5)).abs() < 1E-3);
+                assert!((hi - (peptide.monoisotopic + 0.5)).abs() < 1E-3);
+
+                // Just inside and outside of the ppm window of the neutral mass
+                assert_eq!(ppm.score(&spectrum(z, 8.0, adduct)).len(), 1);
+                assert!(ppm.score(&spectrum(z, 12.0, adduct)).is_empty());
+                assert!(ppm.score(&spectrum(z, -12.0, adduct)).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn precursor_mass_bounds_of_all_precursors() {
+        let fasta = Fasta::parse(">sp|AAAAA\nEQALLKLEQSMRAQLTQLK".into(), "rev_", false);
+        let partitioned = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build_partitioned(fasta, 2);
+        assert_eq!(partitioned.len(), 2);
+        let (first_lo, _) = partitioned.mass_range(0);
+        let (_, second_hi) = partitioned.mass_range(1);
+
+        // One precursor in each partition
+        let precursor = |mass: f32| Precursor {
+            mz: mass / 2.0 + PROTON,
+            charge: Some(2),
+            ..Default::default()
+        };
+        let mut query = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![precursor(first_lo)],
+            ..Default::default()
+        };
+        let scorer = test_scorer(&partitioned);
+        let overlaps = |query: &ProcessedSpectrum, partition: usize| {
+            let (lo, hi) = partitioned.mass_range(partition);
+            let (precursor_lo, precursor_hi) = scorer.precursor_mass_bounds(query);
+            precursor_hi >= lo && precursor_lo <= hi
+        };
+        assert!(overlaps(&query, 0));
+        assert!(!overlaps(&query, 1));
+
+        // Spectra are routed to the partitions of all of their precursors
+        query.precursors.push(precursor(second_hi));
+        assert!(overlaps(&query, 0));
+        assert!(overlaps(&query, 1));
+        let (lo, hi) = scorer.precursor_mass_bounds(&query);
+        assert!((lo - first_lo * (1.0 - 10E-6)).abs() < 1E-3);
+        assert!((hi - second_hi * (1.0 + 10E-6)).abs() < 1E-3);
+    }
+
+    #[test]
+    fn deduplicated_fragment_charges() {
+        let db = test_db();
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        // Every y ion is present at both charge 1 and charge 2
+        let mut peaks = IonSeries::new(peptide, Kind::Y)
+            .flat_map(|ion| {
+                [1.0, 2.0].map(|charge| Peak {
+                    mass: ion.monoisotopic_mass / charge,
+                    intensity: 100.0,
+                })
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 3.0 + PROTON,
+                charge: Some(3),
+                ..Default::default()
+            }],
+            total_ion_current: 100.0 * peaks.len() as f32,
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = |dedup_fragment_charges, fragment_charge_offset| Scorer {
+            min_precursor_charge: 3,
+            max_precursor_charge: 3,
+            max_fragment_charge: Some(2),
+            dedup_fragment_charges,
+            fragment_charge_offset,
+            ..test_scorer(&db)
+        };
+
+        let all = scorer(false, 1).score(&spectrum);
+        let dedup = scorer(true, 1).score(&spectrum);
+        let ions = IonSeries::new(peptide, Kind::Y).count() as u32;
+        assert_eq!(all[0].matched_peaks, 2 * ions);
+        assert_eq!(dedup[0].matched_peaks, ions);
+        assert_eq!(dedup[0].longest_y, all[0].longest_y);
+
+        // Intensity of both charge states still contributes
+        assert_eq!(dedup[0].matched_intensity_pct, 100.0);
+        assert_eq!(dedup[0].matched_intensity_pct, all[0].matched_intensity_pct);
+        assert!(dedup[0].hyperscore < all[0].hyperscore);
+
+        // Doubly charged fragments are only searched for a 2+ precursor if
+        // fragments up to the precursor charge are allowed
+        let doubly = ProcessedSpectrum {
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            ..spectrum.clone()
+        };
+        assert_eq!(scorer(false, 1).score(&doubly)[0].matched_peaks, ions);
+        assert_eq!(scorer(false, 0).score(&doubly)[0].matched_peaks, 2 * ions);
+    }
+
+    #[test]
+    fn isotope_error_penalty() {
+        let db = test_db();
+        let peptide = test_peptide(&db, "AQLTQLK");
+
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 100.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        // Precursor selected at the given isotope
+        let spectrum = |isotope: f32| ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            precursors: vec![Precursor {
+                mz: (peptide.monoisotopic + isotope * NEUTRON) / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            total_ion_current: 100.0 * peaks.len() as f32,
+            peaks: peaks.clone(),
+            ..Default::default()
+        };
+
+        let scorer = |isotope_error_penalty| Scorer {
+            min_isotope_err: -1,
+            max_isotope_err: 2,
+            isotope_error_penalty,
+            max_fragment_charge: None,
+            ..test_scorer(&db)
+        };
+
+        let exact = scorer(0.0).score(&spectrum(0.0));
+        let baseline = scorer(0.0).score(&spectrum(2.0));
+        let penalized = scorer(0.5).score(&spectrum(2.0));
+        assert_eq!(exact[0].isotope_error, 0.0);
+        assert!((baseline[0].isotope_error - 2.0 * NEUTRON).abs() < 1E-4);
+        assert_eq!(penalized[0].isotope_error, baseline[0].isotope_error);
+        assert_eq!(baseline[0].hyperscore, exact[0].hyperscore);
+        assert!((baseline[0].hyperscore - penalized[0].hyperscore - 1.0).abs() < 1E-6);
+
+        // Exact matches are not penalized, and negative errors by magnitude
+        assert_eq!(
+            scorer(0.5).score(&spectrum(0.0))[0].hyperscore,
+            exact[0].hyperscore
+        );
+        assert!(
+            (exact[0].hyperscore - scorer(0.5).score(&spectrum(-1.0))[0].hyperscore - 0.5).abs()
+                < 1E-6
+        );
+    }
+
     #[test]
     fn test_max_fragment_charge() {
-        assert_eq!(max_fragment_charge(None, 1), 2);
-        assert_eq!(max_fragment_charge(None, 2), 2);
-        assert_eq!(max_fragment_charge(None, 3), 3);
-        assert_eq!(max_fragment_charge(None, 4), 4);
-        assert_eq!(max_fragment_charge(Some(1), 2), 2);
-        assert_eq!(max_fragment_charge(Some(1), 3), 2);
-        assert_eq!(max_fragment_charge(Some(2), 4), 3);
-        assert_eq!(max_fragment_charge(Some(4), 1), 2);
+        assert_eq!(max_fragment_charge(None, 1, 1), 2);
+        assert_eq!(max_fragment_charge(None, 2, 1), 2);
+        assert_eq!(max_fragment_charge(None, 3, 1), 3);
+        assert_eq!(max_fragment_charge(None, 4, 1), 4);
+        assert_eq!(max_fragment_charge(Some(1), 2, 1), 2);
+        assert_eq!(max_fragment_charge(Some(1), 3, 1), 2);
+        assert_eq!(max_fragment_charge(Some(2), 4, 1), 3);
+        assert_eq!(max_fragment_charge(Some(4), 1, 1), 2);
+
+        // Fragments up to the precursor charge
+        assert_eq!(max_fragment_charge(None, 1, 0), 2);
+        assert_eq!(max_fragment_charge(None, 3, 0), 4);
+        assert_eq!(max_fragment_charge(Some(2), 4, 0), 3);
+        // Fragments up to 2 charges below the precursor charge
+        assert_eq!(max_fragment_charge(None, 2, 2), 2);
+        assert_eq!(max_fragment_charge(None, 4, 2), 3);
+        // No overflow for extreme charges
+        assert_eq!(max_fragment_charge(None, u8::MAX, 0), u8::MAX);
+        assert_eq!(max_fragment_charge(Some(u8::MAX), u8::MAX, 1), u8::MAX - 1);
     }
 }
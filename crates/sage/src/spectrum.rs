@@ -1,5 +1,10 @@
 use crate::database::binary_search_slice;
-use crate::mass::{Tolerance, NEUTRON, PROTON};
+use crate::ion_series::Kind;
+use crate::mass::{Adduct, Tolerance, NEUTRON, PROTON};
+use fnv::FnvHashMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 /// A charge-less peak at monoisotopic mass
 #[derive(PartialEq, Copy, Clone, Default, Debug)]
@@ -36,6 +41,84 @@ pub struct Deisotoped {
     pub envelope: Option<usize>,
 }
 
+/// Intensity thresholds used to remove low-intensity noise peaks from MS2 spectra.
+///
+/// Peaks must pass all thresholds to be retained. A value of 0 disables a threshold.
+/// Thresholds are applied to the raw peak list, *before* deisotoping and selection
+/// of the `take_top_n` most intense peaks - so they can only shrink the number of
+/// peaks that are searched, never replace discarded peaks with less intense ones
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NoiseFilter {
+    /// Drop peaks with an absolute intensity below this value
+    pub min_intensity: f32,
+    /// Drop peaks with an intensity below this percentage of the base peak
+    pub min_base_peak_pct: f32,
+    /// Drop peaks with a signal-to-noise ratio below this value. The noise level
+    /// is estimated as the median peak intensity of the spectrum
+    pub min_signal_to_noise: f32,
+}
+
+impl NoiseFilter {
+    /// Calculate the minimum intensity a peak must have to be retained, given
+    /// all peak intensities of a spectrum
+    pub fn threshold(&self, intensity: &[f32]) -> f32 {
+        let mut threshold = self.min_intensity;
+        if self.min_base_peak_pct > 0.0 {
+            let base_peak = intensity.iter().copied().fold(0.0f32, f32::max);
+            threshold = threshold.max(base_peak * self.min_base_peak_pct / 100.0);
+        }
+        if self.min_signal_to_noise > 0.0 && !intensity.is_empty() {
+            let mut sorted = intensity.to_vec();
+            sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+            let mid = sorted.len() / 2;
+            let noise = match sorted.len() % 2 {
+                0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+                _ => sorted[mid],
+            };
+            threshold = threshold.max(noise * self.min_signal_to_noise);
+        }
+        threshold
+    }
+}
+
+/// Remove the unfragmented precursor and its isotope envelope from MS2 spectra.
+///
+/// For each of the monoisotopic peak and the following `isotopes` isotopes
+/// (spaced by [`NEUTRON`] / charge), only the single peak closest to the expected
+/// m/z within `tolerance` is removed, and the envelope ends at the first missing
+/// isotope - so fragment peaks close to the precursor are retained. If the
+/// precursor charge is unknown, only the monoisotopic peak is removed
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PrecursorRemoval {
+    /// Number of isotopes following the monoisotopic peak to remove
+    pub isotopes: u8,
+    pub tolerance: Tolerance,
+}
+
+impl PrecursorRemoval {
+    /// Indices of the peaks in `mz` (sorted) attributed to `precursor`
+    pub fn precursor_peaks(&self, mz: &[f32], precursor: &Precursor) -> Vec<usize> {
+        let (charge, isotopes) = match precursor.charge {
+            Some(charge) if charge > 0 => (charge, self.isotopes),
+            _ => (1, 0),
+        };
+        let mut removed = Vec::new();
+        for isotope in 0..=isotopes {
+            let center = precursor.mz + isotope as f32 * NEUTRON / charge as f32;
+            let (lo, hi) = self.tolerance.bounds(center);
+            let (i, j) = binary_search_slice(mz, |mz, query| mz.total_cmp(query), lo, hi);
+            let closest = (i..j)
+                .filter(|&idx| mz[idx] >= lo && mz[idx] <= hi)
+                .min_by(|&a, &b| (mz[a] - center).abs().total_cmp(&(mz[b] - center).abs()));
+            match closest {
+                Some(idx) => removed.push(idx),
+                None => break,
+            }
+        }
+        removed
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SpectrumProcessor {
     pub take_top_n: usize,
@@ -43,6 +126,10 @@ pub struct SpectrumProcessor {
     pub min_fragment_mz: f32,
     pub min_deisotope_mz: f32,
     pub deisotope: bool,
+    /// Intensity thresholds applied to MS2 spectra, disabled by default
+    pub noise_filter: NoiseFilter,
+    /// Remove the precursor isotope envelope from MS2 spectra, disabled by default
+    pub precursor_removal: Option<PrecursorRemoval>,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -54,6 +141,43 @@ pub struct Precursor {
     pub spectrum_ref: Option<String>,
     pub isolation_window: Option<Tolerance>,
     pub inverse_ion_mobility: Option<f32>,
+    /// Charge carrier used to convert `mz` to a neutral mass
+    pub adduct: Adduct,
+    /// Candidate charge states for precursors with an ambiguous charge state
+    /// (e.g. "possible charge state" in mzML, or `CHARGE=2+ and 3+` in MGF).
+    /// All candidates are searched, and the best scoring charge is reported
+    pub possible_charges: Vec<u8>,
+}
+
+impl Precursor {
+    /// Charge states to search this precursor at: all possible charges within
+    /// `min_charge..=max_charge` (and the annotated charge) if there are any,
+    /// otherwise the annotated charge, falling back to `min_charge..=max_charge`
+    /// if no charge is known
+    pub fn candidate_charges(&self, min_charge: u8, max_charge: u8) -> Vec<u8> {
+        let mut charges = self
+            .possible_charges
+            .iter()
+            .copied()
+            .filter(|charge| (min_charge..=max_charge).contains(charge))
+            .collect::<Vec<_>>();
+        if !charges.is_empty() {
+            charges.extend(self.charge);
+            charges.sort_unstable();
+            charges.dedup();
+            return charges;
+        }
+        match self.charge {
+            Some(charge) => vec![charge],
+            None => (min_charge..=max_charge).collect(),
+        }
+    }
+
+    /// Precursor m/z with the mass of the charge carrier removed, i.e. the
+    /// neutral mass divided by charge: [M] instead of [M+H]
+    pub fn neutral_mz(&self) -> f32 {
+        self.mz - self.adduct.mass()
+    }
 }
 
 #[derive(Clone, Default, Debug)]
@@ -68,12 +192,21 @@ pub struct ProcessedSpectrum {
     pub scan_start_time: f32,
     /// Ion injection time
     pub ion_injection_time: f32,
+    /// FAIMS compensation voltage, if acquired with a FAIMS device
+    pub faims_cv: Option<f32>,
+    /// Fragmentation method, if reported by the parser
+    pub activation: Option<Activation>,
     /// Selected ions for precursors, if `level > 1`
     pub precursors: Vec<Precursor>,
     /// MS peaks, sorted by mass in ascending order
     pub peaks: Vec<Peak>,
-    /// Total ion current
+    /// Total ion current, i.e. summed intensity of `peaks`
     pub total_ion_current: f32,
+    /// Intensity of the most intense peak
+    pub base_peak_intensity: f32,
+    /// Ratio of interfering to precursor MS1 intensity within the isolation
+    /// window, see [`assign_chimericity`]
+    pub chimericity: Option<f32>,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -93,6 +226,10 @@ pub struct RawSpectrum {
     pub scan_start_time: f32,
     /// Ion injection time
     pub ion_injection_time: f32,
+    /// FAIMS compensation voltage, if acquired with a FAIMS device
+    pub faims_cv: Option<f32>,
+    /// Fragmentation method, if reported
+    pub activation: Option<Activation>,
     /// Total ion current
     pub total_ion_current: f32,
     /// M/z array
@@ -124,6 +261,50 @@ impl Default for Representation {
     }
 }
 
+/// Fragmentation (activation) method of an MSn spectrum
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activation {
+    /// Collision-induced dissociation (resonance/ion trap)
+    Cid,
+    /// Beam-type collision-induced dissociation
+    Hcd,
+    /// Electron-transfer dissociation
+    Etd,
+    /// Electron-capture dissociation
+    Ecd,
+    /// Electron-transfer dissociation with supplemental collisional activation
+    EThcd,
+}
+
+impl Activation {
+    fn is_collisional(self) -> bool {
+        matches!(self, Activation::Cid | Activation::Hcd)
+    }
+
+    /// Combine two activation methods reported for the same precursor, e.g.
+    /// ETD with supplemental HCD activation is reported as EThcD
+    pub fn combine(self, other: Activation) -> Activation {
+        match (self, other) {
+            (Activation::EThcd, _) | (_, Activation::EThcd) => Activation::EThcd,
+            (a, b) if a.is_collisional() != b.is_collisional() => Activation::EThcd,
+            (a, _) => a,
+        }
+    }
+
+    /// Should fragment ions of `kind` be expected for this activation method?
+    /// Collisional activation cleaves the peptide bond (a/b/x/y ions), while
+    /// electron-based activation cleaves the N-Cα bond (c/z ions)
+    pub fn produces(self, kind: Kind) -> bool {
+        match self {
+            Activation::Cid | Activation::Hcd => {
+                matches!(kind, Kind::A | Kind::B | Kind::X | Kind::Y)
+            }
+            Activation::Etd | Activation::Ecd => matches!(kind, Kind::C | Kind::Z),
+            Activation::EThcd => true,
+        }
+    }
+}
+
 /// Binary search followed by linear search to select the most intense peak within `tolerance` window
 /// * `offset` - this parameter allows for a static adjustment to the lower and upper bounds of the search window.
 ///     Sage subtracts a proton (and assumes z=1) for all experimental peaks, and stores all fragments as monoisotopic
@@ -159,6 +340,57 @@ pub fn select_most_intense_peak(
     best_peak
 }
 
+/// How to choose between multiple observed peaks within the tolerance window
+/// of a theoretical fragment
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeakSelection {
+    /// Select the most intense peak
+    #[default]
+    MostIntense,
+    /// Select the peak closest in m/z to the theoretical fragment
+    Closest,
+}
+
+/// Select a peak within `tolerance` of `center` according to `selection`,
+/// returning its index into `peaks`.
+///
+/// Peaks flagged in `claimed` (indexed like `peaks`) have already been matched
+/// to another theoretical fragment, and are skipped - the next best peak within
+/// the window is selected instead. This allows callers to ensure that each
+/// observed peak is counted for at most one theoretical fragment
+pub fn select_peak(
+    peaks: &[Peak],
+    center: f32,
+    tolerance: Tolerance,
+    selection: PeakSelection,
+    claimed: Option<&[bool]>,
+) -> Option<usize> {
+    let (lo, hi) = tolerance.bounds(center);
+    let (i, j) = binary_search_slice(peaks, |peak, query| peak.mass.total_cmp(query), lo, hi);
+
+    let mut best: Option<usize> = None;
+    for (idx, peak) in peaks[i..j].iter().enumerate() {
+        let idx = i + idx;
+        if peak.mass < lo || peak.mass > hi || claimed.map_or(false, |claimed| claimed[idx]) {
+            continue;
+        }
+        // For consistency with `select_most_intense_peak`, the last of several
+        // equally intense peaks is selected
+        let better = match (selection, best.map(|best| &peaks[best])) {
+            (_, None) => true,
+            (PeakSelection::MostIntense, Some(best)) => peak.intensity >= best.intensity,
+            (PeakSelection::Closest, Some(best)) => {
+                (peak.mass - center).abs() < (best.mass - center).abs()
+            }
+        };
+        if better {
+            best = Some(idx);
+        }
+    }
+    best
+}
+
 // pub fn find_spectrum_by_id(
 //     spectra: &[ProcessedSpectrum],
 //     scan_id: usize,
@@ -243,7 +475,7 @@ impl ProcessedSpectrum {
     pub fn extract_ms1_precursor(&self) -> Option<(f32, u8)> {
         let precursor = self.precursors.get(0)?;
         let charge = precursor.charge?;
-        let mass = (precursor.mz - PROTON) * charge as f32;
+        let mass = precursor.neutral_mz() * charge as f32;
         Some((mass, charge))
     }
 
@@ -252,6 +484,80 @@ impl ProcessedSpectrum {
         let (lo, hi) = precursor.isolation_window?.bounds(precursor.mz - PROTON);
         Some(mz >= lo && mz <= hi)
     }
+
+    /// Does this spectrum contain a singly charged ion at `mz`, within
+    /// `tolerance`? E.g. to detect immonium or diagnostic ions, see
+    /// [`crate::mass::immonium`] and [`crate::mass::DIAGNOSTIC_IONS`]. Note
+    /// that peaks below `min_fragment_mz` are removed during processing
+    pub fn contains_ion(&self, mz: f32, tolerance: Tolerance) -> bool {
+        select_peak(
+            &self.peaks,
+            mz - PROTON,
+            tolerance,
+            PeakSelection::default(),
+            None,
+        )
+        .is_some()
+    }
+
+    /// Transform this spectrum into a control spectrum that should not be
+    /// identified, e.g. to check that a search yields (almost) no confident
+    /// identifications on nonsense input. Precursors and intensities are kept.
+    ///
+    /// Returns `None` for [`ControlSpectrum::Reflect`] if the precursor
+    /// charge (and thus mass) is unknown
+    pub fn scrambled<R: Rng>(
+        &self,
+        control: ControlSpectrum,
+        rng: &mut R,
+    ) -> Option<ProcessedSpectrum> {
+        let mut peaks = match control {
+            ControlSpectrum::Reflect => {
+                let (mass, _) = self.extract_ms1_precursor()?;
+                self.peaks
+                    .iter()
+                    .map(|peak| Peak {
+                        mass: mass - peak.mass - PROTON,
+                        intensity: peak.intensity,
+                    })
+                    .filter(|peak| peak.mass > 0.0)
+                    .collect::<Vec<_>>()
+            }
+            ControlSpectrum::Randomize => {
+                let lo = self.peaks.first().map(|peak| peak.mass).unwrap_or(0.0);
+                let hi = self.peaks.last().map(|peak| peak.mass).unwrap_or(0.0);
+                self.peaks
+                    .iter()
+                    .map(|peak| Peak {
+                        mass: rng.gen_range(lo..=hi),
+                        intensity: peak.intensity,
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let (total_ion_current, base_peak_intensity) = ion_current(&peaks);
+        Some(ProcessedSpectrum {
+            peaks,
+            total_ion_current,
+            base_peak_intensity,
+            ..self.clone()
+        })
+    }
+}
+
+/// Transformation of a [`ProcessedSpectrum`] into a null control spectrum,
+/// see [`ProcessedSpectrum::scrambled`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ControlSpectrum {
+    /// Reflect fragment m/z about the precursor [M+H]: `[M+H] - m/z`. For
+    /// singly charged fragments, b + y = [M+H] + H, so reflected peaks are
+    /// offset from complementary ions by one proton mass, and do not match them
+    Reflect,
+    /// Replace fragment masses with uniformly distributed random masses,
+    /// within the mass range of the original peaks
+    Randomize,
 }
 
 impl SpectrumProcessor {
@@ -275,6 +581,8 @@ impl SpectrumProcessor {
             max_fragment_mz,
             min_deisotope_mz,
             deisotope,
+            noise_filter: NoiseFilter::default(),
+            precursor_removal: None,
         }
     }
 
@@ -294,14 +602,43 @@ impl SpectrumProcessor {
             .and_then(|p| p.charge)
             .unwrap_or(3);
 
+        // Remove noise peaks before any other processing takes place
+        let threshold = self.noise_filter.threshold(&spectrum.intensity);
+        let (mut mz, mut intensity): (Cow<[f32]>, Cow<[f32]>) = match threshold > 0.0 {
+            true => {
+                let (mz, intensity): (Vec<f32>, Vec<f32>) = spectrum
+                    .mz
+                    .iter()
+                    .zip(spectrum.intensity.iter())
+                    .filter(|&(_, int)| *int >= threshold)
+                    .unzip();
+                (mz.into(), intensity.into())
+            }
+            false => (
+                spectrum.mz.as_slice().into(),
+                spectrum.intensity.as_slice().into(),
+            ),
+        };
+
+        if let (Some(removal), Some(precursor)) =
+            (self.precursor_removal, spectrum.precursors.first())
+        {
+            let removed = removal.precursor_peaks(&mz, precursor);
+            if !removed.is_empty() {
+                let (kept_mz, kept_intensity): (Vec<f32>, Vec<f32>) = mz
+                    .iter()
+                    .zip(intensity.iter())
+                    .enumerate()
+                    .filter(|(idx, _)| !removed.contains(idx))
+                    .map(|(_, (mz, intensity))| (*mz, *intensity))
+                    .unzip();
+                mz = kept_mz.into();
+                intensity = kept_intensity.into();
+            }
+        }
+
         if should_deisotope {
-            let mut peaks = deisotope(
-                &spectrum.mz,
-                &spectrum.intensity,
-                charge,
-                10.0,
-                self.min_deisotope_mz,
-            );
+            let mut peaks = deisotope(&mz, &intensity, charge, 10.0, self.min_deisotope_mz);
             peaks.sort_unstable_by(|a, b| {
                 b.intensity
                     .total_cmp(&a.intensity)
@@ -326,10 +663,9 @@ impl SpectrumProcessor {
                 .take(self.take_top_n)
                 .collect::<Vec<Peak>>()
         } else {
-            let mut peaks = spectrum
-                .mz
+            let mut peaks = mz
                 .iter()
-                .zip(spectrum.intensity.iter())
+                .zip(intensity.iter())
                 .filter(|&(mz, _)| *mz >= self.min_fragment_mz && *mz <= self.max_fragment_mz)
                 .map(|(mz, &intensity)| {
                     let mass = (mz - PROTON) * 1.0;
@@ -357,7 +693,7 @@ impl SpectrumProcessor {
         };
 
         peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
-        let total_ion_current = peaks.iter().map(|peak| peak.intensity).sum::<f32>();
+        let (total_ion_current, base_peak_intensity) = ion_current(&peaks);
 
         ProcessedSpectrum {
             level: spectrum.ms_level,
@@ -365,13 +701,118 @@ impl SpectrumProcessor {
             file_id: spectrum.file_id,
             scan_start_time: spectrum.scan_start_time,
             ion_injection_time: spectrum.ion_injection_time,
+            faims_cv: spectrum.faims_cv,
+            activation: spectrum.activation,
             precursors: spectrum.precursors,
             peaks,
             total_ion_current,
+            base_peak_intensity,
+            chimericity: None,
         }
     }
 }
 
+/// Number of isotopes (following the monoisotopic peak) attributed to the
+/// precursor when calculating [`chimericity`]
+const CHIMERICITY_ISOTOPES: u8 = 3;
+
+/// Ratio of interfering intensity to precursor intensity within the isolation
+/// window of `precursor`, measured in the MS1 spectrum `ms1`.
+///
+/// Peaks within `tol` of the precursor or one of its first few isotopes are
+/// attributed to the precursor (only the monoisotopic peak, if the precursor
+/// charge is unknown), and all other peaks within the isolation window are
+/// considered interfering. Returns `None` if the precursor has no isolation
+/// window, or if no precursor peak was found
+pub fn chimericity(ms1: &ProcessedSpectrum, precursor: &Precursor, tol: Tolerance) -> Option<f32> {
+    let center = precursor.mz - PROTON;
+    let (lo, hi) = precursor.isolation_window?.bounds(center);
+    let (charge, isotopes) = match precursor.charge {
+        Some(charge) => (charge.max(1) as f32, CHIMERICITY_ISOTOPES),
+        None => (1.0, 0),
+    };
+
+    let start = ms1.peaks.partition_point(|peak| peak.mass < lo);
+    let (mut signal, mut interference) = (0.0f32, 0.0f32);
+    for peak in ms1.peaks[start..].iter().take_while(|peak| peak.mass <= hi) {
+        let isotope = (0..=isotopes).any(|isotope| {
+            let (lo, hi) = tol.bounds(center + isotope as f32 * NEUTRON / charge);
+            peak.mass >= lo && peak.mass <= hi
+        });
+        match isotope {
+            true => signal += peak.intensity,
+            false => interference += peak.intensity,
+        }
+    }
+    (signal > 0.0).then(|| interference / signal)
+}
+
+/// Calculate the [`chimericity`] of the first precursor of each MSn spectrum
+/// in `spectra`, using the MS1 spectrum referenced by the precursor if there
+/// is one, and otherwise the closest preceding MS1 spectrum of the same file
+/// (in the order of `spectra`)
+pub fn assign_chimericity(spectra: &mut [ProcessedSpectrum], tol: Tolerance) {
+    let ms1 = spectra
+        .iter()
+        .enumerate()
+        .filter(|(_, spectrum)| spectrum.level == 1)
+        .map(|(idx, spectrum)| ((spectrum.file_id, spectrum.id.as_str()), idx))
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut preceding = FnvHashMap::default();
+    let mut values = Vec::with_capacity(spectra.len());
+    for (idx, spectrum) in spectra.iter().enumerate() {
+        if spectrum.level == 1 {
+            preceding.insert(spectrum.file_id, idx);
+            values.push(None);
+            continue;
+        }
+        values.push(spectrum.precursors.first().and_then(|precursor| {
+            let parent = precursor
+                .spectrum_ref
+                .as_deref()
+                .and_then(|id| ms1.get(&(spectrum.file_id, id)))
+                .or_else(|| preceding.get(&spectrum.file_id))?;
+            chimericity(&spectra[*parent], precursor, tol)
+        }));
+    }
+
+    for (spectrum, value) in spectra.iter_mut().zip(values) {
+        spectrum.chimericity = value;
+    }
+}
+
+/// Identifier of the spectrum that each MSn spectrum in `spectra` was acquired
+/// from, e.g. the MS2 scan of an SPS-MS3 spectrum: the scan referenced by its
+/// first precursor if there is one, and otherwise the closest preceding MSn-1
+/// spectrum of the same file (in the order of `spectra`). MS1 spectra have no
+/// parent
+pub fn parent_scans(spectra: &[ProcessedSpectrum]) -> Vec<Option<&str>> {
+    let mut preceding = FnvHashMap::default();
+    spectra
+        .iter()
+        .map(|spectrum| {
+            let parent = match spectrum.level {
+                0 | 1 => None,
+                level => spectrum
+                    .precursors
+                    .first()
+                    .and_then(|precursor| precursor.spectrum_ref.as_deref())
+                    .or_else(|| preceding.get(&(spectrum.file_id, level - 1)).copied()),
+            };
+            preceding.insert((spectrum.file_id, spectrum.level), spectrum.id.as_str());
+            parent
+        })
+        .collect()
+}
+
+/// Total ion current and base peak intensity of `peaks`
+pub(crate) fn ion_current(peaks: &[Peak]) -> (f32, f32) {
+    peaks.iter().fold((0.0, 0.0), |(sum, max), peak| {
+        (sum + peak.intensity, max.max(peak.intensity))
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -526,4 +967,397 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn noise_filter() {
+        let intensity = [1.0, 2.0, 4.0, 10.0, 100.0];
+        assert_eq!(NoiseFilter::default().threshold(&intensity), 0.0);
+
+        let filter = NoiseFilter {
+            min_intensity: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(filter.threshold(&intensity), 3.0);
+
+        let filter = NoiseFilter {
+            min_intensity: 3.0,
+            min_base_peak_pct: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(filter.threshold(&intensity), 5.0);
+
+        // Median intensity is 4.0
+        let filter = NoiseFilter {
+            min_signal_to_noise: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(filter.threshold(&intensity), 8.0);
+
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            representation: Representation::Centroid,
+            mz: vec![200.0, 300.0, 400.0, 500.0, 600.0],
+            intensity: intensity.to_vec(),
+            ..Default::default()
+        };
+        let mut sp = SpectrumProcessor::new(150, 0.0, 2000.0, false, 0.0);
+        sp.noise_filter = filter;
+        let peaks = sp.process(spectrum).peaks;
+        assert_eq!(
+            peaks.iter().map(|peak| peak.intensity).collect::<Vec<_>>(),
+            vec![10.0, 100.0]
+        );
+    }
+
+    #[test]
+    fn ion_current_and_base_peak() {
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            representation: Representation::Centroid,
+            mz: vec![200.0, 300.0, 400.0, 500.0],
+            intensity: vec![10.0, 40.0, 25.0, 5.0],
+            ..Default::default()
+        };
+        let processed = SpectrumProcessor::new(150, 0.0, 2000.0, false, 0.0).process(spectrum);
+        assert_eq!(processed.total_ion_current, 80.0);
+        assert_eq!(processed.base_peak_intensity, 40.0);
+    }
+
+    #[test]
+    fn candidate_charges() {
+        let mut precursor = Precursor::default();
+        assert_eq!(precursor.candidate_charges(2, 4), vec![2, 3, 4]);
+
+        precursor.charge = Some(3);
+        assert_eq!(precursor.candidate_charges(2, 4), vec![3]);
+
+        precursor.possible_charges = vec![4, 2];
+        assert_eq!(precursor.candidate_charges(2, 4), vec![2, 3, 4]);
+
+        precursor.charge = None;
+        assert_eq!(precursor.candidate_charges(1, 6), vec![2, 4]);
+        assert_eq!(precursor.candidate_charges(2, 3), vec![2]);
+        // No possible charge within bounds
+        assert_eq!(precursor.candidate_charges(5, 6), vec![5, 6]);
+    }
+
+    #[test]
+    fn precursor_adducts() {
+        let mass = 1000.0;
+        for adduct in [
+            Adduct::Proton,
+            Adduct::Sodium,
+            Adduct::Ammonium,
+            Adduct::Deprotonation,
+        ] {
+            let spectrum = ProcessedSpectrum {
+                precursors: vec![Precursor {
+                    mz: (mass + 2.0 * adduct.mass()) / 2.0,
+                    charge: Some(2),
+                    adduct,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let (observed, charge) = spectrum.extract_ms1_precursor().unwrap();
+            assert_eq!(charge, 2);
+            assert!((observed - mass).abs() < 1E-3, "{:?} {}", adduct, observed);
+        }
+    }
+
+    #[test]
+    fn competing_fragments() {
+        let peaks = [
+            Peak {
+                mass: 499.999,
+                intensity: 10.0,
+            },
+            Peak {
+                mass: 500.0025,
+                intensity: 100.0,
+            },
+            Peak {
+                mass: 500.1,
+                intensity: 1000.0,
+            },
+        ];
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        // Two theoretical fragments 4 ppm apart, both within tolerance of the first two peaks
+        let fragments = [500.0, 500.002];
+
+        let assign = |selection: PeakSelection| {
+            let mut claimed = vec![false; peaks.len()];
+            fragments
+                .iter()
+                .map(|&mz| {
+                    let idx = select_peak(&peaks, mz, tol, selection, Some(&claimed));
+                    if let Some(idx) = idx {
+                        claimed[idx] = true;
+                    }
+                    idx
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Without tracking claimed peaks, both fragments match the most intense peak
+        for &mz in &fragments {
+            assert_eq!(
+                select_peak(&peaks, mz, tol, PeakSelection::MostIntense, None),
+                Some(1)
+            );
+        }
+        assert_eq!(assign(PeakSelection::MostIntense), vec![Some(1), Some(0)]);
+        assert_eq!(assign(PeakSelection::Closest), vec![Some(0), Some(1)]);
+
+        // All peaks within tolerance have already been claimed
+        let claimed = [true, true, false];
+        assert_eq!(
+            select_peak(
+                &peaks,
+                500.001,
+                tol,
+                PeakSelection::MostIntense,
+                Some(&claimed)
+            ),
+            None
+        );
+        assert_eq!(
+            select_peak(&peaks, 600.0, tol, PeakSelection::Closest, None),
+            None
+        );
+    }
+
+    #[test]
+    fn diagnostic_ions() {
+        use crate::mass::{immonium, DIAGNOSTIC_IONS};
+
+        let (_, py) = DIAGNOSTIC_IONS[0];
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            peaks: vec![
+                Peak {
+                    mass: immonium(b'H').unwrap() - PROTON,
+                    intensity: 10.0,
+                },
+                Peak {
+                    mass: py - PROTON + 0.002,
+                    intensity: 10.0,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let tol = Tolerance::Ppm(-20.0, 20.0);
+        assert!(spectrum.contains_ion(immonium(b'H').unwrap(), tol));
+        assert!(!spectrum.contains_ion(immonium(b'F').unwrap(), tol));
+        assert!(spectrum.contains_ion(py, tol));
+        assert!(!spectrum.contains_ion(py, Tolerance::Ppm(-5.0, 5.0)));
+    }
+
+    #[test]
+    fn precursor_removal() {
+        let spacing = NEUTRON / 2.0;
+        let mz = vec![
+            450.0,
+            500.0 - 0.004,
+            500.0,
+            500.0 + 0.2,
+            500.0 + spacing,
+            500.0 + 2.0 * spacing,
+            500.0 + 3.0 * spacing,
+            700.0,
+        ];
+        let raw = |charge: Option<u8>, mz: Vec<f32>| RawSpectrum {
+            ms_level: 2,
+            representation: Representation::Centroid,
+            precursors: vec![Precursor {
+                mz: 500.0,
+                charge,
+                ..Default::default()
+            }],
+            intensity: vec![100.0; mz.len()],
+            mz,
+            ..Default::default()
+        };
+        let mut sp = SpectrumProcessor::new(150, 0.0, 2000.0, false, 0.0);
+        sp.precursor_removal = Some(PrecursorRemoval {
+            isotopes: 2,
+            tolerance: Tolerance::Ppm(-20.0, 20.0),
+        });
+        let observed = |spectrum: RawSpectrum| {
+            sp.process(spectrum)
+                .peaks
+                .iter()
+                .map(|peak| peak.mass + PROTON)
+                .collect::<Vec<_>>()
+        };
+
+        // Monoisotopic peak and 2 isotopes are removed. The fragment peak
+        // overlapping with the monoisotopic peak and the third isotope are kept
+        assert_eq!(
+            observed(raw(Some(2), mz.clone())),
+            vec![
+                450.0,
+                500.0 - 0.004,
+                500.0 + 0.2,
+                500.0 + 3.0 * spacing,
+                700.0
+            ]
+        );
+
+        // Without a charge state, only the monoisotopic peak is removed
+        assert_eq!(observed(raw(None, mz.clone())).len(), mz.len() - 1);
+
+        // The envelope ends at the first missing isotope
+        let gap = mz
+            .iter()
+            .copied()
+            .filter(|&mz| mz != 500.0 + spacing)
+            .collect::<Vec<_>>();
+        assert_eq!(observed(raw(Some(2), gap.clone())).len(), gap.len() - 1);
+    }
+
+    #[test]
+    fn control_spectra() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        // [M+H] = 1001.0
+        let spectrum = ProcessedSpectrum {
+            level: 2,
+            precursors: vec![Precursor {
+                mz: (1000.0 + 2.0 * PROTON) / 2.0,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks: vec![
+                Peak {
+                    mass: 200.0,
+                    intensity: 10.0,
+                },
+                Peak {
+                    mass: 300.0,
+                    intensity: 20.0,
+                },
+                Peak {
+                    mass: 1200.0,
+                    intensity: 40.0,
+                },
+            ],
+            total_ion_current: 70.0,
+            base_peak_intensity: 40.0,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+
+        // A complementary ion would be at 1000 - 200 = 800 Da. Peaks reflected
+        // below 0 Da are dropped
+        let reflected = spectrum
+            .scrambled(ControlSpectrum::Reflect, &mut rng)
+            .unwrap();
+        let peaks = reflected
+            .peaks
+            .iter()
+            .map(|peak| (peak.mass, peak.intensity))
+            .collect::<Vec<_>>();
+        assert_eq!(peaks, vec![(700.0 - PROTON, 20.0), (800.0 - PROTON, 10.0)]);
+        assert_eq!(reflected.total_ion_current, 30.0);
+        assert_eq!(reflected.base_peak_intensity, 20.0);
+
+        let randomized = spectrum
+            .scrambled(ControlSpectrum::Randomize, &mut rng)
+            .unwrap();
+        assert_eq!(randomized.peaks.len(), 3);
+        assert!(randomized.peaks.windows(2).all(|w| w[0].mass <= w[1].mass));
+        assert!(randomized
+            .peaks
+            .iter()
+            .all(|peak| (200.0..=1200.0).contains(&peak.mass)));
+        assert_eq!(randomized.total_ion_current, 70.0);
+
+        let uncharged = ProcessedSpectrum {
+            precursors: vec![Precursor::default()],
+            ..spectrum
+        };
+        assert!(uncharged
+            .scrambled(ControlSpectrum::Reflect, &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn precursor_chimericity() {
+        let ms1 = |id: &str, peaks: &[(f32, f32)]| ProcessedSpectrum {
+            level: 1,
+            id: id.into(),
+            peaks: peaks
+                .iter()
+                .map(|&(mz, intensity)| Peak {
+                    mass: mz - PROTON,
+                    intensity,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        // Monoisotopic peak and first isotope of a 2+ precursor, an interfering
+        // peak within the isolation window, and another outside of it
+        let clean = ms1(
+            "scan=1",
+            &[
+                (600.0, 100.0),
+                (600.5017, 50.0),
+                (600.8, 30.0),
+                (605.0, 1E4),
+            ],
+        );
+        let noisy = ms1("scan=3", &[(600.0, 10.0), (600.2, 100.0)]);
+
+        let precursor = Precursor {
+            mz: 600.0,
+            charge: Some(2),
+            isolation_window: Some(Tolerance::Da(-1.0, 1.0)),
+            ..Default::default()
+        };
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let ratio = chimericity(&clean, &precursor, tol).unwrap();
+        assert!((ratio - 0.2).abs() < 1E-6);
+
+        // Without a charge state, only the monoisotopic peak is attributed to the precursor
+        let unknown = Precursor {
+            charge: None,
+            ..precursor.clone()
+        };
+        let ratio = chimericity(&clean, &unknown, tol).unwrap();
+        assert!((ratio - 0.8).abs() < 1E-6);
+
+        let no_window = Precursor {
+            isolation_window: None,
+            ..precursor.clone()
+        };
+        assert_eq!(chimericity(&clean, &no_window, tol), None);
+        assert_eq!(chimericity(&ms1("empty", &[]), &precursor, tol), None);
+
+        let ms2 = |file_id, spectrum_ref: Option<&str>| ProcessedSpectrum {
+            level: 2,
+            file_id,
+            precursors: vec![Precursor {
+                spectrum_ref: spectrum_ref.map(String::from),
+                ..precursor.clone()
+            }],
+            ..Default::default()
+        };
+        let mut spectra = vec![
+            clean,
+            ms2(0, None),
+            noisy,
+            ms2(0, None),
+            ms2(0, Some("scan=1")),
+            ms2(1, None),
+        ];
+        assign_chimericity(&mut spectra, tol);
+        let values = spectra.iter().map(|s| s.chimericity).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![None, Some(0.2), None, Some(10.0), Some(0.2), None]
+        );
+    }
 }
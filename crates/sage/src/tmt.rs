@@ -15,7 +15,9 @@ pub enum Isobaric {
     Tmt6,
     Tmt10,
     Tmt11,
+    #[serde(alias = "TmtPro16", alias = "TMTpro16")]
     Tmt16,
+    #[serde(alias = "TmtPro18", alias = "TMTpro18")]
     Tmt18,
     User(Vec<f32>),
 }
@@ -37,25 +39,33 @@ impl Isobaric {
     pub fn modification_mass(&self) -> Option<f32> {
         match self {
             Isobaric::Tmt6 | Isobaric::Tmt10 | Isobaric::Tmt11 => Some(229.162932),
-            Isobaric::Tmt16 => Some(304.2071),
-            Isobaric::Tmt18 => Some(304.2135),
+            Isobaric::Tmt16 | Isobaric::Tmt18 => Some(304.207146),
             Isobaric::User(_) => None,
         }
     }
 
-    /// Return a column name for each tag
-    pub fn headers(&self) -> Vec<String> {
+    /// Return the conventional channel name (e.g. "127N") of each reporter ion,
+    /// or `None` for user-defined reporter ions
+    pub fn channel_names(&self) -> Option<&[&'static str]> {
         match self {
-            Isobaric::User(v) => v
-                .iter()
-                .enumerate()
-                .map(|(idx, _)| format!("user_{}", idx + 1))
-                .collect(),
-            _ => self
+            Isobaric::Tmt6 => Some(&TMT6PLEX_CHANNELS),
+            Isobaric::Tmt10 => Some(&TMT11PLEX_CHANNELS[0..10]),
+            Isobaric::Tmt11 => Some(&TMT11PLEX_CHANNELS),
+            Isobaric::Tmt16 => Some(&TMT18PLEX_CHANNELS[0..16]),
+            Isobaric::Tmt18 => Some(&TMT18PLEX_CHANNELS),
+            Isobaric::User(_) => None,
+        }
+    }
+
+    /// Return a column name for each tag, e.g. "tmt_127N"
+    pub fn headers(&self) -> Vec<String> {
+        match self.channel_names() {
+            Some(names) => names.iter().map(|name| format!("tmt_{}", name)).collect(),
+            None => self
                 .reporter_masses()
                 .iter()
                 .enumerate()
-                .map(|(idx, _)| format!("tmt_{}", idx + 1))
+                .map(|(idx, _)| format!("user_{}", idx + 1))
                 .collect(),
         }
     }
@@ -215,7 +225,18 @@ const TMT11PLEX: [f32; 11] = [
 const TMT18PLEX: [f32; 18] = [
     126.127726, 127.124761, 127.131081, 128.128116, 128.134436, 129.131471, 129.137790, 130.134825,
     130.141145, 131.138180, 131.144500, 132.141535, 132.147855, 133.144890, 133.151210, 134.148245,
-    134.154565, 135.15160,
+    134.154565, 135.151600,
+];
+
+const TMT6PLEX_CHANNELS: [&str; 6] = ["126", "127", "128", "129", "130", "131"];
+
+const TMT11PLEX_CHANNELS: [&str; 11] = [
+    "126", "127N", "127C", "128N", "128C", "129N", "129C", "130N", "130C", "131N", "131C",
+];
+
+const TMT18PLEX_CHANNELS: [&str; 18] = [
+    "126", "127N", "127C", "128N", "128C", "129N", "129C", "130N", "130C", "131N", "131C", "132N",
+    "132C", "133N", "133C", "134N", "134C", "135N",
 ];
 
 /// Search MS/MS and quantify isobaric tag intensities from an SPS-MS3 spectrum
@@ -304,24 +325,27 @@ pub struct TmtQuant {
 /// * `isobaric_labels`: specify label m/zs to be used
 /// * `isobaric_tolerance`: specify label tolerance
 /// * `level`: MSn level to extract isobaric peaks from
+///
+/// MS3 quant is reported under the identifier of the MS2 spectrum each MS3
+/// spectrum was acquired from (see [`spectrum::parent_scans`]), so that it can
+/// be joined to PSMs. MS3 spectra that can't be linked to an MS2 spectrum are
+/// skipped
 pub fn quantify(
     spectra: &[ProcessedSpectrum],
     isobaric_labels: &Isobaric,
     isobaric_tolerance: Tolerance,
     level: u8,
 ) -> Vec<TmtQuant> {
+    let parents = spectrum::parent_scans(spectra);
     spectra
         .par_iter()
-        .filter(|spectrum| spectrum.level == level)
-        .filter_map(|spectrum| {
+        .zip(parents.par_iter())
+        .filter(|(spectrum, _)| spectrum.level == level)
+        .filter_map(|(spectrum, parent)| {
             let spec_id = match level {
                 1 => return None,
                 2 => spectrum.id.clone(),
-                _ => spectrum
-                    .precursors
-                    .first()
-                    .and_then(|precursor| precursor.spectrum_ref.clone())
-                    .unwrap_or_default(),
+                _ => parent.map(String::from)?,
             };
 
             let peaks = find_reporter_ions(
@@ -342,3 +366,82 @@ pub fn quantify(
         })
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tmtpro_channels() {
+        for plex in [Isobaric::Tmt16, Isobaric::Tmt18] {
+            let masses = plex.reporter_masses();
+            let names = plex.channel_names().unwrap();
+            assert_eq!(masses.len(), names.len());
+            assert!(masses.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert_eq!(Isobaric::Tmt16.channel_names().unwrap()[15], "134N");
+        assert_eq!(Isobaric::Tmt18.channel_names().unwrap()[17], "135N");
+        assert!((Isobaric::Tmt18.reporter_masses()[17] - 135.1516).abs() < 1E-4);
+        assert_eq!(
+            Isobaric::Tmt16.modification_mass(),
+            Isobaric::Tmt18.modification_mass()
+        );
+        assert_eq!(Isobaric::Tmt18.headers()[17], "tmt_135N");
+        assert_eq!(Isobaric::User(vec![126.0, 127.0]).headers()[1], "user_2");
+    }
+
+    #[test]
+    fn ms3_quant() {
+        let scan =
+            |level, id: &str, file_id, spectrum_ref: Option<&str>, intensity| ProcessedSpectrum {
+                level,
+                id: id.into(),
+                file_id,
+                precursors: vec![Precursor {
+                    spectrum_ref: spectrum_ref.map(String::from),
+                    ..Default::default()
+                }],
+                peaks: vec![Peak {
+                    mass: TMT6PLEX[0] - PROTON,
+                    intensity,
+                }],
+                ..Default::default()
+            };
+        let spectra = vec![
+            scan(1, "scan=1", 0, None, 1.0),
+            scan(2, "scan=2", 0, Some("scan=1"), 2.0),
+            scan(3, "scan=3", 0, Some("scan=2"), 3.0),
+            scan(2, "scan=4", 0, None, 4.0),
+            // No spectrum reference, linked to the preceding MS2 of the same file
+            scan(3, "scan=5", 0, None, 5.0),
+            // No preceding MS2 in this file
+            scan(3, "scan=1", 1, None, 6.0),
+        ];
+
+        assert_eq!(
+            spectrum::parent_scans(&spectra),
+            vec![
+                None,
+                Some("scan=1"),
+                Some("scan=2"),
+                Some("scan=1"),
+                Some("scan=4"),
+                None
+            ]
+        );
+
+        let tol = Tolerance::Ppm(-20.0, 20.0);
+        let quant = quantify(&spectra, &Isobaric::Tmt6, tol, 3);
+        assert_eq!(quant.len(), 2);
+        assert_eq!(quant[0].spec_id, "scan=2");
+        assert_eq!(quant[0].peaks, vec![3.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(quant[1].spec_id, "scan=4");
+        assert_eq!(quant[1].peaks[0], 5.0);
+
+        let quant = quantify(&spectra, &Isobaric::Tmt6, tol, 2);
+        assert_eq!(
+            quant.iter().map(|q| q.spec_id.as_str()).collect::<Vec<_>>(),
+            vec!["scan=2", "scan=4"]
+        );
+    }
+}
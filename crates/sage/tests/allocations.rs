@@ -0,0 +1,94 @@
+//! Measure heap allocations of repeated database queries, with and without
+//! reusing a `SearchScratch` buffer
+
+use sage_core::database::{Builder, SearchScratch};
+use sage_core::fasta::Fasta;
+use sage_core::ion_series::{IonSeries, Kind};
+use sage_core::mass::{Tolerance, PROTON};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const FASTA: &str = r#"
+>sp|Q99536|VAT1_HUMAN Synaptic vesicle membrane protein VAT-1 homolog OS=Homo sapiens OX=9606 GN=VAT1 PE=1 SV=2
+MSDEREVAEAATGEDASSPPPKTEAASDPQHPAASEGAAAAAASPPLLRCLVLTGFGGYD
+KVKLQSRPAAPPAPGPGQLTLRLRACGLNFADLMARQGLYDRLPPLPVTPGMEGAGVVIA
+VGEGVSDRKAGDRVMVLNRSGMWQEEVTVPSVQTFLIPEAMTFEEAAALLVNYITAYMVL
+FDFGNLQPGHSVLVHMAAGGVGMAAVQLCRTVENVTVFGTASASKHEALKENGVTHPIDY
+HTTDYVDEIKKISPKGVDIVMDPLGGSDTAKGYNLLKPMGKVVTYGMANLLTGPKRNLMA
+LARTWWNQFSVTALQLLQANRAVCGFHLGYLDGEVELVSGVVARLLALYNQGHIKPHIDS
+VWPFEKVADAMKQMQEKKNVGKVLLVPGPEKEN
+"#;
+
+#[test]
+fn scratch_reduces_allocations() {
+    let database = Builder {
+        fasta: Some("static".into()),
+        ..Default::default()
+    }
+    .make_parameters()
+    .build(Fasta::parse(FASTA.into(), "rev_", false));
+
+    // A batch of queries for every peptide, using a wide precursor tolerance so
+    // that each query has many candidates
+    let queries = database
+        .peptides
+        .iter()
+        .map(|peptide| {
+            let fragments = IonSeries::new(peptide, Kind::Y)
+                .map(|ion| ion.monoisotopic_mass + PROTON)
+                .collect::<Vec<_>>();
+            (peptide.monoisotopic, fragments)
+        })
+        .collect::<Vec<_>>();
+    let batch = queries.iter().cycle().take(queries.len() * 10);
+    let precursor_tol = Tolerance::Da(-100.0, 100.0);
+    let fragment_tol = Tolerance::Ppm(-10.0, 10.0);
+
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut matched = 0;
+    for (mass, fragments) in batch.clone() {
+        matched += database
+            .query_mass(*mass, fragments, precursor_tol, fragment_tol)
+            .len();
+    }
+    let allocating = ALLOCATIONS.load(Ordering::Relaxed) - start;
+
+    let mut scratch = SearchScratch::default();
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    let mut reused = 0;
+    for (mass, fragments) in batch.clone() {
+        reused += database
+            .query_mass_with(&mut scratch, *mass, fragments, precursor_tol, fragment_tol)
+            .len();
+    }
+    let scratch_allocations = ALLOCATIONS.load(Ordering::Relaxed) - start;
+
+    let n = batch.count();
+    assert_eq!(matched, reused);
+    assert!(allocating >= n);
+    // Buffers only grow while warming up, i.e. far less than once per query
+    assert!(scratch_allocations * 10 < n);
+}